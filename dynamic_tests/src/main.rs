@@ -13,8 +13,8 @@ use std::thread;
 use std::time::Duration;
 use tokio_test::block_on;
 use url::Url;
-use web_archive::parsing::{ImageResource, Resource};
-use web_archive::{archive, blocking};
+use web_archive::parsing::{ImageResource, Resource, ResourceUrl};
+use web_archive::{archive, blocking, list_resources};
 
 mod pages;
 
@@ -48,7 +48,7 @@ fn main() {
     println!("Server launched!");
 
     // Start running the tests
-    let test_cases = [test_index, test_blog, test_500];
+    let test_cases = [test_index, test_blog, test_500, test_list_resources];
 
     let mut results: Vec<(Mode, &'static str)> =
         Vec::with_capacity(2 * test_cases.len());
@@ -79,7 +79,7 @@ fn test_index(mode: &Mode) -> &'static str {
         a.resource_map
             .get(&Url::parse("http://localhost:8000/style.css").unwrap())
             .unwrap(),
-        &Resource::Css(style().to_string())
+        &Resource::Css(style().into())
     );
     "Index page with CSS"
 }
@@ -99,13 +99,13 @@ fn test_blog(mode: &Mode) -> &'static str {
         a.resource_map
             .get(&Url::parse("http://localhost:8000/style.css").unwrap())
             .unwrap(),
-        &Resource::Css(style().to_string())
+        &Resource::Css(style().into())
     );
     assert_eq!(
         a.resource_map
             .get(&Url::parse("http://localhost:8000/scripts/1.js").unwrap())
             .unwrap(),
-        &Resource::Javascript(js().to_string())
+        &Resource::Javascript(js().into())
     );
     assert_eq!(
         a.resource_map
@@ -163,8 +163,29 @@ fn test_500(mode: &Mode) -> &'static str {
         a.resource_map
             .get(&Url::parse("http://localhost:8000/style.css").unwrap())
             .unwrap(),
-        &Resource::Css(style().to_string())
+        &Resource::Css(style().into())
     );
 
     "Endpoints returning Internal Server Errors"
 }
+
+fn test_list_resources(mode: &Mode) -> &'static str {
+    let u = "http://localhost:8000/pages/blog.html";
+
+    let resources = match mode {
+        Mode::Blocking => {
+            blocking::list_resources(u, Default::default()).unwrap()
+        }
+        Mode::Async => block_on(list_resources(u, Default::default())).unwrap(),
+    };
+
+    assert_eq!(resources.len(), 5);
+    assert!(resources.contains(&ResourceUrl::Css(
+        Url::parse("http://localhost:8000/style.css").unwrap()
+    )));
+    assert!(resources.contains(&ResourceUrl::Image(
+        Url::parse("http://localhost:8000/pages/notfound.jpg").unwrap()
+    )));
+
+    "Listing resources without downloading them"
+}
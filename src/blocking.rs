@@ -22,16 +22,30 @@
 //!
 //! ```
 
+use crate::cookies::CookieJar;
 use crate::error::Error;
-use crate::page_archive::PageArchive;
+use crate::fetcher::{fetched_page, fetched_resource};
+use crate::page_archive::{
+    ArchiveInfo, PageArchive, ResourceInfo, ResourceTimings,
+};
 use crate::parsing::{
-    mimetype_from_response, parse_resource_urls, ImageResource, Resource,
-    ResourceMap, ResourceUrl,
+    build_failed_resource, build_resource, is_downloaded_status, Resource,
+    ResourceMap, ResourceUrl, TextResource,
+};
+use crate::report::{ArchiveReport, ResourceOutcome, ResourceReport};
+use crate::session::{
+    check_login_status, content_type, is_downloaded, sent_headers,
+    DownloadLocks, Fetch, HostLimits, HostSlots, ResourceCache, ResourceQueue,
 };
-use crate::ArchiveOptions;
-use reqwest::{Proxy, StatusCode};
+use crate::wayback::SavePageNowOptions;
+use crate::{client_builder, wayback, ArchiveOptions, FailurePolicy};
+use reqwest::header::{HeaderMap, ACCEPT, AUTHORIZATION};
 use std::convert::TryInto;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime};
 use url::Url;
 
 /// The blocking archive function.
@@ -44,65 +58,680 @@ where
     U: TryInto<Url>,
     <U as TryInto<Url>>::Error: Display,
 {
-    let url: Url = url
-        .try_into()
-        .map_err(|e| Error::ParseError(format!("{}", e)))?;
-
-    // Initialise client
-    let mut client = reqwest::blocking::Client::builder()
-        .use_native_tls()
-        .danger_accept_invalid_certs(options.accept_invalid_certificates)
-        .danger_accept_invalid_hostnames(options.accept_invalid_certificates);
-    if let Some(proxy) = options.proxy {
-        client = client.proxy(Proxy::all(proxy)?);
-    }
-    let client = client.build()?;
-
-    // Fetch the page contents
-    let content = client.get(url.clone()).send()?.text()?;
-
-    // Determine the resources that the page needs
-    let resource_urls = parse_resource_urls(&url, &content);
-    let mut resource_map = ResourceMap::new();
-
-    // Download them
-    for resource_url in resource_urls {
-        use ResourceUrl::*;
-
-        let response = client.get(resource_url.url().clone()).send()?;
-        if response.status() != StatusCode::OK {
-            // Skip any errors
-            println!("Code: {}", response.status());
-            continue;
+    ArchiveSession::new(options)?.archive(url)
+}
+
+/// The blocking function for archiving a page served over a unix domain
+/// socket.
+///
+/// Requests the page at `path` from the HTTP server listening on
+/// `socket`. See [`crate::archive_with_socket`] for details.
+#[cfg(unix)]
+pub fn archive_with_socket<P: Into<std::path::PathBuf>>(
+    socket: P,
+    path: &str,
+    mut options: ArchiveOptions,
+) -> Result<PageArchive, Error> {
+    let fetcher = crate::unix_socket::UnixSocketFetcher::new(socket);
+    let url = fetcher.url(path)?;
+    options.fetchers.insert(0, Arc::new(fetcher));
+    ArchiveSession::new(options)?.archive(url)
+}
+
+/// The blocking function for archiving a page which has already been
+/// requested.
+///
+/// Takes the response to the page request, e.g. one made with the
+/// application's own client, and downloads the page's resources. See
+/// [`crate::archive_from_response`] for details.
+pub fn archive_from_response(
+    response: reqwest::blocking::Response,
+    options: ArchiveOptions,
+) -> Result<PageArchive, Error> {
+    ArchiveSession::new(options)?.archive_from_response(response)
+}
+
+/// The blocking function for archiving a page with a report of what
+/// became of each resource.
+///
+/// See [`crate::archive_with_report`] for details.
+pub fn archive_with_report<U>(
+    url: U,
+    options: ArchiveOptions,
+) -> Result<(PageArchive, ArchiveReport), Error>
+where
+    U: TryInto<Url>,
+    <U as TryInto<Url>>::Error: Display,
+{
+    ArchiveSession::new(options)?.archive_with_report(url)
+}
+
+/// The blocking batch archive function.
+///
+/// Archives each of the URLs with a shared [`ArchiveSession`] on a pool
+/// of up to [`ArchiveOptions::concurrency`] threads, returning the
+/// results in the same order as the URLs. See
+/// [`crate::archive_all`] for details.
+pub fn archive_all<I, U>(
+    urls: I,
+    options: ArchiveOptions,
+) -> Result<Vec<Result<PageArchive, Error>>, Error>
+where
+    I: IntoIterator<Item = U>,
+    U: TryInto<Url> + Send,
+    <U as TryInto<Url>>::Error: Display,
+{
+    Ok(ArchiveSession::new(options)?.archive_all(urls))
+}
+
+/// The blocking resource listing function.
+///
+/// Fetches only the page at the given URL and returns the resources
+/// that [`archive`] would download, without downloading any of them.
+pub fn list_resources<U>(
+    url: U,
+    options: ArchiveOptions,
+) -> Result<Vec<ResourceUrl>, Error>
+where
+    U: TryInto<Url>,
+    <U as TryInto<Url>>::Error: Display,
+{
+    ArchiveSession::new(options)?.list_resources(url)
+}
+
+/// Requests to one host for the blocking API. Each free slot is a token
+/// in a queue, so that waiting requests are sent in turn.
+pub(crate) struct BlockingSlots {
+    free: Mutex<mpsc::Receiver<()>>,
+    returned: mpsc::Sender<()>,
+}
+
+impl HostSlots for BlockingSlots {
+    fn with_capacity(count: usize) -> Self {
+        let (returned, free) = mpsc::channel();
+        for _ in 0..count {
+            let _ = returned.send(());
+        }
+        Self {
+            free: Mutex::new(free),
+            returned,
+        }
+    }
+}
+
+/// A slot taken from [`BlockingSlots`], which is freed when dropped
+pub(crate) struct BlockingSlot(mpsc::Sender<()>);
+
+impl Drop for BlockingSlot {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+impl HostLimits<BlockingSlots> {
+    /// Wait for a free slot for a request to the host of the URL
+    pub(crate) fn acquire(&self, url: &Url) -> Option<BlockingSlot> {
+        let slots = self.slots(url)?;
+        // The sender is held by the slots, so the queue never ends
+        let _ = slots.free.lock().unwrap().recv();
+        Some(BlockingSlot(slots.returned.clone()))
+    }
+}
+
+/// Blocking version of [`crate::ArchiveSession`]
+pub struct ArchiveSession {
+    client: reqwest::blocking::Client,
+    cookies: Arc<CookieJar>,
+    cache: ResourceCache,
+    downloading: DownloadLocks<Mutex<()>>,
+    host_limits: HostLimits<BlockingSlots>,
+    logged_in: Mutex<bool>,
+    options: ArchiveOptions,
+}
+
+impl ArchiveSession {
+    /// Create a session, building a client from the options
+    pub fn new(options: ArchiveOptions) -> Result<Self, Error> {
+        let cookies = Arc::new(CookieJar::default());
+        let client = reqwest::blocking::ClientBuilder::from(
+            client_builder(&options)?.cookie_provider(Arc::clone(&cookies)),
+        )
+        .build()?;
+        Ok(Self {
+            client,
+            cookies,
+            cache: Default::default(),
+            downloading: Default::default(),
+            host_limits: HostLimits::new(options.max_connections_per_host),
+            logged_in: Mutex::new(false),
+            options,
+        })
+    }
+
+    /// Send the requests in [`ArchiveOptions::login`], if they have not
+    /// been sent already. This is done automatically before the first
+    /// page is fetched. Concurrent callers wait for the first to finish
+    /// rather than sending the requests again.
+    pub fn login(&self) -> Result<(), Error> {
+        let mut logged_in = self.logged_in.lock().unwrap();
+        if *logged_in {
+            return Ok(());
+        }
+        for step in &self.options.login {
+            let mut request =
+                self.client.request(step.method.clone(), step.url.clone());
+            for (name, value) in &step.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            if !step.form.is_empty() {
+                request = request.form(&step.form);
+            }
+            let response = request.send()?;
+            check_login_status(step, response.status())?;
+        }
+        *logged_in = true;
+        Ok(())
+    }
+
+    /// The options used for every archive in the session
+    pub fn options(&self) -> &ArchiveOptions {
+        &self.options
+    }
+
+    /// The cookie jar shared by every request in the session
+    pub fn cookies(&self) -> &Arc<CookieJar> {
+        &self.cookies
+    }
+
+    /// The underlying client
+    pub fn client(&self) -> &reqwest::blocking::Client {
+        &self.client
+    }
+
+    /// Forget the resources downloaded so far
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+        self.downloading.clear();
+    }
+
+    /// Download a page and its resources, as with [`archive`].
+    /// Resources which were downloaded for an earlier page in the session
+    /// are taken from the cache.
+    pub fn archive<U>(&self, url: U) -> Result<PageArchive, Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        self.archive_with_report(url).map(|(archive, _)| archive)
+    }
+
+    /// Download a page and its resources, also returning what became of
+    /// each resource, as with [`archive_with_report`]
+    pub fn archive_with_report<U>(
+        &self,
+        url: U,
+    ) -> Result<(PageArchive, ArchiveReport), Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let url: Url = url
+            .try_into()
+            .map_err(|e| Error::ParseError(format!("{}", e)))?;
+        self.login()?;
+
+        // Fetch the page contents, using the final URL after any redirects
+        // as the base for resolving the page's resources
+        let started = Instant::now();
+        let fetched_at = SystemTime::now();
+        let (url, page, body) = self.fetch_page(url)?;
+        self.archive_page(url, page, body, fetched_at, started)
+    }
+
+    /// Archive a page from a response which has already been received,
+    /// as with [`archive_from_response`]
+    pub fn archive_from_response(
+        &self,
+        response: reqwest::blocking::Response,
+    ) -> Result<PageArchive, Error> {
+        self.login()?;
+
+        let started = Instant::now();
+        let fetched_at = SystemTime::now();
+        let url = response.url().clone();
+        // The request was sent by the caller, so its headers are unknown
+        let page = ResourceInfo {
+            status: Some(response.status()),
+            content_type: content_type(response.headers()),
+            headers: self.options.capture_headers.capture(
+                &HeaderMap::new(),
+                response.version(),
+                response.headers(),
+            ),
+            ..Default::default()
+        };
+        let data = response.bytes()?;
+        let body = TextResource::decode(data, page.content_type.as_deref());
+        self.archive_page(url, page, body, fetched_at, started)
+            .map(|(archive, _)| archive)
+    }
+
+    /// Download the resources of a fetched page and build its archive
+    /// and report, as with the async version
+    fn archive_page(
+        &self,
+        url: Url,
+        page: ResourceInfo,
+        body: TextResource,
+        fetched_at: SystemTime,
+        started: Instant,
+    ) -> Result<(PageArchive, ArchiveReport), Error> {
+        let options = &self.options;
+        if let Some(status) = page.status {
+            if options.error_on_http_failure
+                && !(status.is_success() || status.is_redirection())
+            {
+                return Err(Error::HttpStatus { url, status });
+            }
+        }
+        let mut info = ArchiveInfo {
+            page,
+            ..Default::default()
+        };
+        info.page.digest = Some(options.digest_algorithm.digest(&body.data));
+        let content = body.text().to_string();
+
+        // Determine the resources that the page needs
+        let resource_urls = options.resource_urls(&url, &content);
+
+        // Download them, along with the resources their stylesheets need
+        let mut report = ArchiveReport {
+            page_duration: started.elapsed(),
+            ..Default::default()
+        };
+        let mut resource_map = ResourceMap::new();
+        let mut resident = 0;
+        let attachments = options.limited_attachments(&url, &content);
+        let mut queue = ResourceQueue::new(resource_urls);
+        while let Some(resource_url) = queue.next() {
+            let resource = resource_url.url().clone();
+            let kind = resource_url.kind();
+            let resource_started = Instant::now();
+            let mut outcome = self.archive_resource(
+                resource_url,
+                &mut info,
+                &mut resource_map,
+                &mut resident,
+            )?;
+            if attachments.contains(&resource) {
+                outcome = options.limit_attachment(
+                    &resource,
+                    outcome,
+                    &mut info,
+                    &mut resource_map,
+                );
+            }
+            if let Some(stored) = resource_map.get(&resource) {
+                queue.discover(
+                    &resource,
+                    stored,
+                    &self.options.resource_extractors,
+                );
+            }
+            report.resources.push(ResourceReport {
+                url: resource,
+                kind,
+                outcome,
+                duration: resource_started.elapsed(),
+            });
+        }
+        report.total_duration = started.elapsed();
+
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: Some(body.data),
+            resource_map,
+            fetched_at,
+            info,
+            metadata_cache: Default::default(),
+        };
+        Ok((archive, report))
+    }
+
+    /// Fetch a page with a custom fetcher or the HTTP client, returning
+    /// its URL after any redirects, the status it was served with if it
+    /// came over HTTP, and its contents
+    fn fetch_page(
+        &self,
+        url: Url,
+    ) -> Result<(Url, ResourceInfo, TextResource), Error> {
+        if let Some(fetcher) = self.options.fetcher_for(&url) {
+            let (page, body) = fetched_page(&url, fetcher.fetch(&url)?)?;
+            return Ok((url, page, body));
         }
-        match resource_url {
-            Image(u) => {
-                let data = response.bytes()?;
-                let mimetype = mimetype_from_response(&data, &u);
-                resource_map.insert(
-                    u,
-                    Resource::Image(ImageResource { data, mimetype }),
+        let _slot = self.host_limits.acquire(&url);
+        let request = self.client.get(url).build()?;
+        let request_headers = self.sent_headers(&request);
+        let sent = Instant::now();
+        let response = self.client.execute(request)?;
+        let first_byte = Instant::now();
+        let url = response.url().clone();
+        let mut page = ResourceInfo {
+            status: Some(response.status()),
+            content_type: content_type(response.headers()),
+            headers: self.options.capture_headers.capture(
+                &request_headers,
+                response.version(),
+                response.headers(),
+            ),
+            ..Default::default()
+        };
+        let data = response.bytes()?;
+        page.timings = Some(ResourceTimings::since(sent, first_byte));
+        let body = TextResource::decode(data, page.content_type.as_deref());
+        Ok((url, page, body))
+    }
+
+    /// The headers a request is sent with, if they are to be captured
+    fn sent_headers(&self, request: &reqwest::blocking::Request) -> HeaderMap {
+        sent_headers(
+            self.options.capture_headers,
+            request.url(),
+            request.headers(),
+            &self.cookies,
+        )
+    }
+
+    /// Try again to download the resources an archive is missing, as
+    /// with the async
+    /// [`ArchiveSession::refetch_missing`](crate::ArchiveSession::refetch_missing)
+    pub fn refetch_missing(
+        &self,
+        archive: &mut PageArchive,
+    ) -> Result<usize, Error> {
+        self.login()?;
+
+        let mut queue = ResourceQueue::new(archive.missing_resources());
+        let mut resident = archive.resource_map.resident_size();
+        let mut added = 0;
+        while let Some(resource_url) = queue.next() {
+            let url = resource_url.url().clone();
+            if is_downloaded(&archive.resource_map, &url) {
+                continue;
+            }
+            self.archive_resource(
+                resource_url,
+                &mut archive.info,
+                &mut archive.resource_map,
+                &mut resident,
+            )?;
+            if let Some(resource) = archive.resource_map.get(&url) {
+                queue.discover(
+                    &url,
+                    resource,
+                    &self.options.resource_extractors,
                 );
             }
-            Css(u) => {
-                resource_map.insert(u, Resource::Css(response.text()?));
+            if is_downloaded(&archive.resource_map, &url) {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Obtain a resource from the cache, a custom fetcher, or the HTTP
+    /// client and add it to the archive, returning what became of it
+    fn archive_resource(
+        &self,
+        resource_url: ResourceUrl,
+        info: &mut ArchiveInfo,
+        resource_map: &mut ResourceMap,
+        resident: &mut usize,
+    ) -> Result<ResourceOutcome, Error> {
+        let options = &self.options;
+        if !options.should_fetch(&resource_url) {
+            return Ok(ResourceOutcome::Filtered);
+        }
+        // Another page may be downloading the resource, in which case it
+        // is taken from the cache once that page has finished
+        let downloading = self.downloading.get(resource_url.url());
+        let _downloading = downloading.lock().unwrap();
+        if let Some((resource, resource_info)) =
+            self.cache.get(resource_url.url())
+        {
+            let resource = options.apply_memory_budget(resource, resident)?;
+            info.resources
+                .insert(resource_url.url().clone(), resource_info);
+            resource_map.insert(resource_url.url().clone(), resource);
+            return Ok(ResourceOutcome::Cached);
+        }
+
+        let fetched = match options.fetcher_for(resource_url.url()) {
+            Some(fetcher) => {
+                let fetched = fetcher.fetch(resource_url.url())?;
+                fetched_resource(resource_url, fetched, options)?
+            }
+            None => self.fetch_http(resource_url)?,
+        };
+        let (u, resource, resource_info) = match fetched {
+            Ok(fetched) => fetched,
+            Err(outcome) => return Ok(outcome),
+        };
+        let resource = match options.process_resource(&u, resource) {
+            Some(resource) => resource,
+            None => {
+                return Ok(ResourceOutcome::Skipped(
+                    "dropped by the resource hook".to_string(),
+                ))
+            }
+        };
+        let outcome = match &resource {
+            Resource::Failed { status, .. } => ResourceOutcome::Failed {
+                status: *status,
+                recorded: true,
+            },
+            _ => ResourceOutcome::Fetched,
+        };
+        let resource = options.apply_memory_budget(resource, resident)?;
+        self.cache.insert(u.clone(), &resource, &resource_info);
+        info.resources.insert(u.clone(), resource_info);
+        resource_map.insert(u, resource);
+        Ok(outcome)
+    }
+
+    /// Download a resource with the HTTP client, falling back to the
+    /// Wayback Machine and applying the failure policy as configured
+    fn fetch_http(&self, resource_url: ResourceUrl) -> Result<Fetch, Error> {
+        let options = &self.options;
+        let client = &self.client;
+        let _slot = self.host_limits.acquire(resource_url.url());
+        let request = client.get(resource_url.url().clone()).build()?;
+        let request_headers = self.sent_headers(&request);
+        let sent = Instant::now();
+        let result = client.execute(request);
+        let first_byte = Instant::now();
+        // A response from the Wayback Machine comes from other requests,
+        // so no timings are recorded for it
+        let (response, first_byte) = match result {
+            Ok(response) if is_downloaded_status(response.status()) => {
+                (response, Some(first_byte))
+            }
+            result => {
+                // Fall back to the Wayback Machine if allowed
+                let fallback = if options.wayback_fallback {
+                    wayback::fetch_blocking(client, resource_url.url())
+                } else {
+                    None
+                };
+                match (fallback, result) {
+                    (Some(response), _) => (response, None),
+                    (None, Err(e)) => return Err(e.into()),
+                    (None, Ok(response)) => {
+                        match options.failure_policy(resource_url.kind()) {
+                            FailurePolicy::Skip => {
+                                return Ok(Err(ResourceOutcome::Failed {
+                                    status: response.status(),
+                                    recorded: false,
+                                }))
+                            }
+                            FailurePolicy::Record => {
+                                (response, Some(first_byte))
+                            }
+                            FailurePolicy::Abort => {
+                                return Err(Error::HttpStatus {
+                                    url: resource_url.url().clone(),
+                                    status: response.status(),
+                                })
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let content_type = content_type(response.headers());
+        let headers = options.capture_headers.capture(
+            &request_headers,
+            response.version(),
+            response.headers(),
+        );
+        let status = response.status();
+        let data = response.bytes()?;
+        let timings = first_byte
+            .map(|first_byte| ResourceTimings::since(sent, first_byte));
+        let digest = options.digest_algorithm.digest(&data);
+        let (u, resource) = if is_downloaded_status(status) {
+            build_resource(resource_url, data, content_type.as_deref())
+        } else {
+            build_failed_resource(
+                resource_url,
+                status,
+                data,
+                content_type.as_deref(),
+            )
+        };
+        let resource_info = ResourceInfo {
+            digest: Some(digest),
+            status: Some(status),
+            timings,
+            content_type,
+            headers,
+        };
+        Ok(Ok((u, resource, resource_info)))
+    }
+
+    /// Archive several pages in parallel, as with [`archive_all`]
+    pub fn archive_all<I, U>(&self, urls: I) -> Vec<Result<PageArchive, Error>>
+    where
+        I: IntoIterator<Item = U>,
+        U: TryInto<Url> + Send,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let urls: Vec<Mutex<Option<U>>> =
+            urls.into_iter().map(|url| Mutex::new(Some(url))).collect();
+        let next = AtomicUsize::new(0);
+        let workers = self.options.concurrency().min(urls.len());
+
+        let mut results: Vec<(usize, Result<PageArchive, Error>)> =
+            thread::scope(|scope| {
+                let handles: Vec<_> = (0..workers)
+                    .map(|_| {
+                        scope.spawn(|| {
+                            let mut results = Vec::new();
+                            loop {
+                                let idx = next.fetch_add(1, Ordering::Relaxed);
+                                let url = match urls.get(idx) {
+                                    Some(url) => url.lock().unwrap().take(),
+                                    None => break,
+                                };
+                                if let Some(url) = url {
+                                    results.push((idx, self.archive(url)));
+                                }
+                            }
+                            results
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .flat_map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+        results.sort_by_key(|(idx, _)| *idx);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// List the resources of a page without downloading them, as with
+    /// [`list_resources`]
+    pub fn list_resources<U>(&self, url: U) -> Result<Vec<ResourceUrl>, Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let url: Url = url
+            .try_into()
+            .map_err(|e| Error::ParseError(format!("{}", e)))?;
+
+        self.login()?;
+        let (url, _, body) = self.fetch_page(url)?;
+
+        Ok(self.options.resource_urls(&url, body.text()))
+    }
+}
+
+/// The blocking Save Page Now submission function.
+///
+/// Submits the page (and optionally its resources) to the Internet
+/// Archive's Save Page Now service, returning the URLs which were
+/// successfully submitted. See [`wayback::save_page_now`] for details.
+pub fn save_page_now(
+    archive: &PageArchive,
+    options: &SavePageNowOptions,
+) -> Result<Vec<Url>, Error> {
+    let client = reqwest::blocking::ClientBuilder::from(client_builder(
+        &options.client_options,
+    )?)
+    .build()?;
+    let mut submitted = Vec::new();
+
+    for (idx, url) in wayback::submission_urls(archive, options)
+        .into_iter()
+        .enumerate()
+    {
+        let request = match &options.credentials {
+            Some(credentials) => client
+                .post(wayback::SAVE_PAGE_NOW_API)
+                .header(ACCEPT, "application/json")
+                .header(AUTHORIZATION, credentials.authorization())
+                .form(&[("url", url.as_str())]),
+            None => {
+                client.get(format!("{}/{}", wayback::SAVE_PAGE_NOW_API, url))
+            }
+        };
+
+        match request.send() {
+            Ok(response) if response.status().is_success() => {
+                submitted.push(url)
             }
-            Javascript(u) => {
-                resource_map.insert(u, Resource::Javascript(response.text()?));
+            // Only a failure to submit the page itself is an error
+            Ok(response) if idx == 0 => {
+                return Err(Error::HttpStatus {
+                    url,
+                    status: response.status(),
+                })
             }
+            Err(e) if idx == 0 => return Err(e.into()),
+            _ => {}
         }
     }
 
-    Ok(PageArchive {
-        url,
-        content,
-        resource_map,
-    })
+    Ok(submitted)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fetcher::{FetchedResource, FnFetcher};
 
     #[test]
     fn parse_invalid_url_blocking() {
@@ -117,4 +746,137 @@ mod tests {
             panic!("Expected parse error");
         }
     }
+
+    #[test]
+    fn list_resources_invalid_url_blocking() {
+        let u = "this~is~not~a~url";
+
+        let res = list_resources(u, Default::default());
+        assert!(matches!(res, Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn archive_all_invalid_urls_blocking() {
+        let urls = vec!["this~is~not~a~url"; 5];
+        let options = ArchiveOptions {
+            concurrency: Some(2),
+            ..Default::default()
+        };
+        let results = archive_all(urls, options).unwrap();
+        assert_eq!(results.len(), 5);
+        assert!(results
+            .iter()
+            .all(|res| matches!(res, Err(Error::ParseError(_)))));
+        assert!(archive_all(Vec::<&str>::new(), Default::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn login_once_blocking() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let logins = Arc::new(AtomicUsize::new(0));
+        let server_logins = Arc::clone(&logins);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let length = stream.read(&mut request).unwrap();
+                if request[..length].starts_with(b"GET /login ") {
+                    server_logins.fetch_add(1, Ordering::SeqCst);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                          Content-Length: 9\r\nConnection: close\r\n\r\n\
+                          <p>Hi</p>",
+                    )
+                    .unwrap();
+            }
+        });
+
+        let login = Url::parse(&format!("{}/login", origin)).unwrap();
+        let options = ArchiveOptions::builder()
+            .login_step(crate::LoginStep::get(login))
+            .concurrency(4)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let pages = (0..4)
+            .map(|page| Url::parse(&format!("{}/{}", origin, page)).unwrap());
+        let archives = session.archive_all(pages);
+        assert_eq!(archives.len(), 4);
+        assert!(archives.iter().all(Result::is_ok));
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn max_connections_per_host_blocking() {
+        let (origin, max_active) = crate::session::test::serve_slowly();
+        let options = ArchiveOptions::builder()
+            .concurrency(4)
+            .max_connections_per_host(2)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let pages = (0..4).map(|page| {
+            Url::parse(&format!("{}/{}/index.html", origin, page)).unwrap()
+        });
+        assert!(session.archive_all(pages).iter().all(Result::is_ok));
+        assert!(max_active.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn shared_resource_once_blocking() {
+        let (origin, hits) = crate::session::test::serve_shared_style();
+        let options = ArchiveOptions::builder().concurrency(4).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let pages = (0..4)
+            .map(|page| Url::parse(&format!("{}/{}", origin, page)).unwrap());
+        let style = Url::parse(&format!("{}/style.css", origin)).unwrap();
+        for archive in session.archive_all(pages) {
+            assert!(archive.unwrap().resource_map.get_css(&style).is_some());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn refetch_missing_blocking() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/a.css" => {
+                    Ok(Some(FetchedResource::new("a { b: url(a.png) }")))
+                }
+                "/a.png" => Ok(Some(FetchedResource::new("png"))),
+                _ => Ok(None),
+            },
+        );
+        let options =
+            ArchiveOptions::builder().fetcher(fetcher).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let mut archive = PageArchive::from_parts(
+            "asset://app/",
+            r#"<link rel="stylesheet" href="a.css"><img src="b.png">"#,
+            ResourceMap::new(),
+        )
+        .unwrap();
+
+        assert_eq!(archive.refetch_missing_blocking(&session).unwrap(), 2);
+        assert_eq!(
+            archive.missing_resources(),
+            vec![ResourceUrl::Image(Url::parse("asset://app/b.png").unwrap())]
+        );
+        assert_eq!(session.refetch_missing(&mut archive).unwrap(), 0);
+    }
+
+    #[test]
+    fn session_invalid_url_blocking() {
+        let session = ArchiveSession::new(Default::default()).unwrap();
+        let res = session.list_resources("this~is~not~a~url");
+        assert!(matches!(res, Err(Error::ParseError(_))));
+    }
 }
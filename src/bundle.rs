@@ -0,0 +1,195 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for writing several archived pages to a single directory
+//!
+//! [`write_bundle`] is intended for the results of
+//! [`archive_all`](crate::archive_all): each page is written to
+//! `pages/<n>.html`, and every resource is written once to `resources/`
+//! under the SHA-256 hash of its contents, so a stylesheet or logo shared
+//! by every page is only stored once. Stylesheets and the documents shown
+//! in frames are rewritten to reference the resources they use from the
+//! same directory, as with [`write_website`](crate::website::write_website).
+//! An `index.html` links to each of the pages.
+//!
+//! Only directory output is provided; a bundle can be sent elsewhere as
+//! a single file by compressing the directory afterwards.
+//!
+//! ```no_run
+//! use web_archive::archive_all;
+//! use web_archive::bundle::write_bundle;
+//!
+//! # async fn archive_async() {
+//! let urls = vec!["http://example.com/a", "http://example.com/b"];
+//! let archives: Vec<_> = archive_all(urls, Default::default())
+//!     .await
+//!     .unwrap()
+//!     .into_iter()
+//!     .filter_map(Result::ok)
+//!     .collect();
+//! write_bundle(&archives, &"bundle", &Default::default()).unwrap();
+//! # }
+//! ```
+
+use crate::embed::{new_html_element, EmbedOptions};
+use crate::error::Error;
+use crate::page_archive::{Externals, PageArchive};
+use crate::parsing::display_url;
+use crate::website::write_resources;
+use kuchiki::parse_html;
+use kuchiki::traits::TendrilSink;
+use kuchiki::NodeRef;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// Directory of the bundle that pages are written to
+const PAGES_DIR: &str = "pages";
+
+/// Directory of the bundle that resources are written to
+const RESOURCES_DIR: &str = "resources";
+
+/// Write several archives to a directory, sharing identical resources
+/// between the pages.
+///
+/// The pages are processed with the given [`EmbedOptions`], except that
+/// every resource is written to a separate file rather than being
+/// embedded, so [`EmbedOptions::inline_size_limit`] is ignored.
+pub fn write_bundle<P: AsRef<Path>>(
+    archives: &[PageArchive],
+    output_dir: &P,
+    options: &EmbedOptions,
+) -> Result<(), Error> {
+    let output_dir = output_dir.as_ref();
+    let reference_dir = format!("../{}", RESOURCES_DIR);
+    let resources_dir = output_dir.join(RESOURCES_DIR);
+
+    fs::create_dir_all(output_dir.join(PAGES_DIR))?;
+    fs::create_dir_all(&resources_dir)?;
+    // Identical content has the same name, so it is only written once
+    let mut written = HashSet::new();
+    for (index, archive) in archives.iter().enumerate() {
+        let mut externals = Externals::external_only(&reference_dir);
+        let content = archive.embed_into(options, &mut externals);
+        write_resources(
+            archive,
+            &resources_dir,
+            options,
+            &externals,
+            &mut written,
+        )?;
+        fs::write(output_dir.join(page_path(index)), content)?;
+    }
+
+    fs::write(output_dir.join("index.html"), index_page(archives))?;
+    Ok(())
+}
+
+/// Path of a page relative to the bundle directory
+fn page_path(index: usize) -> String {
+    format!("{}/{}.html", PAGES_DIR, index + 1)
+}
+
+/// An HTML page linking to each of the pages in the bundle
+fn index_page(archives: &[PageArchive]) -> String {
+    let document = parse_html().one(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <title>Archived pages</title></head>\
+         <body><h1>Archived pages</h1><ul></ul></body></html>",
+    );
+    let list = document.select_first("ul").unwrap();
+    for (index, archive) in archives.iter().enumerate() {
+        let url = display_url(&archive.url);
+        let title = archive.title().unwrap_or_else(|| url.clone());
+        let fetched_at =
+            humantime::format_rfc3339_seconds(archive.fetched_at).to_string();
+
+        let item = new_html_element("li", &[]);
+        let link = new_html_element("a", &[("href", &page_path(index))]);
+        link.append(NodeRef::new_text(title));
+        item.append(link);
+        item.append(NodeRef::new_text(format!(
+            " ({}, archived {})",
+            url, fetched_at
+        )));
+        list.as_node().append(item);
+    }
+    document.to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::{ImageResource, Resource, ResourceMap};
+    use bytes::Bytes;
+    use url::Url;
+
+    fn page(url: &str, title: &str) -> PageArchive {
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            Url::parse("http://example.com/style.css").unwrap(),
+            Resource::Css("body { background: url(bg.png) }".into()),
+        );
+        resource_map.insert(
+            Url::parse("http://example.com/bg.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"\x89PNG\r\n\x1a\n"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        // Even an empty resource is written rather than embedded
+        resource_map.insert(
+            Url::parse("http://example.com/empty.js").unwrap(),
+            Resource::Javascript("".into()),
+        );
+        PageArchive::from_parts(
+            url,
+            format!(
+                r#"<title>{}</title><link rel="stylesheet" href="/style.css">
+                <script src="/empty.js"></script>"#,
+                title
+            ),
+            resource_map,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_write_bundle() {
+        let dir = tempfile::tempdir().unwrap();
+        let archives = vec![
+            page("http://example.com/a", "Page <A>"),
+            page("http://example.com/b", "Page B"),
+        ];
+        write_bundle(&archives, &dir.path(), &Default::default()).unwrap();
+
+        let resources: Vec<_> = fs::read_dir(dir.path().join("resources"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(resources.len(), 3);
+        // The stylesheet references the image stored alongside it
+        let css = resources
+            .iter()
+            .find(|path| path.extension().unwrap() == "css")
+            .unwrap();
+        let css = fs::read_to_string(css).unwrap();
+        assert!(css.contains(r#"url("./"#));
+        let image = &css[css.find("./").unwrap() + 2..css.find("\")").unwrap()];
+        assert!(dir.path().join("resources").join(image).exists());
+
+        let page = fs::read_to_string(dir.path().join("pages/2.html")).unwrap();
+        assert!(page.contains(r#"href="../resources/"#));
+        assert!(page.contains(r#"<script src="../resources/"#));
+        assert!(!page.contains("<style>"));
+
+        let index = fs::read_to_string(dir.path().join("index.html")).unwrap();
+        assert!(index.contains(r#"<a href="pages/1.html">Page &lt;A&gt;</a>"#));
+        assert!(index.contains(r#"<a href="pages/2.html">Page B</a>"#));
+        assert!(index.contains("http://example.com/b"));
+    }
+}
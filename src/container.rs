@@ -0,0 +1,367 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for storing archives in a compact single-file format
+//!
+//! [`PageArchive::save`](crate::PageArchive::save) writes the page and
+//! its resources unmodified to one file, so that
+//! [`PageArchive::load`](crate::PageArchive::load) can restore exactly
+//! the same archive, to be embedded or exported later. As resources are
+//! not base 64 encoded and identical resources are stored once, the file
+//! is usually far smaller than the page with its resources embedded.
+//!
+//! A container consists of:
+//!
+//! 1. the 8 bytes [`MAGIC`]
+//! 2. the format version, currently [`CONTAINER_VERSION`], as a
+//!    little-endian `u32`
+//! 3. the length of the index as a little-endian `u64`
+//! 4. the index, in JSON: the archive's
+//!    [`Manifest`] as `manifest`, with its
+//!    files laid out as in [`DiskLayout::Flat`](crate::sink::DiskLayout),
+//!    and a `blobs` list giving the `path`, `offset` and `length` of each
+//!    of those files in the blob section
+//! 5. the blob section, in which each file is compressed separately with
+//!    [Zstandard](https://facebook.github.io/zstd/)
+//!
+//! Requires the `container` feature.
+//!
+//! ```no_run
+//! use web_archive::{blocking, PageArchive};
+//!
+//! let archive =
+//!     blocking::archive("http://example.com", Default::default()).unwrap();
+//! archive.save(&"example.webarchive").unwrap();
+//!
+//! let restored = PageArchive::load(&"example.webarchive").unwrap();
+//! println!("{}", restored.embed_resources());
+//! ```
+
+use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::page_archive::PageArchive;
+use crate::parsing::Resource;
+use crate::sink::{ArchiveSink, DiskLayout, LayoutPlanner};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Bytes at the start of every container
+pub const MAGIC: &[u8; 8] = b"\x89WEBARC\n";
+
+/// Version of the container format written by this crate
+pub const CONTAINER_VERSION: u32 = 1;
+
+/// Description of the contents of a container
+#[derive(Debug, Serialize, Deserialize)]
+struct Index {
+    manifest: Manifest,
+    blobs: Vec<Blob>,
+}
+
+/// Location of a compressed file in the blob section
+#[derive(Debug, Serialize, Deserialize)]
+struct Blob {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// Write an archive to the writer in the container format
+pub fn write_container<W: Write>(
+    archive: &PageArchive,
+    writer: W,
+) -> Result<(), Error> {
+    archive.write_to_sink(&mut ContainerSink {
+        writer,
+        planner: LayoutPlanner::new(DiskLayout::Flat),
+        blobs: Vec::new(),
+        stored: HashSet::new(),
+        data: Vec::new(),
+        fetched_at: None,
+    })
+}
+
+/// Read an archive in the container format from the reader. Fails with
+/// [`Error::ParseError`] if the data is not a container, has a version
+/// other than [`CONTAINER_VERSION`], or is corrupt.
+pub fn read_container<R: Read>(mut reader: R) -> Result<PageArchive, Error> {
+    let invalid = |reason: &str| {
+        Error::ParseError(format!("Invalid archive container: {}", reason))
+    };
+    let mut header = [0; 20];
+    reader
+        .read_exact(&mut header)
+        .map_err(|_| invalid("too short"))?;
+    if &header[..8] != MAGIC {
+        return Err(invalid("not a container"));
+    }
+    let version =
+        u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if version != CONTAINER_VERSION {
+        return Err(Error::ParseError(format!(
+            "Unsupported archive container version {}",
+            version
+        )));
+    }
+    let mut index_length = [0; 8];
+    index_length.copy_from_slice(&header[12..]);
+    let index_length = u64::from_le_bytes(index_length);
+
+    let mut index = Vec::new();
+    reader.by_ref().take(index_length).read_to_end(&mut index)?;
+    if index.len() as u64 != index_length {
+        return Err(invalid("truncated index"));
+    }
+    let index: Index = serde_json::from_slice(&index)
+        .map_err(|e| Error::ParseError(e.to_string()))?;
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    let blobs: HashMap<_, _> = index
+        .blobs
+        .iter()
+        .map(|blob| (blob.path.as_path(), blob))
+        .collect();
+    PageArchive::from_manifest(index.manifest, |path| {
+        let blob = blobs.get(path).ok_or_else(|| {
+            invalid(&format!("missing file {}", path.display()))
+        })?;
+        let start = blob.offset as usize;
+        let compressed = start
+            .checked_add(blob.length as usize)
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| invalid("truncated data"))?;
+        let data = zstd::decode_all(compressed)
+            .map_err(|e| invalid(&format!("corrupt data: {}", e)))?;
+        Ok(Bytes::from(data))
+    })
+}
+
+/// An [`ArchiveSink`] which collects the compressed files of a container
+/// and writes the container once the archive is complete
+struct ContainerSink<W: Write> {
+    writer: W,
+    planner: LayoutPlanner,
+    blobs: Vec<Blob>,
+    /// Paths of the blobs, to store the same path once
+    stored: HashSet<PathBuf>,
+    data: Vec<u8>,
+    fetched_at: Option<String>,
+}
+
+impl<W: Write> ContainerSink<W> {
+    /// Compress a file into the blob section, unless identical content
+    /// has already been stored at the same path
+    fn append(&mut self, path: PathBuf, data: &[u8]) -> Result<(), Error> {
+        if !self.stored.insert(path.clone()) {
+            return Ok(());
+        }
+        let compressed =
+            zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        self.blobs.push(Blob {
+            path,
+            offset: self.data.len() as u64,
+            length: compressed.len() as u64,
+        });
+        self.data.extend(compressed);
+        Ok(())
+    }
+}
+
+impl<W: Write> ArchiveSink for ContainerSink<W> {
+    fn write_page(
+        &mut self,
+        archive: &PageArchive,
+        content: &[u8],
+    ) -> Result<(), Error> {
+        // Keep the time to the nanosecond, unlike the manifest on disk
+        self.fetched_at = Some(
+            humantime::format_rfc3339_nanos(archive.fetched_at).to_string(),
+        );
+        let path = self.planner.plan_page(archive, content);
+        self.append(path, content)?;
+        if let Some((path, raw)) = self.planner.plan_raw_page(archive) {
+            self.append(path, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn write_resource(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+    ) -> Result<(), Error> {
+        let path = self.planner.plan_resource(url, resource);
+        self.append(path, &resource.read_data()?)
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        let mut manifest = self.planner.take_manifest().ok_or_else(|| {
+            Error::ParseError("No page written to the container".to_string())
+        })?;
+        if let Some(fetched_at) = self.fetched_at.take() {
+            manifest.fetched_at = fetched_at;
+        }
+        let index = serde_json::to_vec(&Index {
+            manifest,
+            blobs: std::mem::take(&mut self.blobs),
+        })
+        .map_err(|e| Error::ParseError(e.to_string()))?;
+
+        self.writer.write_all(MAGIC)?;
+        self.writer.write_all(&CONTAINER_VERSION.to_le_bytes())?;
+        self.writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&index)?;
+        self.writer.write_all(&std::mem::take(&mut self.data))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Write an archive to a container file, through a temporary file next
+/// to it so that an interrupted write never leaves a partial container
+pub(crate) fn save(archive: &PageArchive, path: &Path) -> Result<(), Error> {
+    let staging = crate::sink::staging_dir(path);
+    let result = std::fs::File::create(&staging)
+        .map_err(Error::from)
+        .and_then(|file| {
+            write_container(archive, std::io::BufWriter::new(file))
+        })
+        .and_then(|()| Ok(std::fs::rename(&staging, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&staging);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::digest::DigestAlgorithm;
+    use crate::page_archive::{ArchiveInfo, ResourceInfo, ResourceTimings};
+    use crate::parsing::{ImageResource, ResourceMap};
+    use reqwest::StatusCode;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn archive() -> PageArchive {
+        let url = Url::parse("http://example.com/").unwrap();
+        let css = "body { color: red; }\n".repeat(100);
+        let mut resource_map = ResourceMap::new();
+        for path in ["a.css", "b.css"] {
+            resource_map.insert(
+                url.join(path).unwrap(),
+                Resource::Css(css.as_str().into()),
+            );
+        }
+        resource_map.insert(
+            url.join("ferris.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"\x89PNG\r\n\x1a\n"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        resource_map.insert(
+            url.join("missing.js").unwrap(),
+            Resource::Failed {
+                status: StatusCode::NOT_FOUND,
+                data: Bytes::from_static(b"Not found"),
+                mimetype: "text/plain".to_string(),
+            },
+        );
+        let mut info = ArchiveInfo::default();
+        info.page.digest = Some(DigestAlgorithm::Sha256.digest(b"<html>"));
+        for url in resource_map.keys() {
+            info.resources.insert(
+                url.clone(),
+                ResourceInfo {
+                    status: Some(StatusCode::OK),
+                    timings: Some(ResourceTimings {
+                        first_byte: Duration::from_millis(5),
+                        total: Duration::from_millis(8),
+                    }),
+                    ..Default::default()
+                },
+            );
+        }
+        info.resources
+            .get_mut(&url.join("missing.js").unwrap())
+            .unwrap()
+            .status = Some(StatusCode::NOT_FOUND);
+
+        PageArchive {
+            url,
+            content: "<html><p>héllo</p></html>".to_string(),
+            raw_content: Some(Bytes::from_static(
+                b"<html><p>h\xE9llo</p></html>",
+            )),
+            resource_map,
+            fetched_at: UNIX_EPOCH + Duration::new(1_612_345_678, 123_456_789),
+            info,
+            metadata_cache: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_container() {
+        let archive = archive();
+        let mut data = Vec::new();
+        write_container(&archive, &mut data).unwrap();
+        assert_eq!(&data[..8], MAGIC);
+        // The identical stylesheets are stored once
+        let mut length = [0; 8];
+        length.copy_from_slice(&data[12..20]);
+        let index: Index = serde_json::from_slice(
+            &data[20..20 + u64::from_le_bytes(length) as usize],
+        )
+        .unwrap();
+        assert_eq!(index.manifest.resources.len(), 4);
+        assert!(index.manifest.raw_page.is_some());
+        assert_eq!(index.blobs.len(), 5);
+
+        let restored = read_container(data.as_slice()).unwrap();
+        assert_eq!(restored.url, archive.url);
+        assert_eq!(restored.content, archive.content);
+        assert_eq!(restored.raw_content, archive.raw_content);
+        assert_eq!(restored.resource_map, archive.resource_map);
+        assert_eq!(restored.fetched_at, archive.fetched_at);
+        assert_eq!(restored.info, archive.info);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("example.webarchive");
+        archive.save(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        let loaded = PageArchive::load(&path).unwrap();
+        assert_eq!(loaded.raw_content, archive.raw_content);
+        assert_eq!(loaded.resource_map, archive.resource_map);
+    }
+
+    #[test]
+    fn test_invalid_container() {
+        let mut data = Vec::new();
+        write_container(&archive(), &mut data).unwrap();
+
+        let read = |data: &[u8]| match read_container(data) {
+            Err(Error::ParseError(e)) => e,
+            other => panic!("Expected a parse error, got {:?}", other),
+        };
+        assert!(read(b"<html>").contains("too short"));
+        assert!(read(b"PK\x03\x04 not a container").contains("not a container"));
+        assert!(read(&data[..data.len() - 10]).contains("truncated"));
+
+        let mut newer = data.clone();
+        newer[8] = CONTAINER_VERSION as u8 + 1;
+        assert!(read(&newer).contains("Unsupported archive container version"));
+        let mut older = data.clone();
+        older[8] = 0;
+        assert!(read(&older).contains("Unsupported archive container version"));
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for the cookie jar of a session and saving it between runs
+//!
+//! Every [`ArchiveSession`](crate::ArchiveSession) keeps the cookies set
+//! while archiving in a [`CookieJar`]. Saving the jar after archiving
+//! and loading it into the next session keeps it logged in, so recurring
+//! archives of pages behind a login need no
+//! [`login`](crate::ArchiveOptions::login) steps while the cookies last.
+//!
+//! Jars are saved in the Netscape `cookies.txt` format used by curl,
+//! wget and browser extensions, or in JSON. Cookies which only last for
+//! the browser session are saved too, as logins often use them.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use web_archive::blocking::ArchiveSession;
+//! use web_archive::cookies::CookieFormat;
+//!
+//! let session = ArchiveSession::new(Default::default()).unwrap();
+//! let cookies = Path::new("cookies.txt");
+//! if cookies.exists() {
+//!     session.cookies().load(&cookies, CookieFormat::Netscape).unwrap();
+//! }
+//! let archive = session.archive("http://example.com/dashboard").unwrap();
+//! session.cookies().save(&cookies, CookieFormat::Netscape).unwrap();
+//! ```
+
+use crate::error::Error;
+use cookie_store::{Cookie, CookieDomain, CookieExpiration, RawCookie};
+use reqwest::header::HeaderValue;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// First line of a Netscape cookies file
+const NETSCAPE_HEADER: &str = "# Netscape HTTP Cookie File";
+
+/// Prefix of the domain of `HttpOnly` cookies in a Netscape cookies file
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// File formats a [`CookieJar`] can be saved in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CookieFormat {
+    /// The tab-separated `cookies.txt` format of Netscape, as read and
+    /// written by curl and wget. `HttpOnly` cookies are marked with the
+    /// `#HttpOnly_` prefix, as curl does.
+    Netscape,
+    /// A JSON array of cookies, keeping every attribute
+    Json,
+}
+
+/// The cookies of a session, shared by all of its requests
+///
+/// The jar is used by the HTTP client, so cookies added to it are sent
+/// with every matching request, and cookies set by responses are kept
+/// in it.
+#[derive(Debug, Default)]
+pub struct CookieJar(RwLock<cookie_store::CookieStore>);
+
+impl CookieJar {
+    /// Add a cookie as if it had been set by a `Set-Cookie` header in a
+    /// response from `url`, e.g. `session=abc123; Domain=example.com`
+    pub fn add_cookie_str(&self, cookie: &str, url: &Url) {
+        let _ = self.store().parse(cookie, url);
+    }
+
+    /// Forget every cookie
+    pub fn clear(&self) {
+        self.store().clear();
+    }
+
+    /// Number of cookies in the jar which have not expired
+    pub fn len(&self) -> usize {
+        self.read_store().iter_unexpired().count()
+    }
+
+    /// Whether the jar holds no cookies which have not expired
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the cookies which have not expired in the given format
+    pub fn write<W: Write>(
+        &self,
+        mut writer: W,
+        format: CookieFormat,
+    ) -> Result<(), Error> {
+        let store = self.read_store();
+        let mut cookies: Vec<_> = store.iter_unexpired().collect();
+        cookies.sort_by(|a, b| {
+            (&a.domain, &*a.path, a.name()).cmp(&(
+                &b.domain,
+                &*b.path,
+                b.name(),
+            ))
+        });
+        match format {
+            CookieFormat::Netscape => {
+                writeln!(writer, "{}", NETSCAPE_HEADER)?;
+                for cookie in cookies {
+                    if let Some(line) = netscape_line(cookie) {
+                        writeln!(writer, "{}", line)?;
+                    }
+                }
+            }
+            CookieFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, &cookies)
+                    .map_err(|e| Error::ParseError(e.to_string()))?;
+                writeln!(writer)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Add the cookies read in the given format to the jar, replacing
+    /// any with the same name, domain and path. Expired cookies are
+    /// skipped. Returns the number of cookies added, or
+    /// [`Error::ParseError`] if the input is not in the format.
+    pub fn read<R: BufRead>(
+        &self,
+        reader: R,
+        format: CookieFormat,
+    ) -> Result<usize, Error> {
+        let cookies = match format {
+            CookieFormat::Netscape => {
+                let mut cookies = Vec::new();
+                for (number, line) in reader.lines().enumerate() {
+                    let cookie = parse_netscape_line(&line?).map_err(|e| {
+                        Error::ParseError(format!(
+                            "Invalid cookie on line {}: {}",
+                            number + 1,
+                            e
+                        ))
+                    })?;
+                    cookies.extend(cookie);
+                }
+                cookies
+            }
+            CookieFormat::Json => serde_json::from_reader(reader)
+                .map_err(|e| Error::ParseError(e.to_string()))?,
+        };
+
+        let mut store = self.store();
+        let mut added = 0;
+        for cookie in cookies {
+            let url = match cookie_url(&cookie) {
+                Some(url) => url,
+                None => continue,
+            };
+            if store.insert(cookie, &url).is_ok() {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Save the cookies which have not expired to a file in the given
+    /// format. The file contains credentials, so should be kept private.
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        format: CookieFormat,
+    ) -> Result<(), Error> {
+        let mut data = Vec::new();
+        self.write(&mut data, format)?;
+        std::fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Add the cookies from a file in the given format to the jar, as
+    /// with [`read`](Self::read)
+    pub fn load<P: AsRef<Path>>(
+        &self,
+        path: &P,
+        format: CookieFormat,
+    ) -> Result<usize, Error> {
+        let file = std::fs::File::open(path)?;
+        self.read(std::io::BufReader::new(file), format)
+    }
+
+    fn store(&self) -> RwLockWriteGuard<'_, cookie_store::CookieStore> {
+        self.0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn read_store(&self) -> RwLockReadGuard<'_, cookie_store::CookieStore> {
+        self.0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(
+        &self,
+        cookie_headers: &mut dyn Iterator<Item = &HeaderValue>,
+        url: &Url,
+    ) {
+        let cookies = cookie_headers.filter_map(|value| {
+            let value = value.to_str().ok()?;
+            RawCookie::parse(value).ok().map(RawCookie::into_owned)
+        });
+        self.store().store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let cookies = self
+            .read_store()
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if cookies.is_empty() {
+            None
+        } else {
+            HeaderValue::from_str(&cookies).ok()
+        }
+    }
+}
+
+/// A URL which the cookie could have been set by, to check it against
+/// when adding it to a jar
+fn cookie_url(cookie: &Cookie) -> Option<Url> {
+    let domain = match &cookie.domain {
+        CookieDomain::HostOnly(domain) | CookieDomain::Suffix(domain) => domain,
+        CookieDomain::NotPresent | CookieDomain::Empty => return None,
+    };
+    let scheme = if cookie.secure() == Some(true) {
+        "https"
+    } else {
+        "http"
+    };
+    Url::parse(&format!("{}://{}{}", scheme, domain, &*cookie.path)).ok()
+}
+
+/// The line describing a cookie in a Netscape cookies file
+fn netscape_line(cookie: &Cookie) -> Option<String> {
+    let (domain, subdomains) = match &cookie.domain {
+        CookieDomain::HostOnly(domain) => (domain.clone(), "FALSE"),
+        CookieDomain::Suffix(domain) => (format!(".{}", domain), "TRUE"),
+        CookieDomain::NotPresent | CookieDomain::Empty => return None,
+    };
+    let prefix = if cookie.http_only() == Some(true) {
+        HTTP_ONLY_PREFIX
+    } else {
+        ""
+    };
+    let secure = if cookie.secure() == Some(true) {
+        "TRUE"
+    } else {
+        "FALSE"
+    };
+    // Session cookies have an expiry time of 0
+    let expires = match &cookie.expires {
+        CookieExpiration::AtUtc(time) => time.unix_timestamp().max(1),
+        CookieExpiration::SessionEnd => 0,
+    };
+    Some(format!(
+        "{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+        prefix,
+        domain,
+        subdomains,
+        &*cookie.path,
+        secure,
+        expires,
+        cookie.name(),
+        cookie.value()
+    ))
+}
+
+/// Parse a line of a Netscape cookies file, which is `None` for comments,
+/// blank lines and cookies which have expired
+fn parse_netscape_line(line: &str) -> Result<Option<Cookie<'static>>, String> {
+    let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+        Some(line) => (line, true),
+        None => (line, false),
+    };
+    if line.trim().is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let fields: Vec<_> = line.split('\t').collect();
+    let (domain, subdomains, path, secure, expires, name, value) =
+        match fields.as_slice() {
+            [domain, subdomains, path, secure, expires, name, value] => {
+                (domain, subdomains, path, secure, expires, name, value)
+            }
+            _ => return Err("expected 7 tab-separated fields".to_string()),
+        };
+    let flag = |field: &str| match field {
+        "TRUE" => Ok(true),
+        "FALSE" => Ok(false),
+        other => Err(format!("expected TRUE or FALSE, found {:?}", other)),
+    };
+    let domain = domain.trim_start_matches('.');
+    let secure = flag(secure)?;
+
+    let mut cookie = format!("{}={}; Path={}", name, value, path);
+    if flag(subdomains)? {
+        cookie.push_str(&format!("; Domain={}", domain));
+    }
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    let expires: u64 = expires
+        .parse()
+        .map_err(|_| format!("invalid expiry time {:?}", expires))?;
+    if expires != 0 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if expires <= now {
+            return Ok(None);
+        }
+        cookie.push_str(&format!("; Max-Age={}", expires - now));
+    }
+
+    let scheme = if secure { "https" } else { "http" };
+    let url = Url::parse(&format!("{}://{}{}", scheme, domain, path))
+        .map_err(|e| format!("invalid domain {:?}: {}", domain, e))?;
+    Cookie::parse(cookie, &url)
+        .map(|cookie| Some(cookie.into_owned()))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::cookie::CookieStore;
+
+    fn jar() -> CookieJar {
+        let jar = CookieJar::default();
+        let url = Url::parse("https://example.com/dashboard/").unwrap();
+        jar.add_cookie_str("session=ferris; HttpOnly; Secure; Path=/", &url);
+        jar.add_cookie_str(
+            "theme=dark; Domain=example.com; Path=/; Max-Age=3600",
+            &url,
+        );
+        jar.add_cookie_str("expired=yes; Max-Age=0", &url);
+        jar
+    }
+
+    #[test]
+    fn test_cookie_jar() {
+        let jar = jar();
+        assert_eq!(jar.len(), 2);
+        let url = Url::parse("https://www.example.com/").unwrap();
+        assert_eq!(jar.cookies(&url).unwrap(), "theme=dark");
+        let url = Url::parse("https://example.com/dashboard/").unwrap();
+        let cookies = jar.cookies(&url).unwrap();
+        assert!(cookies.to_str().unwrap().contains("session=ferris"));
+
+        jar.clear();
+        assert!(jar.is_empty());
+        assert!(jar.cookies(&url).is_none());
+    }
+
+    #[test]
+    fn test_netscape_cookies() {
+        let mut data = Vec::new();
+        jar().write(&mut data, CookieFormat::Netscape).unwrap();
+        let text = String::from_utf8(data.clone()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], NETSCAPE_HEADER);
+        assert_eq!(
+            lines[1],
+            "#HttpOnly_example.com\tFALSE\t/\tTRUE\t0\tsession\tferris"
+        );
+        assert!(lines[2].starts_with(".example.com\tTRUE\t/\tFALSE\t"));
+        assert!(lines[2].ends_with("\ttheme\tdark"));
+
+        let restored = CookieJar::default();
+        assert_eq!(
+            restored
+                .read(data.as_slice(), CookieFormat::Netscape)
+                .unwrap(),
+            2
+        );
+        let mut written = Vec::new();
+        restored
+            .write(&mut written, CookieFormat::Netscape)
+            .unwrap();
+        assert_eq!(written, data);
+
+        // Files written by other tools
+        let other = "# Netscape HTTP Cookie File\n\
+                     \n\
+                     # A comment\n\
+                     .example.org\tTRUE\t/\tFALSE\t1\told\tgone\n\
+                     example.org\tFALSE\t/app\tFALSE\t0\tempty\t\n";
+        let jar = CookieJar::default();
+        assert_eq!(
+            jar.read(other.as_bytes(), CookieFormat::Netscape).unwrap(),
+            1
+        );
+        let url = Url::parse("http://example.org/app/page").unwrap();
+        assert_eq!(jar.cookies(&url).unwrap(), "empty=");
+
+        let invalid = "example.org\tMAYBE\t/\tFALSE\t0\ta\tb";
+        match jar.read(invalid.as_bytes(), CookieFormat::Netscape) {
+            Err(Error::ParseError(e)) => assert!(e.contains("line 1")),
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_json_cookies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cookies.json");
+        jar().save(&path, CookieFormat::Json).unwrap();
+
+        let restored = CookieJar::default();
+        assert_eq!(restored.load(&path, CookieFormat::Json).unwrap(), 2);
+        let mut cookies: Vec<_> = restored
+            .read_store()
+            .iter_unexpired()
+            .map(|cookie| cookie.to_string())
+            .collect();
+        cookies.sort();
+        assert_eq!(cookies.len(), 2);
+        assert!(cookies[0].starts_with("session=ferris; HttpOnly; Secure"));
+        assert!(cookies[1].starts_with("theme=dark;"));
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(matches!(
+            restored.load(&path, CookieFormat::Json),
+            Err(Error::ParseError(_))
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for finding and rewriting the URLs referenced by stylesheets
+//!
+//! Only as much of CSS is tokenised as is needed to find `url(...)`
+//! functions, the strings of `@import` rules and the string candidates of
+//! `image-set(...)` functions, skipping comments and other strings, so
+//! that every other byte of the stylesheet is kept as it was.
+
+use crate::parsing::ResourceUrl;
+use std::borrow::Cow;
+use std::ops::Range;
+use url::Url;
+
+/// A URL referenced by a stylesheet
+#[derive(Debug, PartialEq, Eq)]
+struct Reference {
+    /// Position of the whole `url(...)` function, or of the string
+    span: Range<usize>,
+    /// The URL as written, with quotes and escapes removed
+    url: String,
+    /// Whether the reference is a string rather than a `url(...)`
+    string: bool,
+    /// Whether the reference is the stylesheet of an `@import` rule
+    import: bool,
+}
+
+/// The resources referenced by a stylesheet, resolved against `base`.
+/// Stylesheets of `@import` rules are [`ResourceUrl::Css`], and anything
+/// else, e.g. fonts and background images, is [`ResourceUrl::Other`].
+pub(crate) fn css_resource_urls(base: &Url, css: &str) -> Vec<ResourceUrl> {
+    references(css)
+        .into_iter()
+        .filter_map(|reference| {
+            let url = resolve(base, &reference.url)?;
+            Some(if reference.import {
+                ResourceUrl::Css(url)
+            } else {
+                ResourceUrl::Other(url)
+            })
+        })
+        .collect()
+}
+
+/// Rewrite the URLs referenced by a stylesheet. `replace` is called with
+/// each URL resolved against `base` and whether it is the stylesheet of
+/// an `@import` rule, and returns the URL to use instead or `None` to
+/// leave the reference as it is. The stylesheet is only copied if a
+/// reference is replaced.
+pub(crate) fn rewrite_css_urls<'c, F>(
+    base: &Url,
+    css: &'c str,
+    mut replace: F,
+) -> Cow<'c, str>
+where
+    F: FnMut(&Url, bool) -> Option<String>,
+{
+    let mut output = String::new();
+    let mut position = 0;
+    for reference in references(css) {
+        let replacement = resolve(base, &reference.url)
+            .and_then(|url| replace(&url, reference.import));
+        if let Some(replacement) = replacement {
+            if position == 0 {
+                output.reserve(css.len());
+            }
+            output.push_str(&css[position..reference.span.start]);
+            let replacement = replacement.replace('"', "%22");
+            if reference.string {
+                output.push_str(&format!("\"{}\"", replacement));
+            } else {
+                output.push_str(&format!("url(\"{}\")", replacement));
+            }
+            position = reference.span.end;
+        }
+    }
+    if position == 0 {
+        return Cow::Borrowed(css);
+    }
+    output.push_str(&css[position..]);
+    Cow::Owned(output)
+}
+
+/// Resolve a referenced URL, ignoring references to fragments (e.g. SVG
+/// filters in the same document) and `data:` URIs
+fn resolve(base: &Url, reference: &str) -> Option<Url> {
+    if reference.is_empty() || reference.starts_with('#') {
+        return None;
+    }
+    base.join(reference)
+        .ok()
+        .filter(|url| url.scheme() != "data")
+}
+
+/// Find the URLs referenced by a stylesheet
+fn references(css: &str) -> Vec<Reference> {
+    let bytes = css.as_bytes();
+    let mut references = Vec::new();
+    // Set after `@import` until the end of the rule, so that its string
+    // or `url(...)` is recognised as the imported stylesheet
+    let mut in_import = false;
+    // Depth of the parentheses within an `image-set(...)`, whose strings
+    // directly inside it are image URLs, unlike those of e.g. `type(...)`
+    let mut image_set_depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = css[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + end + 4);
+            }
+            b'"' | b'\'' => {
+                let (url, end) = read_string(css, i);
+                if in_import || image_set_depth == 1 {
+                    references.push(Reference {
+                        span: i..end,
+                        url,
+                        string: true,
+                        import: in_import,
+                    });
+                    in_import = false;
+                }
+                i = end;
+            }
+            b'\\' => i += 2,
+            b';' | b'{' | b'}' => {
+                in_import = false;
+                image_set_depth = 0;
+                i += 1;
+            }
+            b'i' | b'I'
+                if starts_with_ignore_case(&bytes[i..], b"image-set(")
+                    && !is_name_byte(image_set_prefix(bytes, i)) =>
+            {
+                image_set_depth = 1;
+                i += 10;
+            }
+            b'(' if image_set_depth > 0 => {
+                image_set_depth += 1;
+                i += 1;
+            }
+            b')' if image_set_depth > 0 => {
+                image_set_depth -= 1;
+                i += 1;
+            }
+            b'@' if starts_with_ignore_case(&bytes[i + 1..], b"import") => {
+                in_import = true;
+                i += 7;
+            }
+            b'u' | b'U'
+                if starts_with_ignore_case(&bytes[i..], b"url(")
+                    && !is_name_byte(i.checked_sub(1).map(|j| bytes[j])) =>
+            {
+                match read_url_function(css, i) {
+                    Some((url, end)) => {
+                        references.push(Reference {
+                            span: i..end,
+                            url,
+                            string: false,
+                            import: in_import,
+                        });
+                        in_import = false;
+                        i = end;
+                    }
+                    None => i += 4,
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    references
+}
+
+/// Read the quoted string starting at `start`, returning its unescaped
+/// contents and the position after the closing quote
+fn read_string(css: &str, start: usize) -> (String, usize) {
+    let quote = css.as_bytes()[start] as char;
+    let mut value = String::new();
+    let mut chars = css[start + 1..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            c if c == quote => return (value, start + 1 + offset + 1),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    // An escaped newline continues the string
+                    if escaped != '\n' {
+                        value.push(escaped);
+                    }
+                }
+            }
+            // An unescaped newline ends the string early
+            '\n' => return (value, start + 1 + offset),
+            c => value.push(c),
+        }
+    }
+    (value, css.len())
+}
+
+/// Read the `url(...)` function starting at `start`, returning the URL
+/// and the position after the closing parenthesis
+fn read_url_function(css: &str, start: usize) -> Option<(String, usize)> {
+    let bytes = css.as_bytes();
+    let mut i = start + 4;
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    let url = match bytes.get(i)? {
+        b'"' | b'\'' => {
+            let (url, end) = read_string(css, i);
+            i = end;
+            url
+        }
+        _ => {
+            let end = css[i..].find(')').map_or(bytes.len(), |end| i + end);
+            let url = css[i..end].trim_end().replace('\\', "");
+            i = end;
+            url
+        }
+    };
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    if bytes.get(i) == Some(&b')') {
+        Some((url, i + 1))
+    } else {
+        None
+    }
+}
+
+/// The byte before the `image-set` name at `start` and any `-webkit-`
+/// vendor prefix
+fn image_set_prefix(bytes: &[u8], start: usize) -> Option<u8> {
+    const WEBKIT: &[u8] = b"-webkit-";
+    let start = match start.checked_sub(WEBKIT.len()) {
+        Some(prefix) if bytes[prefix..start].eq_ignore_ascii_case(WEBKIT) => {
+            prefix
+        }
+        _ => start,
+    };
+    start.checked_sub(1).map(|i| bytes[i])
+}
+
+/// Whether `bytes` starts with an ASCII `prefix`, ignoring case
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len()
+        && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// Whether a byte could be part of a CSS identifier, in which case a
+/// following `url(` is part of a longer function name
+fn is_name_byte(byte: Option<u8>) -> bool {
+    byte.is_some_and(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CSS: &str = r#"@import "reset.css";
+@import url(theme.css) screen;
+/* url(commented.png) */
+@font-face { font-family: F; src: url("fonts/f.woff2") format("woff2"); }
+body { background: URL( 'img/bg.png' ) no-repeat; content: "url(no.png)"; }
+.a { filter: url(#blur); cursor: url(data:image/png;base64,AA==), auto; }
+.b { background-image: my-url(x.png); }
+"#;
+
+    #[test]
+    fn test_css_resource_urls() {
+        let base = Url::parse("http://example.com/css/main.css").unwrap();
+        let urls: Vec<_> = css_resource_urls(&base, CSS)
+            .into_iter()
+            .map(|resource_url| {
+                let kind = resource_url.kind();
+                (kind, resource_url.url().path().to_string())
+            })
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                (crate::ResourceKind::Css, "/css/reset.css".to_string()),
+                (crate::ResourceKind::Css, "/css/theme.css".to_string()),
+                (crate::ResourceKind::Other, "/css/fonts/f.woff2".to_string()),
+                (crate::ResourceKind::Other, "/css/img/bg.png".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rewrite_css_urls() {
+        let base = Url::parse("http://example.com/css/main.css").unwrap();
+        let output = rewrite_css_urls(&base, CSS, |url, import| {
+            if url.path().ends_with("theme.css") {
+                None
+            } else if import {
+                Some(format!("imported:{}", url.path()))
+            } else {
+                Some(format!("data:{}", url.path()))
+            }
+        });
+        assert!(output.starts_with(
+            "@import \"imported:/css/reset.css\";\n@import url(theme.css) screen;"
+        ));
+        assert!(output.contains("/* url(commented.png) */"));
+        assert!(output.contains(
+            r#"src: url("data:/css/fonts/f.woff2") format("woff2")"#
+        ));
+        assert!(output
+            .contains(r#"background: url("data:/css/img/bg.png") no-repeat"#));
+        assert!(output.contains(r#"content: "url(no.png)""#));
+        assert!(output.contains("filter: url(#blur)"));
+        assert!(output.contains("my-url(x.png)"));
+    }
+
+    #[test]
+    fn test_image_set() {
+        let base = Url::parse("http://example.com/css/main.css").unwrap();
+        let css = r#".hero {
+  background-image: -webkit-image-set("a.png" 1x, "a@2x.png" 2x);
+  background-image: image-set(
+    "a.avif" type("image/avif"),
+    url(a.jpg) 1x
+  );
+  content: "b.png";
+}
+.c { background: my-image-set("no.png" 1x); }
+"#;
+        let paths: Vec<_> = css_resource_urls(&base, css)
+            .into_iter()
+            .map(|resource_url| resource_url.url().path().to_string())
+            .collect();
+        assert_eq!(
+            paths,
+            ["/css/a.png", "/css/a@2x.png", "/css/a.avif", "/css/a.jpg"]
+        );
+
+        let output = rewrite_css_urls(&base, css, |url, _| {
+            Some(format!("data:{}", url.path()))
+        });
+        assert!(output.contains(
+            r#"-webkit-image-set("data:/css/a.png" 1x, "data:/css/a@2x.png" 2x)"#
+        ));
+        assert!(output.contains(r#""data:/css/a.avif" type("image/avif")"#));
+        assert!(output.contains(r#"url("data:/css/a.jpg") 1x"#));
+        assert!(output.contains(r#"content: "b.png";"#));
+        assert!(output.contains(r#"my-image-set("no.png" 1x)"#));
+    }
+
+    #[test]
+    fn test_malformed_css() {
+        let base = Url::parse("http://example.com/").unwrap();
+        for css in [
+            "url(",
+            "url(\"a.png",
+            "@import",
+            "a { b: \"c",
+            "/* x",
+            "image-set(",
+            "image-set(\"a.png",
+        ] {
+            // Just check that nothing panics
+            let _ = css_resource_urls(&base, css);
+            let _ = rewrite_css_urls(&base, css, |_, _| Some("x".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_unchanged_css_is_borrowed() {
+        let base = Url::parse("http://example.com/").unwrap();
+        let output = rewrite_css_urls(&base, CSS, |_, _| None);
+        assert!(matches!(output, Cow::Borrowed(css) if css == CSS));
+        let output = rewrite_css_urls(&base, "a { b: url(c.png) }", |_, _| {
+            Some("d.png".to_string())
+        });
+        assert!(matches!(output, Cow::Owned(_)));
+    }
+}
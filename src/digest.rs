@@ -0,0 +1,156 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for the content digests recorded for archived files
+//!
+//! A digest is computed for the page and for each resource as it is
+//! downloaded, using the algorithm chosen in
+//! [`ArchiveOptions::digest_algorithm`](crate::ArchiveOptions::digest_algorithm).
+//! Digests are written to the disk manifest as `<algorithm>:<hex>`, e.g.
+//! `sha256:9f86d081…`, and can be used to verify the integrity of an
+//! archive or to deduplicate resources.
+
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Sha384, Sha512};
+use std::fmt;
+use std::str::FromStr;
+
+/// Hash algorithms which can be used for content digests
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    /// SHA-256
+    #[default]
+    Sha256,
+    /// SHA-384
+    Sha384,
+    /// SHA-512
+    Sha512,
+}
+
+impl DigestAlgorithm {
+    /// The name of the algorithm used in digest strings
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha384 => "sha384",
+            DigestAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// Compute the digest of some data
+    pub fn digest(self, data: &[u8]) -> Digest {
+        use sha2::Digest as _;
+
+        let hash = match self {
+            DigestAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+            DigestAlgorithm::Sha384 => Sha384::digest(data).to_vec(),
+            DigestAlgorithm::Sha512 => Sha512::digest(data).to_vec(),
+        };
+        Digest {
+            algorithm: self,
+            value: hex(&hash),
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "sha384" => Ok(DigestAlgorithm::Sha384),
+            "sha512" => Ok(DigestAlgorithm::Sha512),
+            _ => Err(Error::ParseError(format!(
+                "Unknown digest algorithm: {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// The digest of a file's contents
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Digest {
+    /// The algorithm used to compute the digest
+    pub algorithm: DigestAlgorithm,
+    /// The lowercase hex-encoded hash
+    pub value: String,
+}
+
+impl Digest {
+    /// Whether `data` has this digest
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.algorithm.digest(data) == *self
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), self.value)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = Error;
+
+    fn from_str(digest: &str) -> Result<Self, Self::Err> {
+        let (algorithm, value) = digest.split_once(':').ok_or_else(|| {
+            Error::ParseError(format!("Invalid digest: {}", digest))
+        })?;
+        Ok(Digest {
+            algorithm: algorithm.parse()?,
+            value: value.to_lowercase(),
+        })
+    }
+}
+
+/// Lowercase hexadecimal encoding of some bytes
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_digest() {
+        let digest = DigestAlgorithm::Sha256.digest(b"test");
+        assert_eq!(
+            digest.to_string(),
+            "sha256:\
+             9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+        );
+        assert!(digest.matches(b"test"));
+        assert!(!digest.matches(b"tset"));
+
+        let digest = DigestAlgorithm::Sha512.digest(b"test");
+        assert!(digest.to_string().starts_with("sha512:ee26b0dd4af7e749"));
+        assert_eq!(digest.value.len(), 128);
+        assert_eq!(DigestAlgorithm::Sha384.digest(b"").value.len(), 96);
+    }
+
+    #[test]
+    fn test_parse_digest() {
+        let digest = DigestAlgorithm::Sha384.digest(b"test");
+        assert_eq!(digest.to_string().parse::<Digest>().unwrap(), digest);
+
+        assert!(matches!(
+            "md5:098f6bcd4621d373cade4e832627b4f6".parse::<Digest>(),
+            Err(Error::ParseError(_))
+        ));
+        assert!(matches!(
+            "sha256".parse::<Digest>(),
+            Err(Error::ParseError(_))
+        ));
+    }
+}
@@ -0,0 +1,292 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for resolving host names with DNS over HTTPS
+//!
+//! When [`ArchiveOptions::dns_over_https`](crate::ArchiveOptions) is set,
+//! the addresses of hosts are looked up by sending DNS queries (RFC 8484)
+//! to the given server instead of using the system's resolver, so that
+//! archiving works where local DNS is unreliable or monitored. The
+//! addresses found for each host are kept for the rest of the session,
+//! so every request to a host goes to the same server.
+//!
+//! The server's own host name is resolved by the system, so a server
+//! given by its IP address, such as `https://1.1.1.1/dns-query`, avoids
+//! local DNS entirely. The queries are sent with the session's proxies,
+//! local address, client identity and certificate options.
+//!
+//! Requires the `doh` feature.
+
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+/// Mime type of DNS messages
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DNS record type of IPv4 addresses
+const TYPE_A: u16 = 1;
+
+/// DNS record type of IPv6 addresses
+const TYPE_AAAA: u16 = 28;
+
+/// DNS class of internet addresses
+const CLASS_IN: u16 = 1;
+
+/// A DNS resolver which queries a DNS over HTTPS server, used by the
+/// client of a session when
+/// [`ArchiveOptions::dns_over_https`](crate::ArchiveOptions) is set
+#[derive(Clone, Debug)]
+pub struct DohResolver {
+    server: Url,
+    client: reqwest::Client,
+    cache: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+}
+
+impl DohResolver {
+    /// A resolver sending its queries to the server at `server`, e.g.
+    /// `https://1.1.1.1/dns-query`, with a client using the default
+    /// settings
+    pub fn new(server: Url) -> Result<Self, crate::Error> {
+        let client = reqwest::Client::builder().use_native_tls().build()?;
+        Ok(Self::with_client(server, client))
+    }
+
+    /// A resolver sending its queries to the server with the given
+    /// client, e.g. one going through a proxy
+    pub fn with_client(server: Url, client: reqwest::Client) -> Self {
+        Self {
+            server,
+            client,
+            cache: Default::default(),
+        }
+    }
+
+    /// Look up the IPv4 and IPv6 addresses of a host, IPv4 first
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Some(addresses) = self.cache.lock().unwrap().get(host) {
+            return Ok(addresses.clone());
+        }
+        let (v4, v6) = futures::future::join(
+            self.query(host, TYPE_A),
+            self.query(host, TYPE_AAAA),
+        )
+        .await;
+        let addresses = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(e),
+            (v4, v6) => {
+                let mut addresses = v4.unwrap_or_default();
+                addresses.extend(v6.unwrap_or_default());
+                addresses
+            }
+        };
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses found for {}", host),
+            ));
+        }
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), addresses.clone());
+        Ok(addresses)
+    }
+
+    /// Send one query to the server, returning the addresses answered
+    async fn query(
+        &self,
+        host: &str,
+        record_type: u16,
+    ) -> io::Result<Vec<IpAddr>> {
+        let error = io::Error::other;
+        let query = encode_query(host, record_type).map_err(error)?;
+        let mut url = self.server.clone();
+        url.query_pairs_mut().append_pair(
+            "dns",
+            &base64::encode_config(query, base64::URL_SAFE_NO_PAD),
+        );
+        let response = self
+            .client
+            .get(url)
+            .header(ACCEPT, DNS_MESSAGE)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                error(format!("DNS over HTTPS query failed: {}", e))
+            })?;
+        let content_type = response.headers().get(CONTENT_TYPE).cloned();
+        if content_type.as_ref().and_then(|value| value.to_str().ok())
+            != Some(DNS_MESSAGE)
+        {
+            return Err(error(format!(
+                "DNS over HTTPS server responded with {:?}",
+                content_type
+            )));
+        }
+        let message = response.bytes().await.map_err(|e| {
+            error(format!("DNS over HTTPS query failed: {}", e))
+        })?;
+        decode_answers(&message, record_type).map_err(error)
+    }
+}
+
+impl Resolve for DohResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addresses = resolver.lookup(name.as_str()).await?;
+            // The port is replaced by that of the URL being requested
+            let addrs: Addrs = Box::new(
+                addresses
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+/// Encode a recursive query for the records of a type for a host
+fn encode_query(host: &str, record_type: u16) -> Result<Vec<u8>, String> {
+    // ID 0, as recommended for caching by RFC 8484, with only the
+    // recursion desired flag set and a single question
+    let mut query = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid host name {:?}", host));
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(&record_type.to_be_bytes());
+    query.extend(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// The addresses in the records of a type answering a query. A host
+/// which does not exist has none.
+fn decode_answers(
+    message: &[u8],
+    record_type: u16,
+) -> Result<Vec<IpAddr>, String> {
+    let truncated = || "truncated DNS response".to_string();
+    let u16_at = |position: usize| {
+        message
+            .get(position..position + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(truncated)
+    };
+    let flags = u16_at(2)?;
+    match flags & 0xf {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        code => return Err(format!("DNS server failed with code {}", code)),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut position = 12;
+    for _ in 0..questions {
+        position = skip_name(message, position).ok_or_else(truncated)? + 4;
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        position = skip_name(message, position).ok_or_else(truncated)?;
+        let answer_type = u16_at(position)?;
+        let class = u16_at(position + 2)?;
+        let length = u16_at(position + 8)? as usize;
+        let start = position + 10;
+        let data = message.get(start..start + length).ok_or_else(truncated)?;
+        position = start + length;
+        if answer_type != record_type || class != CLASS_IN {
+            // e.g. the CNAME records leading to the address
+            continue;
+        }
+        let address = match (record_type, data.len()) {
+            (TYPE_A, 4) => {
+                IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err("malformed address record".to_string()),
+        };
+        addresses.push(address);
+    }
+    Ok(addresses)
+}
+
+/// The position after the name starting at `position`, which may end in
+/// a pointer to another name
+fn skip_name(message: &[u8], mut position: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(position)? as usize;
+        if length & 0xc0 == 0xc0 {
+            return Some(position + 2);
+        } else if length == 0 {
+            return Some(position + 1);
+        }
+        position += 1 + length;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(
+            encode_query("example.com.", TYPE_A).unwrap(),
+            b"\0\0\x01\0\0\x01\0\0\0\0\0\0\
+              \x07example\x03com\0\0\x01\0\x01"
+        );
+        assert!(encode_query("example..com", TYPE_A).is_err());
+        assert!(encode_query(&"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_decode_answers() {
+        let mut response = encode_query("www.example.com", TYPE_A).unwrap();
+        // A response with a CNAME and an address, both pointing back to
+        // names earlier in the message
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+        response.extend(b"\xc0\x0c\0\x05\0\x01\0\0\x0e\x10\0\x02\xc0\x10");
+        response.extend(b"\xc0\x10\0\x01\0\x01\0\0\x0e\x10\0\x04");
+        response.extend(&[93, 184, 216, 34]);
+        assert_eq!(
+            decode_answers(&response, TYPE_A).unwrap(),
+            vec![IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34))]
+        );
+        assert!(decode_answers(&response, TYPE_AAAA).unwrap().is_empty());
+        assert!(decode_answers(&response[..response.len() - 1], TYPE_A)
+            .unwrap_err()
+            .contains("truncated"));
+
+        // Hosts which do not exist have no addresses
+        let mut missing = response.clone();
+        missing[3] = 0x83;
+        assert!(decode_answers(&missing, TYPE_A).unwrap().is_empty());
+        let mut failed = response;
+        failed[3] = 0x82;
+        assert!(decode_answers(&failed, TYPE_A)
+            .unwrap_err()
+            .contains("code 2"));
+    }
+}
@@ -0,0 +1,1429 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for the options controlling how resources are embedded
+
+use crate::parsing::{
+    display_url, encode_svg_data_uri, Resource, ResourceKind, ResourceMap,
+};
+use html5ever::{interface::QualName, namespace_url, ns, LocalName};
+use kuchiki::iter::NodeIterator;
+use kuchiki::traits::TendrilSink;
+use kuchiki::{parse_html, Attribute, ExpandedName, NodeData, NodeRef};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+use url::Url;
+
+/// Configuration options to control how
+/// [`PageArchive::embed_resources_with`](crate::PageArchive::embed_resources_with)
+/// produces its output. The default options match the behaviour of
+/// [`PageArchive::embed_resources`](crate::PageArchive::embed_resources).
+///
+/// ## Example
+/// ```
+/// use web_archive::{
+///     EmbedOptions, PageArchive, Provenance, ResourceMap, StylesheetEmbedding,
+/// };
+///
+/// let archive = PageArchive::from_parts(
+///     "http://example.com/blog/",
+///     r#"<script src="app.js"></script><a href="/about">About</a>"#,
+///     ResourceMap::new(),
+/// )
+/// .unwrap();
+/// let options = EmbedOptions {
+///     keep_unfetched_script_src: true,
+///     absolutize_links: true,
+///     provenance: Some(Provenance::Comment),
+///     stylesheets: StylesheetEmbedding::DataUri,
+///     ..Default::default()
+/// };
+/// let page = archive.embed_resources_with(&options);
+/// assert!(page.contains(r#"src="http://example.com/blog/app.js""#));
+/// assert!(page.contains(r#"href="http://example.com/about""#));
+/// assert!(page.contains("url: http://example.com/blog/"));
+/// ```
+#[derive(Clone, Default)]
+pub struct EmbedOptions {
+    /// Re-emit the page's original DOCTYPE declaration verbatim rather
+    /// than the normalised `<!DOCTYPE html>` produced by the serialiser.
+    ///
+    /// Default: `false`
+    pub preserve_doctype: bool,
+    /// Restore whitespace which the HTML parser discards between the
+    /// DOCTYPE, the `<html>` tag, and the `<head>` tag.
+    ///
+    /// Default: `false`
+    pub preserve_whitespace: bool,
+    /// Keep the `src` attribute of scripts which were not downloaded,
+    /// rewritten to an absolute URL so that the archived page can still
+    /// load them when opened online. When `false`, the `src` is removed
+    /// from every script.
+    ///
+    /// Default: `false`
+    pub keep_unfetched_script_src: bool,
+    /// Rewrite the relative `src`/`href` of images, stylesheets, and
+    /// other resources which were not embedded (e.g. because they failed
+    /// to download) to their absolute URLs, so that the archived page can
+    /// still load them when opened online. Scripts are controlled by
+    /// [`keep_unfetched_script_src`](Self::keep_unfetched_script_src).
+    ///
+    /// Default: `false`
+    pub absolutize_unembedded: bool,
+    /// Rewrite the `href` of every `<a>` and `<area>` to an absolute URL
+    /// against the page's URL, so that navigation from the archived page
+    /// still works. Links to fragments within the page are left alone.
+    ///
+    /// Default: `false`
+    pub absolutize_links: bool,
+    /// Only embed resources up to this many bytes in size. Larger
+    /// resources are treated as if they had not been downloaded, unless
+    /// the page is written with
+    /// [`PageArchive::write_embedded`](crate::PageArchive::write_embedded),
+    /// which stores them in a directory next to the page and references
+    /// them by relative paths. This keeps pages with large images or
+    /// media down to a manageable size.
+    ///
+    /// Default: `None`
+    pub inline_size_limit: Option<usize>,
+    /// Maximum size in bytes of the images embedded as `data:` URIs.
+    /// Larger images are replaced as chosen by
+    /// [`oversized_images`](Self::oversized_images), which keeps pages
+    /// with multi-megabyte photos down to a manageable size without
+    /// needing separate files. Images written to separate files because
+    /// of the [`inline_size_limit`](Self::inline_size_limit) are not
+    /// affected.
+    ///
+    /// Default: `None`
+    pub image_size_limit: Option<usize>,
+    /// What takes the place of the images larger than the
+    /// [`image_size_limit`](Self::image_size_limit). See
+    /// [`OversizedImages`].
+    ///
+    /// Default: [`OversizedImages::Placeholder`]
+    pub oversized_images: OversizedImages,
+    /// Sanitise the output so that it can be re-hosted without the risk
+    /// of cross-site scripting: scripts, inline event handlers such as
+    /// `onclick`, `<meta http-equiv="refresh">` redirects, and SVG
+    /// animations which change a link are removed, as are URLs using
+    /// protocols other than `http`, `https`,
+    /// `mailto`, `tel`, and `ftp`. `data:` URIs are kept unless they
+    /// contain a scriptable document (e.g. HTML or SVG) outside of an
+    /// `<img>`.
+    ///
+    /// Default: `false`
+    pub sanitize: bool,
+    /// How forms in the page are handled, so that the archived page does
+    /// not submit data to the original site. See [`FormHandling`].
+    ///
+    /// Default: [`FormHandling::Keep`]
+    pub forms: FormHandling,
+    /// How downloaded stylesheets are embedded in the page. See
+    /// [`StylesheetEmbedding`].
+    ///
+    /// Default: [`StylesheetEmbedding::Style`]
+    pub stylesheets: StylesheetEmbedding,
+    /// How downloaded scripts are embedded in the page. See
+    /// [`ScriptEmbedding`].
+    ///
+    /// Default: [`ScriptEmbedding::Inline`]
+    pub scripts: ScriptEmbedding,
+    /// How downloaded SVG images are embedded in the page. See
+    /// [`SvgEmbedding`].
+    ///
+    /// Default: [`SvgEmbedding::Base64`]
+    pub svg: SvgEmbedding,
+    /// Replace each `<iframe>` with a placeholder of the same size which
+    /// links to the frame's original URL, instead of leaving a frame
+    /// that tries to load it from the network. Frames without a `src`,
+    /// or with an `about:` or `data:` URL, are left alone.
+    ///
+    /// Default: `false`
+    pub iframe_placeholders: bool,
+    /// Stop the archived page from installing service workers, which
+    /// would otherwise try to fetch and cache the original site's files
+    /// when the page is hosted again. `<link rel="serviceworker">`
+    /// elements are removed, and a script making
+    /// `navigator.serviceWorker.register()` fail is inserted at the start
+    /// of pages with scripts.
+    ///
+    /// Default: `false`
+    pub strip_service_workers: bool,
+    /// Record where and when the page was archived in the output, so
+    /// that saved pages are self-describing. See [`Provenance`].
+    ///
+    /// Default: `None`
+    pub provenance: Option<Provenance>,
+    /// Decide the URL of each resource in the output instead of the
+    /// built-in rules, e.g. to point at a local path or a CDN mirror.
+    /// See [`UrlRewriter`].
+    ///
+    /// Default: `None`
+    pub url_rewriter: Option<UrlRewriter>,
+    /// Steps run over each document after the built-in embedding, e.g.
+    /// to embed resources referenced in ways the crate does not know
+    /// about. See [`Embedder`].
+    ///
+    /// Default: empty
+    pub embedders: Vec<Arc<dyn Embedder>>,
+    /// How the whitespace of the output is laid out. See
+    /// [`OutputFormat`].
+    ///
+    /// Default: [`OutputFormat::Unchanged`]
+    pub format: OutputFormat,
+}
+
+impl fmt::Debug for EmbedOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmbedOptions")
+            .field("preserve_doctype", &self.preserve_doctype)
+            .field("preserve_whitespace", &self.preserve_whitespace)
+            .field("keep_unfetched_script_src", &self.keep_unfetched_script_src)
+            .field("absolutize_unembedded", &self.absolutize_unembedded)
+            .field("absolutize_links", &self.absolutize_links)
+            .field("inline_size_limit", &self.inline_size_limit)
+            .field("image_size_limit", &self.image_size_limit)
+            .field("oversized_images", &self.oversized_images)
+            .field("sanitize", &self.sanitize)
+            .field("forms", &self.forms)
+            .field("stylesheets", &self.stylesheets)
+            .field("scripts", &self.scripts)
+            .field("svg", &self.svg)
+            .field("iframe_placeholders", &self.iframe_placeholders)
+            .field("strip_service_workers", &self.strip_service_workers)
+            .field("provenance", &self.provenance)
+            .field("url_rewriter", &self.url_rewriter.as_ref().map(|_| ".."))
+            .field("embedders", &self.embedders.len())
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+impl EmbedOptions {
+    /// Whether the resource is an SVG image to be embedded with its text
+    /// rather than base 64 encoded
+    pub(crate) fn encode_as_svg(&self, resource: &Resource) -> bool {
+        self.svg != SvgEmbedding::Base64 && resource.mimetype() == SVG_MIMETYPE
+    }
+
+    /// The reference to use instead of embedding the resource, if it is
+    /// an image larger than the [`image_size_limit`](Self::image_size_limit)
+    pub(crate) fn oversized_image(
+        &self,
+        url: &Url,
+        resource: &Resource,
+    ) -> Option<String> {
+        let limit = self.image_size_limit?;
+        if resource.kind() != Some(ResourceKind::Image)
+            || resource.size() <= limit
+        {
+            return None;
+        }
+        match self.oversized_images {
+            OversizedImages::Placeholder => encode_svg_data_uri(
+                image_placeholder(resource.size()).as_bytes(),
+            ),
+            OversizedImages::Link => Some(url.to_string()),
+        }
+    }
+
+    /// The replacement for a resource's URL from the
+    /// [`url_rewriter`](Self::url_rewriter), if there is one
+    pub(crate) fn rewrite_url(
+        &self,
+        url: &Url,
+        resource: Option<&Resource>,
+    ) -> Option<String> {
+        self.url_rewriter.as_ref()?(url, resource)
+    }
+}
+
+/// Callback choosing the URL of a resource in the embedded output, see
+/// [`EmbedOptions::url_rewriter`]
+///
+/// It is called with the absolute URL of each image, stylesheet, script,
+/// frame, and other resource referenced by the page or its stylesheets,
+/// along with the downloaded copy if there is one. Returning `Some`
+/// replaces the reference with the string and leaves the resource out of
+/// the output, while `None` falls back to embedding it as usual.
+///
+/// ## Example
+/// ```
+/// use std::sync::Arc;
+/// use url::Url;
+/// use web_archive::{EmbedOptions, PageArchive, Resource, ResourceMap};
+///
+/// let mut resource_map = ResourceMap::new();
+/// resource_map.insert(
+///     Url::parse("http://example.com/style.css").unwrap(),
+///     Resource::Css("body {}".into()),
+/// );
+/// let archive = PageArchive::from_parts(
+///     "http://example.com",
+///     r#"<link rel="stylesheet" href="style.css"><img src="logo.png">"#,
+///     resource_map,
+/// )
+/// .unwrap();
+/// let options = EmbedOptions {
+///     url_rewriter: Some(Arc::new(|url: &Url, _: Option<&Resource>| {
+///         if url.path().ends_with(".png") {
+///             Some(format!("https://cdn.example.net{}", url.path()))
+///         } else {
+///             None
+///         }
+///     })),
+///     ..Default::default()
+/// };
+/// let page = archive.embed_resources_with(&options);
+/// assert!(page.contains(r#"<img src="https://cdn.example.net/logo.png">"#));
+/// assert!(page.contains("<style>body {}</style>"));
+/// ```
+pub type UrlRewriter =
+    Arc<dyn Fn(&Url, Option<&Resource>) -> Option<String> + Send + Sync>;
+
+/// A step of the embedding pipeline added by the application, see
+/// [`EmbedOptions::embedders`]
+///
+/// Embedders are run in order over the page and over each document
+/// nested in it, after the built-in rules have embedded the images,
+/// stylesheets, scripts, and other resources that they know about. They
+/// may rewrite, insert, or remove any node of the document.
+///
+/// ## Example
+/// ```
+/// use kuchiki::NodeRef;
+/// use std::sync::Arc;
+/// use url::Url;
+/// use web_archive::{
+///     EmbedOptions, Embedder, PageArchive, Resource, ResourceMap,
+/// };
+///
+/// /// Embeds the images listed in a site's JSON asset manifest
+/// struct AssetManifest;
+///
+/// impl Embedder for AssetManifest {
+///     fn embed(&self, doc: &NodeRef, base: &Url, resources: &ResourceMap) {
+///         for script in doc.select("script#assets").unwrap() {
+///             let json = script.text_contents();
+///             let mut assets: serde_json::Map<String, serde_json::Value> =
+///                 serde_json::from_str(&json).unwrap();
+///             for value in assets.values_mut() {
+///                 let url = base.join(value.as_str().unwrap()).unwrap();
+///                 if let Some(resource) = resources.get(&url) {
+///                     *value = resource.to_data_uri().unwrap().into();
+///                 }
+///             }
+///             let node = script.as_node();
+///             node.first_child().unwrap().detach();
+///             node.append(NodeRef::new_text(
+///                 serde_json::to_string(&assets).unwrap(),
+///             ));
+///         }
+///     }
+/// }
+///
+/// let mut resource_map = ResourceMap::new();
+/// resource_map.insert(
+///     Url::parse("http://example.com/logo.svg").unwrap(),
+///     Resource::Other {
+///         data: "<svg/>".into(),
+///         mimetype: "image/svg+xml".to_string(),
+///     },
+/// );
+/// let archive = PageArchive::from_parts(
+///     "http://example.com",
+///     r#"<script id="assets">{"logo": "logo.svg"}</script>"#,
+///     resource_map,
+/// )
+/// .unwrap();
+/// let options = EmbedOptions {
+///     embedders: vec![Arc::new(AssetManifest)],
+///     ..Default::default()
+/// };
+/// let page = archive.embed_resources_with(&options);
+/// assert!(page.contains(r#"{"logo":"data:image/svg+xml;base64,PHN2Zy8+"}"#));
+/// ```
+pub trait Embedder: Send + Sync {
+    /// Rewrite the document, whose relative URLs are resolved against
+    /// `base`, using the archive's downloaded resources
+    fn embed(&self, document: &NodeRef, base: &Url, resources: &ResourceMap);
+}
+
+/// How archival provenance (the original URL, the time at which the page
+/// was fetched, and the version of this crate) is recorded in the
+/// embedded output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provenance {
+    /// Insert an HTML comment at the start of the `<head>`
+    Comment,
+    /// Insert a small visible banner at the top of the `<body>`, with a
+    /// link to the original page
+    Banner,
+}
+
+/// How forms are handled in the embedded output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FormHandling {
+    /// Leave forms as they are
+    #[default]
+    Keep,
+    /// Remove the `action` of each form and the `formaction` of its
+    /// buttons, and disable its controls
+    Disable,
+    /// Replace each `<form>` with a `<div>` containing its disabled
+    /// controls, so that nothing remains which can be submitted
+    Static,
+}
+
+/// How downloaded stylesheets are embedded in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StylesheetEmbedding {
+    /// Replace each `<link>` with a `<style>` containing the CSS.
+    /// Alternate and `disabled` stylesheets are embedded as with
+    /// [`DataUri`](Self::DataUri) instead, as a `<style>` cannot be
+    /// switched on by a theme switcher.
+    #[default]
+    Style,
+    /// Keep each `<link>` and its attributes, rewriting its `href` to a
+    /// `data:text/css` URI, so that its position in the cascade is
+    /// exactly as it was
+    DataUri,
+}
+
+/// How downloaded scripts are embedded in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScriptEmbedding {
+    /// Insert the script's text into its `<script>` and remove the `src`
+    #[default]
+    Inline,
+    /// Rewrite the script's `src` to a `data:text/javascript` URI.
+    /// External scripts behave differently from inline ones, e.g.
+    /// `async` and `defer` only apply to scripts with a `src`, so this
+    /// keeps their original loading behaviour.
+    DataUri,
+}
+
+/// What takes the place of an image which is too large to embed, see
+/// [`EmbedOptions::image_size_limit`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OversizedImages {
+    /// A small grey SVG stating that the image was left out and its
+    /// size, which is scaled to the `width` and `height` of the element
+    /// showing it
+    #[default]
+    Placeholder,
+    /// The image's absolute URL, so that it is loaded from the original
+    /// site when the page is viewed online
+    Link,
+}
+
+/// The SVG shown instead of an image of the given size in bytes, see
+/// [`OversizedImages::Placeholder`]
+fn image_placeholder(size: usize) -> String {
+    let size = if size >= 1_000_000 {
+        format!("{:.1} MB", size as f64 / 1_000_000.0)
+    } else {
+        format!("{} kB", size.div_ceil(1000))
+    };
+    format!(
+        "<svg xmlns='http://www.w3.org/2000/svg' width='300' height='150' \
+         viewBox='0 0 300 150' preserveAspectRatio='none'>\
+         <rect width='300' height='150' fill='#ddd'/>\
+         <text x='150' y='80' text-anchor='middle' font-family='sans-serif' \
+         font-size='14' fill='#555'>Image omitted ({})</text></svg>",
+        size
+    )
+}
+
+/// Mime type of SVG images
+pub(crate) const SVG_MIMETYPE: &str = "image/svg+xml";
+
+/// How downloaded SVG images are embedded in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SvgEmbedding {
+    /// Base 64 encode the image into a `data:image/svg+xml;base64,` URI,
+    /// like any other image
+    #[default]
+    Base64,
+    /// Keep the text of the image in a `data:image/svg+xml,` URI,
+    /// percent-encoding only the characters which need it. This is
+    /// usually a good deal smaller than base 64, which adds a third to
+    /// the size of the image.
+    UrlEncoded,
+    /// Replace each `<img>` showing the image with the `<svg>` element
+    /// itself, so that the page's CSS can style it, e.g. with
+    /// `currentColor`. The `id`, `class`, `style`, `width` and `height`
+    /// of the `<img>` are moved to the `<svg>`, and its `alt` text
+    /// becomes an `aria-label`. An image shown several times is copied
+    /// each time, so the `id`s within it may clash.
+    ///
+    /// Unlike in an `<img>`, the scripts of an inlined SVG would run in
+    /// the page, so each image is sanitised, as with
+    /// [`EmbedOptions::sanitize`], whether or not that option is set:
+    /// `<script>` elements, `on*` event handlers, and `javascript:` and
+    /// other unsafe links are removed. SVGs referenced in
+    /// other ways, e.g. from stylesheets, are embedded as with
+    /// [`UrlEncoded`](Self::UrlEncoded).
+    Inline,
+}
+
+/// How the whitespace of the embedded output is laid out
+///
+/// Whitespace is left alone inside `<pre>`, `<textarea>`, `<script>`,
+/// and `<style>`, and is only removed or added where it has no effect on
+/// how the page is rendered, judged from the elements' default display.
+/// Elements which a stylesheet gives `white-space: pre` may lose their
+/// layout when compacted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Keep the page's whitespace as the HTML serialiser writes it
+    #[default]
+    Unchanged,
+    /// Collapse runs of whitespace in text to a single space, and remove
+    /// whitespace between block-level elements, for the smallest output
+    Compact,
+    /// Compact the output, then put each block-level element which only
+    /// contains other block-level elements on its own line, indented by
+    /// two spaces for each level of nesting
+    Pretty,
+}
+
+/// Elements whose whitespace is rendered as written
+const PRESERVE_WHITESPACE: [&str; 7] = [
+    "pre",
+    "textarea",
+    "script",
+    "style",
+    "listing",
+    "plaintext",
+    "xmp",
+];
+
+/// Elements which are displayed as blocks by default, or not at all, so
+/// that whitespace next to them is not rendered
+const BLOCK_ELEMENTS: [&str; 52] = [
+    "address",
+    "article",
+    "aside",
+    "base",
+    "blockquote",
+    "body",
+    "caption",
+    "col",
+    "colgroup",
+    "dd",
+    "details",
+    "dialog",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "li",
+    "link",
+    "main",
+    "menu",
+    "meta",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "script",
+    "section",
+    "style",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "ul",
+];
+
+/// Apply the options which change the document itself rather than its
+/// resources: form handling, iframe placeholders, sanitising and
+/// formatting, in that order
+pub(crate) fn finish_document(
+    document: &NodeRef,
+    url: &Url,
+    options: &EmbedOptions,
+) {
+    neutralize_forms(document, options.forms);
+
+    if options.iframe_placeholders {
+        replace_iframes(document, url);
+    }
+
+    if options.strip_service_workers {
+        strip_service_workers(document);
+    }
+
+    if options.sanitize {
+        sanitize(document);
+    }
+
+    format_document(document, options.format);
+}
+
+/// Lay out the whitespace of the document according to the format,
+/// including in the documents nested in the `srcdoc` of its iframes
+pub(crate) fn format_document(document: &NodeRef, format: OutputFormat) {
+    if format == OutputFormat::Unchanged {
+        return;
+    }
+    let frames: Vec<_> = document.select("iframe[srcdoc]").unwrap().collect();
+    for frame in frames {
+        let mut attr = frame.attributes.borrow_mut();
+        if let Some(srcdoc) = attr.get_mut("srcdoc") {
+            let nested = parse_html().one(srcdoc.as_str());
+            format_document(&nested, format);
+            *srcdoc = nested.to_string();
+        }
+    }
+
+    compact_whitespace(document);
+    if format == OutputFormat::Pretty {
+        for html in document.select("html").unwrap().take(1) {
+            indent_blocks(html.as_node(), 0);
+        }
+        // Start the `<html>` on the line after the DOCTYPE
+        if let Some(doctype) = document
+            .children()
+            .find(|child| matches!(child.data(), NodeData::Doctype(_)))
+        {
+            doctype.insert_after(NodeRef::new_text("\n"));
+        }
+    }
+}
+
+/// Whether the node is an element displayed as a block, or a document
+fn is_block(node: &NodeRef) -> bool {
+    match node.data() {
+        NodeData::Element(element) => {
+            BLOCK_ELEMENTS.contains(&&*element.name.local)
+        }
+        NodeData::Document(_) => true,
+        _ => false,
+    }
+}
+
+/// Whether whitespace inside the node is rendered as written
+fn preserves_whitespace(node: &NodeRef) -> bool {
+    node.as_element().is_some_and(|element| {
+        PRESERVE_WHITESPACE.contains(&&*element.name.local)
+    })
+}
+
+/// Collapse the whitespace in the text under the node, removing text
+/// which is only whitespace between block boundaries
+fn compact_whitespace(node: &NodeRef) {
+    for child in node.children().collect::<Vec<_>>() {
+        if let Some(text) = child.as_text() {
+            let collapsed = collapse_whitespace(&text.borrow());
+            if collapsed == " " && is_block_boundary(&child) {
+                child.detach();
+            } else {
+                *text.borrow_mut() = collapsed;
+            }
+        } else if !preserves_whitespace(&child) {
+            compact_whitespace(&child);
+        }
+    }
+}
+
+/// Replace each run of ASCII whitespace with a single space
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut in_whitespace = false;
+    for c in text.chars() {
+        if c.is_ascii_whitespace() {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    collapsed
+}
+
+/// Whether a text node lies between the edges of blocks on both sides,
+/// ignoring comments and other whitespace, so that whitespace in it is
+/// not rendered
+fn is_block_boundary(text: &NodeRef) -> bool {
+    let is_transparent = |node: &NodeRef| {
+        node.as_comment().is_some()
+            || node.as_text().is_some_and(|text| {
+                text.borrow().chars().all(|c| c.is_ascii_whitespace())
+            })
+    };
+    let previous = text.preceding_siblings().find(|node| !is_transparent(node));
+    let next = text.following_siblings().find(|node| !is_transparent(node));
+    let parent_is_block = text.parent().is_some_and(|parent| is_block(&parent));
+    previous.map_or(parent_is_block, |node| is_block(&node))
+        && next.map_or(parent_is_block, |node| is_block(&node))
+}
+
+/// Put each child of the node on its own line if they are all blocks or
+/// comments, then do the same for the children
+fn indent_blocks(node: &NodeRef, depth: usize) {
+    let children: Vec<_> = node.children().collect();
+    let only_blocks = children.iter().any(is_block)
+        && children
+            .iter()
+            .all(|child| is_block(child) || child.as_comment().is_some());
+    if only_blocks && !preserves_whitespace(node) {
+        let indent = "  ";
+        for child in &children {
+            child.insert_before(NodeRef::new_text(format!(
+                "\n{}",
+                indent.repeat(depth + 1)
+            )));
+        }
+        node.append(NodeRef::new_text(format!("\n{}", indent.repeat(depth))));
+    }
+    for child in &children {
+        if !preserves_whitespace(child) {
+            indent_blocks(child, depth + 1);
+        }
+    }
+}
+
+/// Form controls which are disabled by [`FormHandling::Disable`]
+const FORM_CONTROLS: &str = "input, button, select, textarea, fieldset";
+
+/// Apply the form handling option to the document
+pub(crate) fn neutralize_forms(document: &NodeRef, handling: FormHandling) {
+    if handling == FormHandling::Keep {
+        return;
+    }
+
+    let forms: Vec<_> = document.select("form").unwrap().collect();
+    for form in forms {
+        let node = form.as_node();
+        form.attributes.borrow_mut().remove("action");
+        for control in node.select(FORM_CONTROLS).unwrap() {
+            let mut attributes = control.attributes.borrow_mut();
+            attributes.remove("formaction");
+            attributes.insert("disabled", String::new());
+        }
+
+        if handling == FormHandling::Static {
+            let attributes = form.attributes.borrow();
+            let retained: Vec<(&str, &str)> = attributes
+                .map
+                .iter()
+                .map(|(name, attr)| (&*name.local, attr.value.as_str()))
+                .filter(|(name, _)| {
+                    !matches!(*name, "method" | "target" | "enctype")
+                })
+                .collect();
+            let div = new_html_element("div", &retained);
+            for child in node.children().collect::<Vec<_>>() {
+                div.append(child);
+            }
+            node.insert_before(div);
+            node.detach();
+        }
+    }
+}
+
+/// Replace the document's iframes with placeholders linking to their
+/// original URLs. See [`EmbedOptions::iframe_placeholders`].
+pub(crate) fn replace_iframes(document: &NodeRef, url: &Url) {
+    let iframes: Vec<_> = document.select("iframe[src]").unwrap().collect();
+    for iframe in iframes {
+        let attributes = iframe.attributes.borrow();
+        let src = match url.join(attributes.get("src").unwrap_or_default()) {
+            Ok(src) if !matches!(src.scheme(), "about" | "data") => src,
+            _ => continue,
+        };
+
+        let mut style = String::from(
+            "display: inline-block; box-sizing: border-box; \
+             overflow: hidden; padding: 8px; font: 12px sans-serif; \
+             background: #eee; color: #000; border: 1px dashed #999;",
+        );
+        for dimension in ["width", "height"].iter() {
+            if let Some(value) = attributes.get(*dimension) {
+                let value = value.trim();
+                if !value.is_empty()
+                    && value.chars().all(|c| c.is_ascii_digit())
+                {
+                    style.push_str(&format!(" {}: {}px;", dimension, value));
+                } else if !value.is_empty() {
+                    style.push_str(&format!(" {}: {};", dimension, value));
+                }
+            }
+        }
+
+        let placeholder = new_html_element(
+            "div",
+            &[("class", "web-archive-iframe"), ("style", &style)],
+        );
+        let title = attributes.get("title").unwrap_or("Embedded frame");
+        placeholder.append(NodeRef::new_text(format!("{}: ", title)));
+        let link = new_html_element("a", &[("href", src.as_str())]);
+        link.append(NodeRef::new_text(display_url(&src)));
+        placeholder.append(link);
+
+        iframe.as_node().insert_before(placeholder);
+        drop(attributes);
+        iframe.as_node().detach();
+    }
+}
+
+/// Script inserted by [`EmbedOptions::strip_service_workers`] so that
+/// service worker registrations are rejected
+const SERVICE_WORKER_BLOCKER: &str = "if (window.ServiceWorkerContainer) { \
+    ServiceWorkerContainer.prototype.register = function () { return \
+    Promise.reject(new Error(\"Service workers are disabled in this \
+    archived page\")); }; }";
+
+/// Remove the document's service worker links and block registrations by
+/// its scripts. See [`EmbedOptions::strip_service_workers`].
+pub(crate) fn strip_service_workers(document: &NodeRef) {
+    let links: Vec<_> = document
+        .select("link[rel~=serviceworker]")
+        .unwrap()
+        .collect();
+    for link in links {
+        link.as_node().detach();
+    }
+
+    if document.select_first("script").is_err() {
+        return;
+    }
+    if let Ok(head) = document.select_first("head") {
+        let script = new_html_element("script", &[]);
+        script.append(NodeRef::new_text(SERVICE_WORKER_BLOCKER));
+        head.as_node().prepend(script);
+    }
+}
+
+/// Insert the provenance record into the document
+pub(crate) fn insert_provenance(
+    document: &NodeRef,
+    provenance: Provenance,
+    url: &Url,
+    timestamp: SystemTime,
+) {
+    let timestamp = humantime::format_rfc3339_seconds(timestamp).to_string();
+    let version = env!("CARGO_PKG_VERSION");
+
+    match provenance {
+        Provenance::Comment => {
+            if let Ok(head) = document.select_first("head") {
+                // "--" may not appear within a comment
+                let url = display_url(url).replace("--", "-%2D");
+                head.as_node().prepend(NodeRef::new_comment(format!(
+                    " Archived by web-archive {}\n     url: {}\n     \
+                     saved: {} ",
+                    version, url, timestamp
+                )));
+            }
+        }
+        Provenance::Banner => {
+            if let Ok(body) = document.select_first("body") {
+                let banner = new_html_element(
+                    "div",
+                    &[
+                        ("id", "web-archive-provenance"),
+                        (
+                            "style",
+                            "font: 12px sans-serif; padding: 4px 8px; \
+                             background: #ffd; color: #000; \
+                             border-bottom: 1px solid #cc9;",
+                        ),
+                    ],
+                );
+                banner.append(NodeRef::new_text("Archived copy of "));
+                let link = new_html_element("a", &[("href", url.as_str())]);
+                link.append(NodeRef::new_text(display_url(url)));
+                banner.append(link);
+                banner.append(NodeRef::new_text(format!(
+                    " saved {} by web-archive {}",
+                    timestamp, version
+                )));
+                body.as_node().prepend(banner);
+            }
+        }
+    }
+}
+
+/// Create a new HTML element with the given attributes
+pub(crate) fn new_html_element(
+    name: &str,
+    attributes: &[(&str, &str)],
+) -> NodeRef {
+    NodeRef::new_element(
+        QualName::new(None, ns!(html), LocalName::from(name)),
+        attributes.iter().map(|(name, value)| {
+            (
+                ExpandedName::new(ns!(), LocalName::from(*name)),
+                Attribute {
+                    prefix: None,
+                    value: value.to_string(),
+                },
+            )
+        }),
+    )
+}
+
+/// Summary of the structural changes introduced by parsing and
+/// re-serialising a page, e.g. elements that were implied by the parser
+/// or a DOCTYPE declaration that was normalised.
+///
+/// The report is produced by comparing the tags in the original markup
+/// with the elements in the parsed document, so it is a best-effort
+/// summary: tag-like text inside scripts or comments is counted as
+/// markup.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FidelityReport {
+    /// The DOCTYPE declaration in the original page, if there was one
+    pub original_doctype: Option<String>,
+    /// Whether the serialised DOCTYPE differs from the original one
+    pub doctype_normalised: bool,
+    /// Elements created by the parser which did not appear in the
+    /// original markup, along with how many were created, e.g. an
+    /// implied `<tbody>`
+    pub inserted_elements: BTreeMap<String, usize>,
+    /// Elements which appeared in the original markup but were dropped
+    /// by the parser, e.g. a duplicate `<body>`
+    pub removed_elements: BTreeMap<String, usize>,
+    /// Whether whitespace between the DOCTYPE, `<html>`, and `<head>`
+    /// tags was discarded
+    pub whitespace_collapsed: bool,
+}
+
+impl FidelityReport {
+    /// Returns `true` if re-serialising the page introduced no
+    /// detectable structural changes
+    pub fn is_faithful(&self) -> bool {
+        !self.doctype_normalised
+            && self.inserted_elements.is_empty()
+            && self.removed_elements.is_empty()
+            && !self.whitespace_collapsed
+    }
+}
+
+/// Build a [`FidelityReport`] comparing the original markup with the
+/// document parsed from it
+pub(crate) fn fidelity_report(
+    source: &str,
+    document: &NodeRef,
+) -> FidelityReport {
+    let original_doctype = source_doctype(source).map(str::to_string);
+    let serialised = document.to_string();
+    let doctype_normalised =
+        original_doctype.as_deref() != serialised_doctype(&serialised);
+
+    let source_tags = count_source_tags(source);
+    let mut document_tags = BTreeMap::new();
+    for element in document.descendants().elements() {
+        *document_tags
+            .entry(element.name.local.to_string())
+            .or_insert(0) += 1;
+    }
+
+    let mut inserted_elements = BTreeMap::new();
+    for (name, count) in document_tags.iter() {
+        let in_source = source_tags.get(name).copied().unwrap_or(0);
+        if *count > in_source {
+            inserted_elements.insert(name.clone(), count - in_source);
+        }
+    }
+    let mut removed_elements = BTreeMap::new();
+    for (name, count) in source_tags.iter() {
+        let in_document = document_tags.get(name).copied().unwrap_or(0);
+        if *count > in_document {
+            removed_elements.insert(name.clone(), count - in_document);
+        }
+    }
+
+    let whitespace = source_whitespace(source);
+    let whitespace_collapsed = !whitespace.before_html.is_empty()
+        || !whitespace.before_head.is_empty();
+
+    FidelityReport {
+        original_doctype,
+        doctype_normalised,
+        inserted_elements,
+        removed_elements,
+        whitespace_collapsed,
+    }
+}
+
+/// URL schemes which are kept by [`sanitize`]
+const SAFE_SCHEMES: [&str; 5] = ["http", "https", "mailto", "tel", "ftp"];
+
+/// Mimetypes of `data:` URIs which can run scripts, except in an `<img>`
+const SCRIPTABLE_MIMETYPES: [&str; 5] = [
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+];
+
+/// Attributes which contain URLs
+const URL_ATTRIBUTES: [&str; 9] = [
+    "href",
+    "src",
+    "action",
+    "formaction",
+    "data",
+    "poster",
+    "background",
+    "cite",
+    "srcset",
+];
+
+/// Remove scripts, event handlers, redirects, and dangerous URLs from
+/// the document, or from an element and its descendants. See
+/// [`EmbedOptions::sanitize`].
+pub(crate) fn sanitize(document: &NodeRef) {
+    let removed: Vec<_> = document
+        .select("script, meta[http-equiv], animate, set")
+        .unwrap()
+        .filter(|element| {
+            let attr = element.attributes.borrow();
+            match &*element.name.local {
+                "script" => true,
+                "meta" => attr.get("http-equiv").is_some_and(|equiv| {
+                    let equiv = equiv.trim().to_ascii_lowercase();
+                    equiv == "refresh" || equiv == "set-cookie"
+                }),
+                // e.g. <set attributeName="href" to="javascript:...">
+                _ => attr.get("attributeName").is_some_and(|name| {
+                    let name = name.trim().to_ascii_lowercase();
+                    name == "href" || name == "xlink:href"
+                }),
+            }
+        })
+        .collect();
+    for element in removed {
+        element.as_node().detach();
+    }
+
+    // Documents nested in iframes could otherwise still run scripts
+    let frames: Vec<_> = document.select("iframe[srcdoc]").unwrap().collect();
+    for frame in frames {
+        let mut attr = frame.attributes.borrow_mut();
+        if let Some(srcdoc) = attr.get_mut("srcdoc") {
+            let nested = parse_html().one(srcdoc.as_str());
+            sanitize(&nested);
+            *srcdoc = nested.to_string();
+        }
+    }
+
+    for element in document.inclusive_descendants().elements() {
+        let is_img = &*element.name.local == "img";
+        element.attributes.borrow_mut().map.retain(|name, attr| {
+            let name = name.local.to_lowercase();
+            if name.starts_with("on") {
+                return false;
+            }
+            if !URL_ATTRIBUTES.contains(&name.as_str()) {
+                return true;
+            }
+            if name == "srcset" {
+                // A comma-separated list of "<url> <descriptor>"
+                return attr.value.split(',').all(|candidate| {
+                    let url = candidate.split_whitespace().next();
+                    url.is_none_or(|url| is_safe_url(url, is_img))
+                });
+            }
+            is_safe_url(&attr.value, is_img)
+        });
+    }
+}
+
+/// Whether a URL uses a safe scheme
+fn is_safe_url(url: &str, is_img: bool) -> bool {
+    // Browsers ignore whitespace and control characters in the scheme
+    let url: String = url
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    let scheme = match url.find(':') {
+        // A colon after a path, query, or fragment delimiter isn't part
+        // of a scheme, so the URL is relative
+        Some(colon) if !url[..colon].contains(|c| "/?#".contains(c)) => {
+            &url[..colon]
+        }
+        _ => return true,
+    };
+    if scheme == "data" {
+        let mimetype = url[5..].split([';', ',']).next();
+        return is_img
+            || !mimetype.is_some_and(|m| SCRIPTABLE_MIMETYPES.contains(&m));
+    }
+    SAFE_SCHEMES.contains(&scheme)
+}
+
+/// Apply the serialisation fidelity options to the serialised output
+pub(crate) fn apply_fidelity(
+    source: &str,
+    mut output: String,
+    options: &EmbedOptions,
+) -> String {
+    if options.preserve_whitespace {
+        let whitespace = source_whitespace(source);
+
+        // Whitespace between the `<html>` and `<head>` tags
+        let lower = output.to_ascii_lowercase();
+        if let Some(html) = lower.find("<html") {
+            if let Some(end) = lower[html..].find('>') {
+                let end = html + end + 1;
+                if lower[end..].starts_with("<head") {
+                    output.insert_str(end, whitespace.before_head);
+                }
+            }
+        }
+
+        // Whitespace between the DOCTYPE and the `<html>` tag
+        let doctype_end = serialised_doctype(&output).map_or(0, str::len);
+        if doctype_end > 0
+            && output[doctype_end..]
+                .to_ascii_lowercase()
+                .starts_with("<html")
+        {
+            output.insert_str(doctype_end, whitespace.before_html);
+        }
+    }
+
+    if options.preserve_doctype {
+        if let Some(doctype) = source_doctype(source) {
+            let existing = serialised_doctype(&output).map_or(0, str::len);
+            output.replace_range(..existing, doctype);
+        }
+    }
+
+    output
+}
+
+/// Locate the DOCTYPE declaration at the start of some markup
+fn source_doctype(source: &str) -> Option<&str> {
+    let trimmed = source.trim_start_matches('\u{feff}').trim_start();
+    if !trimmed.to_ascii_lowercase().starts_with("<!doctype") {
+        return None;
+    }
+    trimmed.find('>').map(|end| &trimmed[..=end])
+}
+
+/// Locate the DOCTYPE declaration at the start of the serialiser output
+fn serialised_doctype(output: &str) -> Option<&str> {
+    if output.starts_with("<!DOCTYPE") {
+        output.find('>').map(|end| &output[..=end])
+    } else {
+        None
+    }
+}
+
+/// Whitespace in the original markup that the parser discards
+struct SourceWhitespace<'a> {
+    before_html: &'a str,
+    before_head: &'a str,
+}
+
+fn source_whitespace(source: &str) -> SourceWhitespace<'_> {
+    let lower = source.to_ascii_lowercase();
+    let mut whitespace = SourceWhitespace {
+        before_html: "",
+        before_head: "",
+    };
+
+    if let Some(html) = lower.find("<html") {
+        if let Some(doctype) = source_doctype(source) {
+            let doctype_end = source.find(doctype).unwrap_or(0) + doctype.len();
+            if doctype_end <= html
+                && source[doctype_end..html].trim().is_empty()
+            {
+                whitespace.before_html = &source[doctype_end..html];
+            }
+        }
+
+        if let Some(end) = lower[html..].find('>') {
+            let end = html + end + 1;
+            let rest = &source[end..];
+            let trimmed = rest.trim_start();
+            if trimmed.to_ascii_lowercase().starts_with("<head") {
+                whitespace.before_head = &rest[..rest.len() - trimmed.len()];
+            }
+        }
+    }
+
+    whitespace
+}
+
+/// Count the start tags of each element name in some markup
+fn count_source_tags(source: &str) -> BTreeMap<String, usize> {
+    let mut tags = BTreeMap::new();
+    let bytes = source.as_bytes();
+
+    for (idx, _) in source.match_indices('<') {
+        let name: String = bytes[idx + 1..]
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric() || **c == b'-')
+            .map(|c| c.to_ascii_lowercase() as char)
+            .collect();
+        if name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            *tags.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    tags
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: &str = "<!doctype HTML>\n<html>\n  <head>\n  </head>\n  \
+        <body><table><tr><td>1</td></tr></table></body>\n</html>";
+
+    #[test]
+    fn test_fidelity_report() {
+        let document = parse_html().one(PAGE);
+        let report = fidelity_report(PAGE, &document);
+
+        assert_eq!(report.original_doctype.as_deref(), Some("<!doctype HTML>"));
+        assert!(report.doctype_normalised);
+        assert!(report.whitespace_collapsed);
+        assert_eq!(report.inserted_elements.get("tbody"), Some(&1));
+        assert!(report.removed_elements.is_empty());
+        assert!(!report.is_faithful());
+    }
+
+    #[test]
+    fn test_faithful_page() {
+        let page = "<!DOCTYPE html><html><head></head><body></body></html>";
+        let document = parse_html().one(page);
+        assert!(fidelity_report(page, &document).is_faithful());
+    }
+
+    #[test]
+    fn test_provenance() {
+        let url = Url::parse("http://example.com/a--b").unwrap();
+        let timestamp = SystemTime::UNIX_EPOCH;
+
+        let document = parse_html().one(PAGE);
+        insert_provenance(&document, Provenance::Comment, &url, timestamp);
+        let output = document.to_string();
+        assert!(output.contains("<head><!-- Archived by web-archive"));
+        assert!(output.contains("url: http://example.com/a-%2Db"));
+        assert!(output.contains("saved: 1970-01-01T00:00:00Z"));
+
+        let document = parse_html().one(PAGE);
+        insert_provenance(&document, Provenance::Banner, &url, timestamp);
+        let output = document.to_string();
+        assert!(output.contains(r#"<body><div id="web-archive-provenance""#));
+        assert!(output.contains(
+            r#"<a href="http://example.com/a--b">http://example.com/a--b</a>"#
+        ));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let document = parse_html().one(
+            r#"<html><head>
+<meta http-equiv="Refresh" content="0; url=http://evil.example/">
+<meta http-equiv="content-type" content="text/html; charset=utf-8">
+<script>alert(1)</script></head>
+<body onload="alert(2)">
+<a href="javascript:alert(3)" OnClick="alert(4)" title="x">js</a>
+<a href=" jav&#x09;ascript:alert(5)">tab</a>
+<a href="page.html?q=a:b">relative</a>
+<a href="mailto:ferris@example.com">mail</a>
+<a href="data:text/html,<script>alert(6)</script>">data</a>
+<img src="data:image/svg+xml;base64,PHN2Zz4=" srcset="a.png 1x, javascript:x 2x">
+<object data="data:image/svg+xml;base64,PHN2Zz4="></object>
+<form action="vbscript:msgbox"><button formaction="https://example.com/">
+</button></form>
+<iframe srcdoc="<script>alert(7)</script><p onclick=alert(8)>frame</p>">
+</iframe>
+</body></html>"#,
+        );
+        sanitize(&document);
+        let output = document.to_string();
+
+        for removed in [
+            "alert",
+            "Refresh",
+            "javascript",
+            "vbscript",
+            "ascript",
+            "data:text/html",
+            "srcset",
+            "<object data",
+        ]
+        .iter()
+        {
+            assert!(!output.contains(removed), "{} in {}", removed, output);
+        }
+        for kept in [
+            r#"<meta content="text/html; charset=utf-8" http-equiv="content-type">"#,
+            r#"<a title="x">js</a>"#,
+            r#"<a href="page.html?q=a:b">"#,
+            r#"<a href="mailto:ferris@example.com">"#,
+            r#"<img src="data:image/svg+xml;base64,PHN2Zz4=">"#,
+            r#"<button formaction="https://example.com/">"#,
+        ]
+        .iter()
+        {
+            assert!(output.contains(kept), "{} not in {}", kept, output);
+        }
+    }
+
+    #[test]
+    fn test_neutralize_forms() {
+        let page = r#"<form id="search" action="/search" method="post">
+<input name="q"><button formaction="/lucky">Go</button></form>"#;
+
+        let document = parse_html().one(page);
+        neutralize_forms(&document, FormHandling::Keep);
+        assert_eq!(document.to_string(), parse_html().one(page).to_string());
+
+        let document = parse_html().one(page);
+        neutralize_forms(&document, FormHandling::Disable);
+        let output = document.to_string();
+        assert!(output.contains(r#"<form id="search" method="post">"#));
+        assert!(output.contains(r#"<input disabled="" name="q">"#));
+        assert!(output.contains(r#"<button disabled="">Go</button>"#));
+
+        let document = parse_html().one(page);
+        neutralize_forms(&document, FormHandling::Static);
+        let output = document.to_string();
+        assert!(!output.contains("<form"));
+        assert!(output.contains(r#"<div id="search">"#));
+        assert!(output.contains(r#"<input disabled="" name="q">"#));
+    }
+
+    #[test]
+    fn test_format_document() {
+        const PAGE: &str = "<!DOCTYPE html><html><head>
+    <title>Hello</title>   <!-- comment -->
+  </head>
+<body>
+  <div>
+    <p>Some  <b>bold</b>
+       <i>text</i></p>
+    <pre>  keep
+    this </pre>
+  </div>
+</body></html>";
+        let format = |format| {
+            let document = parse_html().one(PAGE);
+            format_document(&document, format);
+            document.to_string()
+        };
+
+        assert_eq!(
+            format(OutputFormat::Unchanged),
+            parse_html().one(PAGE).to_string()
+        );
+        assert_eq!(
+            format(OutputFormat::Compact),
+            "<!DOCTYPE html><html><head><title>Hello</title><!-- comment -->\
+             </head><body><div><p>Some <b>bold</b> <i>text</i></p>\
+             <pre>  keep\n    this </pre></div></body></html>"
+        );
+        assert_eq!(
+            format(OutputFormat::Pretty),
+            "<!DOCTYPE html>
+<html>
+  <head>
+    <title>Hello</title>
+    <!-- comment -->
+  </head>
+  <body>
+    <div>
+      <p>Some <b>bold</b> <i>text</i></p>
+      <pre>  keep
+    this </pre>
+    </div>
+  </body>
+</html>"
+        );
+    }
+
+    #[test]
+    fn test_replace_iframes() {
+        let document = parse_html().one(
+            r#"<iframe src="/video/1" width="560" height="50%" title="Video">
+</iframe><iframe src="about:blank"></iframe><iframe srcdoc="<p>hi</p>">
+</iframe>"#,
+        );
+        let url = Url::parse("http://example.com/page").unwrap();
+        replace_iframes(&document, &url);
+        let output = document.to_string();
+
+        assert_eq!(output.matches("<iframe").count(), 2);
+        assert!(!output.contains(r#"src="/video/1""#));
+        assert!(output.contains(
+            r#"Video: <a href="http://example.com/video/1">http://example.com/video/1</a></div>"#
+        ));
+        assert!(output.contains("width: 560px; height: 50%;"));
+    }
+
+    #[test]
+    fn test_strip_service_workers() {
+        let document = parse_html().one(
+            r#"<head><link rel="manifest serviceworker" href="/sw.js">
+            <link rel="stylesheet" href="style.css"></head>
+            <body><script>navigator.serviceWorker.register("sw.js")</script>"#,
+        );
+        strip_service_workers(&document);
+        let output = document.to_string();
+        assert!(!output.contains("/sw.js"));
+        assert!(output.contains("style.css"));
+        assert!(output.starts_with(
+            "<html><head><script>if (window.ServiceWorkerContainer) {"
+        ));
+
+        // Pages without scripts cannot register a worker
+        let document = parse_html().one("<p>Static</p>");
+        strip_service_workers(&document);
+        assert!(!document.to_string().contains("<script>"));
+    }
+
+    #[test]
+    fn test_apply_fidelity() {
+        let output = parse_html().one(PAGE).to_string();
+        assert!(output.starts_with("<!DOCTYPE html><html><head>"));
+
+        let options = EmbedOptions {
+            preserve_doctype: true,
+            preserve_whitespace: true,
+            ..Default::default()
+        };
+        let output = apply_fidelity(PAGE, output, &options);
+        assert!(output.starts_with("<!doctype HTML>\n<html>\n  <head>"));
+    }
+}
@@ -7,7 +7,10 @@
 
 //! Module for the error parsing functionality
 
+use reqwest::StatusCode;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
+use url::Url;
 
 /// Error type used by `web_archive` to wrap the errors returned by
 /// operations in this crate or errors from other sources (e.g. URL
@@ -18,6 +21,25 @@ pub enum Error {
     ParseError(String),
     /// Error fetching a resource
     ReqwestError(String),
+    /// Error reading or writing files
+    IoError(String),
+    /// The [`ArchiveOptions`](crate::ArchiveOptions) are invalid, for the
+    /// reason given
+    InvalidOptions(String),
+    /// An archive failed an integrity or signature check
+    VerificationError(String),
+    /// Two archives being merged hold different resources for the URL
+    MergeConflict(Url),
+    /// The output directory already exists and is not empty, see
+    /// [`ExistingOutput::Error`](crate::sink::ExistingOutput::Error)
+    OutputExists(PathBuf),
+    /// The server responded with an unsuccessful HTTP status
+    HttpStatus {
+        /// URL which was requested
+        url: Url,
+        /// Status code of the response
+        status: StatusCode,
+    },
 }
 
 impl From<reqwest::Error> for Error {
@@ -28,7 +50,7 @@ impl From<reqwest::Error> for Error {
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Self::ParseError(e.to_string())
+        Self::IoError(e.to_string())
     }
 }
 
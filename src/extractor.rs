@@ -0,0 +1,461 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for finding the resources referenced by a page
+//!
+//! Each [`ResourceExtractor`] searches the page's DOM for one kind of
+//! reference, such as the `src` of `<img>` elements. The built-in
+//! extractors are always run, and those added to
+//! [`ArchiveOptions::resource_extractors`](crate::ArchiveOptions::resource_extractors)
+//! are run after them, so that references the crate does not know about,
+//! e.g. in a CMS's data attributes, are archived too. The DOM is a
+//! [`kuchiki`] document, which is re-exported here.
+//!
+//! ```
+//! use web_archive::extractor::AttributeExtractor;
+//! use web_archive::{ArchiveOptions, ResourceKind};
+//!
+//! // Archive the images named in `data-hero` attributes
+//! let hero =
+//!     AttributeExtractor::new("[data-hero]", "data-hero", ResourceKind::Image)
+//!         .unwrap();
+//! let options = ArchiveOptions::builder()
+//!     .resource_extractor(hero)
+//!     .build()
+//!     .unwrap();
+//! ```
+
+use crate::css::css_resource_urls;
+use crate::error::Error;
+use crate::parsing::{
+    is_stylesheet_rel, ResourceKind, ResourceUrl, OTHER_RESOURCE_ATTRIBUTES,
+};
+pub use kuchiki;
+use kuchiki::iter::NodeIterator;
+use kuchiki::traits::TendrilSink;
+use kuchiki::{NodeRef, Selectors};
+use std::sync::Arc;
+use url::Url;
+
+/// A source of resource references in a page's DOM
+pub trait ResourceExtractor: Send + Sync {
+    /// The resources referenced by the document, with relative URLs
+    /// resolved against `base`. The URLs need not be sorted or unique.
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl>;
+}
+
+/// Finds images in the `src` of `<img>` elements
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageExtractor;
+
+impl ResourceExtractor for ImageExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        select_attribute(document, base, "img", "src", ResourceKind::Image)
+    }
+}
+
+/// Finds stylesheets in the `href` of `<link rel="stylesheet">` elements,
+/// including alternate stylesheets
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StylesheetExtractor;
+
+impl ResourceExtractor for StylesheetExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        let mut resource_urls = Vec::new();
+        for element in document.select("link").unwrap() {
+            let attr = element.attributes.borrow();
+            if attr.get("rel").is_some_and(is_stylesheet_rel) {
+                if let Some(u) = attr.get("href") {
+                    if let Ok(u) = base.join(u) {
+                        resource_urls.push(ResourceUrl::Css(u));
+                    }
+                }
+            }
+        }
+        resource_urls
+    }
+}
+
+/// Finds scripts in the `src` of `<script>` elements, and the modules in
+/// the `href` of `<link rel="modulepreload">` elements
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScriptExtractor;
+
+impl ResourceExtractor for ScriptExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        let mut resource_urls = select_attribute(
+            document,
+            base,
+            "script",
+            "src",
+            ResourceKind::Javascript,
+        );
+        resource_urls.extend(select_attribute(
+            document,
+            base,
+            "link[rel~=modulepreload]",
+            "href",
+            ResourceKind::Javascript,
+        ));
+        resource_urls
+    }
+}
+
+/// Finds icons, web app manifests and media files, which are stored as
+/// [`Resource::Other`](crate::Resource::Other)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MediaExtractor;
+
+impl ResourceExtractor for MediaExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        OTHER_RESOURCE_ATTRIBUTES
+            .iter()
+            .flat_map(|(selector, attribute)| {
+                select_attribute(
+                    document,
+                    base,
+                    selector,
+                    attribute,
+                    ResourceKind::Other,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Finds the documents shown in frames and iframes. Blank frames and
+/// those with a `srcdoc` need no download.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameExtractor;
+
+impl ResourceExtractor for FrameExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        let mut resource_urls = Vec::new();
+        for element in document.select("iframe, frame").unwrap() {
+            let attr = element.attributes.borrow();
+            // The `srcdoc` of an iframe takes precedence over its `src`
+            if attr.contains("srcdoc") {
+                continue;
+            }
+            if let Some(u) = attr.get("src") {
+                if let Ok(u) = base.join(u) {
+                    if !matches!(u.scheme(), "about" | "data" | "javascript") {
+                        resource_urls.push(ResourceUrl::Html(u));
+                    }
+                }
+            }
+        }
+        resource_urls
+    }
+}
+
+/// Finds the fonts, images, and imported stylesheets referenced by the
+/// page's `<style>` elements and `style` attributes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InlineStyleExtractor;
+
+impl ResourceExtractor for InlineStyleExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        let mut resource_urls = Vec::new();
+        for element in document.select("style").unwrap() {
+            resource_urls
+                .extend(css_resource_urls(base, &element.text_contents()));
+        }
+        for element in document.select("[style]").unwrap() {
+            let attr = element.attributes.borrow();
+            if let Some(style) = attr.get("style") {
+                resource_urls.extend(css_resource_urls(base, style));
+            }
+        }
+        resource_urls
+    }
+}
+
+/// Finds the files offered for download by `<a download>` and
+/// `<area download>` links, which are stored as
+/// [`Resource::Other`](crate::Resource::Other). Unlike the other
+/// extractors it is only run if
+/// [`download_attachments`](crate::ArchiveOptions::download_attachments)
+/// is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AttachmentExtractor;
+
+impl ResourceExtractor for AttachmentExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        select_attribute(
+            document,
+            base,
+            "a[download], area[download]",
+            "href",
+            ResourceKind::Other,
+        )
+        .into_iter()
+        .filter(|resource_url| {
+            !matches!(
+                resource_url.url().scheme(),
+                "about" | "blob" | "data" | "javascript" | "mailto"
+            )
+        })
+        .collect()
+    }
+}
+
+/// Finds the scripts of the service workers registered by the page, in
+/// the `href` of `<link rel="serviceworker">` elements and in
+/// `navigator.serviceWorker.register("sw.js")` calls with a string
+/// literal in its inline scripts. Like [`AttachmentExtractor`], it is
+/// only run if
+/// [`service_workers`](crate::ArchiveOptions::service_workers) is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ServiceWorkerExtractor;
+
+impl ResourceExtractor for ServiceWorkerExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        let mut resource_urls = select_attribute(
+            document,
+            base,
+            "link[rel~=serviceworker]",
+            "href",
+            ResourceKind::Javascript,
+        );
+        for element in document.select("script:not([src])").unwrap() {
+            let script = element.text_contents();
+            for u in service_worker_registrations(&script) {
+                if let Ok(u) = base.join(u) {
+                    resource_urls.push(ResourceUrl::Javascript(u));
+                }
+            }
+        }
+        resource_urls
+    }
+}
+
+/// The script URLs passed as string literals to
+/// `serviceWorker.register()` calls in the script
+fn service_worker_registrations(script: &str) -> Vec<&str> {
+    const CALL: &str = "serviceWorker.register";
+    let mut urls = Vec::new();
+    let mut rest = script;
+    while let Some(start) = rest.find(CALL) {
+        rest = &rest[start + CALL.len()..];
+        let argument = match rest.trim_start().strip_prefix('(') {
+            Some(argument) => argument.trim_start(),
+            None => continue,
+        };
+        let quote = match argument.chars().next() {
+            Some(quote @ ('"' | '\'' | '`')) => quote,
+            _ => continue,
+        };
+        let literal = &argument[1..];
+        if let Some(end) = literal.find(quote) {
+            let url = &literal[..end];
+            // Template literals with substitutions cannot be resolved
+            if !url.contains('\\') && !url.contains("${") {
+                urls.push(url);
+            }
+        }
+    }
+    urls
+}
+
+/// Finds resources of one kind in an attribute of the elements matching
+/// a CSS selector
+pub struct AttributeExtractor {
+    selectors: Selectors,
+    attribute: String,
+    kind: ResourceKind,
+}
+
+impl AttributeExtractor {
+    /// Extract the URLs in `attribute` of the elements which match
+    /// `selector` as resources of the given kind. Fails if the selector
+    /// is invalid.
+    pub fn new(
+        selector: &str,
+        attribute: &str,
+        kind: ResourceKind,
+    ) -> Result<Self, Error> {
+        let selectors = Selectors::compile(selector).map_err(|()| {
+            Error::ParseError(format!("Invalid selector: {}", selector))
+        })?;
+        Ok(Self {
+            selectors,
+            attribute: attribute.to_string(),
+            kind,
+        })
+    }
+}
+
+impl ResourceExtractor for AttributeExtractor {
+    fn extract(&self, document: &NodeRef, base: &Url) -> Vec<ResourceUrl> {
+        self.selectors
+            .filter(document.inclusive_descendants().elements())
+            .filter_map(|element| {
+                let attr = element.attributes.borrow();
+                let u = base.join(attr.get(self.attribute.as_str())?).ok()?;
+                Some(ResourceUrl::new(self.kind, u))
+            })
+            .collect()
+    }
+}
+
+/// The extractors which are always run, in order
+const BUILTIN_EXTRACTORS: [&dyn ResourceExtractor; 6] = [
+    &ImageExtractor,
+    &StylesheetExtractor,
+    &ScriptExtractor,
+    &MediaExtractor,
+    &FrameExtractor,
+    &InlineStyleExtractor,
+];
+
+/// Run the built-in extractors followed by any others over the document,
+/// and over the documents nested in the `srcdoc` of its iframes, which
+/// use the same base URL. The returned list is sorted and free of
+/// duplicates.
+pub(crate) fn extract_resource_urls(
+    document: &NodeRef,
+    base: &Url,
+    extractors: &[Arc<dyn ResourceExtractor>],
+) -> Vec<ResourceUrl> {
+    let mut resource_urls = Vec::new();
+    push_resource_urls(document, base, extractors, &mut resource_urls);
+
+    // Dedup the URLs to avoid fetching the same one twice
+    resource_urls.sort();
+    resource_urls.dedup();
+
+    resource_urls
+}
+
+fn push_resource_urls(
+    document: &NodeRef,
+    base: &Url,
+    extractors: &[Arc<dyn ResourceExtractor>],
+    resource_urls: &mut Vec<ResourceUrl>,
+) {
+    let all = BUILTIN_EXTRACTORS
+        .iter()
+        .copied()
+        .chain(extractors.iter().map(|extractor| extractor.as_ref()));
+    for extractor in all {
+        resource_urls.extend(extractor.extract(document, base));
+    }
+
+    for element in document.select("iframe[srcdoc]").unwrap() {
+        let attr = element.attributes.borrow();
+        if let Some(srcdoc) = attr.get("srcdoc") {
+            let nested = kuchiki::parse_html().one(srcdoc);
+            push_resource_urls(&nested, base, extractors, resource_urls);
+        }
+    }
+}
+
+/// Resolve the `attribute` of each element matching `selector` as a
+/// resource of the given kind
+fn select_attribute(
+    document: &NodeRef,
+    base: &Url,
+    selector: &str,
+    attribute: &str,
+    kind: ResourceKind,
+) -> Vec<ResourceUrl> {
+    document
+        .select(selector)
+        .unwrap()
+        .filter_map(|element| {
+            let attr = element.attributes.borrow();
+            let u = base.join(attr.get(attribute)?).ok()?;
+            Some(ResourceUrl::new(kind, u))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_attribute_extractor() {
+        let base = Url::parse("http://example.com/blog/").unwrap();
+        let document = kuchiki::parse_html().one(
+            r#"<div data-hero="hero.jpg"></div>
+            <iframe srcdoc="<div data-hero='/nested.jpg'></div>"></iframe>
+            <img src="ferris.png">"#,
+        );
+        let extractor: Arc<dyn ResourceExtractor> = Arc::new(
+            AttributeExtractor::new(
+                "[data-hero]",
+                "data-hero",
+                ResourceKind::Image,
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            extract_resource_urls(&document, &base, &[extractor]),
+            vec![
+                ResourceUrl::Image(base.join("ferris.png").unwrap()),
+                ResourceUrl::Image(base.join("hero.jpg").unwrap()),
+                ResourceUrl::Image(base.join("/nested.jpg").unwrap()),
+            ]
+        );
+        assert_eq!(
+            extract_resource_urls(&document, &base, &[]),
+            vec![ResourceUrl::Image(base.join("ferris.png").unwrap())]
+        );
+
+        assert!(matches!(
+            AttributeExtractor::new("[", "src", ResourceKind::Image),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_attachment_extractor() {
+        let base = Url::parse("http://example.com/blog/").unwrap();
+        let document = kuchiki::parse_html().one(
+            r#"<a href="report.pdf" download>Report</a>
+            <a href="/data.csv" download="figures.csv">Figures</a>
+            <a href="data:text/plain,hello" download>Hello</a>
+            <a href="next.html">Next</a>"#,
+        );
+        assert_eq!(
+            AttachmentExtractor.extract(&document, &base),
+            vec![
+                ResourceUrl::Other(base.join("report.pdf").unwrap()),
+                ResourceUrl::Other(base.join("/data.csv").unwrap()),
+            ]
+        );
+        // Attachments are not extracted unless asked for
+        assert!(extract_resource_urls(&document, &base, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_service_worker_extractor() {
+        let base = Url::parse("http://example.com/blog/").unwrap();
+        let document = kuchiki::parse_html().one(
+            r#"<link rel="serviceworker" href="/sw.js">
+            <script>
+                if ("serviceWorker" in navigator) {
+                    navigator.serviceWorker.register( 'worker.js', {
+                        scope: "./",
+                    });
+                    navigator.serviceWorker.register(`${root}/dynamic.js`);
+                    navigator.serviceWorker.register(workerUrl);
+                }
+            </script>
+            <script src="app.js">navigator.serviceWorker.register("no.js")</script>"#,
+        );
+        assert_eq!(
+            ServiceWorkerExtractor.extract(&document, &base),
+            vec![
+                ResourceUrl::Javascript(base.join("/sw.js").unwrap()),
+                ResourceUrl::Javascript(base.join("worker.js").unwrap()),
+            ]
+        );
+    }
+}
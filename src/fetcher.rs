@@ -0,0 +1,439 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for fetching resources by means other than HTTP
+//!
+//! Pages and resources whose URLs are handled by one of the
+//! [`ArchiveOptions::fetchers`](crate::ArchiveOptions::fetchers) are
+//! obtained from it instead of the HTTP client, e.g. for `ipfs://` URLs,
+//! application-specific schemes, or assets which must come from a local
+//! cache. Archiving which only uses fetchers does not need any particular
+//! async runtime.
+//!
+//! ```
+//! use web_archive::fetcher::{FetchedResource, FnFetcher};
+//! use web_archive::ArchiveOptions;
+//!
+//! let assets = FnFetcher::new(
+//!     |url| url.scheme() == "asset",
+//!     |url| {
+//!         let data = std::fs::read(format!("assets{}", url.path()))?;
+//!         Ok(Some(FetchedResource::new(data)))
+//!     },
+//! );
+//! let options = ArchiveOptions::builder().fetcher(assets).build().unwrap();
+//! ```
+
+use crate::error::Error;
+use crate::options::{ArchiveOptions, FailurePolicy};
+use crate::page_archive::ResourceInfo;
+use crate::parsing::{
+    build_failed_resource, build_resource, is_downloaded_status, ResourceUrl,
+    TextResource,
+};
+use crate::report::ResourceOutcome;
+use crate::session::Fetch;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use reqwest::StatusCode;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use url::Url;
+
+/// A source of resources for the URLs it handles
+///
+/// The async API calls fetchers on a pool of threads, one for each page
+/// archived at the same time, so they may block, e.g. on network or file
+/// system I/O.
+pub trait ResourceFetcher: Send + Sync {
+    /// Whether this fetcher should be used for the URL
+    fn handles(&self, url: &Url) -> bool;
+
+    /// Fetch the resource at the URL, returning `None` to leave it out
+    /// of the archive
+    fn fetch(&self, url: &Url) -> Result<Option<FetchedResource>, Error>;
+}
+
+/// The contents of a resource returned by a [`ResourceFetcher`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchedResource {
+    /// Contents of the resource
+    pub data: Bytes,
+    /// Value of the `Content-Type` the resource would be served with, if
+    /// known. It is used to determine the character encoding of text
+    /// resources and the mime type of others.
+    pub content_type: Option<String>,
+    /// HTTP status the resource was served with, if any. As for resources
+    /// downloaded over HTTP, a status other than `200 OK` means that the
+    /// fetch failed, and
+    /// [`ArchiveOptions::failure_policy`] decides what becomes of the
+    /// resource.
+    pub status: Option<StatusCode>,
+}
+
+impl FetchedResource {
+    /// A resource with no known content type
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self {
+            data: data.into(),
+            content_type: None,
+            status: None,
+        }
+    }
+
+    /// Record the HTTP status the resource was served with
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Whether the fetch failed, going by its status
+    fn failed(&self) -> Option<StatusCode> {
+        self.status.filter(|status| !is_downloaded_status(*status))
+    }
+}
+
+/// A [`ResourceFetcher`] made from a pair of closures
+pub struct FnFetcher<P, F> {
+    predicate: P,
+    fetch: F,
+}
+
+impl<P, F> FnFetcher<P, F>
+where
+    P: Fn(&Url) -> bool + Send + Sync,
+    F: Fn(&Url) -> Result<Option<FetchedResource>, Error> + Send + Sync,
+{
+    /// Use `fetch` for the URLs for which `predicate` returns `true`
+    pub fn new(predicate: P, fetch: F) -> Self {
+        Self { predicate, fetch }
+    }
+}
+
+impl<P, F> ResourceFetcher for FnFetcher<P, F>
+where
+    P: Fn(&Url) -> bool + Send + Sync,
+    F: Fn(&Url) -> Result<Option<FetchedResource>, Error> + Send + Sync,
+{
+    fn handles(&self, url: &Url) -> bool {
+        (self.predicate)(url)
+    }
+
+    fn fetch(&self, url: &Url) -> Result<Option<FetchedResource>, Error> {
+        (self.fetch)(url)
+    }
+}
+
+/// A call to a fetcher waiting for a thread of a [`FetcherPool`]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads on which the async API calls fetchers, so
+/// that they do not block the async runtime. The threads are started
+/// when first needed and stop when the pool is dropped.
+pub(crate) struct FetcherPool {
+    size: usize,
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+}
+
+impl FetcherPool {
+    /// A pool of `size` threads, none of which are started yet
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size: size.max(1),
+            jobs: Mutex::new(None),
+        }
+    }
+
+    /// The queue of the pool's threads, starting them if need be
+    fn queue(&self) -> mpsc::Sender<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for _ in 0..self.size {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    // The queue is only locked while waiting for a job
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        // A fetcher which panics fails only its own call
+                        Ok(job) => {
+                            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                        }
+                        Err(_) => break,
+                    }
+                });
+            }
+            sender
+        })
+        .clone()
+    }
+
+    /// Call the fetcher for the URL on one of the pool's threads
+    pub(crate) async fn fetch(
+        &self,
+        fetcher: &Arc<dyn ResourceFetcher>,
+        url: &Url,
+    ) -> Result<Option<FetchedResource>, Error> {
+        let (sender, receiver) = oneshot::channel();
+        let fetcher = Arc::clone(fetcher);
+        let url = url.clone();
+        let job: Job = Box::new(move || {
+            // Nothing is waiting if the future was dropped
+            let _ = sender.send(fetcher.fetch(&url));
+        });
+        // The threads only stop once the pool is dropped
+        let _ = self.queue().send(job);
+        receiver.await.map_err(|_| {
+            Error::IoError(
+                "the thread running the fetcher panicked".to_string(),
+            )
+        })?
+    }
+}
+
+/// The page obtained by a custom fetcher, with the status it was served
+/// with, if any, and its contents along with their decoded text
+pub(crate) fn fetched_page(
+    url: &Url,
+    fetched: Option<FetchedResource>,
+) -> Result<(ResourceInfo, TextResource), Error> {
+    match fetched {
+        Some(fetched) => Ok((
+            ResourceInfo {
+                status: fetched.status,
+                content_type: fetched.content_type.clone(),
+                ..Default::default()
+            },
+            TextResource::decode(fetched.data, fetched.content_type.as_deref()),
+        )),
+        None => Err(Error::IoError(format!("no content fetched for {}", url))),
+    }
+}
+
+/// The resource obtained by a custom fetcher, or what became of it if
+/// the fetcher returned nothing or reported a failed status
+pub(crate) fn fetched_resource(
+    resource_url: ResourceUrl,
+    fetched: Option<FetchedResource>,
+    options: &ArchiveOptions,
+) -> Result<Fetch, Error> {
+    let fetched = match fetched {
+        Some(fetched) => fetched,
+        None => {
+            return Ok(Err(ResourceOutcome::Skipped(
+                "no content from the fetcher".to_string(),
+            )))
+        }
+    };
+    if let Some(status) = fetched.failed() {
+        match options.failure_policy(resource_url.kind()) {
+            FailurePolicy::Skip => {
+                return Ok(Err(ResourceOutcome::Failed {
+                    status,
+                    recorded: false,
+                }))
+            }
+            FailurePolicy::Record => {}
+            FailurePolicy::Abort => {
+                return Err(Error::HttpStatus {
+                    url: resource_url.url().clone(),
+                    status,
+                })
+            }
+        }
+    }
+    let info = ResourceInfo {
+        digest: Some(options.digest_algorithm.digest(&fetched.data)),
+        status: fetched.status,
+        timings: None,
+        content_type: fetched.content_type.clone(),
+        headers: None,
+    };
+    let content_type = fetched.content_type.as_deref();
+    let (url, resource) = match fetched.failed() {
+        Some(status) => build_failed_resource(
+            resource_url,
+            status,
+            fetched.data,
+            content_type,
+        ),
+        None => build_resource(resource_url, fetched.data, content_type),
+    };
+    Ok(Ok((url, resource, info)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::Resource;
+
+    #[test]
+    fn test_fetched_resource() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/missing.css" => Ok(None),
+                "/gone.css" => Ok(Some(
+                    FetchedResource::new("Gone")
+                        .with_status(StatusCode::NOT_FOUND),
+                )),
+                _ => Ok(Some(FetchedResource {
+                    data: Bytes::from_static(b"body {}"),
+                    content_type: Some("text/css; charset=utf-8".to_string()),
+                    status: None,
+                })),
+            },
+        );
+        let url = Url::parse("asset://app/main.css").unwrap();
+        assert!(fetcher.handles(&url));
+        assert!(!fetcher.handles(&Url::parse("http://example.com").unwrap()));
+        let options = ArchiveOptions::default();
+        let fetch = |url: &Url| {
+            fetched_resource(
+                ResourceUrl::Css(url.clone()),
+                fetcher.fetch(url).unwrap(),
+                &options,
+            )
+        };
+
+        let (u, resource, info) = fetch(&url).unwrap().unwrap();
+        assert_eq!(u, url);
+        assert_eq!(resource, Resource::Css("body {}".into()));
+        assert!(info.status.is_none());
+        assert_eq!(
+            info.content_type.as_deref(),
+            Some("text/css; charset=utf-8")
+        );
+        assert!(info.digest.unwrap().matches(b"body {}"));
+
+        let (page, body) =
+            fetched_page(&url, fetcher.fetch(&url).unwrap()).unwrap();
+        assert!(page.status.is_none());
+        assert_eq!(body.text(), "body {}");
+        assert_eq!(body.data, "body {}");
+
+        let missing = Url::parse("asset://app/missing.css").unwrap();
+        assert!(matches!(
+            fetched_page(&missing, fetcher.fetch(&missing).unwrap()),
+            Err(Error::IoError(_))
+        ));
+        assert!(matches!(
+            fetch(&missing).unwrap(),
+            Err(ResourceOutcome::Skipped(_))
+        ));
+
+        let gone = Url::parse("asset://app/gone.css").unwrap();
+        assert!(matches!(
+            fetch(&gone).unwrap(),
+            Err(ResourceOutcome::Failed {
+                status: StatusCode::NOT_FOUND,
+                recorded: false,
+            })
+        ));
+        let (page, _) =
+            fetched_page(&gone, fetcher.fetch(&gone).unwrap()).unwrap();
+        assert_eq!(page.status, Some(StatusCode::NOT_FOUND));
+
+        // Only 200 OK is a success, as over HTTP
+        for status in [StatusCode::NO_CONTENT, StatusCode::PARTIAL_CONTENT] {
+            let fetched = FetchedResource::new("").with_status(status);
+            assert!(matches!(
+                fetched_resource(
+                    ResourceUrl::Css(url.clone()),
+                    Some(fetched),
+                    &options
+                )
+                .unwrap(),
+                Err(ResourceOutcome::Failed {
+                    recorded: false,
+                    ..
+                })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fetcher_pool() {
+        let threads = Arc::new(Mutex::new(std::collections::HashSet::new()));
+        let seen = Arc::clone(&threads);
+        let fetcher: Arc<dyn ResourceFetcher> = Arc::new(FnFetcher::new(
+            |_| true,
+            move |url| {
+                seen.lock().unwrap().insert(thread::current().id());
+                thread::sleep(std::time::Duration::from_millis(10));
+                match url.path() {
+                    "/panic" => panic!("the fetcher failed"),
+                    _ => Ok(Some(FetchedResource::new(url.path().to_string()))),
+                }
+            },
+        ));
+        let pool = FetcherPool::new(2);
+        let urls: Vec<_> = (0..8)
+            .map(|n| Url::parse(&format!("asset://app/{}", n)).unwrap())
+            .collect();
+        let results = tokio_test::block_on(futures::future::join_all(
+            urls.iter().map(|url| pool.fetch(&fetcher, url)),
+        ));
+        for (url, result) in urls.iter().zip(results) {
+            assert_eq!(result.unwrap().unwrap().data, url.path());
+        }
+        // The fetches share the pool's threads
+        assert!(threads.lock().unwrap().len() <= 2);
+
+        let panic = Url::parse("asset://app/panic").unwrap();
+        assert!(matches!(
+            tokio_test::block_on(pool.fetch(&fetcher, &panic)),
+            Err(Error::IoError(_))
+        ));
+        let url = &urls[0];
+        assert!(tokio_test::block_on(pool.fetch(&fetcher, url)).is_ok());
+    }
+
+    #[test]
+    fn test_failure_policy() {
+        let fetcher = FnFetcher::new(
+            |_| true,
+            |_| {
+                Ok(Some(
+                    FetchedResource::new("Gone").with_status(StatusCode::GONE),
+                ))
+            },
+        );
+        let url = Url::parse("asset://app/gone.css").unwrap();
+        let fetch = |policy| {
+            let options = ArchiveOptions::builder()
+                .resource_failure(policy)
+                .build()
+                .unwrap();
+            fetched_resource(
+                ResourceUrl::Css(url.clone()),
+                fetcher.fetch(&url).unwrap(),
+                &options,
+            )
+        };
+
+        let (_, resource, info) =
+            fetch(FailurePolicy::Record).unwrap().unwrap();
+        assert!(matches!(
+            resource,
+            Resource::Failed {
+                status: StatusCode::GONE,
+                ..
+            }
+        ));
+        assert_eq!(info.status, Some(StatusCode::GONE));
+        assert!(matches!(
+            fetch(FailurePolicy::Abort),
+            Err(Error::HttpStatus {
+                status: StatusCode::GONE,
+                ..
+            })
+        ));
+    }
+}
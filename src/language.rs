@@ -0,0 +1,223 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for guessing the language of a page from its text
+//!
+//! Text mostly in a script used by few languages, e.g. Greek or Hangul,
+//! is recognised by its script. Text in the Latin alphabet is matched
+//! against the words most common in each of a handful of European
+//! languages, which is enough to tell them apart in a page's worth of
+//! prose but not in a few words.
+
+/// The most common words of each language in the Latin alphabet, leaving
+/// out those common in more than one of them
+const COMMON_WORDS: [(&str, &[&str]); 8] = [
+    (
+        "en",
+        &[
+            "the", "and", "of", "to", "that", "it", "with", "for", "was",
+            "this", "are", "be", "have", "you",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "les", "et", "des", "est", "une", "dans", "pour", "pas",
+            "qui", "sur", "du", "au", "avec",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "und", "das", "ist", "nicht", "ein", "eine", "zu",
+            "mit", "sich", "auch", "von", "wird",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "y", "es", "por", "del", "como", "pero", "más",
+            "lo", "fue", "este", "muy",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "di", "che", "è", "per", "non", "sono", "della", "gli",
+            "anche", "questo", "ma", "nel", "alla",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "os", "não", "com", "do", "em", "são", "mais", "também", "muito",
+            "ao", "pelo", "uma", "isso", "foi",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "het", "een", "en", "van", "niet", "dat", "op", "zijn", "met",
+            "voor", "ook", "maar", "wordt", "is",
+        ],
+    ),
+    (
+        "sv",
+        &[
+            "och", "att", "det", "som", "är", "på", "inte", "för", "med",
+            "har", "av", "till", "jag", "om",
+        ],
+    ),
+];
+
+/// Number of words looked at, which is plenty to tell the languages
+/// apart
+const MAX_WORDS: usize = 10_000;
+
+/// Guess the language of some text, returning its ISO 639-1 code, or
+/// `None` if there is too little text or it is in none of the languages
+/// recognised
+pub(crate) fn detect(text: &str) -> Option<&'static str> {
+    detect_script(text).or_else(|| detect_latin(text))
+}
+
+/// Scripts other than Latin, with the language most often written in
+/// each, checked in order so that kana are found before the Chinese
+/// characters Japanese mixes them with
+const SCRIPTS: [(&str, &[(char, char)]); 9] = [
+    ("ja", &[('\u{3040}', '\u{30ff}')]),
+    ("ko", &[('\u{ac00}', '\u{d7af}'), ('\u{1100}', '\u{11ff}')]),
+    ("zh", &[('\u{4e00}', '\u{9fff}'), ('\u{3400}', '\u{4dbf}')]),
+    ("ru", &[('\u{0400}', '\u{04ff}')]),
+    ("el", &[('\u{0370}', '\u{03ff}')]),
+    ("ar", &[('\u{0600}', '\u{06ff}')]),
+    ("he", &[('\u{0590}', '\u{05ff}')]),
+    ("th", &[('\u{0e00}', '\u{0e7f}')]),
+    ("hi", &[('\u{0900}', '\u{097f}')]),
+];
+
+/// Cyrillic letters used in Ukrainian but not in Russian
+const UKRAINIAN_LETTERS: &str = "іїєґІЇЄҐ";
+
+/// The language written in the script used by most of the letters of the
+/// text, if that script is not Latin
+fn detect_script(text: &str) -> Option<&'static str> {
+    let mut counts = [0usize; SCRIPTS.len()];
+    let mut letters = 0;
+    let mut ukrainian = false;
+    for c in text
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .take(MAX_WORDS * 4)
+    {
+        letters += 1;
+        ukrainian |= UKRAINIAN_LETTERS.contains(c);
+        let script = SCRIPTS.iter().position(|(_, ranges)| {
+            ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&c))
+        });
+        if let Some(script) = script {
+            counts[script] += 1;
+        }
+    }
+    if counts.iter().sum::<usize>() * 2 <= letters {
+        return None;
+    }
+
+    let kana = counts[0];
+    let (script, _) = counts.iter().enumerate().max_by_key(|(_, n)| **n)?;
+    match SCRIPTS[script].0 {
+        "zh" if kana > 0 => Some("ja"),
+        "ru" if ukrainian => Some("uk"),
+        language => Some(language),
+    }
+}
+
+/// The language in the Latin alphabet whose common words are most
+/// frequent in the text, if they are frequent enough to be sure
+fn detect_latin(text: &str) -> Option<&'static str> {
+    let mut scores = [0usize; COMMON_WORDS.len()];
+    let mut words = 0;
+    let all_words = text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+        .take(MAX_WORDS);
+    for word in all_words {
+        words += 1;
+        let word = word.to_lowercase();
+        for (score, (_, common)) in scores.iter_mut().zip(&COMMON_WORDS) {
+            if common.contains(&word.as_str()) {
+                *score += 1;
+            }
+        }
+    }
+
+    let mut ranked: Vec<_> = scores.iter().zip(&COMMON_WORDS).collect();
+    ranked.sort_by_key(|(score, _)| std::cmp::Reverse(**score));
+    let (best, (language, _)) = ranked[0];
+    let runner_up = *ranked[1].0;
+    if *best >= 3.max(words / 20) && *best > runner_up * 3 / 2 {
+        Some(language)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        let samples = [
+            (
+                "en",
+                "The crab is the mascot of the Rust community, and it was \
+                 chosen for its friendly look. You can find it on stickers \
+                 and shirts at many of the conferences.",
+            ),
+            (
+                "fr",
+                "Le crabe est la mascotte de la communauté Rust, et il a été \
+                 choisi pour son air sympathique. On le trouve sur des \
+                 autocollants et des tee-shirts dans les conférences.",
+            ),
+            (
+                "de",
+                "Die Krabbe ist das Maskottchen der Rust-Gemeinschaft, und \
+                 sie wurde wegen ihres freundlichen Aussehens gewählt. Man \
+                 findet sie auch auf Aufklebern und T-Shirts.",
+            ),
+            (
+                "es",
+                "El cangrejo es la mascota de la comunidad de Rust, y fue \
+                 elegido por su aspecto amable. Se encuentra en pegatinas y \
+                 camisetas en muchas de las conferencias.",
+            ),
+            (
+                "nl",
+                "De krab is de mascotte van de Rust-gemeenschap, en hij is \
+                 gekozen voor zijn vriendelijke uiterlijk. Je vindt hem op \
+                 stickers en shirts, maar ook op de conferenties.",
+            ),
+            ("ja", "カニはRustコミュニティのマスコットです。"),
+            ("zh", "螃蟹是社区的吉祥物。"),
+            ("ko", "게는 러스트 커뮤니티의 마스코트입니다."),
+            ("ru", "Краб является талисманом сообщества Rust."),
+            ("uk", "Краб є талісманом спільноти Rust."),
+            ("el", "Ο κάβουρας είναι η μασκότ της κοινότητας."),
+        ];
+        for (language, text) in samples.iter() {
+            assert_eq!(detect(text), Some(*language), "{}", text);
+        }
+
+        assert_eq!(detect(""), None);
+        assert_eq!(detect("Ferris"), None);
+        assert_eq!(detect("1234 5678"), None);
+    }
+}
@@ -16,6 +16,17 @@
 
 //! feature.
 //!
+//! ## Async runtimes
+//!
+//! HTTP requests are made with `reqwest`, which must be run within a
+//! [Tokio](https://tokio.rs) 1.x runtime. Under another runtime such as
+//! async-std or smol, wrap the futures with a compatibility layer such as
+//! `async-compat`, or use the blocking API on a separate thread.
+//!
+//! Nothing else depends on the runtime: pages and resources obtained
+//! from custom [`fetcher`]s and files written by
+//! [`PageArchive::write_to_disk_async`] work under any executor.
+//!
 //! ## Examples
 //!
 //! ### Async
@@ -58,10 +69,10 @@
 //!
 //! # async fn archive_async() {
 //! // Fetch page and all its resources
-//! let archive_options = ArchiveOptions {
-//!     accept_invalid_certificates: true,
-//!     ..Default::default()
-//! };
+//! let archive_options = ArchiveOptions::builder()
+//!     .accept_invalid_certificates(true)
+//!     .build()
+//!     .unwrap();
 //! let archive = archive("http://example.com", archive_options)
 //!     .await
 //!     .unwrap();
@@ -73,18 +84,72 @@
 //!
 //! ```
 
+pub use cookies::{CookieFormat, CookieJar};
+pub use digest::{Digest, DigestAlgorithm};
+pub use embed::{
+    EmbedOptions, Embedder, FidelityReport, FormHandling, OutputFormat,
+    OversizedImages, Provenance, ScriptEmbedding, StylesheetEmbedding,
+    SvgEmbedding, UrlRewriter,
+};
 pub use error::Error;
-pub use page_archive::PageArchive;
-use parsing::{mimetype_from_response, parse_resource_urls};
-pub use parsing::{ImageResource, Resource, ResourceMap, ResourceUrl};
-use reqwest::{Proxy, StatusCode};
+use futures::Stream;
+pub use metadata::PageMetadata;
+pub use options::{
+    ArchiveOptions, ArchiveOptionsBuilder, ClientIdentity, FailurePolicy,
+    HeaderCapture, ProxyRule, ResourceFilter, ResourceHook,
+};
+pub use page_archive::{
+    ArchiveInfo, CapturedHeaders, ConflictPolicy, PageArchive, ResourceInfo,
+    ResourceTimings,
+};
+pub use parsing::{
+    display_url, parse_links, parse_resource_urls, ImageResource, Link,
+    Resource, ResourceKind, ResourceMap, ResourceUrl, TextResource,
+};
+pub use readability::Article;
+pub use report::{ArchiveReport, ResourceOutcome, ResourceReport};
+use reqwest::Proxy;
+use session::event_stream;
+pub use session::{ArchiveEvent, ArchiveSession, LoginStep};
+pub use spill::SpilledResource;
 use std::convert::TryInto;
 use std::fmt::Display;
 use url::Url;
 
+pub mod bundle;
+#[cfg(feature = "container")]
+pub mod container;
+pub mod cookies;
+mod css;
+pub mod digest;
+#[cfg(feature = "doh")]
+pub mod doh;
+pub mod embed;
 pub mod error;
+pub mod extractor;
+pub mod fetcher;
+mod language;
+pub mod manifest;
+pub mod metadata;
+pub mod options;
 pub mod page_archive;
 pub mod parsing;
+#[cfg(feature = "pdf")]
+mod pdf;
+pub mod readability;
+pub mod report;
+pub mod session;
+#[cfg(feature = "signing")]
+pub mod signature;
+pub mod sink;
+pub mod spill;
+#[cfg(feature = "streaming")]
+pub mod streaming;
+mod text;
+#[cfg(unix)]
+pub mod unix_socket;
+pub mod wayback;
+pub mod website;
 
 #[cfg(feature = "blocking")]
 pub mod blocking;
@@ -96,120 +161,287 @@ pub mod blocking;
 /// case.
 pub async fn archive<U>(
     url: U,
-    options: ArchiveOptions<'_>,
+    options: ArchiveOptions,
 ) -> Result<PageArchive, Error>
 where
     U: TryInto<Url>,
     <U as TryInto<Url>>::Error: Display,
 {
-    let url: Url = url
-        .try_into()
-        .map_err(|e| Error::ParseError(format!("{}", e)))?;
-
-    // Initialise client
-    let mut client = reqwest::Client::builder()
-        .use_native_tls()
-        .danger_accept_invalid_certs(options.accept_invalid_certificates)
-        .danger_accept_invalid_hostnames(options.accept_invalid_certificates);
-    if let Some(proxy) = options.proxy {
-        client = client.proxy(Proxy::all(proxy)?);
-    }
-    let client = client.build()?;
+    ArchiveSession::new(options)?.archive(url).await
+}
 
-    // Fetch the page contents
-    let content = client.get(url.clone()).send().await?.text().await?;
+/// The async archive function with a report.
+///
+/// Archives the page in the same way as [`archive`], also returning an
+/// [`ArchiveReport`] which lists every resource the page referenced with
+/// whether it was fetched, skipped, filtered out or failed, why, and how
+/// long it took. Automated pipelines can use it to check the quality of
+/// an archive.
+///
+/// ## Example
+/// ```no_run
+/// use web_archive::archive_with_report;
+///
+/// # async fn archive_async() {
+/// let (archive, report) =
+///     archive_with_report("http://example.com", Default::default())
+///         .await
+///         .unwrap();
+/// assert!(report.is_complete(), "{:#?}", report);
+/// # }
+/// ```
+pub async fn archive_with_report<U>(
+    url: U,
+    options: ArchiveOptions,
+) -> Result<(PageArchive, ArchiveReport), Error>
+where
+    U: TryInto<Url>,
+    <U as TryInto<Url>>::Error: Display,
+{
+    ArchiveSession::new(options)?.archive_with_report(url).await
+}
 
-    // Determine the resources that the page needs
-    let resource_urls = parse_resource_urls(&url, &content);
+/// The async function for archiving a page which has already been
+/// requested.
+///
+/// Takes the response to the page request, which the application may
+/// have made with its own client, middlewares and instrumentation, and
+/// reads the page from it. The page's final URL after any redirects is
+/// used to resolve its resources, which are then downloaded as with
+/// [`archive`] using a client configured from the options.
+///
+/// ## Example
+/// ```no_run
+/// use web_archive::archive_from_response;
+///
+/// # async fn archive_async() {
+/// let client = reqwest::Client::new();
+/// let response = client.get("http://example.com").send().await.unwrap();
+/// let archive = archive_from_response(response, Default::default())
+///     .await
+///     .unwrap();
+/// println!("{}", archive.embed_resources());
+/// # }
+/// ```
+pub async fn archive_from_response(
+    response: reqwest::Response,
+    options: ArchiveOptions,
+) -> Result<PageArchive, Error> {
+    ArchiveSession::new(options)?
+        .archive_from_response(response)
+        .await
+}
 
-    // Download them
-    let mut resource_map = ResourceMap::new();
-    for resource_url in resource_urls {
-        use ResourceUrl::*;
+/// The async function for archiving a page served over a unix domain
+/// socket.
+///
+/// Requests the page at `path` from the HTTP server listening on
+/// `socket`, along with the resources it links on the same origin, with
+/// a [`UnixSocketFetcher`](unix_socket::UnixSocketFetcher) taking
+/// precedence over the [`ArchiveOptions::fetchers`]. Resources on other
+/// hosts are downloaded as with [`archive`]. The page's URL is
+/// `http://localhost` followed by the path.
+///
+/// Only available on unix platforms.
+///
+/// ## Example
+/// ```no_run
+/// use web_archive::archive_with_socket;
+///
+/// # async fn archive_async() {
+/// let archive =
+///     archive_with_socket("/run/app.sock", "/page", Default::default())
+///         .await
+///         .unwrap();
+/// println!("{}", archive.embed_resources());
+/// # }
+/// ```
+#[cfg(unix)]
+pub async fn archive_with_socket<P: Into<std::path::PathBuf>>(
+    socket: P,
+    path: &str,
+    mut options: ArchiveOptions,
+) -> Result<PageArchive, Error> {
+    let fetcher = unix_socket::UnixSocketFetcher::new(socket);
+    let url = fetcher.url(path)?;
+    options.fetchers.insert(0, std::sync::Arc::new(fetcher));
+    ArchiveSession::new(options)?.archive(url).await
+}
 
-        let response = client.get(resource_url.url().clone()).send().await?;
-        if response.status() != StatusCode::OK {
-            // Skip any errors
-            continue;
-        }
-        match resource_url {
-            Image(u) => {
-                // Get mimetype of image
-                let data = response.bytes().await?;
-                let mimetype = mimetype_from_response(&data, &u);
-                resource_map.insert(
-                    u,
-                    Resource::Image(ImageResource { data, mimetype }),
-                );
-            }
-            Css(u) => {
-                resource_map.insert(u, Resource::Css(response.text().await?));
-            }
-            Javascript(u) => {
-                resource_map
-                    .insert(u, Resource::Javascript(response.text().await?));
-            }
-        }
-    }
+/// The async batch archive function.
+///
+/// Archives each of the URLs with a shared [`ArchiveSession`], so that
+/// connections are reused and resources which are common to several
+/// pages are downloaded once. Up to
+/// [`ArchiveOptions::concurrency`] pages are archived at a time, and
+/// the results are returned in the same order as the URLs.
+///
+/// ## Example
+/// ```no_run
+/// use web_archive::archive_all;
+///
+/// # async fn archive_async() {
+/// let urls = vec!["http://example.com/a", "http://example.com/b"];
+/// for result in archive_all(urls, Default::default()).await.unwrap() {
+///     match result {
+///         Ok(archive) => println!("{}", archive.embed_resources()),
+///         Err(e) => eprintln!("{:?}", e),
+///     }
+/// }
+/// # }
+/// ```
+pub async fn archive_all<I, U>(
+    urls: I,
+    options: ArchiveOptions,
+) -> Result<Vec<Result<PageArchive, Error>>, Error>
+where
+    I: IntoIterator<Item = U>,
+    U: TryInto<Url>,
+    <U as TryInto<Url>>::Error: Display,
+{
+    let session = ArchiveSession::new(options)?;
+    Ok(session.archive_all(urls).await)
+}
 
-    Ok(PageArchive {
-        url,
-        content,
-        resource_map,
+/// The async streaming archive function.
+///
+/// Archives the page in the same way as [`archive`], but returns a
+/// stream of [`ArchiveEvent`]s reporting progress as each resource is
+/// downloaded, ending with either [`ArchiveEvent::Finished`] or
+/// [`ArchiveEvent::Failed`]. This is useful for showing progress in a
+/// user interface.
+///
+/// ## Example
+/// ```no_run
+/// use futures::StreamExt;
+/// use web_archive::{archive_stream, ArchiveEvent};
+///
+/// # async fn archive_async() {
+/// let mut events = Box::pin(archive_stream(
+///     "http://example.com",
+///     Default::default(),
+/// ));
+/// while let Some(event) = events.next().await {
+///     match event {
+///         ArchiveEvent::ResourceDone { url, .. } => println!("got {}", url),
+///         ArchiveEvent::Finished(archive) => {
+///             println!("{}", archive.embed_resources())
+///         }
+///         _ => {}
+///     }
+/// }
+/// # }
+/// ```
+pub fn archive_stream<U>(
+    url: U,
+    options: ArchiveOptions,
+) -> impl Stream<Item = ArchiveEvent>
+where
+    U: TryInto<Url>,
+    <U as TryInto<Url>>::Error: Display,
+{
+    let url = url
+        .try_into()
+        .map_err(|e| Error::ParseError(format!("{}", e)));
+    event_stream(move |reporter| async move {
+        let session = ArchiveSession::new(options)?;
+        session
+            .archive_reporting(url?, reporter)
+            .await
+            .map(|(archive, _)| archive)
     })
 }
 
-/// Configuration options to control aspects of the archiving behaviour.
-pub struct ArchiveOptions<'a> {
-    /// Accept invalid certificates or certificates that do not match
-    /// the requested hostname. For example, performing an HTTPS request
-    /// against an IP address will more than likely result in a hostname
-    /// mismatch.
-    ///
-    /// Corresponds to [`reqwest::ClientBuilder::danger_accept_invalid_certs`]
-    /// and [`reqwest::ClientBuilder::danger_accept_invalid_hostnames`].
-    ///
-    /// Default: `false`
-    ///
-    /// ## Example
-    /// ```
-    /// use web_archive::ArchiveOptions;
-    /// let options = ArchiveOptions {
-    ///     accept_invalid_certificates: true,
-    ///     ..Default::default()
-    /// };
-    /// ```
-    pub accept_invalid_certificates: bool,
-    /// Connect via specified proxy. Accepts HTTP/HTTPS proxies, and if
-    /// the `socks` feature is enabled then SOCKS proxies may also be
-    /// specified.
-    ///
-    /// Default: `None`
-    /// Related feature: `features = ["socks"]`
-    ///
-    /// ## Example
-    /// ```
-    /// use web_archive::ArchiveOptions;
-    /// let options = ArchiveOptions {
-    ///     proxy: Some("http://localhost:8080"),
-    ///     ..Default::default()
-    /// };
-    /// ```
-    pub proxy: Option<&'a str>,
+/// The async resource listing function.
+///
+/// Fetches only the page at the given URL and returns the resources
+/// that [`archive`] would download, without downloading any of them.
+/// This is useful for estimating the size of an archive or for
+/// filtering the resources before performing a full run.
+pub async fn list_resources<U>(
+    url: U,
+    options: ArchiveOptions,
+) -> Result<Vec<ResourceUrl>, Error>
+where
+    U: TryInto<Url>,
+    <U as TryInto<Url>>::Error: Display,
+{
+    ArchiveSession::new(options)?.list_resources(url).await
 }
 
-impl<'a> Default for ArchiveOptions<'a> {
-    fn default() -> Self {
-        Self {
-            accept_invalid_certificates: false,
-            proxy: None,
-        }
+/// Create a client builder configured according to the provided
+/// options. Shared between the async and blocking APIs, as the blocking
+/// client builder can be created from the async one. The options are
+/// validated first, so that invalid options are reported before anything
+/// is fetched.
+pub(crate) fn client_builder(
+    options: &ArchiveOptions,
+) -> Result<reqwest::ClientBuilder, Error> {
+    options.validate()?;
+    let mut client = connection_builder(options)?;
+    #[cfg(feature = "doh")]
+    if let Some(server) = &options.dns_over_https {
+        let server = Url::parse(server)
+            .map_err(|e| Error::InvalidOptions(e.to_string()))?;
+        // The queries are sent through the same proxies, address and
+        // certificates as the requests whose hosts they resolve
+        let doh_client = connection_builder(options)?.build()?;
+        let resolver = doh::DohResolver::with_client(server, doh_client);
+        client = client.dns_resolver(std::sync::Arc::new(resolver));
+    }
+    if let Some(policy) = options.redirect_policy() {
+        client = client.redirect(policy);
     }
+    Ok(client)
+}
+
+/// The client builder configured with every option except the DNS
+/// resolver and the redirect policy, shared with the client sending DNS
+/// over HTTPS queries
+fn connection_builder(
+    options: &ArchiveOptions,
+) -> Result<reqwest::ClientBuilder, Error> {
+    let mut client = reqwest::Client::builder()
+        .use_native_tls()
+        .danger_accept_invalid_certs(options.accept_invalid_certificates)
+        .danger_accept_invalid_hostnames(options.accept_invalid_certificates);
+    let no_proxy = options.bypassed_hosts();
+    for rule in &options.proxy_rules {
+        client = client.proxy(rule.proxy(no_proxy.clone()));
+    }
+    if let Some(proxy) = &options.proxy {
+        client = client.proxy(Proxy::all(proxy)?.no_proxy(no_proxy));
+    }
+    if let Some(identity) = &options.client_identity {
+        client = client.identity(identity.identity()?);
+    }
+    if let Some(max_idle) = options.pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(timeout) = options.pool_idle_timeout {
+        client = client.pool_idle_timeout(timeout);
+    }
+    if options.http2_prior_knowledge {
+        client = client.http2_prior_knowledge();
+    }
+    if let Some(interval) = options.http2_keep_alive_interval {
+        client = client
+            .http2_keep_alive_interval(interval)
+            .http2_keep_alive_while_idle(true);
+    }
+    if let Some(interval) = options.tcp_keepalive {
+        client = client.tcp_keepalive(interval);
+    }
+    if let Some(address) = options.local_address {
+        client = client.local_address(address);
+    }
+    Ok(client)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::StreamExt;
     use tokio_test::block_on;
 
     #[test]
@@ -225,4 +457,43 @@ mod tests {
             panic!("Expected parse error");
         }
     }
+
+    #[test]
+    fn list_resources_invalid_url_async() {
+        let u = "this~is~not~a~url";
+
+        let res = block_on(list_resources(u, Default::default()));
+        assert!(matches!(res, Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn archive_stream_invalid_url() {
+        let events: Vec<_> = block_on(
+            archive_stream("this~is~not~a~url", Default::default()).collect(),
+        );
+        assert!(matches!(
+            events.as_slice(),
+            [ArchiveEvent::Failed(Error::ParseError(_))]
+        ));
+    }
+
+    #[test]
+    fn archive_invalid_options() {
+        let options = ArchiveOptions {
+            concurrency: Some(0),
+            ..Default::default()
+        };
+        let res = block_on(archive("http://example.com", options));
+        assert!(matches!(res, Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn archive_all_invalid_urls() {
+        let urls = vec!["this~is~not~a~url", "neither~is~this"];
+        let results = block_on(archive_all(urls, Default::default())).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(results
+            .iter()
+            .all(|res| matches!(res, Err(Error::ParseError(_)))));
+    }
 }
@@ -0,0 +1,310 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for the machine-readable manifest written with disk output
+//!
+//! The manifest is written to `manifest.json` in the output directory
+//! and maps the original URL of the page and of each resource to the
+//! path it was written to, so that other tools can index or verify the
+//! archive.
+
+use crate::digest::{Digest, DigestAlgorithm};
+use crate::error::Error;
+use crate::page_archive::{CapturedHeaders, ResourceInfo, ResourceTimings};
+use crate::parsing::{Resource, ResourceKind};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+use url::Url;
+
+/// Name of the manifest file in the output directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Version of the manifest format written by this crate
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Description of an archive written to disk
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of the manifest format
+    pub version: u32,
+    /// Version of `web-archive` that wrote the archive
+    pub generator: String,
+    /// Time at which the page was fetched, in RFC 3339 format
+    pub fetched_at: String,
+    /// The page itself
+    pub page: ManifestEntry,
+    /// The body of the page exactly as it was received, if the archive
+    /// kept it in [`PageArchive::raw_content`](crate::PageArchive::raw_content)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_page: Option<ManifestEntry>,
+    /// The page's resources, in order of their URLs
+    pub resources: Vec<ManifestEntry>,
+}
+
+/// Description of a single file in the archive
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The original URL of the file
+    pub url: Url,
+    /// Path of the file relative to the output directory
+    pub path: PathBuf,
+    /// What kind of file this is
+    pub kind: EntryKind,
+    /// Mime type of the file
+    pub mimetype: String,
+    /// Character encoding of text files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Hex-encoded SHA-256 hash of the file contents
+    pub sha256: String,
+    /// The [`Digest`] recorded when the file was downloaded, in the form
+    /// `<algorithm>:<hex>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// The HTTP status with which the file was served
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// How long the file took to download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<ResourceTimings>,
+    /// The `Content-Type` the file was served with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// The request and response headers, if they were captured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<CapturedHeaders>,
+}
+
+/// The kinds of file recorded in a [`Manifest`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    /// The HTML page
+    Page,
+    /// A [`Resource::Javascript`]
+    Javascript,
+    /// A [`Resource::Css`]
+    Css,
+    /// A [`Resource::Image`]
+    Image,
+    /// A [`Resource::Other`]
+    Other,
+    /// A [`Resource::Html`]
+    Html,
+    /// A [`Resource::Failed`]
+    Failed,
+}
+
+impl ManifestEntry {
+    /// Describe the page
+    pub(crate) fn page(
+        url: &Url,
+        path: PathBuf,
+        content: &[u8],
+        info: &ResourceInfo,
+    ) -> Self {
+        Self {
+            url: url.clone(),
+            path,
+            kind: EntryKind::Page,
+            mimetype: "text/html".to_string(),
+            encoding: None,
+            size: content.len() as u64,
+            sha256: sha256_hex(content),
+            digest: info.digest.as_ref().map(Digest::to_string),
+            status: info.status.map(|status| status.as_u16()),
+            timings: info.timings,
+            content_type: info.content_type.clone(),
+            headers: info.headers.clone(),
+        }
+    }
+
+    /// Describe one of the page's resources
+    pub(crate) fn resource(
+        url: &Url,
+        path: PathBuf,
+        resource: &Resource,
+        info: &ResourceInfo,
+    ) -> Self {
+        let (kind, encoding) = match resource {
+            Resource::Javascript(text) => {
+                (EntryKind::Javascript, Some(text.encoding.clone()))
+            }
+            Resource::Css(text) => {
+                (EntryKind::Css, Some(text.encoding.clone()))
+            }
+            Resource::Html(text) => {
+                (EntryKind::Html, Some(text.encoding.clone()))
+            }
+            Resource::Image(_) => (EntryKind::Image, None),
+            Resource::Other { .. } => (EntryKind::Other, None),
+            Resource::Failed { .. } => (EntryKind::Failed, None),
+            Resource::Spilled(spilled) => match spilled.kind() {
+                ResourceKind::Image => (EntryKind::Image, None),
+                _ => (EntryKind::Other, None),
+            },
+        };
+        Self {
+            url: url.clone(),
+            path,
+            kind,
+            mimetype: resource.mimetype().to_string(),
+            encoding,
+            size: resource.size() as u64,
+            sha256: resource_sha256(resource),
+            digest: info.digest.as_ref().map(Digest::to_string),
+            status: info.status.map(|status| status.as_u16()),
+            timings: info.timings,
+            content_type: info.content_type.clone(),
+            headers: info.headers.clone(),
+        }
+    }
+
+    /// The [`ResourceInfo`] recorded in the entry
+    pub fn info(&self) -> Result<ResourceInfo, Error> {
+        Ok(ResourceInfo {
+            digest: self.digest.as_deref().map(str::parse).transpose()?,
+            status: self
+                .status
+                .map(|status| {
+                    StatusCode::from_u16(status)
+                        .map_err(|e| Error::ParseError(e.to_string()))
+                })
+                .transpose()?,
+            timings: self.timings,
+            content_type: self.content_type.clone(),
+            headers: self.headers.clone(),
+        })
+    }
+}
+
+/// Resolve a path from a manifest against the archive directory,
+/// rejecting paths which would point outside of it
+pub(crate) fn manifest_file_path(
+    dir: &Path,
+    path: &Path,
+) -> Result<PathBuf, Error> {
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(Error::ParseError(format!(
+            "Invalid path in manifest: {}",
+            path.display()
+        )));
+    }
+    Ok(dir.join(path))
+}
+
+/// Hex-encoded SHA-256 hash of some data
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    DigestAlgorithm::Sha256.digest(data).value
+}
+
+/// Hex-encoded SHA-256 hash of the data of a resource, using the hash
+/// computed when it was spilled rather than reading it back
+pub(crate) fn resource_sha256(resource: &Resource) -> String {
+    match resource.stored_data() {
+        Ok(data) => sha256_hex(data),
+        Err(spilled) => spilled.sha256().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_manifest_json() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            generator: "web-archive 0.3.0".to_string(),
+            fetched_at: "2021-02-03T04:05:06Z".to_string(),
+            page: ManifestEntry::page(
+                &url,
+                "index.html".into(),
+                b"<html>",
+                &ResourceInfo {
+                    status: Some(StatusCode::NOT_FOUND),
+                    headers: Some(CapturedHeaders {
+                        version: "HTTP/1.1".to_string(),
+                        request: vec![(
+                            "host".to_string(),
+                            "example.com".to_string(),
+                        )],
+                        response: vec![(
+                            "server".to_string(),
+                            "ferris".to_string(),
+                        )],
+                    }),
+                    ..Default::default()
+                },
+            ),
+            raw_page: None,
+            resources: vec![ManifestEntry::resource(
+                &url.join("a.css").unwrap(),
+                "example.com/a.css".into(),
+                &Resource::Css("body {}".into()),
+                &ResourceInfo {
+                    digest: Some(DigestAlgorithm::Sha512.digest(b"body {}")),
+                    timings: Some(ResourceTimings {
+                        first_byte: Duration::from_millis(12),
+                        total: Duration::from_micros(34_567),
+                    }),
+                    content_type: Some("text/css; charset=utf-8".to_string()),
+                    ..Default::default()
+                },
+            )],
+        };
+
+        let json = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(json["page"]["kind"], "page");
+        assert!(json["page"].get("encoding").is_none());
+        assert!(json["page"].get("digest").is_none());
+        assert_eq!(json["page"]["status"], 404);
+        assert!(json["resources"][0].get("status").is_none());
+        assert_eq!(json["resources"][0]["url"], "http://example.com/a.css");
+        assert_eq!(json["resources"][0]["kind"], "css");
+        assert_eq!(json["resources"][0]["encoding"], "UTF-8");
+        assert!(json["page"].get("content_type").is_none());
+        assert_eq!(
+            json["resources"][0]["content_type"],
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(json["resources"][0]["size"], 7);
+        assert!(json["page"].get("timings").is_none());
+        assert_eq!(json["page"]["headers"]["version"], "HTTP/1.1");
+        assert_eq!(
+            json["page"]["headers"]["response"][0],
+            serde_json::json!(["server", "ferris"])
+        );
+        assert!(json["resources"][0].get("headers").is_none());
+        assert_eq!(json["resources"][0]["timings"]["first_byte"], "12ms");
+        assert_eq!(json["resources"][0]["timings"]["total"], "34ms 567us");
+        assert_eq!(
+            json["resources"][0]["sha256"],
+            "62368a1a29259b30bac235c0e75dc700c9b3bacf1513ad5708e4fe4a6c0d6560"
+        );
+        assert!(json["resources"][0]["digest"]
+            .as_str()
+            .unwrap()
+            .starts_with("sha512:"));
+
+        let parsed: Manifest = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, manifest);
+        assert_eq!(
+            parsed.page.info().unwrap().status,
+            Some(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(parsed.page.info().unwrap().headers, manifest.page.headers);
+    }
+}
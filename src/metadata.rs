@@ -0,0 +1,239 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for extracting descriptive metadata from a page
+
+use crate::readability::non_empty;
+use kuchiki::parse_html;
+use kuchiki::traits::TendrilSink;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+/// Descriptive metadata parsed from a page, returned by
+/// [`PageArchive::metadata`](crate::PageArchive::metadata)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PageMetadata {
+    /// Contents of the page's `<title>`
+    pub title: Option<String>,
+    /// Contents of `<meta name="description">`
+    pub description: Option<String>,
+    /// The `href` of `<link rel="canonical">`, resolved against the
+    /// page's URL
+    pub canonical_url: Option<Url>,
+    /// Open Graph properties from `<meta property="og:...">`, keyed by
+    /// the property name without the `og:` prefix, e.g. `title` or
+    /// `image:width`. Only the first value of repeated properties is
+    /// kept.
+    pub open_graph: BTreeMap<String, String>,
+    /// Twitter card fields from `<meta name="twitter:...">`, keyed by
+    /// the field name without the `twitter:` prefix, e.g. `card`. Only
+    /// the first value of repeated fields is kept.
+    pub twitter: BTreeMap<String, String>,
+    /// Language of the page, from the `lang` attribute of `<html>` or,
+    /// failing that, an ISO 639-1 code detected from its text, e.g. `fr`
+    pub language: Option<String>,
+}
+
+/// The metadata of a page, parsed the first time it is needed. A hash of
+/// the page it was parsed from is kept with it, so that the page is
+/// parsed again if its content or URL are changed.
+#[derive(Debug, Default)]
+pub(crate) struct MetadataCache(Mutex<Option<(u64, Arc<PageMetadata>)>>);
+
+impl MetadataCache {
+    /// The metadata of the page, parsing it unless it is cached
+    pub(crate) fn get(&self, content: &str, url: &Url) -> Arc<PageMetadata> {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        url.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut cached = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match &*cached {
+            Some((cached_hash, metadata)) if *cached_hash == hash => {
+                Arc::clone(metadata)
+            }
+            _ => {
+                let metadata = Arc::new(parse(content, url));
+                *cached = Some((hash, Arc::clone(&metadata)));
+                metadata
+            }
+        }
+    }
+}
+
+/// Parse the metadata from a page fetched from `url`
+pub(crate) fn parse(content: &str, url: &Url) -> PageMetadata {
+    let document = parse_html().one(content);
+    let mut metadata = PageMetadata {
+        title: document
+            .select_first("title")
+            .ok()
+            .and_then(|title| non_empty(&title.text_contents())),
+        canonical_url: document
+            .select_first(r#"link[rel~="canonical"][href]"#)
+            .ok()
+            .and_then(|link| {
+                let href = link.attributes.borrow().get("href")?.to_string();
+                url.join(href.trim()).ok()
+            }),
+        ..Default::default()
+    };
+
+    let metas = document.select("meta[content]").into_iter().flatten();
+    for meta in metas {
+        let attributes = meta.attributes.borrow();
+        let content = match attributes.get("content").and_then(non_empty) {
+            Some(content) => content,
+            None => continue,
+        };
+        // Twitter cards are specified with `name`, but `property` is
+        // common in the wild; Open Graph is the other way around
+        let key = attributes
+            .get("property")
+            .or_else(|| attributes.get("name"))
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        if key == "description" {
+            metadata.description.get_or_insert(content);
+        } else if let Some(property) = key.strip_prefix("og:") {
+            metadata
+                .open_graph
+                .entry(property.to_string())
+                .or_insert(content);
+        } else if let Some(field) = key.strip_prefix("twitter:") {
+            metadata.twitter.entry(field.to_string()).or_insert(content);
+        }
+    }
+
+    metadata.language = document
+        .select_first("html[lang]")
+        .ok()
+        .and_then(|html| non_empty(html.attributes.borrow().get("lang")?))
+        .or_else(|| {
+            let text = crate::text::node_text(&document);
+            crate::language::detect(&text).map(str::to_string)
+        });
+
+    metadata
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_metadata() {
+        let content = r#"<html><head>
+<title>
+  Ferris the crab
+</title>
+<meta name="description" content="All about Ferris">
+<meta name="description" content="Ignored">
+<link rel="canonical" href="/ferris">
+<meta property="og:title" content="Ferris">
+<meta property="og:image" content="http://example.com/ferris.png">
+<meta property="og:image" content="http://example.com/second.png">
+<meta property="og:image:width" content="400">
+<meta name="twitter:card" content="summary">
+<meta property="twitter:site" content="@rustlang">
+<meta name="keywords" content="rust, crab">
+<meta property="og:empty" content=" ">
+</head></html>"#;
+        let url = Url::parse("http://example.com/pages/ferris.html").unwrap();
+        let metadata = parse(content, &url);
+
+        assert_eq!(metadata.title.as_deref(), Some("Ferris the crab"));
+        assert_eq!(metadata.description.as_deref(), Some("All about Ferris"));
+        assert_eq!(
+            metadata.canonical_url.unwrap().as_str(),
+            "http://example.com/ferris"
+        );
+
+        let expected: BTreeMap<String, String> = [
+            ("title", "Ferris"),
+            ("image", "http://example.com/ferris.png"),
+            ("image:width", "400"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(metadata.open_graph, expected);
+
+        let expected: BTreeMap<String, String> =
+            [("card", "summary"), ("site", "@rustlang")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        assert_eq!(metadata.twitter, expected);
+    }
+
+    #[test]
+    fn test_language() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let language = |content: &str| parse(content, &url).language;
+        assert_eq!(
+            language(r#"<html lang=" en-GB "><p>Le crabe est ici</p>"#)
+                .as_deref(),
+            Some("en-GB")
+        );
+        let french = "<p>Le crabe est la mascotte de la communauté, et il \
+                      est sur les autocollants et dans les conférences.</p>";
+        assert_eq!(language(french).as_deref(), Some("fr"));
+        // Scripts and styles are not the text of the page
+        assert_eq!(
+            language(&format!(
+                "{}<script>var the = 'the and of the to the';</script>",
+                french
+            ))
+            .as_deref(),
+            Some("fr")
+        );
+        assert_eq!(language(r#"<html lang=""><p>Hello</p>"#), None);
+    }
+
+    #[test]
+    fn test_parse_empty_metadata() {
+        let url = Url::parse("http://example.com/").unwrap();
+        assert_eq!(parse("<p>Hello</p>", &url), PageMetadata::default());
+    }
+
+    #[test]
+    fn test_metadata_cache() {
+        let mut archive = crate::PageArchive::from_parts(
+            "http://example.com/blog/",
+            r#"<title>First</title><link rel="canonical" href="/first">"#,
+            Default::default(),
+        )
+        .unwrap();
+        let cache = &archive.metadata_cache;
+        let first = cache.get(&archive.content, &archive.url);
+        assert!(Arc::ptr_eq(
+            &first,
+            &cache.get(&archive.content, &archive.url)
+        ));
+        assert_eq!(archive.title().as_deref(), Some("First"));
+        assert_eq!(
+            archive.canonical_url().unwrap().as_str(),
+            "http://example.com/first"
+        );
+
+        // Changes to the page are noticed
+        archive.content = "<title>Second</title>".to_string();
+        assert_eq!(archive.title().as_deref(), Some("Second"));
+        assert!(archive.canonical_url().is_none());
+        assert!(archive.description().is_none());
+    }
+}
@@ -0,0 +1,1777 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for the options controlling how pages are archived
+//!
+//! [`ArchiveOptions`] is `#[non_exhaustive]` so that new options can be
+//! added without breaking existing code. Options are either set with the
+//! [`ArchiveOptionsBuilder`] returned by [`ArchiveOptions::builder`],
+//! which checks them with [`ArchiveOptions::validate`], or by assigning to
+//! the fields of [`ArchiveOptions::default`].
+//!
+//! ```
+//! use web_archive::{ArchiveOptions, FailurePolicy, ResourceKind};
+//!
+//! let options = ArchiveOptions::builder()
+//!     .wayback_fallback(true)
+//!     .resource_failure_for(ResourceKind::Css, FailurePolicy::Abort)
+//!     .concurrency(8)
+//!     .build()
+//!     .unwrap();
+//!
+//! let mut options = ArchiveOptions::default();
+//! options.wayback_fallback = true;
+//! ```
+//!
+//! The options can be serialized with serde so that archiving profiles
+//! can be stored and reused. Fields which are missing when deserializing
+//! take their default values. The
+//! [`client_identity`](ArchiveOptions::client_identity),
+//! [`resource_filter`](ArchiveOptions::resource_filter),
+//! [`resource_hook`](ArchiveOptions::resource_hook),
+//! [`fetchers`](ArchiveOptions::fetchers) and
+//! [`resource_extractors`](ArchiveOptions::resource_extractors) are not
+//! serialized, and must be set again after the options are loaded.
+//!
+//! ```
+//! use web_archive::ArchiveOptions;
+//!
+//! let options: ArchiveOptions = serde_json::from_str(
+//!     r#"{ "wayback_fallback": true, "pool_idle_timeout": "30s" }"#,
+//! )
+//! .unwrap();
+//! assert!(options.wayback_fallback);
+//! ```
+
+use crate::digest::DigestAlgorithm;
+use crate::error::Error;
+use crate::extractor::{
+    extract_resource_urls, AttachmentExtractor, ResourceExtractor,
+    ServiceWorkerExtractor,
+};
+use crate::fetcher::ResourceFetcher;
+use crate::page_archive::{ArchiveInfo, CapturedHeaders};
+use crate::parsing::{Resource, ResourceKind, ResourceMap, ResourceUrl};
+use crate::report::ResourceOutcome;
+use crate::session::LoginStep;
+use crate::spill::SpilledResource;
+use kuchiki::parse_html;
+use kuchiki::traits::TendrilSink;
+use reqwest::header::{
+    HeaderMap, HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION,
+    SET_COOKIE,
+};
+use reqwest::redirect::Policy;
+use reqwest::{NoProxy, Proxy, Version};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Number of pages archived at a time by the batch APIs, unless
+/// [`ArchiveOptions::concurrency`] is set
+const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Configuration options to control aspects of the archiving behaviour.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ArchiveOptions {
+    /// Accept invalid certificates or certificates that do not match
+    /// the requested hostname. For example, performing an HTTPS request
+    /// against an IP address will more than likely result in a hostname
+    /// mismatch.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::danger_accept_invalid_certs`]
+    /// and [`reqwest::ClientBuilder::danger_accept_invalid_hostnames`].
+    ///
+    /// Default: `false`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .accept_invalid_certificates(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub accept_invalid_certificates: bool,
+    /// Connect via specified proxy. Accepts HTTP/HTTPS proxies, and if
+    /// the `socks` feature is enabled then SOCKS proxies may also be
+    /// specified.
+    ///
+    /// Default: `None`
+    /// Related feature: `features = ["socks"]`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .proxy("http://localhost:8080")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub proxy: Option<String>,
+    /// Proxies used instead of [`ArchiveOptions::proxy`] for URLs with
+    /// particular schemes or hosts, like curl's `http_proxy` and
+    /// `https_proxy`. The first rule matching a URL is used, and URLs
+    /// matching none use [`ArchiveOptions::proxy`] or no proxy.
+    ///
+    /// Default: `[]`
+    /// Related feature: `features = ["socks"]`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, ProxyRule};
+    /// let options = ArchiveOptions::builder()
+    ///     .proxy_rule(ProxyRule::new("http://localhost:3128").scheme("https"))
+    ///     .proxy_rule(
+    ///         ProxyRule::new("http://partners:8080").host("partner.example"),
+    ///     )
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub proxy_rules: Vec<ProxyRule>,
+    /// Hosts which are connected to directly, bypassing
+    /// [`ArchiveOptions::proxy`] and [`ArchiveOptions::proxy_rules`],
+    /// e.g. for intranet resources. Entries are matched as in curl's
+    /// `NO_PROXY`: a domain name, with or without a leading dot, also
+    /// matches its subdomains, IP addresses may be given with a subnet
+    /// mask such as `10.0.0.0/8`, and `*` matches every host.
+    ///
+    /// Corresponds to [`reqwest::NoProxy`].
+    ///
+    /// Default: `[]`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .proxy("http://localhost:8080")
+    ///     .no_proxy("intranet.example")
+    ///     .no_proxy("10.0.0.0/8")
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub no_proxy: Vec<String>,
+    /// When a resource cannot be downloaded, look it up in the Internet
+    /// Archive's Wayback Machine and use the most recent snapshot
+    /// instead. This helps to complete archives of partially dead pages,
+    /// at the cost of extra requests to `archive.org`.
+    ///
+    /// Default: `false`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .wayback_fallback(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub wayback_fallback: bool,
+    /// The hash algorithm used for the [`Digest`](crate::Digest)s
+    /// recorded for the page and each resource in
+    /// [`PageArchive::info`](crate::PageArchive::info).
+    ///
+    /// Default: [`DigestAlgorithm::Sha256`]
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, DigestAlgorithm};
+    /// let options = ArchiveOptions::builder()
+    ///     .digest_algorithm(DigestAlgorithm::Sha512)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub digest_algorithm: DigestAlgorithm,
+    /// Return [`Error::HttpStatus`] if the page itself is served with an
+    /// unsuccessful (non-2xx) status, rather than archiving the error
+    /// page as if it were content. Redirects are only returned when
+    /// [`max_redirects`](Self::max_redirects) is `Some(0)`, and are not
+    /// treated as failures. The status is recorded in
+    /// [`PageArchive::info`](crate::PageArchive::info) either way.
+    ///
+    /// Default: `false`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .error_on_http_failure(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub error_on_http_failure: bool,
+    /// What to do when a resource is served with an unsuccessful status
+    /// and no Wayback Machine snapshot is used in its place. Network
+    /// errors always cause the archive to fail.
+    ///
+    /// Default: [`FailurePolicy::Skip`]
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, FailurePolicy};
+    /// let options = ArchiveOptions::builder()
+    ///     .resource_failure(FailurePolicy::Record)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub resource_failure: FailurePolicy,
+    /// Failure policies for particular kinds of resource, overriding
+    /// [`resource_failure`](Self::resource_failure). For example, a page
+    /// is rarely usable without its stylesheets, so the archive could be
+    /// aborted if one fails while missing images are tolerated.
+    ///
+    /// Default: empty
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, FailurePolicy, ResourceKind};
+    /// let options = ArchiveOptions::builder()
+    ///     .resource_failure_for(ResourceKind::Css, FailurePolicy::Abort)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub resource_failure_overrides: HashMap<ResourceKind, FailurePolicy>,
+    /// Client certificate presented to servers which require mutual TLS
+    /// authentication.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::identity`]. The identity
+    /// is not serialized with the rest of the options, so that key
+    /// material is not written to configuration files by accident.
+    ///
+    /// Default: `None`
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use web_archive::{ArchiveOptions, ClientIdentity};
+    /// let options = ArchiveOptions::builder()
+    ///     .client_identity(ClientIdentity::Pem {
+    ///         certificate: std::fs::read("client.crt").unwrap(),
+    ///         key: std::fs::read("client.key").unwrap(),
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(skip)]
+    pub client_identity: Option<ClientIdentity>,
+    /// Maximum number of idle connections kept open to each host for
+    /// reuse. This bounds the connections left open between requests,
+    /// not the connections in use at the same time, which
+    /// [`max_connections_per_host`](Self::max_connections_per_host)
+    /// limits.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::pool_max_idle_per_host`].
+    ///
+    /// Default: `None`, using reqwest's default of no limit
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .pool_max_idle_per_host(2)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Maximum number of requests a session sends to each host at the
+    /// same time. Each page downloads its resources one at a time, but
+    /// the pages archived together by [`archive_all`](crate::archive_all)
+    /// may otherwise each have a request in flight to the same host.
+    /// Requests beyond the limit wait for an earlier one to finish. The
+    /// host is that of the URL requested, before any redirects.
+    ///
+    /// Default: `None`, with no limit beyond
+    /// [`concurrency`](#structfield.concurrency)
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .concurrency(8)
+    ///     .max_connections_per_host(2)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub max_connections_per_host: Option<usize>,
+    /// How long idle connections are kept open before being closed.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::pool_idle_timeout`].
+    /// Serialized as a human-readable duration such as `"90s"`.
+    ///
+    /// Default: `None`, using reqwest's default of 90 seconds
+    ///
+    /// ## Example
+    /// ```
+    /// use std::time::Duration;
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .pool_idle_timeout(Duration::from_secs(10))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(with = "humantime_option")]
+    pub pool_idle_timeout: Option<Duration>,
+    /// Speak HTTP/2 to every server without negotiating it first. The
+    /// TLS backend does not negotiate HTTP/2 itself, so this is the only
+    /// way to use it, letting requests to the same origin share one
+    /// connection. Servers which only support HTTP/1 will fail every
+    /// request, so this is best used for origins known to support
+    /// HTTP/2.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::http2_prior_knowledge`].
+    ///
+    /// Default: `false`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .http2_prior_knowledge(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub http2_prior_knowledge: bool,
+    /// How often HTTP/2 `PING` frames are sent to keep connections alive
+    /// while they are idle in the pool, so that they can be reused
+    /// rather than reopened.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::http2_keep_alive_interval`]
+    /// and [`reqwest::ClientBuilder::http2_keep_alive_while_idle`].
+    /// Serialized as a human-readable duration such as `"30s"`.
+    ///
+    /// Default: `None`, sending no pings
+    ///
+    /// ## Example
+    /// ```
+    /// use std::time::Duration;
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .http2_keep_alive_interval(Duration::from_secs(30))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(with = "humantime_option")]
+    pub http2_keep_alive_interval: Option<Duration>,
+    /// Interval of the TCP keep-alive probes sent on open connections,
+    /// which stop idle connections being dropped by firewalls and NAT.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::tcp_keepalive`].
+    /// Serialized as a human-readable duration such as `"60s"`.
+    ///
+    /// Default: `None`, sending no probes
+    ///
+    /// ## Example
+    /// ```
+    /// use std::time::Duration;
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .tcp_keepalive(Duration::from_secs(60))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(with = "humantime_option")]
+    pub tcp_keepalive: Option<Duration>,
+    /// Local IP address which connections are made from, for hosts with
+    /// several addresses or network interfaces where archive traffic
+    /// must leave by a particular one. Connections to servers only
+    /// reachable over the other IP version fail.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::local_address`].
+    ///
+    /// Default: `None`, letting the system choose
+    ///
+    /// ## Example
+    /// ```
+    /// use std::net::{IpAddr, Ipv4Addr};
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .local_address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub local_address: Option<IpAddr>,
+    /// Resolve host names by sending DNS queries to this DNS over HTTPS
+    /// server rather than using the system's resolver, for where local
+    /// DNS is unreliable or monitored. Each host is resolved once per
+    /// session, so its requests all go to the same address. See the
+    /// [`doh`](crate::doh) module for details.
+    ///
+    /// Default: `None`
+    /// Related feature: `features = ["doh"]`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .dns_over_https("https://1.1.1.1/dns-query")
+    ///     .build();
+    /// # #[cfg(feature = "doh")]
+    /// # options.unwrap();
+    /// ```
+    pub dns_over_https: Option<String>,
+    /// Requests sent before the page is fetched, e.g. to submit a login
+    /// form. Cookies set by the responses are sent with every later
+    /// request, and an unsuccessful response to any of them fails the
+    /// archive. See [`session`](crate::session) for details.
+    ///
+    /// Default: empty
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, LoginStep};
+    /// let options = ArchiveOptions::builder()
+    ///     .login_step(LoginStep::post_form(
+    ///         "https://example.com/login".parse().unwrap(),
+    ///         &[("username", "ferris"), ("password", "hunter2")],
+    ///     ))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub login: Vec<LoginStep>,
+    /// Predicate consulted before each resource is obtained, including
+    /// those found in stylesheets. Resources for which it returns `false`
+    /// are left out of the archive without being fetched.
+    ///
+    /// Default: `None`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, ResourceUrl};
+    /// let options = ArchiveOptions::builder()
+    ///     // Only archive images from the page's own site
+    ///     .resource_filter(|resource_url| match resource_url {
+    ///         ResourceUrl::Image(url) => url.host_str() == Some("example.com"),
+    ///         _ => true,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(skip)]
+    pub resource_filter: Option<ResourceFilter>,
+    /// Hook called with each downloaded resource before it is added to
+    /// the [`ResourceMap`](crate::ResourceMap). It may return the
+    /// resource unchanged, a transformed resource (e.g. with strings
+    /// redacted), or `None` to leave the resource out of the archive. The
+    /// digest and status in [`PageArchive::info`](crate::PageArchive::info)
+    /// describe the resource as it was downloaded.
+    ///
+    /// Default: `None`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, Resource};
+    /// let options = ArchiveOptions::builder()
+    ///     .resource_hook(|url, resource| match resource {
+    ///         // Drop tracking scripts
+    ///         Resource::Javascript(_) if url.path().contains("analytics") => {
+    ///             None
+    ///         }
+    ///         resource => Some(resource),
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(skip)]
+    pub resource_hook: Option<ResourceHook>,
+    /// Custom fetchers used instead of the HTTP client for the URLs they
+    /// handle. The first fetcher which handles a URL is used for it. See
+    /// [`fetcher`](crate::fetcher) for details.
+    ///
+    /// Default: empty
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::fetcher::{FetchedResource, FnFetcher};
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .fetcher(FnFetcher::new(
+    ///         |url| url.scheme() == "ipfs",
+    ///         |_url| Ok(Some(FetchedResource::new(&b"..."[..]))),
+    ///     ))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(skip)]
+    pub fetchers: Vec<Arc<dyn ResourceFetcher>>,
+    /// Extractors run over the page after the built-in ones, to find
+    /// resources referenced in ways the crate does not know about. They
+    /// are not run by the [`streaming`](crate::streaming) backend. See
+    /// [`extractor`](crate::extractor) for details.
+    ///
+    /// Default: empty
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::extractor::AttributeExtractor;
+    /// use web_archive::{ArchiveOptions, ResourceKind};
+    /// let lazy =
+    ///     AttributeExtractor::new("[data-src]", "data-src", ResourceKind::Image)
+    ///         .unwrap();
+    /// let options = ArchiveOptions::builder()
+    ///     .resource_extractor(lazy)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    #[serde(skip)]
+    pub resource_extractors: Vec<Arc<dyn ResourceExtractor>>,
+    /// Maximum number of redirects followed for each request. With
+    /// `Some(0)` redirects are not followed at all, so the redirect
+    /// response itself is archived, e.g. to record where a shortened URL
+    /// leads. Exceeding a non-zero limit fails the request.
+    ///
+    /// Corresponds to [`reqwest::ClientBuilder::redirect`].
+    ///
+    /// Default: `None`, using reqwest's default limit of 10 redirects
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .max_redirects(0)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub max_redirects: Option<usize>,
+    /// Maximum number of pages archived at the same time by the batch
+    /// APIs such as [`archive_all`](crate::archive_all). The blocking
+    /// batch API uses this many threads.
+    ///
+    /// Default: `None`, archiving up to 4 pages at a time
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .concurrency(8)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub concurrency: Option<usize>,
+    /// Maximum size in bytes of the downloaded resources held in memory
+    /// for each page. Images and other binary resources beyond it are
+    /// written to temporary files as [`Resource::Spilled`], and read back
+    /// when the archive is embedded or exported. See the
+    /// [`spill`](crate::spill) module.
+    ///
+    /// Resources taken from the session cache count towards the budget
+    /// of each page they are added to, but the copies held by the cache
+    /// itself are neither counted nor capped: use
+    /// [`ArchiveSession::clear_cache`](crate::ArchiveSession::clear_cache)
+    /// to release them.
+    ///
+    /// Default: `None`, keeping every resource in memory
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .memory_budget(64 * 1024 * 1024)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub memory_budget: Option<usize>,
+    /// Download the files offered for download by the page's
+    /// `<a download>` links, such as PDF reports, so that the links keep
+    /// working in the embedded page. See [`AttachmentExtractor`].
+    ///
+    /// Default: `false`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .download_attachments(true)
+    ///     .max_attachment_size(10 * 1024 * 1024)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub download_attachments: bool,
+    /// Maximum size in bytes of each attachment downloaded because of
+    /// [`download_attachments`](Self::download_attachments). Larger files
+    /// are left out of the archive, and the links to them are left as
+    /// they were.
+    ///
+    /// Default: `None`, storing attachments of any size
+    pub max_attachment_size: Option<usize>,
+    /// Download the scripts of the service workers the page registers,
+    /// so that they are kept with the archive. See
+    /// [`ServiceWorkerExtractor`]. To stop the archived page from
+    /// installing them, set
+    /// [`EmbedOptions::strip_service_workers`](crate::EmbedOptions::strip_service_workers).
+    ///
+    /// Default: `false`
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::builder()
+    ///     .service_workers(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub service_workers: bool,
+    /// Record the complete request and response headers of the page and
+    /// every resource fetched over HTTP in
+    /// [`ResourceInfo::headers`](crate::ResourceInfo::headers), so that
+    /// the original responses can be replayed faithfully after the
+    /// archive is exported. Credentials such as cookies are redacted
+    /// unless [`HeaderCapture::Full`] is chosen.
+    ///
+    /// Default: [`HeaderCapture::Off`]
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{ArchiveOptions, HeaderCapture};
+    /// let options = ArchiveOptions::builder()
+    ///     .capture_headers(HeaderCapture::Redacted)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub capture_headers: HeaderCapture,
+}
+
+/// The hook and fetchers are not `Debug`, so only their presence is shown
+impl std::fmt::Debug for ArchiveOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveOptions")
+            .field(
+                "accept_invalid_certificates",
+                &self.accept_invalid_certificates,
+            )
+            .field("proxy", &self.proxy)
+            .field("proxy_rules", &self.proxy_rules)
+            .field("no_proxy", &self.no_proxy)
+            .field("wayback_fallback", &self.wayback_fallback)
+            .field("digest_algorithm", &self.digest_algorithm)
+            .field("error_on_http_failure", &self.error_on_http_failure)
+            .field("resource_failure", &self.resource_failure)
+            .field(
+                "resource_failure_overrides",
+                &self.resource_failure_overrides,
+            )
+            .field("client_identity", &self.client_identity)
+            .field("pool_max_idle_per_host", &self.pool_max_idle_per_host)
+            .field("max_connections_per_host", &self.max_connections_per_host)
+            .field("pool_idle_timeout", &self.pool_idle_timeout)
+            .field("http2_prior_knowledge", &self.http2_prior_knowledge)
+            .field("http2_keep_alive_interval", &self.http2_keep_alive_interval)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("local_address", &self.local_address)
+            .field("dns_over_https", &self.dns_over_https)
+            .field("login", &self.login)
+            .field(
+                "resource_filter",
+                &self.resource_filter.as_ref().map(|_| ".."),
+            )
+            .field("resource_hook", &self.resource_hook.as_ref().map(|_| ".."))
+            .field("fetchers", &self.fetchers.len())
+            .field("resource_extractors", &self.resource_extractors.len())
+            .field("max_redirects", &self.max_redirects)
+            .field("concurrency", &self.concurrency)
+            .field("memory_budget", &self.memory_budget)
+            .field("download_attachments", &self.download_attachments)
+            .field("max_attachment_size", &self.max_attachment_size)
+            .field("service_workers", &self.service_workers)
+            .field("capture_headers", &self.capture_headers)
+            .finish()
+    }
+}
+
+/// Predicate deciding whether to fetch a resource, see
+/// [`ArchiveOptions::resource_filter`]
+pub type ResourceFilter = Arc<dyn Fn(&ResourceUrl) -> bool + Send + Sync>;
+
+/// Hook for transforming or dropping downloaded resources, see
+/// [`ArchiveOptions::resource_hook`]
+pub type ResourceHook =
+    Arc<dyn Fn(&Url, Resource) -> Option<Resource> + Send + Sync>;
+
+impl ArchiveOptions {
+    /// Start building a set of options from the defaults
+    pub fn builder() -> ArchiveOptionsBuilder {
+        ArchiveOptionsBuilder::default()
+    }
+
+    /// Load options from a TOML document, such as a configuration file
+    /// describing an archiving profile. Missing fields take their default
+    /// values, and the options are checked with [`validate`](Self::validate).
+    ///
+    /// Requires the `config` feature.
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::ArchiveOptions;
+    /// let options = ArchiveOptions::from_toml(
+    ///     r#"
+    ///     wayback_fallback = true
+    ///     concurrency = 8
+    ///     pool_idle_timeout = "30s"
+    ///
+    ///     [resource_failure_overrides]
+    ///     css = "abort"
+    ///     "#,
+    /// )
+    /// .unwrap();
+    /// assert_eq!(options.concurrency, Some(8));
+    /// ```
+    #[cfg(feature = "config")]
+    pub fn from_toml(document: &str) -> Result<Self, Error> {
+        let options: Self = toml::from_str(document).map_err(|e| {
+            // The error's own display quotes the document over several
+            // lines, so only its position is kept, as serde_json gives
+            let message = e.message().trim_end();
+            Error::ParseError(match e.span() {
+                Some(span) => format!(
+                    "{} at line {}",
+                    message,
+                    document[..span.start].matches('\n').count() + 1
+                ),
+                None => message.to_string(),
+            })
+        })?;
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Load options from a JSON document, as with
+    /// [`from_toml`](Self::from_toml).
+    ///
+    /// Requires the `config` feature.
+    #[cfg(feature = "config")]
+    pub fn from_json(document: &str) -> Result<Self, Error> {
+        let options: Self = serde_json::from_str(document)
+            .map_err(|e| Error::ParseError(format!("{}", e)))?;
+        options.validate()?;
+        Ok(options)
+    }
+
+    /// Check that the options are usable, returning
+    /// [`Error::InvalidOptions`] describing the first problem found. This
+    /// is done when a session is created, so invalid options are reported
+    /// before anything is fetched.
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(proxy) = &self.proxy {
+            Proxy::all(proxy).map_err(|e| {
+                Error::InvalidOptions(format!("invalid proxy {}: {}", proxy, e))
+            })?;
+        }
+        for rule in &self.proxy_rules {
+            rule.validate()?;
+        }
+        if let Some(server) = &self.dns_over_https {
+            if !cfg!(feature = "doh") {
+                return Err(Error::InvalidOptions(
+                    "dns_over_https requires the doh feature".to_string(),
+                ));
+            }
+            match Url::parse(server) {
+                Ok(url) if url.scheme() == "https" => {}
+                _ => {
+                    return Err(Error::InvalidOptions(format!(
+                        "invalid DNS over HTTPS server {}",
+                        server
+                    )))
+                }
+            }
+        }
+        if let Some(identity) = &self.client_identity {
+            identity.identity().map_err(|e| {
+                Error::InvalidOptions(format!(
+                    "invalid client identity: {:?}",
+                    e
+                ))
+            })?;
+        }
+        if self.concurrency == Some(0) {
+            return Err(Error::InvalidOptions(
+                "concurrency must be at least 1".to_string(),
+            ));
+        }
+        if self.max_connections_per_host == Some(0) {
+            return Err(Error::InvalidOptions(
+                "max_connections_per_host must be at least 1".to_string(),
+            ));
+        }
+        for step in &self.login {
+            step.validate()?;
+        }
+        Ok(())
+    }
+
+    /// The [`FailurePolicy`] which applies to a kind of resource
+    pub fn failure_policy(&self, kind: ResourceKind) -> FailurePolicy {
+        self.resource_failure_overrides
+            .get(&kind)
+            .copied()
+            .unwrap_or(self.resource_failure)
+    }
+
+    /// The number of pages to archive at the same time
+    pub(crate) fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1)
+    }
+
+    /// The redirect policy corresponding to
+    /// [`max_redirects`](Self::max_redirects), if one is set
+    pub(crate) fn redirect_policy(&self) -> Option<Policy> {
+        self.max_redirects.map(|max| match max {
+            0 => Policy::none(),
+            max => Policy::limited(max),
+        })
+    }
+
+    /// The hosts which bypass the proxies, if any
+    pub(crate) fn bypassed_hosts(&self) -> Option<NoProxy> {
+        NoProxy::from_string(&self.no_proxy.join(","))
+    }
+
+    /// The custom fetcher to use for a URL, if any
+    pub(crate) fn fetcher_for(
+        &self,
+        url: &Url,
+    ) -> Option<&Arc<dyn ResourceFetcher>> {
+        self.fetchers.iter().find(|fetcher| fetcher.handles(url))
+    }
+
+    /// The resources referenced by a page, found by the built-in
+    /// extractors and the [`resource_extractors`](Self::resource_extractors),
+    /// including its attachments and service workers if they are to be
+    /// downloaded
+    pub(crate) fn resource_urls(
+        &self,
+        url: &Url,
+        content: &str,
+    ) -> Vec<ResourceUrl> {
+        let mut extractors = self.resource_extractors.clone();
+        if self.download_attachments {
+            extractors.push(Arc::new(AttachmentExtractor));
+        }
+        if self.service_workers {
+            extractors.push(Arc::new(ServiceWorkerExtractor));
+        }
+        extract_resource_urls(&parse_html().one(content), url, &extractors)
+    }
+
+    /// The URLs of the attachments of a page which are subject to the
+    /// [`max_attachment_size`](Self::max_attachment_size)
+    pub(crate) fn limited_attachments(
+        &self,
+        url: &Url,
+        content: &str,
+    ) -> HashSet<Url> {
+        if !self.download_attachments || self.max_attachment_size.is_none() {
+            return HashSet::new();
+        }
+        AttachmentExtractor
+            .extract(&parse_html().one(content), url)
+            .into_iter()
+            .map(|resource_url| resource_url.url().clone())
+            .collect()
+    }
+
+    /// Remove an attachment from the archive again if it is larger than
+    /// the [`max_attachment_size`](Self::max_attachment_size), returning
+    /// what became of it
+    pub(crate) fn limit_attachment(
+        &self,
+        url: &Url,
+        outcome: ResourceOutcome,
+        info: &mut ArchiveInfo,
+        resource_map: &mut ResourceMap,
+    ) -> ResourceOutcome {
+        let oversized = match (self.max_attachment_size, resource_map.get(url))
+        {
+            (Some(limit), Some(resource)) => resource.size() > limit,
+            _ => false,
+        };
+        if !oversized {
+            return outcome;
+        }
+        resource_map.remove(url);
+        info.resources.remove(url);
+        ResourceOutcome::Skipped(
+            "larger than the attachment size limit".to_string(),
+        )
+    }
+
+    /// Whether the [`resource_filter`](Self::resource_filter) allows the
+    /// resource to be fetched
+    pub(crate) fn should_fetch(&self, resource_url: &ResourceUrl) -> bool {
+        self.resource_filter
+            .as_ref()
+            .is_none_or(|filter| filter(resource_url))
+    }
+
+    /// Pass a downloaded resource through the
+    /// [`resource_hook`](Self::resource_hook), if there is one
+    pub(crate) fn process_resource(
+        &self,
+        url: &Url,
+        resource: Resource,
+    ) -> Option<Resource> {
+        match &self.resource_hook {
+            Some(hook) => hook(url, resource),
+            None => Some(resource),
+        }
+    }
+
+    /// Spill a resource to a temporary file if adding it to the
+    /// `resident` bytes already held in memory for the page would exceed
+    /// the [`memory_budget`](Self::memory_budget), counting it in
+    /// `resident` otherwise
+    pub(crate) fn apply_memory_budget(
+        &self,
+        resource: Resource,
+        resident: &mut usize,
+    ) -> Result<Resource, Error> {
+        let resource = match self.memory_budget {
+            Some(budget) if *resident + resource.size() > budget => {
+                SpilledResource::spill(&resource)?
+                    .map_or(resource, Resource::Spilled)
+            }
+            _ => resource,
+        };
+        *resident += resource.data().map_or(0, |data| data.len());
+        Ok(resource)
+    }
+}
+
+/// Builder for [`ArchiveOptions`], created with
+/// [`ArchiveOptions::builder`]. Each setter corresponds to the field of
+/// the same name.
+#[derive(Default)]
+pub struct ArchiveOptionsBuilder {
+    options: ArchiveOptions,
+}
+
+impl ArchiveOptionsBuilder {
+    /// Set [`ArchiveOptions::accept_invalid_certificates`]
+    pub fn accept_invalid_certificates(mut self, accept: bool) -> Self {
+        self.options.accept_invalid_certificates = accept;
+        self
+    }
+
+    /// Set [`ArchiveOptions::proxy`]
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.options.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Add a rule to [`ArchiveOptions::proxy_rules`]
+    pub fn proxy_rule(mut self, rule: ProxyRule) -> Self {
+        self.options.proxy_rules.push(rule);
+        self
+    }
+
+    /// Add a host to [`ArchiveOptions::no_proxy`]
+    pub fn no_proxy(mut self, host: impl Into<String>) -> Self {
+        self.options.no_proxy.push(host.into());
+        self
+    }
+
+    /// Set [`ArchiveOptions::wayback_fallback`]
+    pub fn wayback_fallback(mut self, fallback: bool) -> Self {
+        self.options.wayback_fallback = fallback;
+        self
+    }
+
+    /// Set [`ArchiveOptions::digest_algorithm`]
+    pub fn digest_algorithm(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.options.digest_algorithm = algorithm;
+        self
+    }
+
+    /// Set [`ArchiveOptions::error_on_http_failure`]
+    pub fn error_on_http_failure(mut self, error: bool) -> Self {
+        self.options.error_on_http_failure = error;
+        self
+    }
+
+    /// Set [`ArchiveOptions::resource_failure`]
+    pub fn resource_failure(mut self, policy: FailurePolicy) -> Self {
+        self.options.resource_failure = policy;
+        self
+    }
+
+    /// Override the failure policy for one kind of resource in
+    /// [`ArchiveOptions::resource_failure_overrides`]
+    pub fn resource_failure_for(
+        mut self,
+        kind: ResourceKind,
+        policy: FailurePolicy,
+    ) -> Self {
+        self.options.resource_failure_overrides.insert(kind, policy);
+        self
+    }
+
+    /// Set [`ArchiveOptions::client_identity`]
+    pub fn client_identity(mut self, identity: ClientIdentity) -> Self {
+        self.options.client_identity = Some(identity);
+        self
+    }
+
+    /// Set [`ArchiveOptions::pool_max_idle_per_host`]
+    pub fn pool_max_idle_per_host(mut self, max_idle: usize) -> Self {
+        self.options.pool_max_idle_per_host = Some(max_idle);
+        self
+    }
+
+    /// Set [`ArchiveOptions::max_connections_per_host`]
+    pub fn max_connections_per_host(mut self, max: usize) -> Self {
+        self.options.max_connections_per_host = Some(max);
+        self
+    }
+
+    /// Set [`ArchiveOptions::pool_idle_timeout`]
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.options.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Set [`ArchiveOptions::http2_prior_knowledge`]
+    pub fn http2_prior_knowledge(mut self, enable: bool) -> Self {
+        self.options.http2_prior_knowledge = enable;
+        self
+    }
+
+    /// Set [`ArchiveOptions::http2_keep_alive_interval`]
+    pub fn http2_keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.options.http2_keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Set [`ArchiveOptions::tcp_keepalive`]
+    pub fn tcp_keepalive(mut self, interval: Duration) -> Self {
+        self.options.tcp_keepalive = Some(interval);
+        self
+    }
+
+    /// Set [`ArchiveOptions::local_address`]
+    pub fn local_address(mut self, address: IpAddr) -> Self {
+        self.options.local_address = Some(address);
+        self
+    }
+
+    /// Set [`ArchiveOptions::dns_over_https`]
+    pub fn dns_over_https<S: ToString>(mut self, server: S) -> Self {
+        self.options.dns_over_https = Some(server.to_string());
+        self
+    }
+
+    /// Add a request to [`ArchiveOptions::login`]
+    pub fn login_step(mut self, step: LoginStep) -> Self {
+        self.options.login.push(step);
+        self
+    }
+
+    /// Set [`ArchiveOptions::resource_filter`]
+    pub fn resource_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&ResourceUrl) -> bool + Send + Sync + 'static,
+    {
+        self.options.resource_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Set [`ArchiveOptions::resource_hook`]
+    pub fn resource_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Url, Resource) -> Option<Resource> + Send + Sync + 'static,
+    {
+        self.options.resource_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Add a fetcher to [`ArchiveOptions::fetchers`]
+    pub fn fetcher<F>(mut self, fetcher: F) -> Self
+    where
+        F: ResourceFetcher + 'static,
+    {
+        self.options.fetchers.push(Arc::new(fetcher));
+        self
+    }
+
+    /// Add an extractor to [`ArchiveOptions::resource_extractors`]
+    pub fn resource_extractor<E>(mut self, extractor: E) -> Self
+    where
+        E: ResourceExtractor + 'static,
+    {
+        self.options.resource_extractors.push(Arc::new(extractor));
+        self
+    }
+
+    /// Set [`ArchiveOptions::max_redirects`]
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.options.max_redirects = Some(max_redirects);
+        self
+    }
+
+    /// Set [`ArchiveOptions::concurrency`]
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.options.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Set [`ArchiveOptions::memory_budget`]
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.options.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Set [`ArchiveOptions::download_attachments`]
+    pub fn download_attachments(mut self, download: bool) -> Self {
+        self.options.download_attachments = download;
+        self
+    }
+
+    /// Set [`ArchiveOptions::max_attachment_size`]
+    pub fn max_attachment_size(mut self, bytes: usize) -> Self {
+        self.options.max_attachment_size = Some(bytes);
+        self
+    }
+
+    /// Set [`ArchiveOptions::service_workers`]
+    pub fn service_workers(mut self, fetch: bool) -> Self {
+        self.options.service_workers = fetch;
+        self
+    }
+
+    /// Set [`ArchiveOptions::capture_headers`]
+    pub fn capture_headers(mut self, capture: HeaderCapture) -> Self {
+        self.options.capture_headers = capture;
+        self
+    }
+
+    /// Finish building the options, checking them with
+    /// [`ArchiveOptions::validate`]
+    pub fn build(self) -> Result<ArchiveOptions, Error> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+/// How a resource which fails to download is handled
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Leave the resource out of the archive
+    #[default]
+    Skip,
+    /// Keep the error response in the resource map as a
+    /// [`Resource::Failed`], so that the archive records what went
+    /// wrong
+    Record,
+    /// Fail the whole archive with [`Error::HttpStatus`]
+    Abort,
+}
+
+/// Which HTTP headers are recorded for each download
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderCapture {
+    /// Only the `Content-Type` of each response is recorded
+    #[default]
+    Off,
+    /// All headers are recorded, but the values of those carrying
+    /// credentials, such as `Cookie`, `Set-Cookie` and `Authorization`,
+    /// are replaced with [`REDACTED`](HeaderCapture::REDACTED)
+    Redacted,
+    /// All headers are recorded as they were sent and received
+    Full,
+}
+
+impl HeaderCapture {
+    /// Value recorded in place of a credential
+    pub const REDACTED: &'static str = "[redacted]";
+
+    /// Headers whose values are redacted by [`HeaderCapture::Redacted`]
+    const CREDENTIALS: [HeaderName; 4] =
+        [COOKIE, SET_COOKIE, AUTHORIZATION, PROXY_AUTHORIZATION];
+
+    /// Record the headers of a request and its response, if enabled
+    pub(crate) fn capture(
+        self,
+        request: &HeaderMap,
+        version: Version,
+        response: &HeaderMap,
+    ) -> Option<CapturedHeaders> {
+        let record = |headers: &HeaderMap| {
+            headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = if self == HeaderCapture::Redacted
+                        && Self::CREDENTIALS.contains(name)
+                    {
+                        Self::REDACTED.to_string()
+                    } else {
+                        String::from_utf8_lossy(value.as_bytes()).into_owned()
+                    };
+                    (name.to_string(), value)
+                })
+                .collect()
+        };
+        match self {
+            HeaderCapture::Off => None,
+            HeaderCapture::Redacted | HeaderCapture::Full => {
+                Some(CapturedHeaders {
+                    version: format!("{:?}", version),
+                    request: record(request),
+                    response: record(response),
+                })
+            }
+        }
+    }
+}
+
+/// A proxy used for the URLs with a particular scheme or host, see
+/// [`ArchiveOptions::proxy_rules`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyRule {
+    /// URL of the proxy, as for [`ArchiveOptions::proxy`]
+    pub proxy: String,
+    /// Scheme of the URLs which use the proxy, `http` or `https`, or
+    /// `None` for both
+    #[serde(default)]
+    pub scheme: Option<String>,
+    /// Hosts whose URLs use the proxy, or every host if empty. A domain
+    /// name, with or without a leading dot, also matches its subdomains,
+    /// and `*` matches every host.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+impl ProxyRule {
+    /// A rule using the proxy for every URL
+    pub fn new(proxy: impl Into<String>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            scheme: None,
+            hosts: Vec::new(),
+        }
+    }
+
+    /// Only use the proxy for URLs with the scheme
+    pub fn scheme(mut self, scheme: impl Into<String>) -> Self {
+        self.scheme = Some(scheme.into());
+        self
+    }
+
+    /// Use the proxy for the host and its subdomains, instead of every
+    /// host
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.push(host.into());
+        self
+    }
+
+    /// Whether the proxy should be used for the URL
+    pub(crate) fn matches(&self, url: &Url) -> bool {
+        if let Some(scheme) = &self.scheme {
+            if !scheme.eq_ignore_ascii_case(url.scheme()) {
+                return false;
+            }
+        }
+        let host = url
+            .host_str()
+            .unwrap_or_default()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        self.hosts.is_empty()
+            || self.hosts.iter().any(|pattern| {
+                let pattern = pattern.trim().trim_start_matches('.');
+                let pattern = pattern.to_ascii_lowercase();
+                pattern == "*"
+                    || host == pattern
+                    || host.ends_with(&format!(".{}", pattern))
+            })
+    }
+
+    /// The proxy for reqwest, bypassed for the hosts in `no_proxy`
+    pub(crate) fn proxy(&self, no_proxy: Option<NoProxy>) -> Proxy {
+        let rule = self.clone();
+        Proxy::custom(move |url| {
+            Some(rule.proxy.clone()).filter(|_| rule.matches(url))
+        })
+        .no_proxy(no_proxy)
+    }
+
+    /// Check that the proxy and scheme are valid
+    fn validate(&self) -> Result<(), Error> {
+        Proxy::all(&self.proxy).map_err(|e| {
+            Error::InvalidOptions(format!(
+                "invalid proxy {}: {}",
+                self.proxy, e
+            ))
+        })?;
+        match self.scheme.as_deref() {
+            None | Some("http") | Some("https") => Ok(()),
+            Some(scheme) => Err(Error::InvalidOptions(format!(
+                "proxy rules only apply to http and https, not {}",
+                scheme
+            ))),
+        }
+    }
+}
+
+/// A client certificate and private key used for mutual TLS
+#[derive(Clone)]
+pub enum ClientIdentity {
+    /// A DER-encoded PKCS #12 archive and the password protecting it
+    Pkcs12 {
+        /// Contents of the archive
+        der: Vec<u8>,
+        /// Password for the archive
+        password: String,
+    },
+    /// A PEM-encoded certificate chain and unencrypted PKCS #8 private
+    /// key
+    Pem {
+        /// The certificate chain, starting with the client certificate
+        certificate: Vec<u8>,
+        /// The private key
+        key: Vec<u8>,
+    },
+}
+
+impl ClientIdentity {
+    /// Parse the identity for use with reqwest
+    pub(crate) fn identity(&self) -> Result<reqwest::Identity, Error> {
+        let identity = match self {
+            ClientIdentity::Pkcs12 { der, password } => {
+                reqwest::Identity::from_pkcs12_der(der, password)?
+            }
+            ClientIdentity::Pem { certificate, key } => {
+                reqwest::Identity::from_pkcs8_pem(certificate, key)?
+            }
+        };
+        Ok(identity)
+    }
+}
+
+/// Key material is omitted so that it does not end up in logs
+impl std::fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientIdentity::Pkcs12 { .. } => f.write_str("Pkcs12 { .. }"),
+            ClientIdentity::Pem { .. } => f.write_str("Pem { .. }"),
+        }
+    }
+}
+
+/// Serde helpers for optional durations in humantime format, e.g. `"1m 30s"`
+mod humantime_option {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => {
+                serializer.collect_str(&humantime::format_duration(*duration))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|duration| {
+                humantime::parse_duration(&duration).map_err(de::Error::custom)
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client_builder;
+
+    #[test]
+    fn test_builder() {
+        let options = ArchiveOptions::builder()
+            .proxy("http://localhost:8080")
+            .wayback_fallback(true)
+            .resource_failure_for(ResourceKind::Css, FailurePolicy::Abort)
+            .concurrency(2)
+            .build()
+            .unwrap();
+        assert_eq!(options.proxy.as_deref(), Some("http://localhost:8080"));
+        assert!(options.wayback_fallback);
+        assert_eq!(
+            options.failure_policy(ResourceKind::Css),
+            FailurePolicy::Abort
+        );
+        assert_eq!(options.concurrency(), 2);
+    }
+
+    #[test]
+    fn test_serde() {
+        let options = ArchiveOptions::builder()
+            .proxy("http://localhost:8080")
+            .resource_failure_for(ResourceKind::Css, FailurePolicy::Abort)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .http2_prior_knowledge(true)
+            .tcp_keepalive(Duration::from_secs(60))
+            .local_address("127.0.0.1".parse().unwrap())
+            .digest_algorithm(DigestAlgorithm::Sha512)
+            .login_step(LoginStep::post_form(
+                "https://example.com/login".parse().unwrap(),
+                &[("username", "ferris")],
+            ))
+            .resource_hook(|_, resource| Some(resource))
+            .build()
+            .unwrap();
+
+        let json = serde_json::to_value(&options).unwrap();
+        assert_eq!(json["proxy"], "http://localhost:8080");
+        assert_eq!(json["resource_failure"], "skip");
+        assert_eq!(json["resource_failure_overrides"]["css"], "abort");
+        assert_eq!(json["pool_idle_timeout"], "1m 30s");
+        assert_eq!(json["http2_prior_knowledge"], true);
+        assert!(json["http2_keep_alive_interval"].is_null());
+        assert_eq!(json["tcp_keepalive"], "1m");
+        assert_eq!(json["local_address"], "127.0.0.1");
+        assert_eq!(json["digest_algorithm"], "sha512");
+        assert_eq!(json["login"][0]["method"], "POST");
+        assert!(json.get("resource_hook").is_none());
+
+        let parsed: ArchiveOptions = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.proxy, options.proxy);
+        assert_eq!(parsed.pool_idle_timeout, options.pool_idle_timeout);
+        assert!(parsed.http2_prior_knowledge);
+        assert_eq!(parsed.tcp_keepalive, options.tcp_keepalive);
+        assert_eq!(parsed.local_address, options.local_address);
+        assert!(client_builder(&parsed).unwrap().build().is_ok());
+        assert_eq!(parsed.login, options.login);
+        assert_eq!(
+            parsed.failure_policy(ResourceKind::Css),
+            FailurePolicy::Abort
+        );
+        assert!(parsed.resource_hook.is_none());
+
+        let parsed: ArchiveOptions =
+            serde_json::from_str(r#"{ "concurrency": 2 }"#).unwrap();
+        assert_eq!(parsed.concurrency, Some(2));
+        assert_eq!(parsed.digest_algorithm, DigestAlgorithm::Sha256);
+    }
+
+    #[test]
+    fn test_dns_over_https() {
+        let options = ArchiveOptions::builder()
+            .dns_over_https("https://1.1.1.1/dns-query")
+            .build();
+        assert_eq!(options.is_ok(), cfg!(feature = "doh"));
+        let options = ArchiveOptions::builder()
+            .dns_over_https("http://1.1.1.1/dns-query")
+            .build();
+        assert!(matches!(options, Err(Error::InvalidOptions(_))));
+    }
+
+    #[cfg(feature = "config")]
+    #[test]
+    fn test_from_config() {
+        let options = ArchiveOptions::from_toml(
+            r#"
+            proxy = "http://localhost:8080"
+            resource_failure = "record"
+            digest_algorithm = "sha384"
+
+            [[login]]
+            method = "POST"
+            url = "https://example.com/login"
+            form = [["username", "ferris"]]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(options.proxy.as_deref(), Some("http://localhost:8080"));
+        assert_eq!(options.resource_failure, FailurePolicy::Record);
+        assert_eq!(options.digest_algorithm, DigestAlgorithm::Sha384);
+        assert_eq!(
+            options.login,
+            vec![LoginStep::post_form(
+                "https://example.com/login".parse().unwrap(),
+                &[("username", "ferris")],
+            )]
+        );
+
+        let options = ArchiveOptions::from_json(
+            r#"{ "error_on_http_failure": true, "concurrency": 3 }"#,
+        )
+        .unwrap();
+        assert!(options.error_on_http_failure);
+        assert_eq!(options.concurrency(), 3);
+
+        match ArchiveOptions::from_toml("\nconcurrency = \"many\"") {
+            Err(Error::ParseError(e)) => {
+                assert!(e.ends_with(" at line 2"), "{}", e);
+                assert!(!e.contains('\n'));
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+        assert!(matches!(
+            ArchiveOptions::from_json(r#"{ "concurrency": 0 }"#),
+            Err(Error::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_clone_debug() {
+        let options = ArchiveOptions::builder()
+            .wayback_fallback(true)
+            .resource_hook(|_, resource| Some(resource))
+            .build()
+            .unwrap();
+        let cloned = options.clone();
+        assert!(cloned.wayback_fallback);
+        assert!(cloned.resource_hook.is_some());
+
+        let debug = format!("{:?}", cloned);
+        assert!(debug.contains("wayback_fallback: true"));
+        assert!(debug.contains(r#"resource_hook: Some("..")"#));
+        assert!(debug.contains("fetchers: 0"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ArchiveOptions::default().validate().is_ok());
+
+        let invalid = vec![
+            ArchiveOptions::builder().concurrency(0),
+            ArchiveOptions::builder().proxy("not a proxy"),
+            ArchiveOptions::builder().proxy_rule(ProxyRule::new("not a proxy")),
+            ArchiveOptions::builder().proxy_rule(
+                ProxyRule::new("http://localhost:8080").scheme("ftp"),
+            ),
+            ArchiveOptions::builder().login_step(LoginStep::get(
+                "ftp://example.com/".parse().unwrap(),
+            )),
+            ArchiveOptions::builder().client_identity(ClientIdentity::Pem {
+                certificate: Vec::new(),
+                key: Vec::new(),
+            }),
+        ];
+        for builder in invalid {
+            assert!(matches!(builder.build(), Err(Error::InvalidOptions(_))));
+        }
+    }
+
+    #[test]
+    fn test_invalid_client_identity() {
+        let identity = ClientIdentity::Pem {
+            certificate: b"not a certificate".to_vec(),
+            key: b"not a key".to_vec(),
+        };
+        assert!(matches!(identity.identity(), Err(Error::ReqwestError(_))));
+        assert_eq!(format!("{:?}", identity), "Pem { .. }");
+
+        let options = ArchiveOptions {
+            client_identity: Some(identity),
+            ..Default::default()
+        };
+        assert!(client_builder(&options).is_err());
+    }
+
+    #[test]
+    fn test_concurrency() {
+        assert_eq!(ArchiveOptions::default().concurrency(), 4);
+        let options = ArchiveOptions {
+            concurrency: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(options.concurrency(), 1);
+    }
+
+    #[test]
+    fn test_redirect_policy() {
+        assert!(ArchiveOptions::default().redirect_policy().is_none());
+        let options =
+            ArchiveOptions::builder().max_redirects(0).build().unwrap();
+        assert_eq!(
+            format!("{:?}", options.redirect_policy().unwrap()),
+            format!("{:?}", Policy::none())
+        );
+        let options =
+            ArchiveOptions::builder().max_redirects(3).build().unwrap();
+        assert_eq!(
+            format!("{:?}", options.redirect_policy().unwrap()),
+            format!("{:?}", Policy::limited(3))
+        );
+    }
+
+    #[test]
+    fn test_resource_hook() {
+        let url = Url::parse("http://example.com/a.css").unwrap();
+        let options = ArchiveOptions::default();
+        assert_eq!(
+            options.process_resource(&url, Resource::Css("a {}".into())),
+            Some(Resource::Css("a {}".into()))
+        );
+
+        let options = ArchiveOptions::builder()
+            .resource_hook(|_, resource| match resource {
+                Resource::Css(css) => {
+                    Some(Resource::Css(css.text().replace("a", "b").into()))
+                }
+                _ => None,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(
+            options.process_resource(&url, Resource::Css("a {}".into())),
+            Some(Resource::Css("b {}".into()))
+        );
+        assert_eq!(
+            options.process_resource(&url, Resource::Javascript("".into())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resource_filter() {
+        let css =
+            ResourceUrl::Css(Url::parse("http://example.com/a.css").unwrap());
+        let image = ResourceUrl::Image(
+            Url::parse("http://cdn.example.com/a.png").unwrap(),
+        );
+        let options = ArchiveOptions::default();
+        assert!(options.should_fetch(&css));
+        assert!(options.should_fetch(&image));
+
+        let options = ArchiveOptions::builder()
+            .resource_filter(|resource_url| {
+                resource_url.url().host_str() == Some("example.com")
+            })
+            .build()
+            .unwrap();
+        assert!(options.should_fetch(&css));
+        assert!(!options.should_fetch(&image));
+        assert!(
+            format!("{:?}", options).contains(r#"resource_filter: Some("..")"#)
+        );
+    }
+
+    #[test]
+    fn test_failure_policy_overrides() {
+        let mut options = ArchiveOptions {
+            resource_failure: FailurePolicy::Record,
+            ..Default::default()
+        };
+        options
+            .resource_failure_overrides
+            .insert(ResourceKind::Css, FailurePolicy::Abort);
+        assert_eq!(
+            options.failure_policy(ResourceKind::Css),
+            FailurePolicy::Abort
+        );
+        assert_eq!(
+            options.failure_policy(ResourceKind::Image),
+            FailurePolicy::Record
+        );
+    }
+
+    #[test]
+    fn test_header_capture() {
+        let mut request = HeaderMap::new();
+        request.insert(COOKIE, "session=secret".parse().unwrap());
+        let mut response = HeaderMap::new();
+        response.insert("server", "ferris".parse().unwrap());
+        response.append(SET_COOKIE, "a=1".parse().unwrap());
+        response.append(SET_COOKIE, "b=2".parse().unwrap());
+        let capture = |capture: HeaderCapture| {
+            capture.capture(&request, Version::HTTP_11, &response)
+        };
+        let pairs = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert!(capture(HeaderCapture::Off).is_none());
+
+        let full = capture(HeaderCapture::Full).unwrap();
+        assert_eq!(full.version, "HTTP/1.1");
+        assert_eq!(full.request, pairs(&[("cookie", "session=secret")]));
+        assert_eq!(
+            full.response,
+            pairs(&[
+                ("server", "ferris"),
+                ("set-cookie", "a=1"),
+                ("set-cookie", "b=2"),
+            ])
+        );
+
+        let redacted = capture(HeaderCapture::Redacted).unwrap();
+        assert_eq!(
+            redacted.request,
+            pairs(&[("cookie", HeaderCapture::REDACTED)])
+        );
+        assert_eq!(
+            redacted.response,
+            pairs(&[
+                ("server", "ferris"),
+                ("set-cookie", HeaderCapture::REDACTED),
+                ("set-cookie", HeaderCapture::REDACTED),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_proxy_rules() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        let rule = ProxyRule::new("http://localhost:3128").scheme("https");
+        assert!(rule.matches(&url("https://example.com/")));
+        assert!(!rule.matches(&url("http://example.com/")));
+
+        let rule = ProxyRule::new("http://localhost:3128")
+            .host(".Example.com")
+            .host("::1");
+        assert!(rule.matches(&url("http://example.com/")));
+        assert!(rule.matches(&url("https://www.example.com/")));
+        assert!(rule.matches(&url("http://[::1]:8080/")));
+        assert!(!rule.matches(&url("http://notexample.com/")));
+        assert!(ProxyRule::new("http://localhost:3128")
+            .host("*")
+            .matches(&url("http://example.org/")));
+
+        let options = ArchiveOptions::builder()
+            .proxy("http://localhost:8080")
+            .proxy_rule(rule)
+            .no_proxy("intranet.example")
+            .no_proxy("10.0.0.0/8")
+            .build()
+            .unwrap();
+        assert!(ArchiveOptions::default().bypassed_hosts().is_none());
+        assert!(options.bypassed_hosts().is_some());
+        assert!(client_builder(&options).unwrap().build().is_ok());
+
+        let json = serde_json::to_value(&options).unwrap();
+        assert_eq!(json["proxy_rules"][0]["hosts"][1], "::1");
+        assert_eq!(json["no_proxy"][1], "10.0.0.0/8");
+        let parsed: ArchiveOptions = serde_json::from_str(
+            r#"{ "proxy_rules": [{ "proxy": "http://localhost:3128" }] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.proxy_rules,
+            vec![ProxyRule::new("http://localhost:3128")]
+        );
+    }
+}
@@ -7,38 +7,405 @@
 
 //! Module for the core archiving functionality
 
-use crate::parsing::{Resource, ResourceMap};
-use html5ever::{interface::QualName, local_name, namespace_url, ns};
+#[cfg(feature = "container")]
+use crate::container;
+use crate::css::rewrite_css_urls;
+use crate::digest::Digest;
+use crate::embed::{
+    apply_fidelity, fidelity_report, finish_document, insert_provenance,
+    new_html_element, sanitize, EmbedOptions, FidelityReport, ScriptEmbedding,
+    StylesheetEmbedding, SvgEmbedding,
+};
+use crate::error::Error;
+use crate::manifest::{manifest_file_path, EntryKind, Manifest, MANIFEST_FILE};
+use crate::metadata::{MetadataCache, PageMetadata};
+#[cfg(feature = "parallel")]
+use crate::parsing::encode_data_uris_parallel;
+use crate::parsing::{
+    encode_resource_data_uri, encode_svg_data_uri, encode_text_data_uri,
+    is_binary, is_stylesheet_rel, is_switchable_stylesheet, parse_links,
+    parse_resource_urls, ImageResource, Link, Resource, ResourceKind,
+    ResourceMap, ResourceUrl, TextResource, OTHER_RESOURCE_ATTRIBUTES,
+};
+use crate::readability::{self, Article};
+use crate::session::{is_downloaded, ArchiveSession, ResourceQueue};
+#[cfg(feature = "signing")]
+use crate::signature::{self, Signature, SigningKey, VerifyingKey};
+use crate::sink::{
+    content_addressed_name, ArchiveSink, DiskLayout, DiskOptions,
+    FileSystemSink, PlannedFile, TarGzSink,
+};
+use crate::spill::write_resource_file;
+use bytes::Bytes;
 use kuchiki::traits::TendrilSink;
 use kuchiki::{parse_html, NodeData, NodeRef};
-use std::io;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
 use url::Url;
 
+/// Attributes of a stylesheet `<link>` which are copied to the `<style>`
+/// replacing it
+pub(crate) const STYLE_ATTRIBUTES: [&str; 2] = ["media", "title"];
+
 /// Intermediate struct storing the downloaded resources
 #[derive(Debug)]
 pub struct PageArchive {
-    /// Base URL of the page being archived
+    /// Base URL of the page being archived. When the page is fetched by
+    /// [`archive`](crate::archive), this is the final URL after any
+    /// redirects have been followed.
     pub url: Url,
     /// The content/body of the page
     pub content: String,
+    /// The body of the page exactly as it was received, before its
+    /// character encoding was decoded into
+    /// [`content`](Self::content), so that byte-exact exports and
+    /// checks against [`ResourceInfo::digest`] remain possible. `None`
+    /// for archives built from text, e.g. by
+    /// [`from_parts`](Self::from_parts). It is written alongside the
+    /// page on disk and in containers, and restored when they are read
+    /// back, e.g. by [`read_from_disk`](Self::read_from_disk).
+    pub raw_content: Option<Bytes>,
     /// A mapping of resource URLs to the downloaded resources
     pub resource_map: ResourceMap,
+    /// The time at which the page was downloaded
+    pub fetched_at: SystemTime,
+    /// Information recorded about the page and its resources as they
+    /// were downloaded
+    pub info: ArchiveInfo,
+    /// Metadata parsed from the content, see [`metadata`](Self::metadata)
+    pub(crate) metadata_cache: MetadataCache,
+}
+
+/// How [`PageArchive::merge`] decides between two different resources
+/// for the same URL
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the resource already in the archive
+    #[default]
+    KeepExisting,
+    /// Replace it with the resource being merged in
+    Replace,
+    /// Keep the resource already in the archive, unless it failed to
+    /// download and the one being merged in did not
+    PreferSuccessful,
+    /// Return [`Error::MergeConflict`] without merging anything
+    Error,
+}
+
+/// Information recorded about the files in a [`PageArchive`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    /// Information about the page itself
+    pub page: ResourceInfo,
+    /// Information about each of the resources in the
+    /// [`resource_map`](PageArchive::resource_map)
+    pub resources: HashMap<Url, ResourceInfo>,
+}
+
+/// Information recorded about a single downloaded file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceInfo {
+    /// Digest of the file's contents
+    pub digest: Option<Digest>,
+    /// The HTTP status with which the file was served
+    pub status: Option<StatusCode>,
+    /// How long the file took to download, if it was fetched over HTTP
+    /// rather than by a fetcher or from the Wayback Machine fallback
+    pub timings: Option<ResourceTimings>,
+    /// The `Content-Type` the file was served with, including parameters
+    /// such as its `charset`, so that exports can reproduce the original
+    /// headers
+    pub content_type: Option<String>,
+    /// All of the request and response headers, if
+    /// [`ArchiveOptions::capture_headers`](crate::ArchiveOptions::capture_headers)
+    /// was enabled and the file was fetched over HTTP
+    pub headers: Option<CapturedHeaders>,
+}
+
+/// The headers of an HTTP request and its response, in the order they
+/// were sent and received, recorded by
+/// [`HeaderCapture`](crate::HeaderCapture)
+///
+/// The request headers are the `Host`, those set on the request, and the
+/// `Cookie` from the session's cookie jar. Other headers which the HTTP
+/// client adds as the request is sent, such as `Accept`, are not
+/// included, and none are known for a page passed to
+/// [`archive_from_response`](crate::archive_from_response).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedHeaders {
+    /// The HTTP version of the response, e.g. `HTTP/1.1`
+    pub version: String,
+    /// Names and values of the request headers
+    pub request: Vec<(String, String)>,
+    /// Names and values of the response headers, including repeated
+    /// headers such as `Set-Cookie` once for each value
+    pub response: Vec<(String, String)>,
+}
+
+/// How long it took to download a single file
+///
+/// The HTTP client does not report DNS resolution, connection and TLS
+/// handshake times separately, so they are included in
+/// [`first_byte`](Self::first_byte) for requests which opened a new
+/// connection.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct ResourceTimings {
+    /// Time from sending the request until the response headers were
+    /// received
+    #[serde(with = "humantime_duration")]
+    pub first_byte: Duration,
+    /// Time from sending the request until the whole body had been
+    /// received
+    #[serde(with = "humantime_duration")]
+    pub total: Duration,
+}
+
+impl ResourceTimings {
+    /// Timings of a request sent at `sent` whose response headers were
+    /// received at `first_byte`, ending now
+    pub(crate) fn since(sent: Instant, first_byte: Instant) -> Self {
+        Self {
+            first_byte: first_byte.duration_since(sent),
+            total: sent.elapsed(),
+        }
+    }
+}
+
+/// Serde helpers for durations in humantime format, e.g. `"12ms 345us"`
+mod humantime_duration {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(
+        duration: &Duration,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*duration))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        let duration = String::deserialize(deserializer)?;
+        humantime::parse_duration(&duration).map_err(de::Error::custom)
+    }
 }
 
 impl PageArchive {
+    /// Build an archive from a page and resources which have already
+    /// been obtained, e.g. from a cache or a test fixture. Embedding and
+    /// writing the archive never access the network, so this allows the
+    /// rewriting logic to be used on its own.
+    ///
+    /// Resources are looked up by their absolute URL, after resolving
+    /// the page's references against `url`. The archive is recorded as
+    /// fetched now, with no digests or statuses in its
+    /// [`info`](Self::info).
+    ///
+    /// ## Example
+    /// ```
+    /// use url::Url;
+    /// use web_archive::{PageArchive, Resource, ResourceMap};
+    ///
+    /// let mut resource_map = ResourceMap::new();
+    /// resource_map.insert(
+    ///     Url::parse("http://example.com/style.css").unwrap(),
+    ///     Resource::Css("body { color: red; }".into()),
+    /// );
+    /// let archive = PageArchive::from_parts(
+    ///     "http://example.com/",
+    ///     r#"<link rel="stylesheet" href="style.css">"#,
+    ///     resource_map,
+    /// )
+    /// .unwrap();
+    /// assert!(archive
+    ///     .embed_resources()
+    ///     .contains("<style>body { color: red; }</style>"));
+    /// ```
+    pub fn from_parts<U>(
+        url: U,
+        content: impl Into<String>,
+        resource_map: ResourceMap,
+    ) -> Result<Self, Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let url = url
+            .try_into()
+            .map_err(|e| Error::ParseError(format!("{}", e)))?;
+        Ok(Self {
+            url,
+            content: content.into(),
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        })
+    }
+
     /// Searches `img`, `link`, and `script` tags in the page body and
     /// substitutes in the downloaded content.
     ///
     /// * Images are base-64 encoded and inserted as `data:` URIs
+    /// * Other resources such as icons and media files are also inserted
+    ///   as `data:` URIs
     /// * Stylesheets are inserted as inline `<style>` tags, replacing
     ///   the `<link>` tags they originated from
     /// * Scripts are inserted into their originating `<script>` tags
-    ///   and the original `src` attribute is deleted.
+    ///   and the original `src` attribute is deleted. The `src` is
+    ///   deleted even if the script could not be downloaded; see
+    ///   [`EmbedOptions::keep_unfetched_script_src`] to change this.
     pub fn embed_resources(&self) -> String {
+        self.embed_resources_with(&EmbedOptions::default())
+    }
+
+    /// Embeds the downloaded resources into the page in the same way as
+    /// [`embed_resources`](Self::embed_resources), with the output
+    /// controlled by the provided [`EmbedOptions`].
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{EmbedOptions, PageArchive, ResourceMap};
+    ///
+    /// let archive = PageArchive::from_parts(
+    ///     "http://example.com",
+    ///     "<!doctype html>\n<html><body></body></html>",
+    ///     ResourceMap::new(),
+    /// )
+    /// .unwrap();
+    /// let options = EmbedOptions {
+    ///     preserve_doctype: true,
+    ///     ..Default::default()
+    /// };
+    /// let page = archive.embed_resources_with(&options);
+    /// assert!(page.starts_with("<!doctype html>"));
+    /// ```
+    pub fn embed_resources_with(&self, options: &EmbedOptions) -> String {
+        self.embed(options, None).0
+    }
+
+    /// Embed the resources, returning the page along with any resources
+    /// which were too large to embed and are instead referenced from
+    /// `external_dir`
+    pub(crate) fn embed<'a>(
+        &'a self,
+        options: &EmbedOptions,
+        external_dir: Option<&'a str>,
+    ) -> (String, BTreeMap<String, &'a Resource>) {
+        let mut externals = Externals::new(external_dir);
+        let content = self.embed_into(options, &mut externals);
+        (content, externals.files)
+    }
+
+    /// Embed the resources, adding those which are stored separately to
+    /// `externals`
+    pub(crate) fn embed_into<'a>(
+        &'a self,
+        options: &EmbedOptions,
+        externals: &mut Externals<'a>,
+    ) -> String {
+        #[cfg(feature = "parallel")]
+        if options.url_rewriter.is_none() && !externals.external_only {
+            externals.encoded = self.encode_binary_resources(options);
+        }
+
         // Parse DOM again, and substitute in the downloaded resources
 
         let document = parse_html().one(self.content.as_str());
+        self.embed_document(
+            &document,
+            &self.url,
+            options,
+            externals,
+            &mut vec![self.url.clone()],
+        );
+
+        if let Some(provenance) = options.provenance {
+            insert_provenance(
+                &document,
+                provenance,
+                &self.url,
+                self.fetched_at,
+            );
+        }
+
+        finish_document(&document, &self.url, options);
+
+        // Serialise into a buffer big enough for the page and everything
+        // embedded in it, rather than growing it repeatedly
+        let inlined: usize = self
+            .resource_map
+            .values()
+            .map(Resource::size)
+            .filter(|len| options.inline_size_limit.is_none_or(|l| *len <= l))
+            .sum();
+        let mut output =
+            Vec::with_capacity(self.content.len() + inlined / 3 * 4);
+        document
+            .serialize(&mut output)
+            .expect("writing to a Vec cannot fail");
+        let output =
+            String::from_utf8(output).expect("the serialiser writes UTF-8");
+
+        apply_fidelity(&self.content, output, options)
+    }
+
+    /// Encode the referenced images and other binary resources which
+    /// will be embedded as `data:` URIs ahead of time, on several threads
+    #[cfg(feature = "parallel")]
+    fn encode_binary_resources(
+        &self,
+        options: &EmbedOptions,
+    ) -> HashMap<Url, String> {
+        let resources = self
+            .referenced_urls()
+            .into_iter()
+            .filter_map(|resource_url| {
+                self.resource_map.get_key_value(resource_url.url())
+            })
+            .filter(|(url, resource)| {
+                is_binary(resource)
+                    && !options.encode_as_svg(resource)
+                    && options.oversized_image(url, resource).is_none()
+                    && options
+                        .inline_size_limit
+                        .is_none_or(|limit| resource.size() <= limit)
+            })
+            .collect();
+        encode_data_uris_parallel(resources)
+    }
+
+    /// Substitute the downloaded resources into a parsed document,
+    /// including the documents nested in the `srcdoc` of its iframes and
+    /// those shown in its frames. `ancestors` lists the URLs of the
+    /// documents containing this one, so that a frame which shows itself
+    /// is not embedded forever.
+    pub(crate) fn embed_document<'a>(
+        &'a self,
+        document: &NodeRef,
+        base: &Url,
+        options: &EmbedOptions,
+        externals: &mut Externals<'a>,
+        ancestors: &mut Vec<Url>,
+    ) {
+        if options.svg == SvgEmbedding::Inline {
+            let images: Vec<_> = document.select("img").unwrap().collect();
+            for image in images {
+                self.inline_svg(image.as_node(), base, options, externals);
+            }
+        }
 
         // Replace images
         for element in document.select("img").unwrap() {
@@ -48,42 +415,153 @@ impl PageArchive {
                 let mut attr = data.attributes.borrow_mut();
                 if let Some(u) = attr.get_mut("src") {
                     // has a src attribute
-                    if let Ok(url) = self.url.join(u) {
+                    if let Ok(url) = base.join(u) {
                         // The url parses correctly
-                        if let Some(Resource::Image(image_data)) =
-                            self.resource_map.get(&url)
-                        {
-                            // We have a stored copy of this resource
-                            *u = image_data.to_data_uri();
+                        if let Some(reference) = self.binary_reference(
+                            &url,
+                            |r| r.kind() == Some(ResourceKind::Image),
+                            options,
+                            externals,
+                        ) {
+                            *u = reference;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Replace other resources, e.g. favicons and media
+        for (selector, attribute) in OTHER_RESOURCE_ATTRIBUTES.iter() {
+            for element in document.select(selector).unwrap() {
+                let node = element.as_node();
+                if let NodeData::Element(data) = node.data() {
+                    let mut attr = data.attributes.borrow_mut();
+                    if let Some(u) = attr.get_mut(*attribute) {
+                        if let Ok(url) = base.join(u) {
+                            if let Some(reference) = self.binary_reference(
+                                &url, is_binary, options, externals,
+                            ) {
+                                *u = reference;
+                            }
                         }
                     }
                 }
             }
         }
 
-        // Replace CSS
-        for element in document.select("link").unwrap() {
+        // Rewrite the URLs in inline styles. This is done before the
+        // stylesheets are inlined, which have their URLs rewritten
+        // against their own URL.
+        for element in document.select("style").unwrap() {
+            let css = element.text_contents();
+            let embedded =
+                self.embed_css(&css, base, options, externals, &mut vec![]);
+            if let Cow::Owned(embedded) = embedded {
+                let node = element.as_node();
+                for child in node.children().collect::<Vec<_>>() {
+                    child.detach();
+                }
+                node.append(NodeRef::new_text(embedded));
+            }
+        }
+        for element in document.select("[style]").unwrap() {
+            let mut attr = element.attributes.borrow_mut();
+            if let Some(style) = attr.get_mut("style") {
+                let embedded = self.embed_css(
+                    style,
+                    base,
+                    options,
+                    externals,
+                    &mut vec![],
+                );
+                if let Cow::Owned(embedded) = embedded {
+                    *style = embedded;
+                }
+            }
+        }
+
+        // Replace CSS. The matches are collected first, as detaching a
+        // `<link>` while iterating would skip the element after it.
+        let links: Vec<_> = document.select("link").unwrap().collect();
+        for element in links {
             let node = element.as_node();
 
             // Create a place to store the css data reference so that
             // the horribly nested borrows can be dropped before we
             // replace the `<link>` element with a `<style>`.
-            let mut css_data: Option<&String> = None;
+            let mut css_data = None;
 
             if let NodeData::Element(data) = node.data() {
                 // node is an 'element'
-                let attr = data.attributes.borrow();
-                if Some("stylesheet") == attr.get("rel") {
-                    // rel="stylesheet"
-                    if let Some(u) = attr.get("href") {
+                let mut attr = data.attributes.borrow_mut();
+                let rel = attr.get("rel").unwrap_or_default();
+                if is_stylesheet_rel(rel) {
+                    // rel="stylesheet", or an alternate stylesheet, which
+                    // stays a `<link>` so that it can still be switched on
+                    let switchable = is_switchable_stylesheet(
+                        rel,
+                        attr.contains("disabled"),
+                    );
+                    if let Some(u) = attr.get_mut("href") {
                         // href="style.css"
-                        if let Ok(u) = self.url.join(u) {
+                        if let Ok(url) = base.join(u) {
                             // href parses properly
-                            if let Some(Resource::Css(css)) =
-                                self.resource_map.get(&u)
+                            if let Some(rewritten) = options
+                                .rewrite_url(&url, self.resource_map.get(&url))
                             {
-                                // we have a stored copy of the CSS
-                                css_data = Some(css);
+                                *u = rewritten;
+                                continue;
+                            }
+                            let mut data_uri = None;
+                            let placement = match self.resource_map.get(&url) {
+                                Some(resource @ Resource::Css(css)) => {
+                                    // we have a stored copy of the CSS
+                                    match externals
+                                        .place(&url, resource, options)
+                                    {
+                                        Placement::Inline => {
+                                            let css = self.embed_css(
+                                                css.text(),
+                                                &url,
+                                                options,
+                                                externals,
+                                                &mut vec![url.clone()],
+                                            );
+                                            match options.stylesheets {
+                                                StylesheetEmbedding::Style
+                                                    if !switchable =>
+                                                {
+                                                    css_data = Some(css)
+                                                }
+                                                _ => {
+                                                    data_uri = Some(
+                                                        encode_text_data_uri(
+                                                            resource.mimetype(),
+                                                            &css,
+                                                        ),
+                                                    )
+                                                }
+                                            }
+                                            Placement::Inline
+                                        }
+                                        placement => placement,
+                                    }
+                                }
+                                _ => Placement::Omitted,
+                            };
+                            match placement {
+                                Placement::Inline => {
+                                    if let Some(data_uri) = data_uri {
+                                        *u = data_uri;
+                                    }
+                                }
+                                Placement::External(path) => *u = path,
+                                Placement::Omitted
+                                    if options.absolutize_unembedded =>
+                                {
+                                    *u = url.to_string();
+                                }
+                                Placement::Omitted => {}
                             }
                         }
                     }
@@ -93,20 +571,29 @@ impl PageArchive {
             if let Some(css) = css_data {
                 // CSS data was successfully retrieved by the above steps,
                 // so now:
-                // * locate the `<link>`'s parent
                 // * create a new `<style>` tag containg the CSS
-                // * attach it to the parent
+                // * insert it where the `<link>` was, so that its place in
+                //   the cascade is unchanged
                 // * delete the original `<link>` tag
 
-                if let Some(parent) = node.parent() {
+                if node.parent().is_some() {
                     // This probably won't ever fail, but if it does then
-                    // ignore it
-                    let style = NodeRef::new_element(
-                        QualName::new(None, ns!(html), local_name!("style")),
-                        None,
-                    );
+                    // ignore it. The media query and the title of a
+                    // preferred stylesheet affect how the CSS is applied,
+                    // so carry them over to the `<style>`.
+                    let style = new_html_element("style", &[]);
+                    if let Some(style_element) = style.as_element() {
+                        let attr = element.attributes.borrow();
+                        let mut style_attr =
+                            style_element.attributes.borrow_mut();
+                        for name in STYLE_ATTRIBUTES.iter() {
+                            if let Some(value) = attr.get(*name) {
+                                style_attr.insert(*name, value.to_string());
+                            }
+                        }
+                    }
                     style.append(NodeRef::new_text(css));
-                    parent.append(style);
+                    node.insert_before(style);
 
                     // Remove the original `<link>` tag
                     node.detach();
@@ -120,145 +607,1886 @@ impl PageArchive {
             if let NodeData::Element(data) = node.data() {
                 // node is an 'element'
                 let mut attr = data.attributes.borrow_mut();
+                let mut embedded = false;
                 if let Some(u) = attr.get_mut("src") {
                     // has a src attribute
-                    if let Ok(url) = self.url.join(u) {
+                    if let Ok(url) = base.join(u) {
                         // The url parses correctly
-                        if let Some(Resource::Javascript(script_text)) =
-                            self.resource_map.get(&url)
+                        if let Some(rewritten) = options
+                            .rewrite_url(&url, self.resource_map.get(&url))
+                        {
+                            *u = rewritten;
+                            continue;
+                        }
+                        let placement = match self.resource_map.get(&url) {
+                            Some(resource @ Resource::Javascript(_)) => {
+                                externals.place(&url, resource, options)
+                            }
+                            _ => Placement::Omitted,
+                        };
+                        if let (Placement::Inline, Some(script_text)) =
+                            (&placement, self.resource_map.get_javascript(&url))
                         {
                             // We have a stored copy of this resource
-                            node.append(NodeRef::new_text(script_text));
+                            if options.scripts == ScriptEmbedding::DataUri {
+                                *u = encode_text_data_uri(
+                                    "text/javascript",
+                                    script_text.text(),
+                                );
+                                continue;
+                            }
+                            node.append(NodeRef::new_text(script_text.text()));
+                            embedded = true;
+                        } else if let Placement::External(path) = placement {
+                            // Too large to embed, so point the script at
+                            // the stored copy
+                            *u = path;
+                            continue;
+                        } else if options.keep_unfetched_script_src {
+                            // Point the script at its original location
+                            *u = url.to_string();
+                            continue;
+                        }
+                    }
+                }
+                // Remove the original 'src' attribute - unless the
+                // options say otherwise it doesn't matter whether we
+                // managed to archive it or not because external
+                // resources won't be reachable from the archived page
+                if embedded || !options.keep_unfetched_script_src {
+                    let _ = attr.remove("src");
+                }
+            }
+        }
+
+        // Preloading a module only helps when it is loaded from the same
+        // URL, so the preloads of embedded modules are removed. As with
+        // scripts, the others point at the stored copy or, if the options
+        // say so, at the original location.
+        let preloads: Vec<_> = document
+            .select("link[rel~=modulepreload]")
+            .unwrap()
+            .collect();
+        for element in preloads {
+            let mut attr = element.attributes.borrow_mut();
+            let url = match attr.get("href").and_then(|u| base.join(u).ok()) {
+                Some(url) => url,
+                None => continue,
+            };
+            let resource = self.resource_map.get(&url);
+            let reference = match options.rewrite_url(&url, resource) {
+                Some(rewritten) => Some(rewritten),
+                None => match resource {
+                    Some(resource @ Resource::Javascript(_)) => {
+                        match externals.place(&url, resource, options) {
+                            Placement::External(path) => Some(path),
+                            _ => None,
+                        }
+                    }
+                    _ if options.keep_unfetched_script_src => {
+                        Some(url.to_string())
+                    }
+                    _ => None,
+                },
+            };
+            match reference {
+                Some(reference) => {
+                    attr.insert("href", reference);
+                }
+                None => {
+                    drop(attr);
+                    element.as_node().detach();
+                }
+            }
+        }
+
+        // Rewrite links to point at the original site
+        if options.absolutize_links {
+            for element in document.select("a, area").unwrap() {
+                let mut attr = element.attributes.borrow_mut();
+                if let Some(u) = attr.get_mut("href") {
+                    // Links within the page still work as they are
+                    if u.starts_with('#') {
+                        continue;
+                    }
+                    if let Ok(url) = base.join(u) {
+                        *u = url.to_string();
+                    }
+                }
+            }
+        }
+
+        // Point download links at the attachments in the archive, keeping
+        // the original file name for the download
+        for element in document.select("a[download], area[download]").unwrap() {
+            let mut attr = element.attributes.borrow_mut();
+            let url = match attr.get("href").and_then(|u| base.join(u).ok()) {
+                Some(url) => url,
+                None => continue,
+            };
+            if !self.resource_map.get(&url).is_some_and(is_binary) {
+                continue;
+            }
+            if let Some(reference) =
+                self.binary_reference(&url, is_binary, options, externals)
+            {
+                let name = attr.get("download").unwrap_or_default();
+                if name.is_empty() {
+                    if let Some(file) = url
+                        .path_segments()
+                        .and_then(|mut segments| segments.next_back())
+                        .filter(|file| !file.is_empty())
+                    {
+                        attr.insert("download", file.to_string());
+                    }
+                }
+                attr.insert("href", reference);
+            }
+        }
+
+        for embedder in &options.embedders {
+            embedder.embed(document, base, &self.resource_map);
+        }
+
+        // Embed the resources of documents nested in iframes, which are
+        // resolved against the URL of the document containing them
+        let frames: Vec<_> =
+            document.select("iframe[srcdoc]").unwrap().collect();
+        for frame in &frames {
+            let mut attr = frame.attributes.borrow_mut();
+            if let Some(srcdoc) = attr.get_mut("srcdoc") {
+                let nested = parse_html().one(srcdoc.as_str());
+                self.embed_document(
+                    &nested, base, options, externals, ancestors,
+                );
+                *srcdoc = nested.to_string();
+            }
+        }
+
+        // Embed the documents shown in frames, which take precedence
+        // over their `src` as a `srcdoc` for iframes or a `data:` URI for
+        // frames. Relative URLs within them are resolved against the
+        // frame's URL.
+        for element in document.select("iframe[src], frame[src]").unwrap() {
+            if frames.contains(&element) {
+                continue;
+            }
+            let mut attr = element.attributes.borrow_mut();
+            let url = match attr.get("src").map(|u| base.join(u)) {
+                Some(Ok(url)) => url,
+                _ => continue,
+            };
+            if let Some(rewritten) =
+                options.rewrite_url(&url, self.resource_map.get(&url))
+            {
+                attr.insert("src", rewritten);
+                continue;
+            }
+            let placement = match self.resource_map.get(&url) {
+                // A document which contains itself is left as a link
+                _ if ancestors.contains(&url) => Placement::Omitted,
+                Some(resource @ Resource::Html(html)) => {
+                    match externals.place(&url, resource, options) {
+                        Placement::Inline => {
+                            let nested = parse_html().one(html.text());
+                            ancestors.push(url.clone());
+                            self.embed_document(
+                                &nested, &url, options, externals, ancestors,
+                            );
+                            ancestors.pop();
+                            if &*element.name.local == "iframe" {
+                                attr.remove("src");
+                                attr.insert("srcdoc", nested.to_string());
+                            } else {
+                                attr.insert(
+                                    "src",
+                                    encode_text_data_uri(
+                                        resource.mimetype(),
+                                        &nested.to_string(),
+                                    ),
+                                );
+                            }
+                            continue;
                         }
+                        placement => placement,
                     }
                 }
-                // Remove the original 'src' attribute - doesn't matter
-                // whether we managed to archive it or not because
-                // external resources won't be reachable from the archived
-                // page
-                let _ = attr.remove("src");
+                _ => Placement::Omitted,
+            };
+            match placement {
+                Placement::External(path) => {
+                    attr.insert("src", path);
+                }
+                Placement::Omitted if options.absolutize_unembedded => {
+                    attr.insert("src", url.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The new value of an attribute referencing an image or other binary
+    /// resource, if it should change: the URL from the
+    /// [`url_rewriter`](EmbedOptions::url_rewriter), a reference to the
+    /// stored copy if it is a resource accepted by `accept`, or the
+    /// absolute URL if the options ask for unembedded resources to be
+    /// absolutized
+    pub(crate) fn binary_reference<'a>(
+        &'a self,
+        url: &Url,
+        accept: fn(&Resource) -> bool,
+        options: &EmbedOptions,
+        externals: &mut Externals<'a>,
+    ) -> Option<String> {
+        let resource = self.resource_map.get(url);
+        if let Some(rewritten) = options.rewrite_url(url, resource) {
+            return Some(rewritten);
+        }
+        resource
+            .filter(|r| accept(r))
+            .and_then(|r| externals.reference(url, r, options))
+            .or_else(|| {
+                if options.absolutize_unembedded {
+                    Some(url.to_string())
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Replace an `<img>` showing a downloaded SVG image small enough to
+    /// embed with the image's `<svg>` element, sanitised first. See
+    /// [`SvgEmbedding::Inline`].
+    fn inline_svg<'a>(
+        &'a self,
+        image: &NodeRef,
+        base: &Url,
+        options: &EmbedOptions,
+        externals: &mut Externals<'a>,
+    ) {
+        let element = match image.as_element() {
+            Some(element) => element,
+            None => return,
+        };
+        let url = match element
+            .attributes
+            .borrow()
+            .get("src")
+            .and_then(|u| base.join(u).ok())
+        {
+            Some(url) => url,
+            None => return,
+        };
+        let resource = match self.resource_map.get(&url) {
+            Some(resource)
+                if resource.kind() == Some(ResourceKind::Image)
+                    && options.encode_as_svg(resource) =>
+            {
+                resource
+            }
+            _ => return,
+        };
+        if options.rewrite_url(&url, Some(resource)).is_some()
+            || options.oversized_image(&url, resource).is_some()
+            || !matches!(
+                externals.place(&url, resource, options),
+                Placement::Inline
+            )
+        {
+            return;
+        }
+        let svg = match resource
+            .read_data()
+            .ok()
+            .and_then(|data| String::from_utf8(data.to_vec()).ok())
+            .and_then(|text| parse_html().one(text).select_first("svg").ok())
+        {
+            Some(svg) => svg,
+            None => return,
+        };
+        // Scripts are inert in an <img>, but not once part of the page
+        sanitize(svg.as_node());
+
+        {
+            let attr = element.attributes.borrow();
+            let mut svg_attr = svg.attributes.borrow_mut();
+            for name in ["id", "class", "style", "width", "height"] {
+                if let Some(value) = attr.get(name) {
+                    svg_attr.insert(name, value.to_string());
+                }
+            }
+            match attr.get("alt") {
+                Some("") => {
+                    svg_attr.insert("aria-hidden", "true".to_string());
+                }
+                Some(alt) => {
+                    svg_attr.insert("role", "img".to_string());
+                    svg_attr.insert("aria-label", alt.to_string());
+                }
+                None => {}
             }
         }
+        svg.as_node().detach();
+        image.insert_before(svg.as_node().clone());
+        image.detach();
+    }
+
+    /// Rewrite the URLs referenced by a stylesheet at
+    /// the embedded resources. Imported stylesheets are embedded as
+    /// `data:` URIs, and `imports` lists the URLs of the stylesheets
+    /// importing this one, so that circular imports are not followed.
+    pub(crate) fn embed_css<'a, 'c>(
+        &'a self,
+        css: &'c str,
+        base: &Url,
+        options: &EmbedOptions,
+        externals: &mut Externals<'a>,
+        imports: &mut Vec<Url>,
+    ) -> Cow<'c, str> {
+        rewrite_css_urls(base, css, |url, import| {
+            let rewritten =
+                options.rewrite_url(url, self.resource_map.get(url));
+            if rewritten.is_some() {
+                return rewritten;
+            }
+            let placement = match self.resource_map.get(url) {
+                Some(resource @ Resource::Css(css))
+                    if import && !imports.contains(url) =>
+                {
+                    match externals.place(url, resource, options) {
+                        Placement::Inline => {
+                            imports.push(url.clone());
+                            let css = self.embed_css(
+                                css.text(),
+                                url,
+                                options,
+                                externals,
+                                imports,
+                            );
+                            imports.pop();
+                            return Some(encode_text_data_uri(
+                                resource.mimetype(),
+                                &css,
+                            ));
+                        }
+                        placement => placement,
+                    }
+                }
+                Some(resource) if !import && is_binary(resource) => {
+                    if let Some(reference) =
+                        externals.reference(url, resource, options)
+                    {
+                        return Some(reference);
+                    }
+                    Placement::Omitted
+                }
+                _ => Placement::Omitted,
+            };
+            match placement {
+                Placement::External(path) => Some(path),
+                _ if options.absolutize_unembedded => Some(url.to_string()),
+                _ => None,
+            }
+        })
+    }
 
-        document.to_string()
+    /// Reports the structural changes that parsing and re-serialising
+    /// the page introduces, independently of any embedded resources.
+    /// See [`FidelityReport`] for details.
+    pub fn fidelity_report(&self) -> FidelityReport {
+        let document = parse_html().one(self.content.as_str());
+        fidelity_report(&self.content, &document)
     }
 
-    /// NOT YET IMPLEMENTED
+    /// Remove the resources which the page no longer references, e.g.
+    /// after its [`content`](Self::content) has been filtered or edited,
+    /// along with their [`info`](Self::info). Resources referenced by the
+    /// page's stylesheets are kept. Returns the number of resources
+    /// removed.
     ///
-    /// Write the downloaded resources to disk in the directory specified
-    pub fn write_to_disk<P: AsRef<Path>>(
-        &self,
-        _output_dir: &P,
-    ) -> Result<(), io::Error> {
-        todo!()
+    /// ## Example
+    /// ```
+    /// use url::Url;
+    /// use web_archive::{PageArchive, Resource, ResourceMap};
+    ///
+    /// let mut resource_map = ResourceMap::new();
+    /// resource_map.insert(
+    ///     Url::parse("http://example.com/style.css").unwrap(),
+    ///     Resource::Css("body {}".into()),
+    /// );
+    /// let mut archive = PageArchive::from_parts(
+    ///     "http://example.com",
+    ///     r#"<link rel="stylesheet" href="style.css">"#,
+    ///     resource_map,
+    /// )
+    /// .unwrap();
+    ///
+    /// archive.content = "<p>No styles</p>".to_string();
+    /// assert_eq!(archive.prune_unreferenced(), 1);
+    /// assert!(archive.resource_map.is_empty());
+    /// ```
+    pub fn prune_unreferenced(&mut self) -> usize {
+        let referenced: HashSet<Url> = self
+            .referenced_urls()
+            .into_iter()
+            .map(|resource_url| resource_url.url().clone())
+            .collect();
+        let before = self.resource_map.len();
+        self.resource_map.retain(|url, _| referenced.contains(url));
+        self.info
+            .resources
+            .retain(|url, _| referenced.contains(url));
+        before - self.resource_map.len()
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::*;
-    use bytes::Bytes;
+    /// The resources which the page references but which have no
+    /// downloaded copy in the [`resource_map`](Self::resource_map),
+    /// either because they were never fetched or because they failed to
+    /// download. References in the page's stylesheets are included.
+    ///
+    /// ## Example
+    /// ```
+    /// use url::Url;
+    /// use web_archive::{PageArchive, ResourceMap, ResourceUrl};
+    ///
+    /// let archive = PageArchive::from_parts(
+    ///     "http://example.com",
+    ///     r#"<img src="logo.png">"#,
+    ///     ResourceMap::new(),
+    /// )
+    /// .unwrap();
+    /// assert_eq!(
+    ///     archive.missing_resources(),
+    ///     vec![ResourceUrl::Image(
+    ///         Url::parse("http://example.com/logo.png").unwrap()
+    ///     )]
+    /// );
+    /// ```
+    pub fn missing_resources(&self) -> Vec<ResourceUrl> {
+        self.referenced_urls()
+            .into_iter()
+            .filter(|resource_url| {
+                !is_downloaded(&self.resource_map, resource_url.url())
+            })
+            .collect()
+    }
 
-    #[test]
-    fn test_single_css() {
-        let content = r#"
-		<html>
-			<head>
-				<link rel="stylesheet" href="style.css" />
-			</head>
-			<body></body>
-		</html>
-		"#
-        .to_string();
-        let url = Url::parse("http://example.com").unwrap();
-        let mut resource_map = ResourceMap::new();
-        resource_map.insert(
-            url.join("style.css").unwrap(),
-            Resource::Css(
-                r#"
-					body { background-color: blue; }
-				"#
-                .to_string(),
-            ),
-        );
-        let archive = PageArchive {
-            url,
-            content,
-            resource_map,
-        };
+    /// Check that every resource the page references has been
+    /// downloaded, returning [`Error::VerificationError`] listing the
+    /// [`missing_resources`](Self::missing_resources) if not.
+    ///
+    /// This is separate from `verify`, which checks a signature over the
+    /// archive when the `signing` feature is enabled.
+    pub fn verify_completeness(&self) -> Result<(), Error> {
+        let missing = self.missing_resources();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let urls: Vec<_> = missing
+            .iter()
+            .map(|resource_url| resource_url.url().as_str())
+            .collect();
+        Err(Error::VerificationError(format!(
+            "{} referenced resource(s) missing from the archive: {}",
+            missing.len(),
+            urls.join(", ")
+        )))
+    }
 
-        let output = archive.embed_resources();
-        assert_eq!(
-            output.replace("\t", "").replace("\n", ""),
-            r#"
-		<html>
-			<head>
-				<style>
-					body { background-color: blue; }
-				</style>
-			</head>
-			<body></body>
-		</html>
-		"#
-            .to_string()
-            .replace("\t", "")
-            .replace("\n", "")
-        );
+    /// The resources referenced by the page, including those referenced
+    /// by the stored copies of its stylesheets
+    fn referenced_urls(&self) -> Vec<ResourceUrl> {
+        let mut queue =
+            ResourceQueue::new(parse_resource_urls(&self.url, &self.content));
+        let mut referenced = Vec::new();
+        while let Some(resource_url) = queue.next() {
+            if let Some(resource) = self.resource_map.get(resource_url.url()) {
+                queue.discover(resource_url.url(), resource, &[]);
+            }
+            referenced.push(resource_url);
+        }
+        referenced
     }
 
-    #[test]
-    fn test_single_image() {
-        let content = r#"
-		<html>
-			<head></head>
-			<body>
-				<img src="rustacean.png" />
-			</body>
-		</html>
-		"#
-        .to_string();
-        let url = Url::parse("http://example.com").unwrap();
-        let mut resource_map = ResourceMap::new();
-        resource_map.insert(
-            url.join("rustacean.png").unwrap(),
-            Resource::Image(ImageResource {
-                data: Bytes::from(
-                    include_bytes!(
-                        "../dynamic_tests/resources/rustacean-flat-happy.png"
-                    )
-                    .to_vec(),
-                ),
-                mimetype: "image/png".to_string(),
-            }),
-        );
-        let archive = PageArchive {
-            url,
-            content,
-            resource_map,
-        };
+    /// Try again to download the resources the archive is missing with
+    /// the session, as with [`ArchiveSession::refetch_missing`], returning
+    /// the number of resources added
+    pub async fn refetch_missing(
+        &mut self,
+        session: &ArchiveSession,
+    ) -> Result<usize, Error> {
+        session.refetch_missing(self).await
+    }
 
-        let output = archive.embed_resources();
-        println!("{}", output);
-        // base64 < dynamic_tests/resources/rustacean-flat-happy.png
-        assert!(output.contains(
-            r#"<img src="data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAB"#
-        ));
-        // chunk from middle of image
-        assert!(output.contains("gfuBxu3QDwEsoDXx5J5KCU+2/DF2JAQAoDHV"))
+    /// Try again to download the resources the archive is missing with a
+    /// blocking session, as with
+    /// [`blocking::ArchiveSession::refetch_missing`](crate::blocking::ArchiveSession::refetch_missing),
+    /// returning the number of resources added
+    ///
+    /// Requires the `blocking` feature.
+    #[cfg(feature = "blocking")]
+    pub fn refetch_missing_blocking(
+        &mut self,
+        session: &crate::blocking::ArchiveSession,
+    ) -> Result<usize, Error> {
+        session.refetch_missing(self)
     }
 
-    #[test]
-    fn test_single_js() {
-        let content = r#"
-		<html>
-			<head>
-				<script src="script.js"></script>
-			</head>
-			<body></body>
-		</html>
-		"#
-        .to_string();
+    /// Merge the resources of another archive into this one, e.g. those
+    /// fetched by a second pass or by a different tool, along with their
+    /// [`info`](Self::info). The page content of `other` is discarded.
+    ///
+    /// Resources present in only one of the archives are kept, and the
+    /// `policy` decides between two different resources for the same
+    /// URL. With [`ConflictPolicy::Error`], nothing is merged if there
+    /// are any conflicts.
+    ///
+    /// ## Example
+    /// ```
+    /// use url::Url;
+    /// use web_archive::{ConflictPolicy, PageArchive, Resource, ResourceMap};
+    ///
+    /// let url = Url::parse("http://example.com/style.css").unwrap();
+    /// let mut archive = PageArchive::from_parts(
+    ///     "http://example.com",
+    ///     r#"<link rel="stylesheet" href="style.css">"#,
+    ///     ResourceMap::new(),
+    /// )
+    /// .unwrap();
+    /// let mut resource_map = ResourceMap::new();
+    /// resource_map.insert(url.clone(), Resource::Css("a {}".into()));
+    /// let second_pass =
+    ///     PageArchive::from_parts("http://example.com", "", resource_map)
+    ///         .unwrap();
+    ///
+    /// archive.merge(second_pass, ConflictPolicy::KeepExisting).unwrap();
+    /// assert!(archive.resource_map.get_css(&url).is_some());
+    /// ```
+    pub fn merge(
+        &mut self,
+        other: PageArchive,
+        policy: ConflictPolicy,
+    ) -> Result<(), Error> {
+        if policy == ConflictPolicy::Error {
+            let conflict = other.resource_map.iter().find(|(url, resource)| {
+                self.resource_map
+                    .get(url)
+                    .is_some_and(|existing| existing != *resource)
+            });
+            if let Some((url, _)) = conflict {
+                return Err(Error::MergeConflict(url.clone()));
+            }
+        }
+
+        let mut other_info = other.info.resources;
+        for (url, resource) in other.resource_map {
+            let replace = match self.resource_map.get(&url) {
+                None => true,
+                Some(existing) => match policy {
+                    ConflictPolicy::KeepExisting | ConflictPolicy::Error => {
+                        false
+                    }
+                    ConflictPolicy::Replace => true,
+                    ConflictPolicy::PreferSuccessful => {
+                        existing.kind().is_none() && resource.kind().is_some()
+                    }
+                },
+            };
+            if replace {
+                match other_info.remove(&url) {
+                    Some(info) => self.info.resources.insert(url.clone(), info),
+                    None => self.info.resources.remove(&url),
+                };
+                self.resource_map.insert(url, resource);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the page with its resources embedded to a single HTML file.
+    ///
+    /// If [`EmbedOptions::inline_size_limit`] is set then resources
+    /// larger than the limit are written to a directory next to the
+    /// file, named after it, and referenced by relative paths: for
+    /// `page.html`, a large image would be written to
+    /// `page_files/<sha256>.png`.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use web_archive::{blocking, EmbedOptions};
+    ///
+    /// let archive =
+    ///     blocking::archive("http://example.com", Default::default()).unwrap();
+    /// let options = EmbedOptions {
+    ///     inline_size_limit: Some(64 * 1024),
+    ///     ..Default::default()
+    /// };
+    /// archive.write_embedded(&"example.html", &options).unwrap();
+    /// ```
+    pub fn write_embedded<P: AsRef<Path>>(
+        &self,
+        output_file: &P,
+        options: &EmbedOptions,
+    ) -> Result<(), Error> {
+        let output_file = output_file.as_ref();
+        let stem = output_file
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "page".to_string());
+        let dir_name = format!("{}_files", stem);
+        let reference_dir = encode_path_segment(&dir_name);
+
+        let (content, externals) = self.embed(options, Some(&reference_dir));
+
+        let parent = output_file.parent().unwrap_or_else(|| Path::new(""));
+        if !externals.is_empty() {
+            let dir = parent.join(&dir_name);
+            fs::create_dir_all(&dir)?;
+            for (name, resource) in externals {
+                write_resource_file(&dir.join(name), resource)?;
+            }
+        } else if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(output_file, content)?;
+        Ok(())
+    }
+
+    /// Sign the archive with an Ed25519 key. The signature covers the
+    /// page's URL and fetch time and the SHA-256 hashes of the page and
+    /// every resource, so any later modification of the archive can be
+    /// detected with [`verify`](Self::verify).
+    ///
+    /// Requires the `signing` feature. See the
+    /// [`signature`](crate::signature) module for signing archives
+    /// written to disk.
+    #[cfg(feature = "signing")]
+    pub fn sign(&self, key: &SigningKey) -> Signature {
+        signature::sign(&signature::archive_message(self), key)
+    }
+
+    /// Check that a [`sign`](Self::sign)ature of the archive is valid,
+    /// returning [`Error::VerificationError`] if it is not.
+    ///
+    /// Requires the `signing` feature.
+    #[cfg(feature = "signing")]
+    pub fn verify(
+        &self,
+        signature: &Signature,
+        key: &VerifyingKey,
+    ) -> Result<(), Error> {
+        signature::verify(&signature::archive_message(self), signature, key)
+    }
+
+    /// Write the page and the downloaded resources to disk in the
+    /// directory specified, using a [`FileSystemSink`] with the default
+    /// [`DiskOptions`]
+    pub fn write_to_disk<P: AsRef<Path>>(
+        &self,
+        output_dir: &P,
+    ) -> Result<(), Error> {
+        self.write_to_disk_with(output_dir, DiskOptions::default())
+    }
+
+    /// Write the page and the downloaded resources to disk in the
+    /// directory specified, with the layout controlled by the provided
+    /// [`DiskOptions`]
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use web_archive::blocking;
+    /// use web_archive::sink::{DiskLayout, DiskOptions};
+    ///
+    /// let archive =
+    ///     blocking::archive("http://example.com", Default::default()).unwrap();
+    /// let options = DiskOptions {
+    ///     layout: DiskLayout::Flat,
+    ///     ..Default::default()
+    /// };
+    /// archive.write_to_disk_with(&"example_archive", options).unwrap();
+    /// ```
+    pub fn write_to_disk_with<P: AsRef<Path>>(
+        &self,
+        output_dir: &P,
+        options: DiskOptions,
+    ) -> Result<(), Error> {
+        self.write_to_sink(&mut FileSystemSink::with_options(
+            output_dir, options,
+        ))
+    }
+
+    /// Async version of [`write_to_disk`](Self::write_to_disk). Files
+    /// are written on a separate thread so that large archives can be
+    /// written without blocking the async runtime, which may be any
+    /// runtime.
+    pub async fn write_to_disk_async<P: AsRef<Path>>(
+        &self,
+        output_dir: &P,
+    ) -> Result<(), Error> {
+        self.write_to_disk_async_with(output_dir, DiskOptions::default())
+            .await
+    }
+
+    /// Async version of [`write_to_disk_with`](Self::write_to_disk_with)
+    pub async fn write_to_disk_async_with<P: AsRef<Path>>(
+        &self,
+        output_dir: &P,
+        options: DiskOptions,
+    ) -> Result<(), Error> {
+        let mut sink = FileSystemSink::with_options(output_dir, options);
+
+        // The files are planned here and written together on one thread
+        let path = sink.plan_page(self, self.content.as_bytes())?;
+        let mut files =
+            vec![(path, PlannedFile::Data(Bytes::from(self.content.clone())))];
+        if let Some((path, raw)) = sink.plan_raw_page(self) {
+            files.push((path, PlannedFile::Data(raw)));
+        }
+        for (url, resource) in self.sorted_resources() {
+            let path = sink.plan_resource(url, resource);
+            files.push((path, PlannedFile::Resource(resource.clone())));
+        }
+        for (path, data) in sink.plan_finalize()? {
+            files.push((path, PlannedFile::Data(data.into())));
+        }
+        sink.write_all_async(files).await
+    }
+
+    /// Write the page and the downloaded resources to the writer as a
+    /// gzip-compressed tarball, using a [`TarGzSink`] with the same
+    /// files as [`write_to_disk`](Self::write_to_disk) would write. This
+    /// is convenient for sending an archive elsewhere or storing it as a
+    /// single object.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::fs::File;
+    /// use web_archive::blocking;
+    ///
+    /// let archive =
+    ///     blocking::archive("http://example.com", Default::default()).unwrap();
+    /// let file = File::create("example.tar.gz").unwrap();
+    /// archive.write_tar_gz(file).unwrap();
+    /// ```
+    pub fn write_tar_gz<W: Write>(&self, writer: W) -> Result<(), Error> {
+        self.write_tar_gz_with(writer, DiskLayout::default())
+    }
+
+    /// Write the page and the downloaded resources to the writer as a
+    /// gzip-compressed tarball, with the given layout of the files
+    pub fn write_tar_gz_with<W: Write>(
+        &self,
+        writer: W,
+        layout: DiskLayout,
+    ) -> Result<(), Error> {
+        self.write_to_sink(&mut TarGzSink::with_layout(writer, layout))
+    }
+
+    /// Store the archive losslessly in a single compact file, which can
+    /// be reopened with [`load`](Self::load). See the
+    /// [`container`] module for the format.
+    ///
+    /// Requires the `container` feature.
+    #[cfg(feature = "container")]
+    pub fn save<P: AsRef<Path>>(&self, path: &P) -> Result<(), Error> {
+        container::save(self, path.as_ref())
+    }
+
+    /// Reopen an archive stored with [`save`](Self::save)
+    ///
+    /// Requires the `container` feature.
+    #[cfg(feature = "container")]
+    pub fn load<P: AsRef<Path>>(path: &P) -> Result<Self, Error> {
+        let file = fs::File::open(path)?;
+        container::read_container(std::io::BufReader::new(file))
+    }
+
+    /// Reconstruct an archive previously written with
+    /// [`write_to_disk`](Self::write_to_disk), using the
+    /// [`Manifest`](crate::manifest::Manifest) in the directory to locate
+    /// the page and its resources. This allows a saved archive to be
+    /// re-embedded or exported to another format without fetching it
+    /// again.
+    pub fn read_from_disk<P: AsRef<Path>>(
+        input_dir: &P,
+    ) -> Result<Self, Error> {
+        let dir = input_dir.as_ref();
+        let manifest: Manifest =
+            serde_json::from_slice(&fs::read(dir.join(MANIFEST_FILE))?)
+                .map_err(|e| Error::ParseError(e.to_string()))?;
+
+        Self::from_manifest(manifest, |path| {
+            Ok(Bytes::from(fs::read(manifest_file_path(dir, path)?)?))
+        })
+    }
+
+    /// Reconstruct an archive from its [`Manifest`], reading the data of
+    /// each file with `read`
+    pub(crate) fn from_manifest<F>(
+        manifest: Manifest,
+        read: F,
+    ) -> Result<Self, Error>
+    where
+        F: Fn(&Path) -> Result<Bytes, Error>,
+    {
+        let content = String::from_utf8(read(&manifest.page.path)?.to_vec())?;
+        let raw_content = manifest
+            .raw_page
+            .as_ref()
+            .map(|entry| read(&entry.path))
+            .transpose()?;
+        let fetched_at = humantime::parse_rfc3339(&manifest.fetched_at)
+            .map_err(|e| Error::ParseError(e.to_string()))?;
+
+        let mut info = ArchiveInfo {
+            page: manifest.page.info()?,
+            resources: HashMap::new(),
+        };
+
+        let mut resource_map = ResourceMap::new();
+        for entry in manifest.resources {
+            info.resources.insert(entry.url.clone(), entry.info()?);
+            let data = read(&entry.path)?;
+            let encoding = entry.encoding.as_deref();
+            let resource = match entry.kind {
+                EntryKind::Javascript => Resource::Javascript(
+                    TextResource::decode_with_label(data, encoding),
+                ),
+                EntryKind::Css => Resource::Css(
+                    TextResource::decode_with_label(data, encoding),
+                ),
+                EntryKind::Html => Resource::Html(
+                    TextResource::decode_with_label(data, encoding),
+                ),
+                EntryKind::Image => Resource::Image(ImageResource {
+                    data,
+                    mimetype: entry.mimetype,
+                }),
+                EntryKind::Other | EntryKind::Page => Resource::Other {
+                    data,
+                    mimetype: entry.mimetype,
+                },
+                EntryKind::Failed => Resource::Failed {
+                    status: info.resources[&entry.url].status.ok_or_else(
+                        || {
+                            Error::ParseError(format!(
+                                "Missing status for failed resource {}",
+                                entry.url
+                            ))
+                        },
+                    )?,
+                    data,
+                    mimetype: entry.mimetype,
+                },
+            };
+            resource_map.insert(entry.url, resource);
+        }
+
+        Ok(Self {
+            url: manifest.page.url,
+            content,
+            raw_content,
+            resource_map,
+            fetched_at,
+            info,
+            metadata_cache: Default::default(),
+        })
+    }
+
+    /// Resolve the URLs of the page's links, classified as internal or
+    /// external to the page's site. See [`parse_links`] for details.
+    pub fn links(&self) -> Vec<Link> {
+        parse_links(&self.url, &self.content)
+    }
+
+    /// Parse the page's title, description, canonical URL, and Open
+    /// Graph and Twitter card metadata. The page is only parsed the
+    /// first time its metadata is needed, or after its
+    /// [`content`](Self::content) or [`url`](Self::url) have changed.
+    pub fn metadata(&self) -> PageMetadata {
+        (*self.metadata_cache.get(&self.content, &self.url)).clone()
+    }
+
+    /// The text of the page's `<title>`, with its whitespace normalised,
+    /// e.g. for naming the files an archive is written to
+    ///
+    /// ## Example
+    /// ```
+    /// use web_archive::{PageArchive, ResourceMap};
+    ///
+    /// let archive = PageArchive::from_parts(
+    ///     "http://example.com/",
+    ///     "<title> Ferris\n the crab </title>",
+    ///     ResourceMap::new(),
+    /// )
+    /// .unwrap();
+    /// assert_eq!(archive.title().as_deref(), Some("Ferris the crab"));
+    /// ```
+    pub fn title(&self) -> Option<String> {
+        self.metadata_cache
+            .get(&self.content, &self.url)
+            .title
+            .clone()
+    }
+
+    /// The page's `<meta name="description">`
+    pub fn description(&self) -> Option<String> {
+        self.metadata_cache
+            .get(&self.content, &self.url)
+            .description
+            .clone()
+    }
+
+    /// The page's `<link rel="canonical">`, resolved against its URL
+    pub fn canonical_url(&self) -> Option<Url> {
+        self.metadata_cache
+            .get(&self.content, &self.url)
+            .canonical_url
+            .clone()
+    }
+
+    /// The language of the page, from `<html lang>` or, if that is not
+    /// set, detected from its text, which is only attempted for a few
+    /// common languages and needs a paragraph or so of text. Useful for
+    /// cataloguing large collections of archives.
+    pub fn language(&self) -> Option<String> {
+        self.metadata_cache
+            .get(&self.content, &self.url)
+            .language
+            .clone()
+    }
+
+    /// The visible text of the page, without scripts, styles, or the
+    /// contents of `<head>`, and with the text of each block element such
+    /// as a paragraph or list item on a line of its own. Useful for
+    /// indexing archives for full-text search.
+    pub fn extract_text(&self) -> String {
+        crate::text::extract(&self.content)
+    }
+
+    /// Extract the main content of the page for offline reading,
+    /// discarding scripts, styling, navigation, and other clutter. Images
+    /// in the article are kept, embedded as data URIs where they were
+    /// downloaded, and links are rewritten to absolute URLs.
+    ///
+    /// See the [`readability`](crate::readability) module for details of
+    /// how the content is identified.
+    pub fn extract_article(&self) -> Article {
+        let options = EmbedOptions {
+            absolutize_unembedded: true,
+            absolutize_links: true,
+            ..Default::default()
+        };
+        readability::extract(&self.embed_resources_with(&options))
+    }
+
+    /// Render the page with its resources embedded to PDF, for when a
+    /// fixed-layout record of the page is needed.
+    ///
+    /// Rendering is done by a headless Chromium-based browser, which must
+    /// be installed. The `chromium`, `chromium-browser`, `google-chrome`,
+    /// and `chrome` executables are tried in turn, or a specific browser
+    /// can be given with the `WEB_ARCHIVE_BROWSER` environment variable.
+    ///
+    /// Requires the `pdf` feature.
+    #[cfg(feature = "pdf")]
+    pub fn to_pdf(&self) -> Result<Vec<u8>, Error> {
+        crate::pdf::render(&self.embed_resources())
+    }
+
+    /// Write the page and the downloaded resources to the given
+    /// [`ArchiveSink`]. Resources are written in order of their URLs.
+    pub fn write_to_sink<S: ArchiveSink + ?Sized>(
+        &self,
+        sink: &mut S,
+    ) -> Result<(), Error> {
+        sink.write_page(self, self.content.as_bytes())?;
+
+        for (url, resource) in self.sorted_resources() {
+            sink.write_resource(url, resource)?;
+        }
+
+        sink.finalize()
+    }
+
+    /// The resources in the resource map, in order of their URLs
+    pub(crate) fn sorted_resources(&self) -> Vec<(&Url, &Resource)> {
+        let mut resources: Vec<_> = self.resource_map.iter().collect();
+        resources.sort_by(|a, b| a.0.cmp(b.0));
+        resources
+    }
+}
+
+/// Where a resource ends up in the embedded output
+pub(crate) enum Placement {
+    /// Embedded in the page
+    Inline,
+    /// Stored separately and referenced by this relative path
+    External(String),
+    /// Not included in the output
+    Omitted,
+}
+
+/// Resources which are too large to embed, keyed by the file name they
+/// are stored under
+pub(crate) struct Externals<'a> {
+    dir: Option<&'a str>,
+    /// Whether every resource is stored separately, whatever its size
+    pub(crate) external_only: bool,
+    pub(crate) files: BTreeMap<String, &'a Resource>,
+    /// URL of each of the stored resources
+    pub(crate) urls: HashMap<String, Url>,
+    /// `data:` URIs which have already been encoded, each of which is
+    /// used by the first reference to its resource
+    encoded: HashMap<Url, String>,
+}
+
+impl<'a> Externals<'a> {
+    /// Nothing stored yet, with large resources referenced from `dir` or
+    /// left out if there is no directory
+    pub(crate) fn new(dir: Option<&'a str>) -> Self {
+        Self {
+            dir,
+            external_only: false,
+            files: BTreeMap::new(),
+            urls: HashMap::new(),
+            encoded: HashMap::new(),
+        }
+    }
+
+    /// Nothing stored yet, with every resource referenced from `dir`
+    /// rather than embedded
+    pub(crate) fn external_only(dir: &'a str) -> Self {
+        Self {
+            external_only: true,
+            ..Self::new(Some(dir))
+        }
+    }
+
+    /// Decide where a resource should be placed according to the size
+    /// limit in the options
+    pub(crate) fn place(
+        &mut self,
+        url: &Url,
+        resource: &'a Resource,
+        options: &EmbedOptions,
+    ) -> Placement {
+        let inline = match options.inline_size_limit {
+            _ if self.external_only => false,
+            Some(limit) => resource.size() <= limit,
+            None => true,
+        };
+        match (inline, self.dir) {
+            (true, _) => Placement::Inline,
+            (false, Some(dir)) => {
+                let name = content_addressed_name(url, resource);
+                let path = format!("{}/{}", dir, name);
+                self.urls.insert(name.clone(), url.clone());
+                self.files.insert(name, resource);
+                Placement::External(path)
+            }
+            (false, None) => Placement::Omitted,
+        }
+    }
+
+    /// The attribute value referencing a binary resource: either a
+    /// `data:` URI or a relative path
+    fn reference(
+        &mut self,
+        url: &Url,
+        resource: &'a Resource,
+        options: &EmbedOptions,
+    ) -> Option<String> {
+        match self.place(url, resource, options) {
+            Placement::Inline => options
+                .oversized_image(url, resource)
+                .or_else(|| self.inline_reference(url, resource, options)),
+            Placement::External(path) => Some(path),
+            Placement::Omitted => None,
+        }
+    }
+
+    /// The `data:` URI of a resource placed inline
+    fn inline_reference(
+        &mut self,
+        url: &Url,
+        resource: &'a Resource,
+        options: &EmbedOptions,
+    ) -> Option<String> {
+        if options.encode_as_svg(resource) {
+            return resource
+                .read_data()
+                .ok()
+                .and_then(|data| encode_svg_data_uri(&data))
+                .or_else(|| encode_resource_data_uri(resource));
+        }
+        // A spilled resource which cannot be read back is left out
+        self.encoded
+            .remove(url)
+            .or_else(|| encode_resource_data_uri(resource))
+    }
+}
+
+/// Percent-encode a path segment for use in a relative URL
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_single_css() {
+        let content = r#"
+		<html>
+			<head>
+				<link rel="stylesheet" href="style.css" />
+			</head>
+			<body></body>
+		</html>
+		"#
+        .to_string();
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("style.css").unwrap(),
+            Resource::Css(
+                r#"
+					body { background-color: blue; }
+				"#
+                .into(),
+            ),
+        );
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let output = archive.embed_resources();
+        assert_eq!(
+            output.replace("\t", "").replace("\n", ""),
+            r#"
+		<html>
+			<head>
+				<style>
+					body { background-color: blue; }
+				</style>
+			</head>
+			<body></body>
+		</html>
+		"#
+            .to_string()
+            .replace("\t", "")
+            .replace("\n", "")
+        );
+    }
+
+    #[test]
+    fn test_css_media_and_title() {
+        let content = r#"<html><head>
+<link rel="stylesheet" href="print.css" media="print">
+<link rel="stylesheet" href="dark.css" title="Dark">
+</head><body></body></html>"#;
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("print.css").unwrap(),
+            Resource::Css("a{}".into()),
+        );
+        resource_map
+            .insert(url.join("dark.css").unwrap(), Resource::Css("b{}".into()));
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let output = archive.embed_resources();
+        assert!(output.contains(r#"<style media="print">a{}</style>"#));
+        assert!(output.contains(r#"<style title="Dark">b{}</style>"#));
+    }
+
+    #[test]
+    fn test_alternate_stylesheets() {
+        let content = r#"<html><head>
+<link rel="stylesheet" href="light.css" title="Light">
+<link rel="Alternate StyleSheet" href="dark.css" title="Dark">
+<link rel="stylesheet" href="print.css" disabled>
+</head><body></body></html>"#;
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        for name in ["light.css", "dark.css", "print.css"] {
+            resource_map
+                .insert(url.join(name).unwrap(), Resource::Css("a{}".into()));
+        }
+        assert_eq!(parse_resource_urls(&url, content).len(), 3);
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        // Only the preferred stylesheet becomes a `<style>`, so that the
+        // others can still be switched on by their `<link>`
+        let output = archive.embed_resources();
+        let data_uri = "data:text/css;charset=utf-8;base64,YXt9";
+        assert!(output.contains(r#"<style title="Light">a{}</style>"#));
+        assert!(output.contains(&format!(
+            r#"<link href="{}" rel="Alternate StyleSheet" title="Dark">"#,
+            data_uri
+        )));
+        assert!(output.contains(&format!(
+            r#"<link disabled="" href="{}" rel="stylesheet">"#,
+            data_uri
+        )));
+    }
+
+    #[test]
+    fn test_css_data_uri() {
+        let content = r#"<html><head>
+<style>a{color:red}</style>
+<link rel="stylesheet" href="style.css" media="screen">
+</head><body></body></html>"#;
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("style.css").unwrap(),
+            Resource::Css("a{}".into()),
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let options = EmbedOptions {
+            stylesheets: StylesheetEmbedding::DataUri,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(
+            r#"<style>a{color:red}</style>
+<link href="data:text/css;charset=utf-8;base64,YXt9" media="screen" rel="stylesheet">"#
+        ));
+    }
+
+    #[test]
+    fn test_script_data_uri() {
+        let content =
+            r#"<html><head><script src="app.js" defer></script></head></html>"#;
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("app.js").unwrap(),
+            Resource::Javascript("go()".into()),
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let options = EmbedOptions {
+            scripts: ScriptEmbedding::DataUri,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(
+            r#"<script defer="" src="data:text/javascript;charset=utf-8;base64,Z28oKQ=="></script>"#
+        ));
+    }
+
+    #[test]
+    fn test_css_position() {
+        let content = r#"<html><head><link rel="stylesheet" href="a.css">
+<style>b{}</style><link rel="stylesheet" href="c.css"><style>d{}</style>
+</head><body></body></html>"#;
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map
+            .insert(url.join("a.css").unwrap(), Resource::Css("a{}".into()));
+        resource_map
+            .insert(url.join("c.css").unwrap(), Resource::Css("c{}".into()));
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let output = archive.embed_resources();
+        assert!(output.contains(
+            "<head><style>a{}</style>\n<style>b{}</style><style>c{}</style>\
+             <style>d{}</style>"
+        ));
+    }
+
+    #[test]
+    fn test_iframe_srcdoc() {
+        let content = r#"<html><body><iframe srcdoc="<img src=&quot;a.png&quot;><iframe srcdoc='<script src=b.js></script>'></iframe>"></iframe></body></html>"#;
+        let url = Url::parse("http://example.com/page/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("a.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"png"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        resource_map.insert(
+            url.join("b.js").unwrap(),
+            Resource::Javascript("go()".into()),
+        );
+        assert_eq!(
+            parse_resource_urls(&url, content),
+            vec![
+                ResourceUrl::Image(url.join("a.png").unwrap()),
+                ResourceUrl::Javascript(url.join("b.js").unwrap()),
+            ]
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let output = archive.embed_resources();
+        let document = parse_html().one(output.as_str());
+        let frame = document.select_first("iframe").unwrap();
+        let srcdoc =
+            frame.attributes.borrow().get("srcdoc").unwrap().to_string();
+        assert!(srcdoc.contains(r#"<img src="data:image/png;base64,cG5n">"#));
+
+        let nested = parse_html().one(srcdoc.as_str());
+        let frame = nested.select_first("iframe").unwrap();
+        let srcdoc =
+            frame.attributes.borrow().get("srcdoc").unwrap().to_string();
+        assert!(srcdoc.contains("<script>go()</script>"));
+    }
+
+    #[test]
+    fn test_frame_documents() {
+        let content = r#"<html><body><iframe src="frames/a.html"></iframe>
+<iframe src="missing.html"></iframe></body></html>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("frames/a.html").unwrap(),
+            Resource::Html(
+                r#"<link rel="stylesheet" href="a.css"><iframe src="a.html">"#
+                    .into(),
+            ),
+        );
+        resource_map.insert(
+            url.join("frames/a.css").unwrap(),
+            Resource::Css("a{}".into()),
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let output = archive.embed_resources();
+        let document = parse_html().one(output.as_str());
+        let frames: Vec<_> = document.select("iframe").unwrap().collect();
+        let attr = frames[0].attributes.borrow();
+        assert!(attr.get("src").is_none());
+        let srcdoc = attr.get("srcdoc").unwrap();
+        assert!(srcdoc.contains("<style>a{}</style>"));
+        // The frame containing itself is not embedded again
+        assert!(srcdoc.contains(r#"<iframe src="a.html">"#));
+        assert_eq!(
+            frames[1].attributes.borrow().get("src"),
+            Some("missing.html")
+        );
+    }
+
+    #[test]
+    fn test_frameset() {
+        let content =
+            r#"<html><frameset><frame src="menu.html"></frameset></html>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("menu.html").unwrap(),
+            Resource::Html("<p>menu</p>".into()),
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let output = archive.embed_resources();
+        assert!(output
+            .contains(r#"<frame src="data:text/html;charset=utf-8;base64,"#));
+    }
+
+    #[test]
+    fn test_css_urls() {
+        let content = r#"<html><head>
+<style>body { background: url(img/bg.png) }</style>
+<link rel="stylesheet" href="css/main.css">
+</head><body><p style="background: url('img/bg.png')"></p></body></html>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let image = |data: &'static [u8]| {
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(data),
+                mimetype: "image/png".to_string(),
+            })
+        };
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(url.join("img/bg.png").unwrap(), image(b"bg"));
+        resource_map.insert(
+            url.join("css/main.css").unwrap(),
+            Resource::Css(
+                "@import 'theme.css'; a { background: url(../img/a.png) }"
+                    .into(),
+            ),
+        );
+        resource_map.insert(
+            url.join("css/theme.css").unwrap(),
+            Resource::Css("@import 'main.css'; b { c: url(b.png) }".into()),
+        );
+        resource_map.insert(url.join("css/b.png").unwrap(), image(b"b"));
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let output = archive.embed_resources();
+        let bg = r#"url(&quot;data:image/png;base64,Ymc=&quot;)"#;
+        assert!(output.contains(&format!(r#"style="background: {}""#, bg)));
+        assert!(output.contains(
+            r#"<style>body { background: url("data:image/png;base64,Ymc=") }</style>"#
+        ));
+        // The missing image is left as it was
+        assert!(output.contains("a { background: url(../img/a.png) }"));
+
+        let document = parse_html().one(output.as_str());
+        let styles: Vec<_> = document
+            .select("style")
+            .unwrap()
+            .map(|style| style.text_contents())
+            .collect();
+        let theme = styles[1]
+            .strip_prefix(r#"@import "data:text/css;charset=utf-8;base64,"#)
+            .and_then(|css| css.split('"').next())
+            .unwrap();
+        let theme = String::from_utf8(base64::decode(theme).unwrap()).unwrap();
+        // The circular import is not followed
+        assert_eq!(
+            theme,
+            r#"@import 'main.css'; b { c: url("data:image/png;base64,Yg==") }"#
+        );
+    }
+
+    #[test]
+    fn test_prune_unreferenced() {
+        let url = |path: &str| {
+            Url::parse("http://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        let content = r#"<link rel="stylesheet" href="a.css">
+<img src="a.png"><script src="a.js"></script>"#;
+        let mut resource_map = ResourceMap::new();
+        for (path, resource) in [
+            ("a.css", Resource::Css("a { b: url(font.woff2) }".into())),
+            ("font.woff2", Resource::Css("not really".into())),
+            ("a.js", Resource::Javascript("a()".into())),
+            ("a.png", Resource::Css("not really".into())),
+            ("old.css", Resource::Css("b { c: url(old.png) }".into())),
+            ("old.png", Resource::Css("not really".into())),
+        ] {
+            resource_map.insert(url(path), resource);
+        }
+        let mut archive =
+            PageArchive::from_parts(url("/"), content, resource_map).unwrap();
+        for url in archive.resource_map.keys() {
+            archive
+                .info
+                .resources
+                .insert(url.clone(), Default::default());
+        }
+
+        assert_eq!(archive.prune_unreferenced(), 2);
+        let mut remaining: Vec<_> =
+            archive.resource_map.keys().map(|u| u.path()).collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec!["/a.css", "/a.js", "/a.png", "/font.woff2"]);
+        assert_eq!(archive.info.resources.len(), 4);
+
+        archive.content = r#"<script src="a.js"></script>"#.to_string();
+        assert_eq!(archive.prune_unreferenced(), 3);
+        assert_eq!(archive.prune_unreferenced(), 0);
+        assert!(archive.resource_map.get_javascript(&url("a.js")).is_some());
+    }
+
+    #[test]
+    fn test_missing_resources() {
+        let url = |path: &str| {
+            Url::parse("http://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        let content = r#"<link rel="stylesheet" href="a.css">
+<img src="a.png"><img src="b.png"><script src="a.js"></script>"#;
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url("a.css"),
+            Resource::Css("a { b: url(font.woff2) }".into()),
+        );
+        resource_map.insert(url("a.js"), Resource::Javascript("a()".into()));
+        resource_map.insert(
+            url("b.png"),
+            Resource::Failed {
+                status: StatusCode::NOT_FOUND,
+                data: Bytes::new(),
+                mimetype: "text/html".to_string(),
+            },
+        );
+        let mut archive =
+            PageArchive::from_parts(url("/"), content, resource_map).unwrap();
+
+        let mut missing: Vec<_> = archive
+            .missing_resources()
+            .into_iter()
+            .map(|resource_url| resource_url.url().path().to_string())
+            .collect();
+        missing.sort_unstable();
+        assert_eq!(missing, vec!["/a.png", "/b.png", "/font.woff2"]);
+        match archive.verify_completeness() {
+            Err(Error::VerificationError(message)) => {
+                assert!(message.starts_with("3 referenced resource(s) missing"));
+                assert!(message.contains("http://example.com/font.woff2"));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        archive.content = r#"<script src="a.js"></script>"#.to_string();
+        assert!(archive.missing_resources().is_empty());
+        archive.verify_completeness().unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let url = |path: &str| {
+            Url::parse("http://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        let failed = || Resource::Failed {
+            status: StatusCode::NOT_FOUND,
+            data: Bytes::new(),
+            mimetype: "text/html".to_string(),
+        };
+        let archive = |resources: Vec<(&str, Resource)>| {
+            let mut archive = PageArchive::from_parts(
+                "http://example.com/",
+                "<p>",
+                resources
+                    .into_iter()
+                    .map(|(path, resource)| (url(path), resource))
+                    .collect(),
+            )
+            .unwrap();
+            for url in archive.resource_map.keys() {
+                archive
+                    .info
+                    .resources
+                    .insert(url.clone(), Default::default());
+            }
+            archive
+        };
+        let first = || {
+            archive(vec![
+                ("a.css", Resource::Css("a {}".into())),
+                ("b.css", failed()),
+                ("same.css", Resource::Css("same".into())),
+            ])
+        };
+        let second = || {
+            let mut second = archive(vec![
+                ("a.css", Resource::Css("a { b: c }".into())),
+                ("b.css", Resource::Css("b {}".into())),
+                ("c.css", Resource::Css("c {}".into())),
+                ("same.css", Resource::Css("same".into())),
+            ]);
+            second.info.resources.get_mut(&url("b.css")).unwrap().status =
+                Some(StatusCode::OK);
+            second.content = "ignored".to_string();
+            second
+        };
+
+        let mut merged = first();
+        merged
+            .merge(second(), ConflictPolicy::KeepExisting)
+            .unwrap();
+        assert_eq!(merged.content, "<p>");
+        assert_eq!(merged.resource_map.len(), 4);
+        assert_eq!(
+            merged.resource_map.get_css(&url("a.css")).unwrap().text(),
+            "a {}"
+        );
+        assert_eq!(merged.resource_map[&url("b.css")], failed());
+        assert!(merged.resource_map.get_css(&url("c.css")).is_some());
+
+        let mut merged = first();
+        merged
+            .merge(second(), ConflictPolicy::PreferSuccessful)
+            .unwrap();
+        assert_eq!(
+            merged.resource_map.get_css(&url("a.css")).unwrap().text(),
+            "a {}"
+        );
+        assert_eq!(
+            merged.resource_map.get_css(&url("b.css")).unwrap().text(),
+            "b {}"
+        );
+        assert_eq!(
+            merged.info.resources[&url("b.css")].status,
+            Some(StatusCode::OK)
+        );
+
+        let mut merged = first();
+        merged.merge(second(), ConflictPolicy::Replace).unwrap();
+        assert_eq!(
+            merged.resource_map.get_css(&url("a.css")).unwrap().text(),
+            "a { b: c }"
+        );
+
+        let mut merged = first();
+        match merged.merge(second(), ConflictPolicy::Error) {
+            Err(Error::MergeConflict(u)) => {
+                assert!(u == url("a.css") || u == url("b.css"))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(merged.resource_map, first().resource_map);
+
+        let mut merged = first();
+        merged
+            .merge(
+                archive(vec![("same.css", Resource::Css("same".into()))]),
+                ConflictPolicy::Error,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_url_rewriter() {
+        let content = r#"<html><head>
+<link rel="stylesheet" href="main.css">
+<link rel="stylesheet" href="other.css">
+<link rel="icon" href="favicon.ico">
+<script src="app.js"></script>
+</head><body><img src="a.png"><img src="b.png">
+<iframe src="frame.html"></iframe></body></html>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("main.css").unwrap(),
+            Resource::Css("a { b: url(font.woff2) }".into()),
+        );
+        resource_map.insert(
+            url.join("other.css").unwrap(),
+            Resource::Css("c {}".into()),
+        );
+        resource_map.insert(
+            url.join("a.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"png"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+
+        let options = EmbedOptions {
+            url_rewriter: Some(Arc::new(|url: &Url, resource| {
+                match url.path() {
+                    "/other.css" | "/a.png" => None,
+                    path => Some(format!(
+                        "/mirror{}{}",
+                        path,
+                        if resource.is_some() { "?stored" } else { "" }
+                    )),
+                }
+            })),
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(r#"href="/mirror/main.css?stored""#));
+        assert!(output.contains("<style>c {}</style>"));
+        assert!(output.contains(r#"href="/mirror/favicon.ico""#));
+        assert!(output.contains(r#"<script src="/mirror/app.js"></script>"#));
+        assert!(output.contains(r#"<img src="data:image/png;base64,cG5n">"#));
+        assert!(output.contains(r#"<img src="/mirror/b.png">"#));
+        assert!(output.contains(r#"<iframe src="/mirror/frame.html">"#));
+
+        // URLs in embedded stylesheets are rewritten too
+        let options = EmbedOptions {
+            url_rewriter: Some(Arc::new(|url: &Url, _| {
+                if url.path().ends_with(".woff2") {
+                    Some("fonts/f.woff2".to_string())
+                } else {
+                    None
+                }
+            })),
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(
+            output.contains(r#"<style>a { b: url("fonts/f.woff2") }</style>"#)
+        );
+    }
+
+    #[test]
+    fn test_embedders() {
+        /// Records the base URL of each document in its `<body>`, after
+        /// the built-in embedding
+        struct MarkBase;
+
+        impl Embedder for MarkBase {
+            fn embed(
+                &self,
+                document: &NodeRef,
+                base: &Url,
+                resources: &ResourceMap,
+            ) {
+                for body in document.select("body").unwrap() {
+                    let mut attr = body.attributes.borrow_mut();
+                    attr.insert("data-base", base.to_string());
+                    attr.insert("data-resources", resources.len().to_string());
+                }
+                // The image has already been embedded
+                let img = document.select_first("img").unwrap();
+                assert!(img.attributes.borrow().get("src").is_some());
+            }
+        }
+
+        let content = r#"<body><img src="a.png">
+<iframe src="frame.html"></iframe></body>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("a.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"png"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        resource_map.insert(
+            url.join("frame.html").unwrap(),
+            Resource::Html(r#"<img src="a.png">"#.into()),
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+        let options = EmbedOptions {
+            embedders: vec![Arc::new(MarkBase)],
+            ..Default::default()
+        };
+        let page = archive.embed_resources_with(&options);
+        assert!(page.contains(
+            r#"<body data-base="http://example.com/" data-resources="2">"#
+        ));
+        assert!(page
+            .contains("data-base=&quot;http://example.com/frame.html&quot;"));
+        assert!(!page.contains(r#"src="a.png""#));
+        assert!(!archive.embed_resources().contains("data-base"));
+    }
+
+    #[test]
+    fn test_single_image() {
+        let content = r#"
+		<html>
+			<head></head>
+			<body>
+				<img src="rustacean.png" />
+			</body>
+		</html>
+		"#
+        .to_string();
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("rustacean.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from(
+                    include_bytes!(
+                        "../dynamic_tests/resources/rustacean-flat-happy.png"
+                    )
+                    .to_vec(),
+                ),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let output = archive.embed_resources();
+        println!("{}", output);
+        // base64 < dynamic_tests/resources/rustacean-flat-happy.png
+        assert!(output.contains(
+            r#"<img src="data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAB"#
+        ));
+        // chunk from middle of image
+        assert!(output.contains("gfuBxu3QDwEsoDXx5J5KCU+2/DF2JAQAoDHV"))
+    }
+
+    #[test]
+    fn test_other_resource() {
+        let content = r#"
+		<html>
+			<head>
+				<link rel="icon" href="favicon.ico" />
+			</head>
+			<body></body>
+		</html>
+		"#
+        .to_string();
+        let url = Url::parse("http://example.com").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("favicon.ico").unwrap(),
+            Resource::Other {
+                data: Bytes::from_static(b"\x00\x00\x01\x00"),
+                mimetype: "image/x-icon".to_string(),
+            },
+        );
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let output = archive.embed_resources();
+        assert!(output.contains(
+            r#"<link href="data:image/x-icon;base64,AAABAA==" rel="icon">"#
+        ));
+    }
+
+    #[test]
+    fn test_single_js() {
+        let content = r#"
+		<html>
+			<head>
+				<script src="script.js"></script>
+			</head>
+			<body></body>
+		</html>
+		"#
+        .to_string();
         let url = Url::parse("http://example.com").unwrap();
         let mut resource_map = ResourceMap::new();
         resource_map.insert(
@@ -269,13 +2497,17 @@ mod test {
 						console.log("Hello!");
 					}
 				"#
-                .to_string(),
+                .into(),
             ),
         );
         let archive = PageArchive {
             url,
             content,
+            raw_content: None,
             resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
         };
 
         let output = archive.embed_resources();
@@ -297,4 +2529,641 @@ mod test {
             .replace("\n", "")
         );
     }
+
+    #[test]
+    fn test_keep_unfetched_script_src() {
+        let content = r#"
+		<html>
+			<head>
+				<script src="fetched.js"></script>
+				<script src="missing.js"></script>
+			</head>
+			<body></body>
+		</html>
+		"#
+        .to_string();
+        let url = Url::parse("http://example.com/page/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("fetched.js").unwrap(),
+            Resource::Javascript("let a = 1;".into()),
+        );
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let output = archive.embed_resources();
+        assert!(output.contains("<script>let a = 1;</script>"));
+        assert!(output.contains("<script></script>"));
+
+        let options = EmbedOptions {
+            keep_unfetched_script_src: true,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains("<script>let a = 1;</script>"));
+        assert!(output.contains(
+            r#"<script src="http://example.com/page/missing.js"></script>"#
+        ));
+    }
+
+    #[test]
+    fn test_svg_embedding() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 8 8">
+<circle cx="4" cy="4" r="4" fill="#c00"/></svg>"##;
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("dot.svg").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from(svg),
+                mimetype: "image/svg+xml".to_string(),
+            }),
+        );
+        let archive = PageArchive::from_parts(
+            url.as_str(),
+            r#"<style>p { background: url(dot.svg) }</style>
+            <img src="dot.svg" class="icon" alt="Dot">"#,
+            resource_map,
+        )
+        .unwrap();
+
+        let output = archive.embed_resources();
+        assert_eq!(output.matches("data:image/svg+xml;base64,").count(), 2);
+
+        let options = EmbedOptions {
+            svg: SvgEmbedding::UrlEncoded,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        let encoded = "data:image/svg+xml,%3Csvg \
+            xmlns=%22http://www.w3.org/2000/svg%22 viewBox=%220 0 8 8%22%3E%0A\
+            %3Ccircle cx=%224%22 cy=%224%22 r=%224%22 fill=%22%23c00%22/%3E\
+            %3C/svg%3E";
+        assert!(output.contains(&format!(
+            r#"<img alt="Dot" class="icon" src="{}">"#,
+            encoded
+        )));
+        assert!(output.contains(&format!(r#"url("{}")"#, encoded)));
+
+        let options = EmbedOptions {
+            svg: SvgEmbedding::Inline,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(!output.contains("<img"));
+        assert!(output.contains(
+            r#"<svg aria-label="Dot" class="icon" role="img" viewBox="0 0 8 8" xmlns="http://www.w3.org/2000/svg">"#
+        ));
+        assert!(output.contains(&format!(r#"url("{}")"#, encoded)));
+    }
+
+    #[test]
+    fn test_inline_svg_sanitized() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)">
+<script>alert(2)</script>
+<a href="javascript:alert(3)"><circle r="4" onclick="alert(4)"/></a>
+<a xlink:href="javascript:alert(5)"><set attributeName="href" to="x"/></a>
+<a href="https://example.com/"><rect width="8"/></a></svg>"#;
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("logo.svg").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from(svg),
+                mimetype: "image/svg+xml".to_string(),
+            }),
+        );
+        let archive = PageArchive::from_parts(
+            url.as_str(),
+            r#"<img src="logo.svg">"#,
+            resource_map,
+        )
+        .unwrap();
+
+        let options = EmbedOptions {
+            svg: SvgEmbedding::Inline,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains("<svg"), "{}", output);
+        for removed in ["alert", "javascript", "<script", "<set"] {
+            assert!(!output.contains(removed), "{} in {}", removed, output);
+        }
+        assert!(output.contains(r#"<a href="https://example.com/">"#));
+    }
+
+    #[test]
+    fn test_image_size_limit() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let png = |size| {
+            let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+            data.resize(size, 0);
+            Resource::Image(ImageResource {
+                data: Bytes::from(data),
+                mimetype: "image/png".to_string(),
+            })
+        };
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(url.join("small.png").unwrap(), png(100));
+        resource_map.insert(url.join("photo.png").unwrap(), png(2_500_000));
+        let archive = PageArchive::from_parts(
+            url.as_str(),
+            r#"<style>body { background: url(photo.png) }</style>
+            <img src="small.png"><img src="photo.png" width="640">"#,
+            resource_map,
+        )
+        .unwrap();
+
+        let options = EmbedOptions {
+            image_size_limit: Some(1_000_000),
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.len() < 10_000);
+        assert_eq!(output.matches("data:image/png;base64,").count(), 1);
+        assert_eq!(output.matches("Image omitted (2.5 MB)").count(), 2);
+        assert!(output.contains(r#"width="640""#));
+
+        let options = EmbedOptions {
+            image_size_limit: Some(1_000_000),
+            oversized_images: OversizedImages::Link,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(r#"<img src="http://example.com/photo.png""#));
+        assert!(output.contains(r#"url("http://example.com/photo.png")"#));
+
+        // Large images written to separate files are kept
+        let options = EmbedOptions {
+            image_size_limit: Some(1_000_000),
+            inline_size_limit: Some(1_000_000),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.html");
+        archive.write_embedded(&path, &options).unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert!(!output.contains("Image omitted"));
+        assert!(output.contains(r#"src="page_files/"#));
+    }
+
+    #[test]
+    fn test_modulepreload() {
+        let url = Url::parse("http://example.com/page/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("chunk.js").unwrap(),
+            Resource::Javascript("export const a = 1;".into()),
+        );
+        let archive = PageArchive::from_parts(
+            url.as_str(),
+            r#"<link rel="modulepreload" href="chunk.js">
+            <link rel="modulepreload" href="missing.js">
+            <link rel="stylesheet" href="style.css">"#,
+            resource_map,
+        )
+        .unwrap();
+        assert_eq!(
+            archive.missing_resources(),
+            vec![
+                ResourceUrl::Javascript(url.join("missing.js").unwrap()),
+                ResourceUrl::Css(url.join("style.css").unwrap()),
+            ]
+        );
+
+        let output = archive.embed_resources();
+        assert!(!output.contains("modulepreload"));
+        assert!(output.contains("style.css"));
+
+        let options = EmbedOptions {
+            keep_unfetched_script_src: true,
+            inline_size_limit: Some(4),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("page.html");
+        archive.write_embedded(&path, &options).unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert!(output.contains(
+            r#"href="http://example.com/page/missing.js" rel="modulepreload""#
+        ));
+        assert!(output.contains(r#"href="page_files/"#));
+    }
+
+    #[test]
+    fn test_absolutize_unembedded() {
+        let content = r#"
+		<html>
+			<head>
+				<link rel="stylesheet" href="missing.css" />
+				<link rel="icon" href="/favicon.ico" />
+			</head>
+			<body>
+				<img src="fetched.png" />
+				<img src="../missing.png" />
+			</body>
+		</html>
+		"#
+        .to_string();
+        let url = Url::parse("http://example.com/page/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("fetched.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"\x89PNG\x0D\x0A\x1A\x0A"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let output = archive.embed_resources();
+        assert!(output.contains(r#"href="missing.css""#));
+        assert!(output.contains(r#"<img src="../missing.png">"#));
+
+        let options = EmbedOptions {
+            absolutize_unembedded: true,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(
+            output.contains(r#"href="http://example.com/page/missing.css""#)
+        );
+        assert!(output.contains(r#"href="http://example.com/favicon.ico""#));
+        assert!(
+            output.contains(r#"<img src="http://example.com/missing.png">"#)
+        );
+        assert!(output.contains(r#"<img src="data:image/png;base64,"#));
+    }
+
+    #[test]
+    fn test_absolutize_links() {
+        let content = r##"
+		<html>
+			<body>
+				<a href="../about.html">About</a>
+				<a href="#top">Top</a>
+				<a href="https://www.rust-lang.org/">Rust</a>
+			</body>
+		</html>
+		"##
+        .to_string();
+        let url = Url::parse("http://example.com/blog/post.html").unwrap();
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: None,
+            resource_map: ResourceMap::new(),
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let output = archive.embed_resources();
+        assert!(output.contains(r#"<a href="../about.html">"#));
+
+        let options = EmbedOptions {
+            absolutize_links: true,
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(r#"<a href="http://example.com/about.html">"#));
+        assert!(output.contains(r##"<a href="#top">"##));
+        assert!(output.contains(r#"<a href="https://www.rust-lang.org/">"#));
+    }
+
+    #[test]
+    fn test_write_to_disk() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("scripts/1.js").unwrap(),
+            Resource::Javascript("let a = 1;".into()),
+        );
+        let archive = PageArchive {
+            url,
+            content: "<html></html>".to_string(),
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let blocking_dir = tempfile::tempdir().unwrap();
+        archive.write_to_disk(&blocking_dir.path()).unwrap();
+        let async_dir = tempfile::tempdir().unwrap();
+        tokio_test::block_on(archive.write_to_disk_async(&async_dir.path()))
+            .unwrap();
+
+        for dir in [blocking_dir, async_dir].iter() {
+            assert_eq!(
+                std::fs::read_to_string(dir.path().join("index.html")).unwrap(),
+                "<html></html>"
+            );
+            assert_eq!(
+                std::fs::read_to_string(
+                    dir.path().join("example.com/scripts/1.js")
+                )
+                .unwrap(),
+                "let a = 1;"
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_from_disk() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("style.css").unwrap(),
+            Resource::Css(TextResource::decode(
+                Bytes::from_static(b"/* caf\xE9 */"),
+                Some("text/css; charset=latin1"),
+            )),
+        );
+        resource_map.insert(
+            url.join("ferris.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"\x89PNG\x0D\x0A\x1A\x0A"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        resource_map.insert(
+            url.join("favicon.ico").unwrap(),
+            Resource::Other {
+                data: Bytes::from_static(b"\x00\x00\x01\x00"),
+                mimetype: "image/x-icon".to_string(),
+            },
+        );
+        let mut info = ArchiveInfo::default();
+        info.page.digest = Some(DigestAlgorithm::Sha384.digest(b"page"));
+        info.page.status = Some(StatusCode::GONE);
+        for (url, resource) in &resource_map {
+            let digest =
+                DigestAlgorithm::Sha256.digest(resource.data().unwrap());
+            info.resources.insert(
+                url.clone(),
+                ResourceInfo {
+                    digest: Some(digest),
+                    status: Some(StatusCode::OK),
+                    timings: Some(ResourceTimings {
+                        first_byte: Duration::from_millis(12),
+                        total: Duration::from_millis(34),
+                    }),
+                    content_type: Some(resource.mimetype().to_string()),
+                    headers: None,
+                },
+            );
+        }
+        let archive = PageArchive {
+            url,
+            content: "<html>café</html>".to_string(),
+            raw_content: Some(Bytes::from_static(b"<html>caf\xE9</html>")),
+            resource_map,
+            fetched_at: std::time::UNIX_EPOCH + Duration::from_secs(1000),
+            info,
+            metadata_cache: Default::default(),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        archive.write_to_disk(&dir.path()).unwrap();
+        let loaded = PageArchive::read_from_disk(&dir.path()).unwrap();
+
+        assert_eq!(loaded.url, archive.url);
+        assert_eq!(loaded.content, archive.content);
+        assert_eq!(loaded.raw_content, archive.raw_content);
+        assert_eq!(loaded.fetched_at, archive.fetched_at);
+        assert_eq!(loaded.resource_map, archive.resource_map);
+        assert_eq!(loaded.info, archive.info);
+        assert_eq!(
+            fs::read(dir.path().join("index.raw")).unwrap(),
+            b"<html>caf\xE9</html>"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        tokio_test::block_on(archive.write_to_disk_async(&dir.path())).unwrap();
+        let loaded = PageArchive::read_from_disk(&dir.path()).unwrap();
+        assert_eq!(loaded.raw_content, archive.raw_content);
+    }
+
+    #[test]
+    fn test_read_from_disk_rejects_escaping_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = r#"{
+            "version": 1,
+            "generator": "web-archive",
+            "fetched_at": "2021-02-03T04:05:06Z",
+            "page": {
+                "url": "http://example.com/",
+                "path": "../index.html",
+                "kind": "page",
+                "mimetype": "text/html",
+                "size": 0,
+                "sha256": ""
+            },
+            "resources": []
+        }"#;
+        std::fs::write(dir.path().join(MANIFEST_FILE), manifest).unwrap();
+
+        let res = PageArchive::read_from_disk(&dir.path());
+        assert!(matches!(res, Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn test_extract_article() {
+        let content = r#"<html><head><title>Ferris</title></head><body>
+<div class="menu"><a href="/">Home</a></div>
+<div class="entry">
+<p>Ferris is a crab, and the unofficial mascot of Rust.</p>
+<img src="ferris.png"><a href="/more">More about Ferris</a>
+</div></body></html>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("ferris.png").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"png"),
+                mimetype: "image/png".to_string(),
+            }),
+        );
+        let archive = PageArchive {
+            url,
+            content: content.to_string(),
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let article = archive.extract_article();
+        assert_eq!(article.title.as_deref(), Some("Ferris"));
+        assert!(article.content.contains("unofficial mascot"));
+        assert!(article
+            .content
+            .contains(r#"<img src="data:image/png;base64,"#));
+        assert!(article
+            .content
+            .contains(r#"href="http://example.com/more""#));
+        assert!(!article.content.contains("Home"));
+    }
+
+    #[test]
+    fn test_write_embedded() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let content = r#"<html><head><link rel="stylesheet" href="big.css">
+<link rel="stylesheet" href="small.css"></head><body>
+<img src="big.png"><img src="small.png"><img src="big.png">
+<script src="big.js"></script></body></html>"#;
+        let mut resource_map = ResourceMap::new();
+        let image = |data: &'static [u8]| {
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(data),
+                mimetype: "image/png".to_string(),
+            })
+        };
+        resource_map.insert(url.join("big.png").unwrap(), image(b"0123456789"));
+        resource_map.insert(url.join("small.png").unwrap(), image(b"0123"));
+        resource_map.insert(
+            url.join("big.css").unwrap(),
+            Resource::Css("body { color: red; }".into()),
+        );
+        resource_map.insert(
+            url.join("small.css").unwrap(),
+            Resource::Css("a{}".into()),
+        );
+        resource_map.insert(
+            url.join("big.js").unwrap(),
+            Resource::Javascript("alert('hello');".into()),
+        );
+        let archive = PageArchive {
+            url,
+            content: content.to_string(),
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+        let options = EmbedOptions {
+            inline_size_limit: Some(8),
+            ..Default::default()
+        };
+
+        // Without a directory, large resources are left alone
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(r#"<img src="big.png">"#));
+        assert!(output.contains(r#"<link href="big.css" rel="stylesheet">"#));
+        assert!(
+            output.contains(r#"<img src="data:image/png;base64,MDEyMw==">"#)
+        );
+        assert!(output.contains("<style>a{}</style>"));
+        assert!(output.contains("<script></script>"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("my page.html");
+        archive.write_embedded(&file, &options).unwrap();
+        let output = std::fs::read_to_string(&file).unwrap();
+
+        let png = format!("{}.png", manifest::sha256_hex(b"0123456789"));
+        let css =
+            format!("{}.css", manifest::sha256_hex(b"body { color: red; }"));
+        let js = format!("{}.js", manifest::sha256_hex(b"alert('hello');"));
+        assert_eq!(
+            output
+                .matches(&format!(r#"<img src="my%20page_files/{}">"#, png))
+                .count(),
+            2
+        );
+        assert!(output.contains(&format!(
+            r#"<link href="my%20page_files/{}" rel="stylesheet">"#,
+            css
+        )));
+        assert!(output.contains(&format!(
+            r#"<script src="my%20page_files/{}"></script>"#,
+            js
+        )));
+        assert!(output.contains("<style>a{}</style>"));
+
+        let files = dir.path().join("my page_files");
+        assert_eq!(std::fs::read(files.join(&png)).unwrap(), b"0123456789");
+        assert_eq!(
+            std::fs::read(files.join(&css)).unwrap(),
+            b"body { color: red; }"
+        );
+        assert_eq!(std::fs::read(files.join(js)).unwrap(), b"alert('hello');");
+        assert_eq!(std::fs::read_dir(files).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_embed_idn() {
+        let content = r#"<html><head></head><body>
+<img src="http://BÜCHER.example/bilder/schön.png"></body></html>"#;
+        let url = Url::parse("http://bücher.example/ä.html").unwrap();
+        let mut resource_map = ResourceMap::new();
+        for resource_url in parse_resource_urls(&url, content) {
+            assert_eq!(
+                resource_url.url().as_str(),
+                "http://xn--bcher-kva.example/bilder/sch%C3%B6n.png"
+            );
+            resource_map.insert(
+                resource_url.url().clone(),
+                Resource::Image(ImageResource {
+                    data: Bytes::from_static(b"png"),
+                    mimetype: "image/png".to_string(),
+                }),
+            );
+        }
+        let archive = PageArchive {
+            url,
+            content: content.to_string(),
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let options = EmbedOptions {
+            provenance: Some(Provenance::Banner),
+            ..Default::default()
+        };
+        let output = archive.embed_resources_with(&options);
+        assert!(output.contains(r#"<img src="data:image/png;base64,cG5n">"#));
+        assert!(output.contains(
+            r#"<a href="http://xn--bcher-kva.example/%C3%A4.html">http://bücher.example/ä.html</a>"#
+        ));
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let archive = PageArchive::from_parts(
+            "http://example.com",
+            "<p>",
+            ResourceMap::new(),
+        )
+        .unwrap();
+        assert_eq!(archive.url.as_str(), "http://example.com/");
+        assert_eq!(archive.content, "<p>");
+        assert_eq!(archive.info, ArchiveInfo::default());
+
+        let res =
+            PageArchive::from_parts("not a url", "<p>", ResourceMap::new());
+        assert!(matches!(res, Err(Error::ParseError(_))));
+    }
 }
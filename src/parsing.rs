@@ -7,22 +7,46 @@
 
 //! Module for the core parsing functionality
 
+use crate::error::Error;
+use crate::extractor::extract_resource_urls;
+use crate::spill::SpilledResource;
 use bytes::Bytes;
+use bytestring::ByteString;
+use encoding_rs::{Encoding, UTF_8};
+use kuchiki::parse_html;
 use kuchiki::traits::TendrilSink;
-use kuchiki::{parse_html, NodeData};
+use reqwest::StatusCode;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use url::Url;
+use std::convert::TryFrom;
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use url::{Host, Position, Url};
 
 // https://github.com/Y2Z/monolith/blob/fa71f6a42c94df4c48d01819922afe1248eabad5/src/utils.rs#L13
-const MAGIC: [(&[u8], &str); 18] = [
+//
+// A `.` in a signature matches any byte, so more specific signatures
+// must come before more general ones (e.g. AVIF before MP4).
+const MAGIC: [(&[u8], &str); 25] = [
     // Image
     (b"GIF87a", "image/gif"),
     (b"GIF89a", "image/gif"),
     (b"\xFF\xD8\xFF", "image/jpeg"),
     (b"\x89PNG\x0D\x0A\x1A\x0A", "image/png"),
     (b"<svg ", "image/svg+xml"),
-    (b"RIFF....WEBPVP8 ", "image/webp"),
+    (b"RIFF....WEBPVP8", "image/webp"),
     (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"....ftypavif", "image/avif"),
+    (b"....ftypavis", "image/avif"),
+    // Fonts
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"\x00\x01\x00\x00\x00", "font/ttf"),
+    (b"OTTO", "font/otf"),
+    // Documents
+    (b"%PDF-", "application/pdf"),
     // Audio
     (b"ID3", "audio/mpeg"),
     (b"\xFF\x0E", "audio/mpeg"),
@@ -38,59 +62,50 @@ const MAGIC: [(&[u8], &str); 18] = [
     (b"\x1A\x45\xDF\xA3", "video/webm"),
 ];
 
-/// Search image, style, and script resources and store their URIs
-pub(crate) fn parse_resource_urls(
-    url_base: &Url,
-    page: &str,
-) -> Vec<ResourceUrl> {
-    let document = parse_html().one(page);
-
-    // Collect resource URLs for each element type
-    let mut resource_urls = Vec::new();
-
-    for element in document.select("img").unwrap() {
-        let node = element.as_node();
-        if let NodeData::Element(data) = node.data() {
-            let attr = data.attributes.borrow();
-            if let Some(u) = attr.get("src") {
-                if let Ok(u) = url_base.join(u) {
-                    resource_urls.push(ResourceUrl::Image(u));
-                }
-            }
-        }
-    }
-
-    for element in document.select("link").unwrap() {
-        let node = element.as_node();
-        if let NodeData::Element(data) = node.data() {
-            let attr = data.attributes.borrow();
-            if Some("stylesheet") == attr.get("rel") {
-                if let Some(u) = attr.get("href") {
-                    if let Ok(u) = url_base.join(u) {
-                        resource_urls.push(ResourceUrl::Css(u));
-                    }
-                }
-            }
-        }
-    }
-
-    for element in document.select("script").unwrap() {
-        let node = element.as_node();
-        if let NodeData::Element(data) = node.data() {
-            let attr = data.attributes.borrow();
-            if let Some(u) = attr.get("src") {
-                if let Ok(u) = url_base.join(u) {
-                    resource_urls.push(ResourceUrl::Javascript(u));
-                }
-            }
-        }
-    }
+/// Mimetype used when nothing more specific can be determined
+pub const DEFAULT_MIMETYPE: &str = "application/octet-stream";
 
-    // Dedup the URLs to avoid fetching the same one twice
-    resource_urls.sort();
-    resource_urls.dedup();
+/// Element selectors and the attribute on each which references a
+/// resource that is stored as a [`Resource::Other`], such as favicons,
+/// web app manifests, and media files
+pub(crate) const OTHER_RESOURCE_ATTRIBUTES: [(&str, &str); 8] = [
+    (r#"link[rel~="icon"]"#, "href"),
+    (r#"link[rel="manifest"]"#, "href"),
+    ("audio", "src"),
+    ("video", "src"),
+    ("source", "src"),
+    ("track", "src"),
+    ("embed", "src"),
+    ("object", "data"),
+];
 
-    resource_urls
+/// Search image, style, and script resources and store their URIs
+///
+/// Relative URLs are resolved against `url_base`, and the returned list
+/// is sorted and free of duplicates. The page's inline styles and the
+/// documents nested in the `srcdoc` of iframes are searched too, but
+/// stylesheets are not downloaded to find the resources they reference.
+/// No network requests are made, so this can be used to run resource
+/// discovery on HTML which has been obtained by other means.
+///
+/// ## Example
+/// ```
+/// use url::Url;
+/// use web_archive::parsing::{parse_resource_urls, ResourceUrl};
+///
+/// let base = Url::parse("http://example.com/blog/").unwrap();
+/// let html = r#"<html><body><img src="ferris.png" /></body></html>"#;
+///
+/// let resource_urls = parse_resource_urls(&base, html);
+/// assert_eq!(
+///     resource_urls,
+///     vec![ResourceUrl::Image(
+///         Url::parse("http://example.com/blog/ferris.png").unwrap()
+///     )]
+/// );
+/// ```
+pub fn parse_resource_urls(url_base: &Url, page: &str) -> Vec<ResourceUrl> {
+    extract_resource_urls(&parse_html().one(page), url_base, &[])
 }
 
 /// Tag the resource URLs with the type of resource they correspond to
@@ -102,23 +117,118 @@ pub enum ResourceUrl {
     Css(Url),
     /// Image files
     Image(Url),
+    /// Any other kind of linked file, e.g. icons, fonts, or media
+    Other(Url),
+    /// Documents shown in frames and iframes
+    Html(Url),
+}
+
+/// The kinds of resource which a page can link to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    /// Javascript files
+    Javascript,
+    /// CSS files
+    Css,
+    /// Image files
+    Image,
+    /// Any other kind of linked file
+    Other,
+    /// HTML documents
+    Html,
+}
+
+impl ResourceKind {
+    /// The name of the kind used in configuration, e.g. `"css"`
+    pub fn name(self) -> &'static str {
+        match self {
+            ResourceKind::Javascript => "javascript",
+            ResourceKind::Css => "css",
+            ResourceKind::Image => "image",
+            ResourceKind::Other => "other",
+            ResourceKind::Html => "html",
+        }
+    }
+}
+
+impl FromStr for ResourceKind {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "javascript" => Ok(ResourceKind::Javascript),
+            "css" => Ok(ResourceKind::Css),
+            "image" => Ok(ResourceKind::Image),
+            "other" => Ok(ResourceKind::Other),
+            "html" => Ok(ResourceKind::Html),
+            _ => Err(Error::ParseError(format!(
+                "Unknown resource kind: {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// Serialized as a string, so that kinds can be used as map keys in any
+/// format
+impl Serialize for ResourceKind {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for ResourceKind {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse()
+            .map_err(|e: Error| de::Error::custom(format!("{:?}", e)))
+    }
 }
 
 impl ResourceUrl {
+    /// Tag the URL as referring to the given kind of resource
+    pub fn new(kind: ResourceKind, url: Url) -> Self {
+        match kind {
+            ResourceKind::Javascript => ResourceUrl::Javascript(url),
+            ResourceKind::Css => ResourceUrl::Css(url),
+            ResourceKind::Image => ResourceUrl::Image(url),
+            ResourceKind::Other => ResourceUrl::Other(url),
+            ResourceKind::Html => ResourceUrl::Html(url),
+        }
+    }
+
+    /// The kind of resource the URL refers to
+    pub fn kind(&self) -> ResourceKind {
+        match self {
+            ResourceUrl::Javascript(_) => ResourceKind::Javascript,
+            ResourceUrl::Css(_) => ResourceKind::Css,
+            ResourceUrl::Image(_) => ResourceKind::Image,
+            ResourceUrl::Other(_) => ResourceKind::Other,
+            ResourceUrl::Html(_) => ResourceKind::Html,
+        }
+    }
+
     /// Returns a reference to the inner [`Url`]
     pub fn url(&self) -> &Url {
         use ResourceUrl::*;
         match self {
-            Javascript(u) => &u,
-            Css(u) => &u,
-            Image(u) => &u,
+            Javascript(u) => u,
+            Css(u) => u,
+            Image(u) => u,
+            Other(u) => u,
+            Html(u) => u,
         }
     }
 }
 
 impl PartialOrd for ResourceUrl {
     fn partial_cmp(&self, rhs: &ResourceUrl) -> Option<std::cmp::Ordering> {
-        Some(self.url().cmp(rhs.url()))
+        Some(self.cmp(rhs))
     }
 }
 
@@ -128,24 +238,576 @@ impl Ord for ResourceUrl {
     }
 }
 
+/// Search the page's `<a>` and `<area>` elements for the URLs it links to
+///
+/// Relative URLs are resolved against `url_base`, and each link is
+/// classified as [`Link::Internal`] if it has the same origin as
+/// `url_base` or [`Link::External`] otherwise. Only `http` and `https`
+/// links are returned, with any fragment removed, and the returned list
+/// is sorted and free of duplicates.
+///
+/// ## Example
+/// ```
+/// use url::Url;
+/// use web_archive::parsing::{parse_links, Link};
+///
+/// let base = Url::parse("http://example.com/blog/").unwrap();
+/// let html = r#"<a href="post.html#comments">Post</a>
+///     <a href="https://www.rust-lang.org/">Rust</a>"#;
+///
+/// let links = parse_links(&base, html);
+/// assert_eq!(
+///     links,
+///     vec![
+///         Link::Internal(Url::parse("http://example.com/blog/post.html").unwrap()),
+///         Link::External(Url::parse("https://www.rust-lang.org/").unwrap()),
+///     ]
+/// );
+/// ```
+pub fn parse_links(url_base: &Url, page: &str) -> Vec<Link> {
+    let document = parse_html().one(page);
+    let origin = url_base.origin();
+
+    let mut links = Vec::new();
+    for element in document.select("a[href], area[href]").unwrap() {
+        let attr = element.attributes.borrow();
+        let href = attr.get("href").unwrap_or_default();
+        let mut u = match url_base.join(href.trim()) {
+            Ok(u) if matches!(u.scheme(), "http" | "https") => u,
+            _ => continue,
+        };
+        u.set_fragment(None);
+        if u.origin() == origin {
+            links.push(Link::Internal(u));
+        } else {
+            links.push(Link::External(u));
+        }
+    }
+
+    links.sort();
+    links.dedup();
+
+    links
+}
+
+/// A link from a page, tagged by whether it points to the same site
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Link {
+    /// A link to a page with the same origin as the archived page
+    Internal(Url),
+    /// A link to another site
+    External(Url),
+}
+
+impl Link {
+    /// Returns a reference to the inner [`Url`]
+    pub fn url(&self) -> &Url {
+        match self {
+            Link::Internal(u) => u,
+            Link::External(u) => u,
+        }
+    }
+
+    /// Whether the link points to the same origin as the page
+    pub fn is_internal(&self) -> bool {
+        matches!(self, Link::Internal(_))
+    }
+}
+
+impl PartialOrd for Link {
+    fn partial_cmp(&self, rhs: &Link) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl Ord for Link {
+    fn cmp(&self, rhs: &Link) -> std::cmp::Ordering {
+        self.url().cmp(rhs.url())
+    }
+}
+
+/// Format a URL for display, the reverse of the conversion done when it
+/// is parsed: an internationalised domain name is shown in its Unicode
+/// form rather than as punycode, and percent-encoded non-ASCII
+/// characters are decoded. ASCII escapes such as `%20` or `%2F` are left
+/// encoded so that the result is unambiguous.
+///
+/// ## Example
+/// ```
+/// use url::Url;
+/// use web_archive::parsing::display_url;
+///
+/// let url = Url::parse("http://bücher.example/café.png").unwrap();
+/// assert_eq!(url.as_str(), "http://xn--bcher-kva.example/caf%C3%A9.png");
+/// assert_eq!(display_url(&url), "http://bücher.example/café.png");
+/// ```
+pub fn display_url(url: &Url) -> String {
+    let mut display = url[..Position::BeforeHost].to_string();
+    if let Some(host) = display_host(url) {
+        display.push_str(&host);
+    }
+    display.push_str(&decode_non_ascii(&url[Position::AfterHost..]));
+    display
+}
+
+/// The host of a URL, with domain names converted from punycode
+pub(crate) fn display_host(url: &Url) -> Option<String> {
+    match url.host()? {
+        Host::Domain(domain) => Some(idna::domain_to_unicode(domain).0),
+        _ => url.host_str().map(str::to_string),
+    }
+}
+
+/// Decode the percent-encoded non-ASCII characters in part of a URL,
+/// leaving it unchanged if they are not valid UTF-8
+pub(crate) fn decode_non_ascii(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let byte = encoded
+            .get(idx + 1..idx + 3)
+            .filter(|_| bytes[idx] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(|byte| !byte.is_ascii());
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                idx += 3;
+            }
+            None => {
+                decoded.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| encoded.to_string())
+}
+
 /// Newtype wrapper around [`HashMap`], mapping between resource URLs
 /// and the downloaded file contents
-pub type ResourceMap = HashMap<Url, Resource>;
+///
+/// The typed accessors avoid matching on [`Resource`], and the map itself
+/// is available through `Deref`, e.g. for `get`, `insert`, or `len`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceMap(HashMap<Url, Resource>);
+
+impl ResourceMap {
+    /// An empty map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The stylesheet downloaded from the URL, if any
+    pub fn get_css(&self, url: &Url) -> Option<&TextResource> {
+        match self.0.get(url)? {
+            Resource::Css(css) => Some(css),
+            _ => None,
+        }
+    }
+
+    /// The script downloaded from the URL, if any
+    pub fn get_javascript(&self, url: &Url) -> Option<&TextResource> {
+        match self.0.get(url)? {
+            Resource::Javascript(script) => Some(script),
+            _ => None,
+        }
+    }
+
+    /// The frame document downloaded from the URL, if any
+    pub fn get_html(&self, url: &Url) -> Option<&TextResource> {
+        match self.0.get(url)? {
+            Resource::Html(html) => Some(html),
+            _ => None,
+        }
+    }
+
+    /// The image downloaded from the URL, if it is held in memory
+    pub fn get_image(&self, url: &Url) -> Option<&ImageResource> {
+        match self.0.get(url)? {
+            Resource::Image(image) => Some(image),
+            _ => None,
+        }
+    }
+
+    /// Iterate over the stylesheets, in no particular order
+    pub fn iter_css(&self) -> impl Iterator<Item = (&Url, &TextResource)> {
+        self.0.iter().filter_map(|(url, resource)| match resource {
+            Resource::Css(css) => Some((url, css)),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the scripts, in no particular order
+    pub fn iter_javascript(
+        &self,
+    ) -> impl Iterator<Item = (&Url, &TextResource)> {
+        self.0.iter().filter_map(|(url, resource)| match resource {
+            Resource::Javascript(script) => Some((url, script)),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the images held in memory, in no particular order
+    pub fn iter_images(&self) -> impl Iterator<Item = (&Url, &ImageResource)> {
+        self.0.iter().filter_map(|(url, resource)| match resource {
+            Resource::Image(image) => Some((url, image)),
+            _ => None,
+        })
+    }
+
+    /// Iterate over the resources which could not be downloaded, which
+    /// are only kept when the [`FailurePolicy`](crate::FailurePolicy) is
+    /// `Record`
+    pub fn iter_failed(&self) -> impl Iterator<Item = (&Url, &Resource)> {
+        self.0
+            .iter()
+            .filter(|(_, resource)| matches!(resource, Resource::Failed { .. }))
+    }
+
+    /// Number of resources of the given kind, not counting failed ones
+    pub fn len_by_type(&self, kind: ResourceKind) -> usize {
+        self.0
+            .values()
+            .filter(|resource| resource.kind() == Some(kind))
+            .count()
+    }
+
+    /// Total size in bytes of the data of the resources held in memory,
+    /// i.e. all but the [spilled](Resource::Spilled) ones
+    pub fn resident_size(&self) -> usize {
+        self.0
+            .values()
+            .filter_map(Resource::data)
+            .map(Bytes::len)
+            .sum()
+    }
+
+    /// Unwrap the underlying [`HashMap`]
+    pub fn into_inner(self) -> HashMap<Url, Resource> {
+        self.0
+    }
+}
+
+impl Deref for ResourceMap {
+    type Target = HashMap<Url, Resource>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for ResourceMap {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl From<HashMap<Url, Resource>> for ResourceMap {
+    fn from(map: HashMap<Url, Resource>) -> Self {
+        Self(map)
+    }
+}
+
+impl From<ResourceMap> for HashMap<Url, Resource> {
+    fn from(map: ResourceMap) -> Self {
+        map.0
+    }
+}
+
+impl FromIterator<(Url, Resource)> for ResourceMap {
+    fn from_iter<I: IntoIterator<Item = (Url, Resource)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Extend<(Url, Resource)> for ResourceMap {
+    fn extend<I: IntoIterator<Item = (Url, Resource)>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl IntoIterator for ResourceMap {
+    type Item = (Url, Resource);
+    type IntoIter = std::collections::hash_map::IntoIter<Url, Resource>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ResourceMap {
+    type Item = (&'a Url, &'a Resource);
+    type IntoIter = std::collections::hash_map::Iter<'a, Url, Resource>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
 
 /// Generic resource type
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Resource {
-    /// Javascript is stored as a String
-    Javascript(String),
-    /// Stylesheets are stored as a String
-    Css(String),
+    /// Javascript is stored as a [`TextResource`]
+    Javascript(TextResource),
+    /// Stylesheets are stored as a [`TextResource`]
+    Css(TextResource),
+    /// Documents shown in frames are stored as a [`TextResource`], and
+    /// are embedded with their own resources
+    Html(TextResource),
     /// Images are stored as an [`ImageResource`] to allow the mimetype
     /// metadata to be useful
     Image(ImageResource),
+    /// Arbitrary binary resources which are not otherwise recognised,
+    /// such as icons, fonts, or media files
+    Other {
+        /// Raw resource data
+        data: Bytes,
+        /// Mime type of the resource, e.g. `font/woff2`
+        mimetype: String,
+    },
+    /// The error response for a resource which could not be downloaded,
+    /// kept when the [`FailurePolicy`](crate::FailurePolicy) is
+    /// `Record`. Failed resources are never embedded.
+    Failed {
+        /// Status code of the response
+        status: StatusCode,
+        /// Body of the error response
+        data: Bytes,
+        /// Mime type of the error response
+        mimetype: String,
+    },
+    /// An image or other binary resource whose data was written to a
+    /// temporary file to keep within the
+    /// [`ArchiveOptions::memory_budget`](crate::ArchiveOptions::memory_budget)
+    Spilled(SpilledResource),
+}
+
+impl Resource {
+    /// Returns a reference to the raw data of the resource if it is held
+    /// in memory, or `None` if it was [spilled](Resource::Spilled); use
+    /// [`read_data`](Self::read_data) to read it back.
+    pub fn data(&self) -> Option<&Bytes> {
+        self.stored_data().ok()
+    }
+
+    /// The raw data of the resource, or the temporary file holding it
+    pub(crate) fn stored_data(&self) -> Result<&Bytes, &SpilledResource> {
+        match self {
+            Resource::Javascript(text)
+            | Resource::Css(text)
+            | Resource::Html(text) => Ok(&text.data),
+            Resource::Image(image) => Ok(&image.data),
+            Resource::Other { data, .. } | Resource::Failed { data, .. } => {
+                Ok(data)
+            }
+            Resource::Spilled(spilled) => Err(spilled),
+        }
+    }
+
+    /// Returns the raw data of the resource, reading it back from its
+    /// temporary file if it was spilled
+    pub fn read_data(&self) -> Result<Cow<'_, Bytes>, Error> {
+        match self.stored_data() {
+            Ok(data) => Ok(Cow::Borrowed(data)),
+            Err(spilled) => Ok(Cow::Owned(spilled.read()?)),
+        }
+    }
+
+    /// Encode the raw data of the resource as base 64 and embed it into
+    /// a `data:` URI with its mime type, reading it back if it was
+    /// spilled
+    pub fn to_data_uri(&self) -> Result<String, Error> {
+        let data = self.read_data()?;
+        Ok(encode_data_uri(self.mimetype(), &data))
+    }
+
+    /// Size of the raw data of the resource in bytes, whether or not it
+    /// is held in memory
+    pub fn size(&self) -> usize {
+        match self.stored_data() {
+            Ok(data) => data.len(),
+            Err(spilled) => spilled.size(),
+        }
+    }
+
+    /// The kind of the resource, or `None` if it could not be downloaded
+    pub fn kind(&self) -> Option<ResourceKind> {
+        match self {
+            Resource::Javascript(_) => Some(ResourceKind::Javascript),
+            Resource::Css(_) => Some(ResourceKind::Css),
+            Resource::Html(_) => Some(ResourceKind::Html),
+            Resource::Image(_) => Some(ResourceKind::Image),
+            Resource::Other { .. } => Some(ResourceKind::Other),
+            Resource::Failed { .. } => None,
+            Resource::Spilled(spilled) => Some(spilled.kind()),
+        }
+    }
+
+    /// Returns the mimetype of the resource
+    pub fn mimetype(&self) -> &str {
+        match self {
+            Resource::Javascript(_) => "text/javascript",
+            Resource::Css(_) => "text/css",
+            Resource::Html(_) => "text/html",
+            Resource::Image(image) => &image.mimetype,
+            Resource::Other { mimetype, .. }
+            | Resource::Failed { mimetype, .. } => mimetype,
+            Resource::Spilled(spilled) => spilled.mimetype(),
+        }
+    }
+}
+
+/// Data type representing a text resource such as a stylesheet or a
+/// script. The original bytes are kept alongside the decoded text so
+/// that byte-faithful exports remain possible. Text which was already
+/// UTF-8 shares the buffer of the original bytes rather than being
+/// copied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextResource {
+    /// Raw data as received from the server
+    pub data: Bytes,
+    /// The data decoded into UTF-8
+    text: ByteString,
+    /// Name of the character encoding used to decode the data, e.g.
+    /// `UTF-8`
+    pub encoding: String,
+}
+
+impl TextResource {
+    /// Decode the raw resource data using the charset from the
+    /// `Content-Type` header, if any. A byte order mark takes precedence
+    /// over the header, and UTF-8 is assumed if neither is present.
+    /// Malformed sequences are replaced with U+FFFD.
+    pub fn decode(data: Bytes, content_type: Option<&str>) -> Self {
+        let charset = content_type.and_then(charset_from_content_type);
+        Self::decode_with_label(data, charset)
+    }
+
+    /// Decode the raw resource data using the named character encoding,
+    /// falling back to UTF-8 if the label is not recognised. A byte order
+    /// mark takes precedence over the label.
+    pub fn decode_with_label(data: Bytes, label: Option<&str>) -> Self {
+        let encoding = label
+            .and_then(|label| Encoding::for_label(label.as_bytes()))
+            .unwrap_or(UTF_8);
+        let (text, encoding, _) = encoding.decode(&data);
+        let text = match text {
+            // Valid UTF-8, or ASCII in a compatible encoding, possibly
+            // after a byte order mark
+            Cow::Borrowed(text) => {
+                ByteString::try_from(data.slice_ref(text.as_bytes()))
+                    .expect("the text is decoded UTF-8")
+            }
+            Cow::Owned(text) => ByteString::from(text),
+        };
+
+        Self {
+            data,
+            text,
+            encoding: encoding.name().to_string(),
+        }
+    }
+
+    /// The data decoded into text, used when embedding
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl From<String> for TextResource {
+    fn from(text: String) -> Self {
+        let text = ByteString::from(text);
+        Self {
+            data: text.as_bytes().clone(),
+            text,
+            encoding: UTF_8.name().to_string(),
+        }
+    }
+}
+
+impl From<&str> for TextResource {
+    fn from(text: &str) -> Self {
+        text.to_string().into()
+    }
+}
+
+/// Extract the `charset` parameter from a `Content-Type` header value
+fn charset_from_content_type(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let mut parts = param.splitn(2, '=');
+        let name = parts.next()?.trim();
+        let value = parts.next()?.trim().trim_matches('"');
+        if name.eq_ignore_ascii_case("charset") {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Convert a downloaded resource into its stored form, returning it
+/// along with the URL it is keyed by in the [`ResourceMap`]
+pub(crate) fn build_resource(
+    resource_url: ResourceUrl,
+    data: Bytes,
+    content_type: Option<&str>,
+) -> (Url, Resource) {
+    use ResourceUrl::*;
+
+    match resource_url {
+        Image(u) => {
+            let mimetype = mimetype_from_response(&data, &u);
+            (u, Resource::Image(ImageResource { data, mimetype }))
+        }
+        Css(u) => (u, Resource::Css(TextResource::decode(data, content_type))),
+        Javascript(u) => (
+            u,
+            Resource::Javascript(TextResource::decode(data, content_type)),
+        ),
+        Other(u) => {
+            let mimetype = mimetype_from_response(&data, &u);
+            (u, Resource::Other { data, mimetype })
+        }
+        Html(u) => {
+            (u, Resource::Html(TextResource::decode(data, content_type)))
+        }
+    }
+}
+
+/// Whether a resource served with the status was downloaded in full.
+/// Only `200 OK` counts, as a `204 No Content` has no contents and a
+/// `206 Partial Content` only part of them, so they are failures, as are
+/// statuses outside of the 2xx range.
+pub(crate) fn is_downloaded_status(status: StatusCode) -> bool {
+    status == StatusCode::OK
+}
+
+/// Store the error response for a resource which failed to download
+pub(crate) fn build_failed_resource(
+    resource_url: ResourceUrl,
+    status: StatusCode,
+    data: Bytes,
+    content_type: Option<&str>,
+) -> (Url, Resource) {
+    let url = resource_url.url().clone();
+    let mimetype = content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mimetype| mimetype.trim().to_ascii_lowercase())
+        .filter(|mimetype| !mimetype.is_empty())
+        .unwrap_or_else(|| mimetype_from_response(&data, &url));
+    (
+        url,
+        Resource::Failed {
+            status,
+            data,
+            mimetype,
+        },
+    )
 }
 
 /// Data type representing an image
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ImageResource {
     /// Raw image data
     pub data: Bytes,
@@ -157,24 +819,174 @@ impl ImageResource {
     /// Encode the image data as base 64 and embed it into a `data:` URI,
     /// e.g. `data:image/png;base64,iVBORw0...`.
     pub fn to_data_uri(&self) -> String {
-        let encoded = base64::encode(&self.data);
-        format!("data:{};base64,{}", self.mimetype, encoded)
+        encode_data_uri(&self.mimetype, &self.data)
     }
 }
 
+/// Base 64 encode some data and embed it into a `data:` URI with the
+/// given mimetype
+pub(crate) fn encode_data_uri(mimetype: &str, data: &[u8]) -> String {
+    // Encode straight into a buffer of the final size, as this is the
+    // largest allocation made for image-heavy pages
+    let mut uri = String::with_capacity(
+        "data:;base64,".len() + mimetype.len() + data.len().div_ceil(3) * 4,
+    );
+    uri.push_str("data:");
+    uri.push_str(mimetype);
+    uri.push_str(";base64,");
+    base64::encode_config_buf(data, base64::STANDARD, &mut uri);
+    uri
+}
+
+/// Encode an SVG image as a `data:` URI which keeps its text, escaping
+/// only the characters which are not allowed or have a meaning in URLs
+/// and CSS strings. Fails if the image is not valid UTF-8.
+pub(crate) fn encode_svg_data_uri(data: &[u8]) -> Option<String> {
+    let svg = std::str::from_utf8(data).ok()?;
+    let mut uri =
+        String::with_capacity("data:image/svg+xml,".len() + svg.len());
+    uri.push_str("data:image/svg+xml,");
+    for c in svg.chars() {
+        match c {
+            '%' | '#' | '<' | '>' | '"' | '\\' | '`' | '^' | '{' | '}'
+            | '|' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c if c.is_ascii_control() => {
+                uri.push_str(&format!("%{:02X}", c as u32))
+            }
+            c => uri.push(c),
+        }
+    }
+    Some(uri)
+}
+
+/// Encode the data of a resource as a `data:` URI, reading it back if
+/// it was spilled
+pub(crate) fn encode_resource_data_uri(resource: &Resource) -> Option<String> {
+    resource.to_data_uri().ok()
+}
+
+/// Whether a resource is an image or other binary resource, which is
+/// embedded as a `data:` URI
+pub(crate) fn is_binary(resource: &Resource) -> bool {
+    matches!(
+        resource.kind(),
+        Some(ResourceKind::Image) | Some(ResourceKind::Other)
+    )
+}
+
+/// Whether the `rel` of a `<link>` makes it a stylesheet. Link types are
+/// a space-separated, case-insensitive list, so this includes alternate
+/// stylesheets such as `rel="alternate stylesheet"`.
+pub(crate) fn is_stylesheet_rel(rel: &str) -> bool {
+    has_link_type(rel, "stylesheet")
+}
+
+/// Whether an embedded stylesheet must keep its `<link>` rather than be
+/// replaced with a `<style>`, because it is an alternate or `disabled`
+/// stylesheet, which theme switchers enable through the `<link>`
+pub(crate) fn is_switchable_stylesheet(rel: &str, disabled: bool) -> bool {
+    disabled || has_link_type(rel, "alternate")
+}
+
+/// Whether a `rel` attribute contains the link type
+fn has_link_type(rel: &str, link_type: &str) -> bool {
+    rel.split_ascii_whitespace()
+        .any(|token| token.eq_ignore_ascii_case(link_type))
+}
+
+/// Encode several resources as `data:` URIs using a thread per
+/// available core. The largest resources are shared out first so that
+/// the threads finish at about the same time.
+#[cfg(feature = "parallel")]
+pub(crate) fn encode_data_uris_parallel(
+    mut resources: Vec<(&Url, &Resource)>,
+) -> HashMap<Url, String> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(resources.len());
+    if threads <= 1 {
+        return resources
+            .into_iter()
+            .filter_map(|(url, resource)| {
+                Some((url.clone(), encode_resource_data_uri(resource)?))
+            })
+            .collect();
+    }
+
+    resources.sort_by_key(|(_, resource)| std::cmp::Reverse(resource.size()));
+    let mut batches = vec![Vec::new(); threads];
+    for (idx, resource) in resources.into_iter().enumerate() {
+        batches[idx % threads].push(resource);
+    }
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = batches
+            .into_iter()
+            .map(|batch| {
+                scope.spawn(move || {
+                    batch
+                        .into_iter()
+                        .filter_map(|(url, resource)| {
+                            let uri = encode_resource_data_uri(resource)?;
+                            Some((url.clone(), uri))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("encoding panicked"))
+            .collect()
+    })
+}
+
+/// Encode decoded text as a UTF-8 `data:` URI. The charset parameter
+/// takes precedence over any `@charset` rule left in the text.
+pub(crate) fn encode_text_data_uri(mimetype: &str, text: &str) -> String {
+    encode_data_uri(&format!("{};charset=utf-8", mimetype), text.as_bytes())
+}
+
 // https://github.com/Y2Z/monolith/blob/fa71f6a42c94df4c48d01819922afe1248eabad5/src/utils.rs#L44
-pub(crate) fn mimetype_from_response(data: &[u8], url: &Url) -> String {
+/// Determine the mimetype of a downloaded resource.
+///
+/// The magic bytes at the start of `data` are checked first, then the
+/// file extension of the URL is used to guess a type. If neither of
+/// these succeeds then [`DEFAULT_MIMETYPE`] is returned, so the result
+/// is always usable in a `data:` URI.
+///
+/// ## Example
+/// ```
+/// use url::Url;
+/// use web_archive::parsing::mimetype_from_response;
+///
+/// let url = Url::parse("http://example.com/report").unwrap();
+/// assert_eq!(mimetype_from_response(b"%PDF-1.7", &url), "application/pdf");
+///
+/// let url = Url::parse("http://example.com/font.woff2").unwrap();
+/// assert_eq!(mimetype_from_response(b"", &url), "font/woff2");
+/// ```
+pub fn mimetype_from_response(data: &[u8], url: &Url) -> String {
     for item in MAGIC.iter() {
-        if data.starts_with(item.0) {
+        if magic_matches(data, item.0) {
             return item.1.to_string();
         }
     }
 
-    if url.path().to_lowercase().ends_with(".svg") {
-        return "image/svg+xml".to_string();
+    if let Some(mimetype) = mime_guess::from_path(url.path()).first_raw() {
+        return mimetype.to_string();
     }
 
-    "".to_string()
+    DEFAULT_MIMETYPE.to_string()
+}
+
+/// Check whether `data` starts with the given signature, treating `.`
+/// as a wildcard
+fn magic_matches(data: &[u8], signature: &[u8]) -> bool {
+    data.len() >= signature.len()
+        && signature
+            .iter()
+            .zip(data)
+            .all(|(expected, actual)| *expected == b'.' || expected == actual)
 }
 
 #[cfg(test)]
@@ -219,7 +1031,7 @@ mod test {
         </html>
         "#;
 
-        let resource_urls = parse_resource_urls(&u(), &html);
+        let resource_urls = parse_resource_urls(&u(), html);
 
         assert_eq!(resource_urls.len(), 1);
         assert_eq!(
@@ -246,7 +1058,7 @@ mod test {
         </html>
         "#;
 
-        let resource_urls = parse_resource_urls(&u(), &html);
+        let resource_urls = parse_resource_urls(&u(), html);
 
         assert_eq!(resource_urls.len(), 1);
         assert_eq!(
@@ -272,7 +1084,7 @@ mod test {
         </html>
         "#;
 
-        let resource_urls = parse_resource_urls(&u(), &html);
+        let resource_urls = parse_resource_urls(&u(), html);
 
         assert_eq!(resource_urls.len(), 1);
         assert_eq!(
@@ -307,7 +1119,7 @@ mod test {
         </html>
         "#;
 
-        let resource_urls = parse_resource_urls(&u(), &html);
+        let resource_urls = parse_resource_urls(&u(), html);
 
         let mut test_urls = vec![
             ResourceUrl::Javascript(
@@ -345,7 +1157,7 @@ mod test {
         "#;
 
         let u = Url::parse("http://example.com/one/two/three/four/").unwrap();
-        let resource_urls = parse_resource_urls(&u, &html);
+        let resource_urls = parse_resource_urls(&u, html);
         let mut test_urls = vec![
             ResourceUrl::Image(
                 Url::parse("http://example.com/one/two/images/fun.png")
@@ -381,7 +1193,7 @@ mod test {
         </HTML>
         "#;
 
-        let resource_urls = parse_resource_urls(&u(), &html);
+        let resource_urls = parse_resource_urls(&u(), html);
 
         assert_eq!(resource_urls.len(), 1);
         assert_eq!(
@@ -409,7 +1221,7 @@ mod test {
         </html>
         "#;
 
-        let resource_urls = parse_resource_urls(&u(), &html);
+        let resource_urls = parse_resource_urls(&u(), html);
         let mut test_urls = vec![
             ResourceUrl::Javascript(
                 Url::parse("http://example.com/js.js").unwrap(),
@@ -422,19 +1234,374 @@ mod test {
         assert_eq!(resource_urls, test_urls);
     }
 
+    #[test]
+    fn test_other_resources() {
+        let html = r#"
+        <!DOCTYPE html>
+        <html>
+            <head>
+                <link rel="shortcut icon" href="/favicon.ico" />
+                <link rel="manifest" href="/site.webmanifest" />
+            </head>
+            <body>
+                <video src="intro.webm">
+                    <track src="intro.vtt" />
+                </video>
+                <audio><source src="theme.ogg" /></audio>
+                <object data="report.pdf"></object>
+            </body>
+        </html>
+        "#;
+
+        let resource_urls = parse_resource_urls(&u(), html);
+        let mut test_urls = vec![
+            ResourceUrl::Other(
+                Url::parse("http://example.com/favicon.ico").unwrap(),
+            ),
+            ResourceUrl::Other(
+                Url::parse("http://example.com/site.webmanifest").unwrap(),
+            ),
+            ResourceUrl::Other(
+                Url::parse("http://example.com/intro.webm").unwrap(),
+            ),
+            ResourceUrl::Other(
+                Url::parse("http://example.com/intro.vtt").unwrap(),
+            ),
+            ResourceUrl::Other(
+                Url::parse("http://example.com/theme.ogg").unwrap(),
+            ),
+            ResourceUrl::Other(
+                Url::parse("http://example.com/report.pdf").unwrap(),
+            ),
+        ];
+        test_urls.sort();
+
+        assert_eq!(resource_urls, test_urls);
+    }
+
+    #[test]
+    fn test_frame_documents() {
+        let html = r#"
+        <iframe src="/embed"></iframe>
+        <iframe src="about:blank"></iframe>
+        <iframe src="/ignored" srcdoc="<p>inline</p>"></iframe>
+        "#;
+
+        let resource_urls = parse_resource_urls(&u(), html);
+        assert_eq!(
+            resource_urls,
+            vec![ResourceUrl::Html(
+                Url::parse("http://example.com/embed").unwrap()
+            )]
+        );
+        assert_eq!(resource_urls[0].kind(), ResourceKind::Html);
+
+        let html = r#"<html><frameset><frame src="menu.html"></frameset>"#;
+        assert_eq!(
+            parse_resource_urls(&u(), html),
+            vec![ResourceUrl::Html(
+                Url::parse("http://example.com/menu.html").unwrap()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_encode_data_uri() {
+        for data in [&b""[..], b"a", b"ab", b"abc", b"abcd"] {
+            let uri = encode_data_uri("image/png", data);
+            assert_eq!(
+                uri,
+                format!("data:image/png;base64,{}", base64::encode(data))
+            );
+            assert_eq!(uri.len(), uri.capacity());
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_encode_data_uris_parallel() {
+        let resources: Vec<_> = (0..20)
+            .map(|idx| {
+                (
+                    u().join(&format!("{}.png", idx)).unwrap(),
+                    Resource::Image(ImageResource {
+                        data: Bytes::from(vec![idx as u8; idx * 100]),
+                        mimetype: "image/png".to_string(),
+                    }),
+                )
+            })
+            .collect();
+        let encoded = encode_data_uris_parallel(
+            resources
+                .iter()
+                .map(|(url, resource)| (url, resource))
+                .collect(),
+        );
+        assert_eq!(encoded.len(), 20);
+        for (url, resource) in &resources {
+            assert_eq!(
+                encoded[url],
+                encode_data_uri(resource.mimetype(), resource.data().unwrap())
+            );
+        }
+        assert!(encode_data_uris_parallel(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_resource_map() {
+        let url = |path| u().join(path).unwrap();
+        let mut map: ResourceMap = vec![
+            (url("a.css"), Resource::Css("a {}".into())),
+            (url("b.css"), Resource::Css("b {}".into())),
+            (url("a.js"), Resource::Javascript("a()".into())),
+            (
+                url("a.png"),
+                Resource::Image(ImageResource {
+                    data: Bytes::from_static(b"png"),
+                    mimetype: "image/png".to_string(),
+                }),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        map.insert(
+            url("missing.png"),
+            Resource::Failed {
+                status: StatusCode::NOT_FOUND,
+                data: Bytes::new(),
+                mimetype: "text/html".to_string(),
+            },
+        );
+
+        assert_eq!(map.get_css(&url("a.css")).unwrap().text(), "a {}");
+        assert!(map.get_css(&url("a.js")).is_none());
+        assert_eq!(map.get_javascript(&url("a.js")).unwrap().text(), "a()");
+        assert_eq!(map.get_image(&url("a.png")).unwrap().mimetype, "image/png");
+        assert!(map.get_image(&url("missing.png")).is_none());
+        assert!(map.get_html(&url("none.html")).is_none());
+
+        let images: Vec<_> = map.iter_images().map(|(u, _)| u).collect();
+        assert_eq!(images, vec![&url("a.png")]);
+        assert_eq!(map.iter_css().count(), 2);
+        assert_eq!(map.iter_javascript().count(), 1);
+        assert_eq!(map.iter_failed().count(), 1);
+
+        assert_eq!(map.len_by_type(ResourceKind::Css), 2);
+        assert_eq!(map.len_by_type(ResourceKind::Image), 1);
+        assert_eq!(map.len_by_type(ResourceKind::Html), 0);
+        assert_eq!(map.len(), 5);
+        assert_eq!(map.into_inner().len(), 5);
+    }
+
+    #[test]
+    fn test_inline_style_resources() {
+        let html = r#"<style>@import "a.css"; b { c: url(b.png) }</style>
+        <p style="background: url('/c.png')"></p>"#;
+
+        let resource_urls = parse_resource_urls(&u(), html);
+        assert_eq!(
+            resource_urls,
+            vec![
+                ResourceUrl::Css(
+                    Url::parse("http://example.com/a.css").unwrap()
+                ),
+                ResourceUrl::Other(
+                    Url::parse("http://example.com/b.png").unwrap()
+                ),
+                ResourceUrl::Other(
+                    Url::parse("http://example.com/c.png").unwrap()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_resource_decoding() {
+        // "café" in ISO-8859-1
+        let data = Bytes::from_static(b"caf\xE9");
+        let res = TextResource::decode(
+            data.clone(),
+            Some("text/css; charset=\"ISO-8859-1\""),
+        );
+        assert_eq!(res.text(), "café");
+        assert_eq!(res.encoding, "windows-1252");
+        assert_eq!(res.data, data);
+
+        // Defaults to UTF-8
+        let res = TextResource::decode(
+            Bytes::from_static("café".as_bytes()),
+            Some("text/css"),
+        );
+        assert_eq!(res.text(), "café");
+        assert_eq!(res.encoding, "UTF-8");
+        // UTF-8 text is not copied, even after a byte order mark
+        assert_eq!(res.text().as_ptr(), res.data.as_ptr());
+        let res =
+            TextResource::decode(Bytes::from_static(b"\xEF\xBB\xBFhi"), None);
+        assert_eq!(res.text(), "hi");
+        assert_eq!(res.text().as_ptr(), res.data[3..].as_ptr());
+
+        // BOM overrides the header
+        let res = TextResource::decode(
+            Bytes::from_static(b"\xFE\xFF\x00h\x00i"),
+            Some("application/javascript; charset=utf-8"),
+        );
+        assert_eq!(res.text(), "hi");
+        assert_eq!(res.encoding, "UTF-16BE");
+    }
+
     #[test]
     fn test_mimetype_detection() {
         let data: &[u8] = include_bytes!(
             "../dynamic_tests/resources/rustacean-flat-happy.png"
         );
         let url = Url::parse("http://example.com/ferris.png").unwrap();
-        let mimetype = mimetype_from_response(&data, &url);
+        let mimetype = mimetype_from_response(data, &url);
         assert_eq!(mimetype, "image/png");
 
         let data: &[u8] =
             include_bytes!("../dynamic_tests/resources/rust-logo-blk.svg");
         let url = Url::parse("http://example.com/rust.svg").unwrap();
-        let mimetype = mimetype_from_response(&data, &url);
+        let mimetype = mimetype_from_response(data, &url);
         assert_eq!(mimetype, "image/svg+xml");
     }
+
+    #[test]
+    fn test_mimetype_magic_wildcards() {
+        let url = Url::parse("http://example.com/no_extension").unwrap();
+
+        let webp = b"RIFF\x24\x00\x00\x00WEBPVP8 ";
+        assert_eq!(mimetype_from_response(webp, &url), "image/webp");
+
+        let avif = b"\x00\x00\x00\x1cftypavif";
+        assert_eq!(mimetype_from_response(avif, &url), "image/avif");
+
+        let mp4 = b"\x00\x00\x00\x18ftypmp42";
+        assert_eq!(mimetype_from_response(mp4, &url), "video/mp4");
+
+        assert_eq!(mimetype_from_response(b"wOF2\x00\x01", &url), "font/woff2");
+        assert_eq!(
+            mimetype_from_response(b"%PDF-1.4", &url),
+            "application/pdf"
+        );
+    }
+
+    #[test]
+    fn test_mimetype_fallbacks() {
+        let url = Url::parse("http://example.com/font.ttf?v=2").unwrap();
+        assert_eq!(mimetype_from_response(b"", &url), "font/ttf");
+
+        let url = Url::parse("http://example.com/unknown").unwrap();
+        assert_eq!(
+            mimetype_from_response(b"not magic", &url),
+            DEFAULT_MIMETYPE
+        );
+    }
+
+    #[test]
+    fn test_parse_links() {
+        let base = Url::parse("http://example.com/a/b.html").unwrap();
+        let html = r##"
+            <a href="c.html">C</a>
+            <a href=" /a/c.html#top ">C again</a>
+            <a href="#section">Fragment</a>
+            <a href="http://example.com:8080/">Other port</a>
+            <a href="https://example.com/">Other scheme</a>
+            <a href="mailto:ferris@example.com">Mail</a>
+            <a href="javascript:void(0)">Script</a>
+            <a>No href</a>
+            <map><area href="//rust-lang.org/learn"></map>
+        "##;
+
+        let links = parse_links(&base, html);
+        assert_eq!(
+            links,
+            vec![
+                Link::Internal(
+                    Url::parse("http://example.com/a/b.html").unwrap()
+                ),
+                Link::Internal(
+                    Url::parse("http://example.com/a/c.html").unwrap()
+                ),
+                Link::External(Url::parse("http://example.com:8080/").unwrap()),
+                Link::External(
+                    Url::parse("http://rust-lang.org/learn").unwrap()
+                ),
+                Link::External(Url::parse("https://example.com/").unwrap()),
+            ]
+        );
+        assert!(links[0].is_internal());
+        assert!(!links[2].is_internal());
+    }
+
+    #[test]
+    fn test_display_url() {
+        let display = |u: &str| display_url(&Url::parse(u).unwrap());
+        assert_eq!(
+            display("https://例え.テスト/パス?q=値#ä"),
+            "https://例え.テスト/パス?q=値#ä"
+        );
+        assert_eq!(
+            display("http://example.com/a%20b%2Fc/%C3%A9"),
+            "http://example.com/a%20b%2Fc/é"
+        );
+        // Invalid UTF-8 is left encoded
+        assert_eq!(
+            display("http://example.com/caf%E9"),
+            "http://example.com/caf%E9"
+        );
+        assert_eq!(
+            display("http://user@[::1]:8080/"),
+            "http://user@[::1]:8080/"
+        );
+    }
+
+    #[test]
+    fn test_idn_resources() {
+        let base = Url::parse("http://bücher.example/ä/").unwrap();
+        assert_eq!(base.as_str(), "http://xn--bcher-kva.example/%C3%A4/");
+
+        let html = r#"<img src="bild.png"><img src="http://BÜCHER.example/ä/bild.png">
+            <script src="/skript.js?q=ü"></script>"#;
+        let urls: Vec<String> = parse_resource_urls(&base, html)
+            .iter()
+            .map(|u| u.url().to_string())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://xn--bcher-kva.example/%C3%A4/bild.png",
+                "http://xn--bcher-kva.example/skript.js?q=%C3%BC",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_failed_resource() {
+        let url = Url::parse("http://example.com/missing.png").unwrap();
+        let (u, resource) = build_failed_resource(
+            ResourceUrl::Image(url.clone()),
+            StatusCode::NOT_FOUND,
+            Bytes::from_static(b"<h1>Not Found</h1>"),
+            Some("Text/HTML; charset=utf-8"),
+        );
+        assert_eq!(u, url);
+        assert_eq!(
+            resource,
+            Resource::Failed {
+                status: StatusCode::NOT_FOUND,
+                data: Bytes::from_static(b"<h1>Not Found</h1>"),
+                mimetype: "text/html".to_string(),
+            }
+        );
+
+        // Without a Content-Type the mimetype is guessed
+        let (_, resource) = build_failed_resource(
+            ResourceUrl::Image(url),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Bytes::new(),
+            None,
+        );
+        assert_eq!(resource.mimetype(), "image/png");
+    }
 }
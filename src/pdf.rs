@@ -0,0 +1,118 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for rendering embedded pages to PDF with a headless browser
+
+use crate::error::Error;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Environment variable which overrides the browser used for rendering
+pub(crate) const BROWSER_VAR: &str = "WEB_ARCHIVE_BROWSER";
+
+/// Browsers tried in order if [`BROWSER_VAR`] is not set
+const BROWSERS: [&str; 4] =
+    ["chromium", "chromium-browser", "google-chrome", "chrome"];
+
+/// Arguments to make a Chromium-based browser print `input` to `output`
+pub(crate) fn browser_args(input: &Path, output: &Path) -> Vec<OsString> {
+    let mut print_to_pdf = OsString::from("--print-to-pdf=");
+    print_to_pdf.push(output);
+    vec![
+        "--headless".into(),
+        "--disable-gpu".into(),
+        "--no-pdf-header-footer".into(),
+        print_to_pdf,
+        input.into(),
+    ]
+}
+
+/// Render an HTML document to PDF, returning the PDF bytes
+pub(crate) fn render(html: &str) -> Result<Vec<u8>, Error> {
+    let dir = tempfile::tempdir()?;
+    let input = dir.path().join("page.html");
+    let output = dir.path().join("page.pdf");
+    fs::write(&input, html)?;
+
+    let browsers: Vec<OsString> = match env::var_os(BROWSER_VAR) {
+        Some(browser) => vec![browser],
+        None => BROWSERS.iter().map(OsString::from).collect(),
+    };
+
+    for browser in &browsers {
+        if run(browser, &input, &output)? {
+            return Ok(fs::read(&output)?);
+        }
+    }
+
+    Err(Error::IoError(format!(
+        "Unable to render PDF: no browser found (tried {}); set {} to \
+         the path of a Chromium-based browser",
+        browsers
+            .iter()
+            .map(|b| b.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", "),
+        BROWSER_VAR
+    )))
+}
+
+/// Run a browser, returning `false` if it could not be found
+fn run(browser: &OsStr, input: &Path, output: &Path) -> Result<bool, Error> {
+    let result = Command::new(browser)
+        .args(browser_args(input, output))
+        .output();
+    let out = match result {
+        Ok(out) => out,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !out.status.success() || !output.exists() {
+        return Err(Error::IoError(format!(
+            "{} failed to render PDF ({}): {}",
+            browser.to_string_lossy(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_browser_args() {
+        let args =
+            browser_args(Path::new("/tmp/in.html"), Path::new("/tmp/out.pdf"));
+        assert_eq!(
+            args,
+            vec![
+                "--headless",
+                "--disable-gpu",
+                "--no-pdf-header-footer",
+                "--print-to-pdf=/tmp/out.pdf",
+                "/tmp/in.html",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_browser() {
+        let res = run(
+            OsStr::new("web-archive-no-such-browser"),
+            Path::new("in.html"),
+            Path::new("out.pdf"),
+        );
+        assert!(matches!(res, Ok(false)));
+    }
+}
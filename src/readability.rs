@@ -0,0 +1,356 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for extracting the main content of a page for offline reading
+//!
+//! The extraction is a simplified version of the scoring used by
+//! Mozilla's Readability: paragraphs award points to their parent and
+//! grandparent elements based on their length and number of commas,
+//! class names and IDs such as `content` or `sidebar` adjust the scores,
+//! and the element with the highest score after accounting for the
+//! density of links within it is taken as the article body.
+
+use crate::embed::new_html_element;
+use kuchiki::iter::NodeIterator;
+use kuchiki::traits::TendrilSink;
+use kuchiki::{parse_html, NodeRef};
+
+/// Elements which never form part of the article
+const REMOVED_ELEMENTS: &str = "script, style, noscript, template, iframe, \
+    object, embed, form, button, input, select, textarea, nav, aside, \
+    footer, link, meta";
+
+/// Elements whose text awards points to their ancestors
+const SCORED_ELEMENTS: &str = "p, pre, td, blockquote";
+
+/// Attributes kept on elements in the extracted article
+const KEPT_ATTRIBUTES: [&str; 7] = [
+    "href", "src", "srcset", "alt", "title", "colspan", "rowspan",
+];
+
+/// Class name and ID fragments suggesting an element is not content
+const NEGATIVE_HINTS: [&str; 14] = [
+    "comment", "sidebar", "footer", "footnote", "masthead", "menu", "nav",
+    "share", "social", "sponsor", "advert", "promo", "related", "popup",
+];
+
+/// Class name and ID fragments suggesting an element is content
+const POSITIVE_HINTS: [&str; 8] = [
+    "article", "body", "content", "entry", "main", "page", "post", "text",
+];
+
+/// The main content of a page, produced by
+/// [`PageArchive::extract_article`](crate::PageArchive::extract_article)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Article {
+    /// Title of the article, from its Open Graph metadata, `<title>`, or
+    /// first `<h1>`
+    pub title: Option<String>,
+    /// Author of the article, from its metadata or byline
+    pub byline: Option<String>,
+    /// A standalone HTML document containing the title, byline, and
+    /// body of the article with scripts, styling, and navigation removed
+    pub content: String,
+}
+
+/// Extract the article from an HTML document
+pub(crate) fn extract(html: &str) -> Article {
+    let document = parse_html().one(html);
+    let title = title(&document);
+    let byline = byline(&document);
+
+    detach_all(&document, REMOVED_ELEMENTS);
+    let unlikely: Vec<_> = document
+        .descendants()
+        .elements()
+        .filter(|el| {
+            !matches!(&*el.name.local, "html" | "body" | "article" | "main")
+                && class_weight(el.as_node()) < 0.0
+        })
+        .collect();
+    for el in unlikely {
+        el.as_node().detach();
+    }
+
+    let body = best_candidate(&document)
+        .or_else(|| {
+            document
+                .select_first("body")
+                .ok()
+                .map(|b| b.as_node().clone())
+        })
+        .unwrap_or_else(|| document.clone());
+    clean(&body);
+
+    let output = parse_html().one(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"></head>\
+         <body><article></article></body></html>",
+    );
+    if let Some(title) = &title {
+        let head = output.select_first("head").unwrap();
+        let element = new_html_element("title", &[]);
+        element.append(NodeRef::new_text(title.as_str()));
+        head.as_node().append(element);
+    }
+    let article = output.select_first("article").unwrap();
+    let article = article.as_node();
+    if let Some(title) = &title {
+        let element = new_html_element("h1", &[]);
+        element.append(NodeRef::new_text(title.as_str()));
+        article.append(element);
+    }
+    if let Some(byline) = &byline {
+        let element = new_html_element("p", &[("class", "byline")]);
+        element.append(NodeRef::new_text(byline.as_str()));
+        article.append(element);
+    }
+    for child in body.children().collect::<Vec<_>>() {
+        article.append(child);
+    }
+
+    Article {
+        title,
+        byline,
+        content: output.to_string(),
+    }
+}
+
+/// Value of the `content` attribute of the first element matching a
+/// selector
+fn meta_content(document: &NodeRef, selector: &str) -> Option<String> {
+    let element = document.select_first(selector).ok()?;
+    let attributes = element.attributes.borrow();
+    non_empty(attributes.get("content")?)
+}
+
+/// Whitespace-normalised text, or `None` if it is empty
+pub(crate) fn non_empty(text: &str) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+fn title(document: &NodeRef) -> Option<String> {
+    meta_content(document, r#"meta[property="og:title"]"#)
+        .or_else(|| {
+            let title = document.select_first("title").ok()?;
+            non_empty(&title.text_contents())
+        })
+        .or_else(|| {
+            let h1 = document.select_first("h1").ok()?;
+            non_empty(&h1.text_contents())
+        })
+}
+
+fn byline(document: &NodeRef) -> Option<String> {
+    meta_content(document, r#"meta[name="author"]"#).or_else(|| {
+        let byline = document
+            .select_first(r#"[rel~="author"], .byline, .author"#)
+            .ok()?;
+        non_empty(&byline.text_contents())
+    })
+}
+
+fn detach_all(node: &NodeRef, selector: &str) {
+    if let Ok(selected) = node.select(selector) {
+        for element in selected.collect::<Vec<_>>() {
+            element.as_node().detach();
+        }
+    }
+}
+
+/// Score adjustment based on an element's class names and ID
+fn class_weight(node: &NodeRef) -> f64 {
+    let element = match node.as_element() {
+        Some(element) => element,
+        None => return 0.0,
+    };
+    let attributes = element.attributes.borrow();
+    let mut weight = 0.0;
+    for name in ["class", "id"].iter() {
+        let value = match attributes.get(*name) {
+            Some(value) => value.to_lowercase(),
+            None => continue,
+        };
+        if NEGATIVE_HINTS.iter().any(|hint| value.contains(hint)) {
+            weight -= 25.0;
+        }
+        if POSITIVE_HINTS.iter().any(|hint| value.contains(hint)) {
+            weight += 25.0;
+        }
+    }
+    weight
+}
+
+/// Proportion of an element's text which is inside links
+fn link_density(node: &NodeRef) -> f64 {
+    let length = node.text_contents().trim().len();
+    if length == 0 {
+        return 0.0;
+    }
+    let links: usize = node
+        .select("a")
+        .map(|links| links.map(|a| a.text_contents().trim().len()).sum())
+        .unwrap_or(0);
+    links as f64 / length as f64
+}
+
+/// Initial score of a candidate element
+fn initial_score(node: &NodeRef) -> f64 {
+    let tag_weight = match node.as_element().map(|el| &*el.name.local) {
+        Some("article") => 10.0,
+        Some("div") => 5.0,
+        Some("pre") | Some("td") | Some("blockquote") => 3.0,
+        Some("ol") | Some("ul") | Some("form") => -3.0,
+        Some("h1") | Some("h2") | Some("h3") | Some("h4") | Some("th") => -5.0,
+        _ => 0.0,
+    };
+    tag_weight + class_weight(node)
+}
+
+/// The element most likely to contain the article body
+fn best_candidate(document: &NodeRef) -> Option<NodeRef> {
+    let mut candidates: Vec<(NodeRef, f64)> = Vec::new();
+    let mut award = |node: NodeRef, points: f64| {
+        if node.as_element().is_none() {
+            return;
+        }
+        match candidates.iter_mut().find(|(c, _)| *c == node) {
+            Some((_, score)) => *score += points,
+            None => {
+                let score = initial_score(&node) + points;
+                candidates.push((node, score));
+            }
+        }
+    };
+
+    for element in document.select(SCORED_ELEMENTS).ok()? {
+        let text = element.text_contents();
+        let text = text.trim();
+        if text.len() < 25 {
+            continue;
+        }
+        let points = 1.0
+            + text.matches(',').count() as f64
+            + (text.len() as f64 / 100.0).min(3.0);
+        let parent = match element.as_node().parent() {
+            Some(parent) => parent,
+            None => continue,
+        };
+        if let Some(grandparent) = parent.parent() {
+            award(grandparent, points / 2.0);
+        }
+        award(parent, points);
+    }
+
+    candidates
+        .into_iter()
+        .map(|(node, score)| {
+            let score = score * (1.0 - link_density(&node));
+            (node, score)
+        })
+        .fold(
+            None,
+            |best: Option<(NodeRef, f64)>, (node, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((node, score)),
+            },
+        )
+        .map(|(node, _)| node)
+}
+
+/// Remove link-heavy blocks and presentational attributes from the
+/// article body
+fn clean(body: &NodeRef) {
+    let link_lists: Vec<_> = body
+        .descendants()
+        .elements()
+        .filter(|el| {
+            matches!(&*el.name.local, "div" | "ul" | "ol" | "table")
+                && link_density(el.as_node()) > 0.5
+        })
+        .collect();
+    for el in link_lists {
+        el.as_node().detach();
+    }
+
+    for el in body.inclusive_descendants().elements() {
+        el.attributes
+            .borrow_mut()
+            .map
+            .retain(|name, _| KEPT_ATTRIBUTES.contains(&&*name.local));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <title>Site name</title>
+  <meta property="og:title" content="An article about crabs">
+  <meta name="author" content="Ferris">
+  <script>track()</script>
+</head>
+<body>
+  <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+  <div id="sidebar"><p>Popular posts, trending topics, and more.</p></div>
+  <div class="post-content" style="color: red">
+    <p>Crabs are decapod crustaceans, and they are found in every ocean.</p>
+    <img src="crab.png" alt="A crab" onclick="zoom()">
+    <p>They have a thick exoskeleton, a single pair of claws, and ten legs.</p>
+    <ul><li><a href="/a">Share</a></li><li><a href="/b">Tweet</a></li></ul>
+  </div>
+  <div class="comments"><p>First comment, with lots of words in it.</p></div>
+</body>
+</html>"#;
+
+    #[test]
+    fn test_extract() {
+        let article = extract(PAGE);
+        assert_eq!(article.title.as_deref(), Some("An article about crabs"));
+        assert_eq!(article.byline.as_deref(), Some("Ferris"));
+
+        let content = &article.content;
+        assert!(content.contains("<title>An article about crabs</title>"));
+        assert!(content.contains("<h1>An article about crabs</h1>"));
+        assert!(content.contains(r#"<p class="byline">Ferris</p>"#));
+        assert!(content.contains("decapod crustaceans"));
+        assert!(content.contains("ten legs"));
+        assert!(
+            content.contains(r#"<img alt="A crab" src="crab.png">"#)
+                || content.contains(r#"<img src="crab.png" alt="A crab">"#)
+        );
+
+        for removed in [
+            "track()",
+            "About",
+            "Popular posts",
+            "First comment",
+            "Tweet",
+            "style=",
+            "onclick",
+        ]
+        .iter()
+        {
+            assert!(!content.contains(removed), "{} in {}", removed, content);
+        }
+    }
+
+    #[test]
+    fn test_extract_without_candidates() {
+        let article = extract("<h1>Hello</h1><span>short</span>");
+        assert_eq!(article.title.as_deref(), Some("Hello"));
+        assert!(article.byline.is_none());
+        assert!(article.content.contains("short"));
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for reporting what happened to each resource of an archive
+//!
+//! [`archive_with_report`](crate::archive_with_report) returns an
+//! [`ArchiveReport`] alongside the archive, listing every resource the
+//! page referenced with what became of it and how long it took, so that
+//! automated pipelines can check the quality of an archive.
+//!
+//! ```no_run
+//! use web_archive::archive_with_report;
+//!
+//! # async fn archive_async() {
+//! let (archive, report) =
+//!     archive_with_report("http://example.com", Default::default())
+//!         .await
+//!         .unwrap();
+//! for entry in report.failed() {
+//!     eprintln!("{} failed: {}", entry.url, entry.outcome);
+//! }
+//! assert!(report.is_complete());
+//! # }
+//! ```
+
+use crate::parsing::ResourceKind;
+use reqwest::StatusCode;
+use std::fmt;
+use std::time::Duration;
+use url::Url;
+
+/// What happened to each resource while archiving a page
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveReport {
+    /// Time taken to fetch the page itself
+    pub page_duration: Duration,
+    /// Time taken to archive the page and all of its resources
+    pub total_duration: Duration,
+    /// One entry per resource, in the order they were dealt with
+    pub resources: Vec<ResourceReport>,
+}
+
+impl ArchiveReport {
+    /// The resources which were downloaded and stored in the archive
+    pub fn fetched(&self) -> impl Iterator<Item = &ResourceReport> {
+        self.resources.iter().filter(|entry| {
+            matches!(
+                entry.outcome,
+                ResourceOutcome::Fetched | ResourceOutcome::Cached
+            )
+        })
+    }
+
+    /// The resources which were left out of the archive without being
+    /// fetched or after being fetched, e.g. by the
+    /// [`resource_hook`](crate::ArchiveOptions::resource_hook)
+    pub fn skipped(&self) -> impl Iterator<Item = &ResourceReport> {
+        self.resources.iter().filter(|entry| {
+            matches!(entry.outcome, ResourceOutcome::Skipped(_))
+        })
+    }
+
+    /// The resources which the
+    /// [`resource_filter`](crate::ArchiveOptions::resource_filter)
+    /// decided not to fetch
+    pub fn filtered(&self) -> impl Iterator<Item = &ResourceReport> {
+        self.resources
+            .iter()
+            .filter(|entry| entry.outcome == ResourceOutcome::Filtered)
+    }
+
+    /// The resources which could not be downloaded
+    pub fn failed(&self) -> impl Iterator<Item = &ResourceReport> {
+        self.resources.iter().filter(|entry| {
+            matches!(entry.outcome, ResourceOutcome::Failed { .. })
+        })
+    }
+
+    /// Whether every resource which was not filtered out was stored
+    pub fn is_complete(&self) -> bool {
+        self.skipped().next().is_none() && self.failed().next().is_none()
+    }
+}
+
+/// What happened to a single resource
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResourceReport {
+    /// URL of the resource
+    pub url: Url,
+    /// The kind of resource the page referenced
+    pub kind: ResourceKind,
+    /// What became of the resource
+    pub outcome: ResourceOutcome,
+    /// Time spent fetching and processing the resource
+    pub duration: Duration,
+}
+
+/// What became of a resource
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResourceOutcome {
+    /// Downloaded and stored in the archive
+    Fetched,
+    /// Stored in the archive from the session's copy for an earlier page
+    Cached,
+    /// Not fetched, as the
+    /// [`resource_filter`](crate::ArchiveOptions::resource_filter)
+    /// rejected it
+    Filtered,
+    /// Left out of the archive, for the reason given
+    Skipped(String),
+    /// The server responded with an unsuccessful status
+    Failed {
+        /// Status of the response
+        status: StatusCode,
+        /// Whether the error response was stored as a
+        /// [`Resource::Failed`](crate::Resource::Failed), according to
+        /// the [`FailurePolicy`](crate::FailurePolicy)
+        recorded: bool,
+    },
+}
+
+impl ResourceOutcome {
+    /// Whether the resource was added to the resource map, including
+    /// recorded failures
+    pub fn is_stored(&self) -> bool {
+        match self {
+            ResourceOutcome::Fetched | ResourceOutcome::Cached => true,
+            ResourceOutcome::Failed { recorded, .. } => *recorded,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ResourceOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceOutcome::Fetched => write!(f, "fetched"),
+            ResourceOutcome::Cached => write!(f, "taken from the cache"),
+            ResourceOutcome::Filtered => {
+                write!(f, "rejected by the resource filter")
+            }
+            ResourceOutcome::Skipped(reason) => {
+                write!(f, "skipped: {}", reason)
+            }
+            ResourceOutcome::Failed { status, recorded } => {
+                write!(f, "failed with status {}", status)?;
+                if *recorded {
+                    write!(f, " (recorded)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_archive_report() {
+        let entry = |path: &str, outcome| ResourceReport {
+            url: Url::parse("http://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            kind: ResourceKind::Image,
+            outcome,
+            duration: Duration::from_millis(1),
+        };
+        let mut report = ArchiveReport {
+            resources: vec![
+                entry("a.png", ResourceOutcome::Fetched),
+                entry("b.png", ResourceOutcome::Cached),
+                entry("c.png", ResourceOutcome::Filtered),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(report.fetched().count(), 2);
+        assert_eq!(report.filtered().count(), 1);
+        assert!(report.is_complete());
+
+        report.resources.push(entry(
+            "d.png",
+            ResourceOutcome::Failed {
+                status: StatusCode::NOT_FOUND,
+                recorded: true,
+            },
+        ));
+        report.resources.push(entry(
+            "e.png",
+            ResourceOutcome::Skipped("dropped by the resource hook".into()),
+        ));
+        assert_eq!(report.failed().count(), 1);
+        assert_eq!(report.skipped().count(), 1);
+        assert!(!report.is_complete());
+
+        let outcomes: Vec<_> = report
+            .resources
+            .iter()
+            .map(|entry| (entry.outcome.is_stored(), entry.outcome.to_string()))
+            .collect();
+        assert_eq!(
+            outcomes[3],
+            (
+                true,
+                "failed with status 404 Not Found (recorded)".to_string()
+            )
+        );
+        assert_eq!(
+            outcomes[4],
+            (false, "skipped: dropped by the resource hook".to_string())
+        );
+    }
+}
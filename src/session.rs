@@ -0,0 +1,1912 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for archiving several pages with shared state
+//!
+//! An [`ArchiveSession`] owns a single client, so connections, cookies
+//! set by the site (e.g. after logging in), and resources shared between
+//! pages are reused across every page archived with it.
+//!
+//! ```no_run
+//! use web_archive::ArchiveSession;
+//!
+//! # async fn archive_async() {
+//! let session = ArchiveSession::new(Default::default()).unwrap();
+//! for url in &["http://example.com/a", "http://example.com/b"] {
+//!     let archive = session.archive(*url).await.unwrap();
+//!     println!("{}", archive.embed_resources());
+//! }
+//! # }
+//! ```
+//!
+//! Pages behind form-based authentication can be archived by listing
+//! the requests needed to log in in
+//! [`ArchiveOptions::login`](crate::ArchiveOptions::login). They are
+//! sent once, before the first page of the session is fetched, and any
+//! cookies they set are used for the rest of the session.
+//!
+//! ```no_run
+//! use web_archive::{ArchiveOptions, ArchiveSession, LoginStep};
+//!
+//! # async fn archive_async() {
+//! let login = LoginStep::post_form(
+//!     "https://example.com/login".parse().unwrap(),
+//!     &[("username", "ferris"), ("password", "hunter2")],
+//! );
+//! let options = ArchiveOptions::builder().login_step(login).build().unwrap();
+//! let session = ArchiveSession::new(options).unwrap();
+//! let archive = session.archive("https://example.com/account").await;
+//! # }
+//! ```
+
+use crate::cookies::CookieJar;
+use crate::css::css_resource_urls;
+use crate::error::Error;
+use crate::extractor::{extract_resource_urls, ResourceExtractor};
+use crate::fetcher::{fetched_page, fetched_resource, FetcherPool};
+use crate::page_archive::{
+    ArchiveInfo, PageArchive, ResourceInfo, ResourceTimings,
+};
+use crate::parsing::{
+    build_failed_resource, build_resource, is_downloaded_status, Resource,
+    ResourceMap, ResourceUrl, TextResource,
+};
+use crate::report::{ArchiveReport, ResourceOutcome, ResourceReport};
+use crate::{
+    client_builder, wayback, ArchiveOptions, FailurePolicy, HeaderCapture,
+};
+use futures::channel::mpsc;
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::{self, Stream, StreamExt};
+use kuchiki::parse_html;
+use kuchiki::traits::TendrilSink;
+use reqwest::cookie::CookieStore;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, COOKIE, HOST,
+};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use url::Url;
+
+/// A request sent before archiving, e.g. to submit a login form
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoginStep {
+    /// HTTP method of the request, serialized as its name, e.g. `"POST"`
+    #[serde(with = "method_serde")]
+    pub method: Method,
+    /// URL to send the request to
+    pub url: Url,
+    /// Fields sent as an `application/x-www-form-urlencoded` body, if
+    /// there are any
+    #[serde(default)]
+    pub form: Vec<(String, String)>,
+    /// Additional headers sent with the request
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+}
+
+impl LoginStep {
+    /// A `GET` request, e.g. to pick up the cookies set by a login page
+    pub fn get(url: Url) -> Self {
+        Self {
+            method: Method::GET,
+            url,
+            form: Vec::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// A `POST` request submitting a form
+    pub fn post_form(url: Url, form: &[(&str, &str)]) -> Self {
+        Self {
+            method: Method::POST,
+            url,
+            form: form
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// Check that the request can be sent, returning
+    /// [`Error::InvalidOptions`] if it cannot
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let invalid = |problem: String| {
+            Err(Error::InvalidOptions(format!(
+                "invalid login step for {}: {}",
+                self.url, problem
+            )))
+        };
+        if !matches!(self.url.scheme(), "http" | "https") {
+            return invalid(format!(
+                "unsupported scheme {}",
+                self.url.scheme()
+            ));
+        }
+        if !self.form.is_empty()
+            && matches!(self.method, Method::GET | Method::HEAD)
+        {
+            return invalid(format!(
+                "{} requests cannot send a form",
+                self.method
+            ));
+        }
+        for (name, value) in &self.headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return invalid(format!("invalid header name {:?}", name));
+            }
+            if HeaderValue::from_str(value).is_err() {
+                return invalid(format!("invalid value for header {}", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Form values and headers often hold credentials, so only their names
+/// are shown to keep them out of logs
+impl std::fmt::Debug for LoginStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = |fields: &[(String, String)]| -> Vec<String> {
+            fields.iter().map(|(name, _)| name.clone()).collect()
+        };
+        f.debug_struct("LoginStep")
+            .field("method", &self.method)
+            .field("url", &self.url.as_str())
+            .field("form", &names(&self.form))
+            .field("headers", &names(&self.headers))
+            .finish()
+    }
+}
+
+/// Check the response to a login step. Redirects have already been
+/// followed unless [`ArchiveOptions::max_redirects`] disables them, in
+/// which case the redirect itself is a success. Anything else is a
+/// failure.
+pub(crate) fn check_login_status(
+    step: &LoginStep,
+    status: StatusCode,
+) -> Result<(), Error> {
+    if status.is_success() || status.is_redirection() {
+        Ok(())
+    } else {
+        Err(Error::HttpStatus {
+            url: step.url.clone(),
+            status,
+        })
+    }
+}
+
+/// The `Content-Type` a response was served with, if it is valid
+pub(crate) fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// The headers a request for `url` is sent with, if they are to be
+/// captured: `Host`, those set on the request, and the `Cookie` which
+/// the client adds from the session's cookie jar. Must be called before
+/// the request is sent, as the response may set cookies.
+pub(crate) fn sent_headers(
+    capture: HeaderCapture,
+    url: &Url,
+    headers: &HeaderMap,
+    cookies: &CookieJar,
+) -> HeaderMap {
+    let mut sent = HeaderMap::new();
+    if capture == HeaderCapture::Off {
+        return sent;
+    }
+    if let Some(host) = url.host_str() {
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        if let Ok(host) = HeaderValue::from_str(&host) {
+            sent.insert(HOST, host);
+        }
+    }
+    sent.extend(headers.clone());
+    if let Some(cookie) = cookies.cookies(url) {
+        sent.insert(COOKIE, cookie);
+    }
+    sent
+}
+
+/// Progress of an archive, reported by
+/// [`archive_stream`](crate::archive_stream)
+#[derive(Debug)]
+pub enum ArchiveEvent {
+    /// The page has been downloaded
+    PageFetched {
+        /// URL of the page, after any redirects
+        url: Url,
+        /// Status with which the page was served, or `200 OK` if it was
+        /// obtained from a custom [`fetcher`](crate::fetcher)
+        status: StatusCode,
+        /// Number of resources the page links to. More may be found in
+        /// its stylesheets as they are downloaded.
+        resources: usize,
+    },
+    /// A resource is about to be fetched
+    ResourceStarted(Url),
+    /// A resource has been dealt with
+    ResourceDone {
+        /// URL of the resource
+        url: Url,
+        /// Whether the resource was added to the archive, rather than
+        /// being skipped or dropped
+        stored: bool,
+    },
+    /// The archive is complete. This is always the last event. The
+    /// archive is boxed, as it is far larger than the other events.
+    Finished(Box<PageArchive>),
+    /// Archiving failed. This is always the last event.
+    Failed(Error),
+}
+
+/// Destination for the events of an archive in progress
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Reporter(Option<mpsc::UnboundedSender<ArchiveEvent>>);
+
+impl Reporter {
+    /// Pass on an event, if anything is listening for them
+    pub(crate) fn report(&self, event: ArchiveEvent) {
+        if let Some(sender) = &self.0 {
+            // Nothing is listening once the receiver has been dropped
+            let _ = sender.unbounded_send(event);
+        }
+    }
+}
+
+/// Run an archiving future which reports its progress to the
+/// [`Reporter`] it is given, producing a stream of its events followed
+/// by the outcome. Every event goes through the same channel, so the
+/// final event always comes last.
+pub(crate) fn event_stream<'a, F, Fut>(
+    archive: F,
+) -> impl Stream<Item = ArchiveEvent> + 'a
+where
+    F: FnOnce(Reporter) -> Fut + 'a,
+    Fut: Future<Output = Result<PageArchive, Error>> + 'a,
+{
+    let (sender, receiver) = mpsc::unbounded();
+    let reporter = Reporter(Some(sender));
+    let drive = async move {
+        let event = match archive(reporter.clone()).await {
+            Ok(archive) => ArchiveEvent::Finished(Box::new(archive)),
+            Err(e) => ArchiveEvent::Failed(e),
+        };
+        reporter.report(event);
+    };
+    stream::select(
+        receiver,
+        stream::once(drive).filter_map(|()| async { None }),
+    )
+}
+
+/// The resources still to be downloaded for a page. Stylesheets are
+/// searched for the fonts, images, and imported stylesheets they
+/// reference once they are downloaded, and the documents shown in frames
+/// for their own resources and frames, which are added to the queue if
+/// they have not been seen before.
+#[derive(Debug)]
+pub(crate) struct ResourceQueue {
+    pending: VecDeque<ResourceUrl>,
+    seen: HashSet<Url>,
+}
+
+impl ResourceQueue {
+    /// Queue the resources found in the page
+    pub(crate) fn new(resource_urls: Vec<ResourceUrl>) -> Self {
+        let seen = resource_urls.iter().map(|u| u.url().clone()).collect();
+        Self {
+            pending: resource_urls.into(),
+            seen,
+        }
+    }
+
+    /// The next resource to download
+    pub(crate) fn next(&mut self) -> Option<ResourceUrl> {
+        self.pending.pop_front()
+    }
+
+    /// Queue the resources referenced by a downloaded resource. Frame
+    /// documents are searched by the built-in extractors followed by
+    /// `extractors`, as the page is.
+    pub(crate) fn discover(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+        extractors: &[Arc<dyn ResourceExtractor>],
+    ) {
+        let resource_urls = match resource {
+            Resource::Css(css) => css_resource_urls(url, css.text()),
+            Resource::Html(html) => extract_resource_urls(
+                &parse_html().one(html.text()),
+                url,
+                extractors,
+            ),
+            _ => return,
+        };
+        for resource_url in resource_urls {
+            if self.seen.insert(resource_url.url().clone()) {
+                self.pending.push_back(resource_url);
+            }
+        }
+    }
+}
+
+/// Whether the map holds a successfully downloaded copy of the resource
+pub(crate) fn is_downloaded(resource_map: &ResourceMap, url: &Url) -> bool {
+    resource_map
+        .get(url)
+        .is_some_and(|resource| resource.kind().is_some())
+}
+
+/// Outcome of fetching a resource: what to store in the archive, or what
+/// became of the resource instead
+pub(crate) type Fetch = Result<(Url, Resource, ResourceInfo), ResourceOutcome>;
+
+/// Resources downloaded by a session, keyed by their URL
+#[derive(Debug, Default)]
+pub(crate) struct ResourceCache(Mutex<HashMap<Url, (Resource, ResourceInfo)>>);
+
+impl ResourceCache {
+    /// Look up a previously downloaded resource
+    pub(crate) fn get(&self, url: &Url) -> Option<(Resource, ResourceInfo)> {
+        self.0.lock().unwrap().get(url).cloned()
+    }
+
+    /// Remember a downloaded resource. Failed resources are not cached,
+    /// so that they are retried for the next page.
+    pub(crate) fn insert(
+        &self,
+        url: Url,
+        resource: &Resource,
+        info: &ResourceInfo,
+    ) {
+        if !matches!(resource, Resource::Failed { .. }) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(url, (resource.clone(), info.clone()));
+        }
+    }
+
+    /// Forget every cached resource
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// A lock for each resource URL, held while the resource is obtained so
+/// that pages archived at the same time wait for each other rather than
+/// downloading a shared resource more than once
+pub(crate) struct DownloadLocks<M>(Mutex<HashMap<Url, Arc<M>>>);
+
+impl<M> Default for DownloadLocks<M> {
+    fn default() -> Self {
+        Self(Mutex::default())
+    }
+}
+
+impl<M: Default> DownloadLocks<M> {
+    /// The lock for the resource at the URL
+    pub(crate) fn get(&self, url: &Url) -> Arc<M> {
+        Arc::clone(self.0.lock().unwrap().entry(url.clone()).or_default())
+    }
+
+    /// Forget the locks along with the cached resources
+    pub(crate) fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// The requests which may be sent to one host at the same time
+pub(crate) trait HostSlots {
+    /// Slots for `count` requests, all of them free
+    fn with_capacity(count: usize) -> Self;
+}
+
+/// Limits the requests a session sends to each host at the same time to
+/// [`ArchiveOptions::max_connections_per_host`]
+pub(crate) struct HostLimits<S> {
+    max: Option<usize>,
+    hosts: Mutex<HashMap<String, Arc<S>>>,
+}
+
+impl<S: HostSlots> HostLimits<S> {
+    pub(crate) fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// The slots for the host of the URL, if requests are limited
+    pub(crate) fn slots(&self, url: &Url) -> Option<Arc<S>> {
+        let max = self.max?;
+        let host = url.host_str()?;
+        let mut hosts = self.hosts.lock().unwrap();
+        let slots = hosts
+            .entry(host.to_string())
+            .or_insert_with(|| Arc::new(S::with_capacity(max)));
+        Some(Arc::clone(slots))
+    }
+}
+
+/// Requests to one host for the async API. Each free slot is a token in
+/// a queue, so that waiting requests are sent in turn.
+pub(crate) struct AsyncSlots {
+    free: AsyncMutex<mpsc::UnboundedReceiver<()>>,
+    returned: mpsc::UnboundedSender<()>,
+}
+
+impl HostSlots for AsyncSlots {
+    fn with_capacity(count: usize) -> Self {
+        let (returned, free) = mpsc::unbounded();
+        for _ in 0..count {
+            let _ = returned.unbounded_send(());
+        }
+        Self {
+            free: AsyncMutex::new(free),
+            returned,
+        }
+    }
+}
+
+/// A slot taken from [`AsyncSlots`], which is freed when dropped
+pub(crate) struct AsyncSlot(mpsc::UnboundedSender<()>);
+
+impl Drop for AsyncSlot {
+    fn drop(&mut self) {
+        let _ = self.0.unbounded_send(());
+    }
+}
+
+impl HostLimits<AsyncSlots> {
+    /// Wait for a free slot for a request to the host of the URL
+    pub(crate) async fn acquire(&self, url: &Url) -> Option<AsyncSlot> {
+        let slots = self.slots(url)?;
+        // The sender is held by the slots, so the queue never ends
+        slots.free.lock().await.next().await;
+        Some(AsyncSlot(slots.returned.clone()))
+    }
+}
+
+/// Reusable state for archiving many pages with the same options
+pub struct ArchiveSession {
+    client: reqwest::Client,
+    cookies: Arc<CookieJar>,
+    cache: ResourceCache,
+    downloading: DownloadLocks<AsyncMutex<()>>,
+    host_limits: HostLimits<AsyncSlots>,
+    logged_in: AsyncMutex<bool>,
+    fetcher_pool: FetcherPool,
+    options: ArchiveOptions,
+}
+
+impl ArchiveSession {
+    /// Create a session, building a client from the options
+    pub fn new(options: ArchiveOptions) -> Result<Self, Error> {
+        let cookies = Arc::new(CookieJar::default());
+        let client = client_builder(&options)?
+            .cookie_provider(Arc::clone(&cookies))
+            .build()?;
+        Ok(Self {
+            client,
+            cookies,
+            cache: Default::default(),
+            downloading: Default::default(),
+            host_limits: HostLimits::new(options.max_connections_per_host),
+            logged_in: AsyncMutex::new(false),
+            // Each page being archived fetches one resource at a time
+            fetcher_pool: FetcherPool::new(options.concurrency()),
+            options,
+        })
+    }
+
+    /// Send the requests in [`ArchiveOptions::login`], if they have not
+    /// been sent already. This is done automatically before the first
+    /// page is fetched. Concurrent callers wait for the first to finish
+    /// rather than sending the requests again.
+    pub async fn login(&self) -> Result<(), Error> {
+        let mut logged_in = self.logged_in.lock().await;
+        if *logged_in {
+            return Ok(());
+        }
+        for step in &self.options.login {
+            let mut request =
+                self.client.request(step.method.clone(), step.url.clone());
+            for (name, value) in &step.headers {
+                request = request.header(name.as_str(), value.as_str());
+            }
+            if !step.form.is_empty() {
+                request = request.form(&step.form);
+            }
+            let response = request.send().await?;
+            check_login_status(step, response.status())?;
+        }
+        *logged_in = true;
+        Ok(())
+    }
+
+    /// The options used for every archive in the session
+    pub fn options(&self) -> &ArchiveOptions {
+        &self.options
+    }
+
+    /// The cookie jar shared by every request in the session. Cookies
+    /// can be added to it, for example to reuse a login from a browser,
+    /// or saved and loaded between runs, see the
+    /// [`cookies`](crate::cookies) module.
+    pub fn cookies(&self) -> &Arc<CookieJar> {
+        &self.cookies
+    }
+
+    /// The underlying client
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Forget the resources downloaded so far, so that they are fetched
+    /// again for the next page
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+        self.downloading.clear();
+    }
+
+    /// Download a page and its resources, as with [`archive`](crate::archive).
+    /// Resources which were downloaded for an earlier page in the session
+    /// are taken from the cache.
+    pub async fn archive<U>(&self, url: U) -> Result<PageArchive, Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        self.archive_with_report(url)
+            .await
+            .map(|(archive, _)| archive)
+    }
+
+    /// Archive a page, reporting progress as a stream of events, as with
+    /// [`archive_stream`](crate::archive_stream)
+    pub fn archive_stream<U>(
+        &self,
+        url: U,
+    ) -> impl Stream<Item = ArchiveEvent> + '_
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let url = url
+            .try_into()
+            .map_err(|e| Error::ParseError(format!("{}", e)));
+        event_stream(move |reporter| async move {
+            self.archive_reporting(url?, reporter)
+                .await
+                .map(|(archive, _)| archive)
+        })
+    }
+
+    /// Archive a page, passing each [`ArchiveEvent`] other than the
+    /// final one to the reporter
+    pub(crate) async fn archive_reporting(
+        &self,
+        url: Url,
+        reporter: Reporter,
+    ) -> Result<(PageArchive, ArchiveReport), Error> {
+        self.login().await?;
+
+        // Fetch the page contents, using the final URL after any redirects
+        // as the base for resolving the page's resources
+        let started = Instant::now();
+        let fetched_at = SystemTime::now();
+        let (url, page, body) = self.fetch_page(url).await?;
+        self.archive_page(url, page, body, fetched_at, started, reporter)
+            .await
+    }
+
+    /// Download a page and its resources, as with
+    /// [`archive_with_report`](crate::archive_with_report), also
+    /// returning what became of each resource
+    pub async fn archive_with_report<U>(
+        &self,
+        url: U,
+    ) -> Result<(PageArchive, ArchiveReport), Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let url: Url = url
+            .try_into()
+            .map_err(|e| Error::ParseError(format!("{}", e)))?;
+        self.archive_reporting(url, Reporter::default()).await
+    }
+
+    /// Archive a page from a response which has already been received,
+    /// as with [`archive_from_response`](crate::archive_from_response).
+    /// Only the resources are fetched with the session's client.
+    pub async fn archive_from_response(
+        &self,
+        response: reqwest::Response,
+    ) -> Result<PageArchive, Error> {
+        self.login().await?;
+
+        let started = Instant::now();
+        let fetched_at = SystemTime::now();
+        let url = response.url().clone();
+        // The request was sent by the caller, so its headers are unknown
+        let page = ResourceInfo {
+            status: Some(response.status()),
+            content_type: content_type(response.headers()),
+            headers: self.options.capture_headers.capture(
+                &HeaderMap::new(),
+                response.version(),
+                response.headers(),
+            ),
+            ..Default::default()
+        };
+        let data = response.bytes().await?;
+        let body = TextResource::decode(data, page.content_type.as_deref());
+        self.archive_page(
+            url,
+            page,
+            body,
+            fetched_at,
+            started,
+            Reporter::default(),
+        )
+        .await
+        .map(|(archive, _)| archive)
+    }
+
+    /// Download the resources of a fetched page and build its archive
+    /// and report. `url` is the page's URL after any redirects, used as
+    /// the base for resolving its resources, `page` what was recorded as
+    /// it was fetched, `body` its contents, and `started` when fetching
+    /// the page began.
+    async fn archive_page(
+        &self,
+        url: Url,
+        page: ResourceInfo,
+        body: TextResource,
+        fetched_at: SystemTime,
+        started: Instant,
+        reporter: Reporter,
+    ) -> Result<(PageArchive, ArchiveReport), Error> {
+        let options = &self.options;
+        let status = page.status;
+        if let Some(status) = status {
+            if options.error_on_http_failure
+                && !(status.is_success() || status.is_redirection())
+            {
+                return Err(Error::HttpStatus { url, status });
+            }
+        }
+        let mut info = ArchiveInfo {
+            page,
+            ..Default::default()
+        };
+        info.page.digest = Some(options.digest_algorithm.digest(&body.data));
+        let content = body.text().to_string();
+
+        // Determine the resources that the page needs
+        let resource_urls = options.resource_urls(&url, &content);
+        reporter.report(ArchiveEvent::PageFetched {
+            url: url.clone(),
+            status: status.unwrap_or(StatusCode::OK),
+            resources: resource_urls.len(),
+        });
+
+        // Download them, along with the resources their stylesheets need
+        let mut report = ArchiveReport {
+            page_duration: started.elapsed(),
+            ..Default::default()
+        };
+        let mut resource_map = ResourceMap::new();
+        let mut resident = 0;
+        let attachments = options.limited_attachments(&url, &content);
+        let mut queue = ResourceQueue::new(resource_urls);
+        while let Some(resource_url) = queue.next() {
+            let resource = resource_url.url().clone();
+            let kind = resource_url.kind();
+            reporter.report(ArchiveEvent::ResourceStarted(resource.clone()));
+            let resource_started = Instant::now();
+            let mut outcome = self
+                .archive_resource(
+                    resource_url,
+                    &mut info,
+                    &mut resource_map,
+                    &mut resident,
+                )
+                .await?;
+            if attachments.contains(&resource) {
+                outcome = options.limit_attachment(
+                    &resource,
+                    outcome,
+                    &mut info,
+                    &mut resource_map,
+                );
+            }
+            if let Some(stored) = resource_map.get(&resource) {
+                queue.discover(
+                    &resource,
+                    stored,
+                    &self.options.resource_extractors,
+                );
+            }
+            reporter.report(ArchiveEvent::ResourceDone {
+                url: resource.clone(),
+                stored: outcome.is_stored(),
+            });
+            report.resources.push(ResourceReport {
+                url: resource,
+                kind,
+                outcome,
+                duration: resource_started.elapsed(),
+            });
+        }
+        report.total_duration = started.elapsed();
+
+        let archive = PageArchive {
+            url,
+            content,
+            raw_content: Some(body.data),
+            resource_map,
+            fetched_at,
+            info,
+            metadata_cache: Default::default(),
+        };
+        Ok((archive, report))
+    }
+
+    /// Fetch a page with a custom fetcher or the HTTP client, returning
+    /// its URL after any redirects, the status it was served with if it
+    /// came over HTTP, and its contents
+    async fn fetch_page(
+        &self,
+        url: Url,
+    ) -> Result<(Url, ResourceInfo, TextResource), Error> {
+        if let Some(fetcher) = self.options.fetcher_for(&url) {
+            let fetched = self.fetcher_pool.fetch(fetcher, &url).await?;
+            let (page, body) = fetched_page(&url, fetched)?;
+            return Ok((url, page, body));
+        }
+        let _slot = self.host_limits.acquire(&url).await;
+        let request = self.client.get(url).build()?;
+        let request_headers = self.sent_headers(&request);
+        let sent = Instant::now();
+        let response = self.client.execute(request).await?;
+        let first_byte = Instant::now();
+        let url = response.url().clone();
+        let mut page = ResourceInfo {
+            status: Some(response.status()),
+            content_type: content_type(response.headers()),
+            headers: self.options.capture_headers.capture(
+                &request_headers,
+                response.version(),
+                response.headers(),
+            ),
+            ..Default::default()
+        };
+        let data = response.bytes().await?;
+        page.timings = Some(ResourceTimings::since(sent, first_byte));
+        let body = TextResource::decode(data, page.content_type.as_deref());
+        Ok((url, page, body))
+    }
+
+    /// The headers a request is sent with, if they are to be captured
+    fn sent_headers(&self, request: &reqwest::Request) -> HeaderMap {
+        sent_headers(
+            self.options.capture_headers,
+            request.url(),
+            request.headers(),
+            &self.cookies,
+        )
+    }
+
+    /// Try again to download the
+    /// [`missing_resources`](PageArchive::missing_resources) of an
+    /// archive, e.g. those which failed because a host was unavailable,
+    /// without fetching the page or any of its other resources. Resources
+    /// referenced by newly downloaded stylesheets are fetched too.
+    ///
+    /// Returns the number of resources added to the archive. Resources
+    /// which fail again are handled according to the session's
+    /// [`FailurePolicy`], so with `Abort` an error is returned, keeping
+    /// the resources downloaded before it.
+    ///
+    /// ```no_run
+    /// use web_archive::ArchiveSession;
+    ///
+    /// # async fn archive_async() {
+    /// let session = ArchiveSession::new(Default::default()).unwrap();
+    /// let mut archive = session.archive("http://example.com").await.unwrap();
+    /// // Later, once the flaky host has recovered
+    /// let added = session.refetch_missing(&mut archive).await.unwrap();
+    /// println!("{} resources added", added);
+    /// # }
+    /// ```
+    pub async fn refetch_missing(
+        &self,
+        archive: &mut PageArchive,
+    ) -> Result<usize, Error> {
+        self.login().await?;
+
+        let mut queue = ResourceQueue::new(archive.missing_resources());
+        let mut resident = archive.resource_map.resident_size();
+        let mut added = 0;
+        while let Some(resource_url) = queue.next() {
+            let url = resource_url.url().clone();
+            if is_downloaded(&archive.resource_map, &url) {
+                continue;
+            }
+            self.archive_resource(
+                resource_url,
+                &mut archive.info,
+                &mut archive.resource_map,
+                &mut resident,
+            )
+            .await?;
+            if let Some(resource) = archive.resource_map.get(&url) {
+                queue.discover(
+                    &url,
+                    resource,
+                    &self.options.resource_extractors,
+                );
+            }
+            if is_downloaded(&archive.resource_map, &url) {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Obtain a resource from the cache, a custom fetcher, or the HTTP
+    /// client and add it to the archive, returning what became of it
+    async fn archive_resource(
+        &self,
+        resource_url: ResourceUrl,
+        info: &mut ArchiveInfo,
+        resource_map: &mut ResourceMap,
+        resident: &mut usize,
+    ) -> Result<ResourceOutcome, Error> {
+        let options = &self.options;
+        if !options.should_fetch(&resource_url) {
+            return Ok(ResourceOutcome::Filtered);
+        }
+        // Another page may be downloading the resource, in which case it
+        // is taken from the cache once that page has finished
+        let downloading = self.downloading.get(resource_url.url());
+        let _downloading = downloading.lock().await;
+        if let Some((resource, resource_info)) =
+            self.cache.get(resource_url.url())
+        {
+            let resource = options.apply_memory_budget(resource, resident)?;
+            info.resources
+                .insert(resource_url.url().clone(), resource_info);
+            resource_map.insert(resource_url.url().clone(), resource);
+            return Ok(ResourceOutcome::Cached);
+        }
+
+        let fetched = match options.fetcher_for(resource_url.url()) {
+            Some(fetcher) => {
+                let fetched = self
+                    .fetcher_pool
+                    .fetch(fetcher, resource_url.url())
+                    .await?;
+                fetched_resource(resource_url, fetched, options)?
+            }
+            None => self.fetch_http(resource_url).await?,
+        };
+        let (u, resource, resource_info) = match fetched {
+            Ok(fetched) => fetched,
+            Err(outcome) => return Ok(outcome),
+        };
+        let resource = match options.process_resource(&u, resource) {
+            Some(resource) => resource,
+            None => {
+                return Ok(ResourceOutcome::Skipped(
+                    "dropped by the resource hook".to_string(),
+                ))
+            }
+        };
+        let outcome = match &resource {
+            Resource::Failed { status, .. } => ResourceOutcome::Failed {
+                status: *status,
+                recorded: true,
+            },
+            _ => ResourceOutcome::Fetched,
+        };
+        let resource = options.apply_memory_budget(resource, resident)?;
+        self.cache.insert(u.clone(), &resource, &resource_info);
+        info.resources.insert(u.clone(), resource_info);
+        resource_map.insert(u, resource);
+        Ok(outcome)
+    }
+
+    /// Download a resource with the HTTP client, falling back to the
+    /// Wayback Machine and applying the failure policy as configured
+    async fn fetch_http(
+        &self,
+        resource_url: ResourceUrl,
+    ) -> Result<Fetch, Error> {
+        let options = &self.options;
+        let client = &self.client;
+        let _slot = self.host_limits.acquire(resource_url.url()).await;
+        let request = client.get(resource_url.url().clone()).build()?;
+        let request_headers = self.sent_headers(&request);
+        let sent = Instant::now();
+        let result = client.execute(request).await;
+        let first_byte = Instant::now();
+        // A response from the Wayback Machine comes from other requests,
+        // so no timings are recorded for it
+        let (response, first_byte) = match result {
+            Ok(response) if is_downloaded_status(response.status()) => {
+                (response, Some(first_byte))
+            }
+            result => {
+                // Fall back to the Wayback Machine if allowed
+                let fallback = if options.wayback_fallback {
+                    wayback::fetch(client, resource_url.url()).await
+                } else {
+                    None
+                };
+                match (fallback, result) {
+                    (Some(response), _) => (response, None),
+                    (None, Err(e)) => return Err(e.into()),
+                    (None, Ok(response)) => {
+                        match options.failure_policy(resource_url.kind()) {
+                            FailurePolicy::Skip => {
+                                return Ok(Err(ResourceOutcome::Failed {
+                                    status: response.status(),
+                                    recorded: false,
+                                }))
+                            }
+                            FailurePolicy::Record => {
+                                (response, Some(first_byte))
+                            }
+                            FailurePolicy::Abort => {
+                                return Err(Error::HttpStatus {
+                                    url: resource_url.url().clone(),
+                                    status: response.status(),
+                                })
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let content_type = content_type(response.headers());
+        let headers = options.capture_headers.capture(
+            &request_headers,
+            response.version(),
+            response.headers(),
+        );
+        let status = response.status();
+        let data = response.bytes().await?;
+        let timings = first_byte
+            .map(|first_byte| ResourceTimings::since(sent, first_byte));
+        let digest = options.digest_algorithm.digest(&data);
+        let (u, resource) = if is_downloaded_status(status) {
+            build_resource(resource_url, data, content_type.as_deref())
+        } else {
+            build_failed_resource(
+                resource_url,
+                status,
+                data,
+                content_type.as_deref(),
+            )
+        };
+        let resource_info = ResourceInfo {
+            digest: Some(digest),
+            status: Some(status),
+            timings,
+            content_type,
+            headers,
+        };
+        Ok(Ok((u, resource, resource_info)))
+    }
+
+    /// Archive several pages, as with [`archive_all`](crate::archive_all)
+    pub async fn archive_all<I, U>(
+        &self,
+        urls: I,
+    ) -> Vec<Result<PageArchive, Error>>
+    where
+        I: IntoIterator<Item = U>,
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        stream::iter(urls)
+            .map(|url| self.archive(url))
+            .buffered(self.options.concurrency())
+            .collect()
+            .await
+    }
+
+    /// List the resources of a page without downloading them, as with
+    /// [`list_resources`](crate::list_resources)
+    pub async fn list_resources<U>(
+        &self,
+        url: U,
+    ) -> Result<Vec<ResourceUrl>, Error>
+    where
+        U: TryInto<Url>,
+        <U as TryInto<Url>>::Error: Display,
+    {
+        let url: Url = url
+            .try_into()
+            .map_err(|e| Error::ParseError(format!("{}", e)))?;
+
+        self.login().await?;
+        let (url, _, body) = self.fetch_page(url).await?;
+
+        Ok(self.options.resource_urls(&url, body.text()))
+    }
+}
+
+/// Serde helpers for HTTP methods, which reqwest does not provide
+mod method_serde {
+    use reqwest::Method;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        method: &Method,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(method.as_str())
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Method, D::Error> {
+        let method = String::deserialize(deserializer)?;
+        method.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::extractor::AttributeExtractor;
+    use crate::fetcher::{FetchedResource, FnFetcher};
+    use crate::{ArchiveOptionsBuilder, EmbedOptions, ResourceKind};
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll, Wake};
+    use std::time::Duration;
+    use tokio_test::block_on;
+
+    #[test]
+    fn test_resource_cache() {
+        let cache = ResourceCache::default();
+        let url = Url::parse("http://example.com/a.css").unwrap();
+        let info = ResourceInfo {
+            status: Some(StatusCode::OK),
+            ..Default::default()
+        };
+        cache.insert(url.clone(), &Resource::Css("body {}".into()), &info);
+        assert_eq!(
+            cache.get(&url),
+            Some((Resource::Css("body {}".into()), info))
+        );
+
+        cache.clear();
+        assert!(cache.get(&url).is_none());
+
+        let failed = Resource::Failed {
+            status: StatusCode::NOT_FOUND,
+            data: Default::default(),
+            mimetype: "text/html".to_string(),
+        };
+        cache.insert(url.clone(), &failed, &Default::default());
+        assert!(cache.get(&url).is_none());
+    }
+
+    #[test]
+    fn test_sent_headers() {
+        let url = Url::parse("http://example.com:8080/a.css").unwrap();
+        let cookies = CookieJar::default();
+        cookies.add_cookie_str("session=ferris", &url);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-test", HeaderValue::from_static("1"));
+
+        assert!(sent_headers(HeaderCapture::Off, &url, &headers, &cookies)
+            .is_empty());
+        let sent = sent_headers(HeaderCapture::Full, &url, &headers, &cookies);
+        let sent: Vec<_> = sent
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("host", "example.com:8080"),
+                ("x-test", "1"),
+                ("cookie", "session=ferris"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_login_step() {
+        let url = Url::parse("https://example.com/login").unwrap();
+        let step = LoginStep::post_form(url.clone(), &[("user", "ferris")]);
+        assert_eq!(step.method, Method::POST);
+        assert_eq!(step.form, vec![("user".to_string(), "ferris".to_string())]);
+        let mut with_header = step.clone();
+        with_header
+            .headers
+            .push(("Authorization".to_string(), "Bearer token".to_string()));
+        assert_eq!(
+            format!("{:?}", with_header),
+            r#"LoginStep { method: POST, url: "https://example.com/login", form: ["user"], headers: ["Authorization"] }"#
+        );
+
+        assert!(check_login_status(&step, StatusCode::OK).is_ok());
+        assert!(check_login_status(&step, StatusCode::SEE_OTHER).is_ok());
+        assert!(matches!(
+            check_login_status(&step, StatusCode::UNAUTHORIZED),
+            Err(Error::HttpStatus { url: u, status: StatusCode::UNAUTHORIZED })
+                if u == url
+        ));
+
+        assert!(step.validate().is_ok());
+        let mut invalid = step.clone();
+        invalid
+            .headers
+            .push(("X Bad".to_string(), "value".to_string()));
+        assert!(matches!(invalid.validate(), Err(Error::InvalidOptions(_))));
+        let mut invalid = step;
+        invalid.method = Method::GET;
+        assert!(matches!(invalid.validate(), Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn test_login_once() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let logins = Arc::new(AtomicUsize::new(0));
+        let server_logins = Arc::clone(&logins);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let length = stream.read(&mut request).unwrap();
+                if request[..length].starts_with(b"GET /login ") {
+                    server_logins.fetch_add(1, Ordering::SeqCst);
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                          Content-Length: 9\r\nConnection: close\r\n\r\n\
+                          <p>Hi</p>",
+                    )
+                    .unwrap();
+            }
+        });
+
+        let login = Url::parse(&format!("{}/login", origin)).unwrap();
+        let options = ArchiveOptions::builder()
+            .login_step(LoginStep::get(login))
+            .concurrency(4)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let pages = (0..4)
+            .map(|page| Url::parse(&format!("{}/{}", origin, page)).unwrap());
+        let archives = tokio_test::block_on(session.archive_all(pages));
+        assert_eq!(archives.len(), 4);
+        assert!(archives.iter().all(Result::is_ok));
+        assert_eq!(logins.load(Ordering::SeqCst), 1);
+        let page = &archives[0].as_ref().unwrap().info.page;
+        assert!(page.timings.is_some());
+    }
+
+    /// Serve pages which all link `/style.css`, which is slow to respond
+    /// so that the pages ask for it at the same time, returning the
+    /// origin and the number of requests for the stylesheet
+    pub(crate) fn serve_shared_style() -> (String, Arc<AtomicUsize>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server_hits = Arc::clone(&hits);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let hits = Arc::clone(&server_hits);
+                std::thread::spawn(move || {
+                    let mut stream = stream.unwrap();
+                    let mut request = [0; 1024];
+                    let length = stream.read(&mut request).unwrap();
+                    let response: &[u8] =
+                        if request[..length].starts_with(b"GET /style.css ") {
+                            hits.fetch_add(1, Ordering::SeqCst);
+                            std::thread::sleep(Duration::from_millis(200));
+                            b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\n\
+                          Content-Length: 7\r\nConnection: close\r\n\r\n\
+                          body {}"
+                        } else {
+                            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                          Content-Length: 36\r\nConnection: close\r\n\r\n\
+                          <link rel=stylesheet href=style.css>"
+                        };
+                    stream.write_all(response).unwrap();
+                });
+            }
+        });
+        (origin, hits)
+    }
+
+    /// Serve slow pages which each link their own stylesheet, returning
+    /// the origin and the largest number of requests handled at once
+    pub(crate) fn serve_slowly() -> (String, Arc<AtomicUsize>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let origin = format!("http://{}", listener.local_addr().unwrap());
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let server_max_active = Arc::clone(&max_active);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let active = Arc::clone(&active);
+                let max_active = Arc::clone(&server_max_active);
+                std::thread::spawn(move || {
+                    let mut stream = stream.unwrap();
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request).unwrap();
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(50));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                              Content-Length: 36\r\nConnection: close\r\n\
+                              \r\n<link rel=stylesheet href=style.css>",
+                        )
+                        .unwrap();
+                });
+            }
+        });
+        (origin, max_active)
+    }
+
+    #[test]
+    fn test_max_connections_per_host() {
+        let (origin, max_active) = serve_slowly();
+        let options = ArchiveOptions::builder()
+            .concurrency(4)
+            .max_connections_per_host(1)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let pages = (0..4).map(|page| {
+            Url::parse(&format!("{}/{}/index.html", origin, page)).unwrap()
+        });
+        let archives = tokio_test::block_on(session.archive_all(pages));
+        assert!(archives.iter().all(Result::is_ok));
+        assert_eq!(max_active.load(Ordering::SeqCst), 1);
+
+        assert!(matches!(
+            ArchiveOptions::builder()
+                .max_connections_per_host(0)
+                .build(),
+            Err(Error::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_shared_resource_once() {
+        let (origin, hits) = serve_shared_style();
+        let options = ArchiveOptions::builder().concurrency(4).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let pages = (0..4)
+            .map(|page| Url::parse(&format!("{}/{}", origin, page)).unwrap());
+        let archives = tokio_test::block_on(session.archive_all(pages));
+        let style = Url::parse(&format!("{}/style.css", origin)).unwrap();
+        for archive in archives {
+            assert!(archive.unwrap().resource_map.get_css(&style).is_some());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    fn asset_archive(
+        options: ArchiveOptionsBuilder,
+    ) -> (ArchiveSession, PageArchive) {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/a.css" => {
+                    Ok(Some(FetchedResource::new("a { b: url(a.png) }")))
+                }
+                "/a.png" => Ok(Some(FetchedResource::new("png"))),
+                _ => Ok(None),
+            },
+        );
+        let options = options.fetcher(fetcher).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            Url::parse("asset://app/a.css").unwrap(),
+            Resource::Failed {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                data: Bytes::new(),
+                mimetype: "text/html".to_string(),
+            },
+        );
+        let archive = PageArchive::from_parts(
+            "asset://app/",
+            r#"<link rel="stylesheet" href="a.css"><script src="b.js"></script>"#,
+            resource_map,
+        )
+        .unwrap();
+        (session, archive)
+    }
+
+    #[test]
+    fn test_refetch_missing() {
+        let (session, mut archive) = asset_archive(ArchiveOptions::builder());
+        assert_eq!(archive.missing_resources().len(), 2);
+
+        let added = block_on(session.refetch_missing(&mut archive)).unwrap();
+        assert_eq!(added, 2);
+        let css = Url::parse("asset://app/a.css").unwrap();
+        assert!(archive.resource_map.get_css(&css).is_some());
+        assert!(archive.info.resources[&css].digest.is_some());
+        assert!(archive
+            .resource_map
+            .contains_key(&Url::parse("asset://app/a.png").unwrap()));
+        // The script is still not available
+        assert_eq!(archive.missing_resources().len(), 1);
+        assert_eq!(block_on(archive.refetch_missing(&session)).unwrap(), 0);
+    }
+
+    /// Run a future on the current thread without any async runtime, to
+    /// check that nothing depends on one
+    fn block_on_without_runtime<F: Future>(future: F) -> F::Output {
+        struct ThreadWaker(std::thread::Thread);
+        impl Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let mut future = Box::pin(future);
+        let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_archive_without_runtime() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/" => Ok(Some(FetchedResource {
+                    data: Bytes::from_static(
+                        b"<link rel=stylesheet href=a.css><img src=a.png>",
+                    ),
+                    content_type: Some("text/html; charset=utf-8".to_string()),
+                    status: None,
+                })),
+                "/a.css" => Ok(Some(FetchedResource::new("a {}"))),
+                _ => Ok(None),
+            },
+        );
+        let options =
+            ArchiveOptions::builder().fetcher(fetcher).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+
+        let archive =
+            block_on_without_runtime(session.archive("asset://app/")).unwrap();
+        assert!(archive.info.page.status.is_none());
+        assert!(archive.info.page.digest.is_some());
+        assert_eq!(archive.resource_map.len(), 1);
+        assert_eq!(
+            block_on_without_runtime(session.list_resources("asset://app/"))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(matches!(
+            block_on_without_runtime(session.archive("asset://app/missing")),
+            Err(Error::IoError(_))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        block_on_without_runtime(archive.write_to_disk_async(&dir.path()))
+            .unwrap();
+        assert!(dir.path().join("index.html").exists());
+    }
+
+    #[test]
+    fn test_raw_content() {
+        use reqwest::ResponseBuilderExt;
+
+        let session = ArchiveSession::new(Default::default()).unwrap();
+        let body: &[u8] = b"<html><p>caf\xe9</p></html>";
+        let response = http::Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=iso-8859-1")
+            .url(Url::parse("http://example.com/").unwrap())
+            .body(body)
+            .unwrap();
+        let archive = block_on(
+            session.archive_from_response(reqwest::Response::from(response)),
+        )
+        .unwrap();
+        assert_eq!(archive.content, "<html><p>café</p></html>");
+        assert_eq!(archive.raw_content.as_deref(), Some(body));
+        assert_eq!(
+            archive.info.page.content_type.as_deref(),
+            Some("text/html; charset=iso-8859-1")
+        );
+        assert!(archive.info.page.digest.unwrap().matches(body));
+    }
+
+    #[test]
+    fn test_archive_from_response() {
+        use reqwest::ResponseBuilderExt;
+
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |_| Ok(Some(FetchedResource::new("a {}"))),
+        );
+        let options =
+            ArchiveOptions::builder().fetcher(fetcher).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let response = |status| {
+            let response = http::Response::builder()
+                .status(status)
+                .url(Url::parse("asset://app/blog/").unwrap())
+                .body("<link rel=stylesheet href=a.css>")
+                .unwrap();
+            reqwest::Response::from(response)
+        };
+
+        let archive =
+            block_on(session.archive_from_response(response(200))).unwrap();
+        assert_eq!(archive.url.as_str(), "asset://app/blog/");
+        assert_eq!(archive.info.page.status, Some(StatusCode::OK));
+        assert!(archive
+            .resource_map
+            .get_css(&Url::parse("asset://app/blog/a.css").unwrap())
+            .is_some());
+
+        let options = ArchiveOptions::builder()
+            .error_on_http_failure(true)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        assert!(matches!(
+            block_on(session.archive_from_response(response(404))),
+            Err(Error::HttpStatus { .. })
+        ));
+    }
+
+    #[test]
+    fn test_archive_with_report() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| {
+                match url.path() {
+                "/" => Ok(Some(FetchedResource::new(
+                    "<link rel=stylesheet href=a.css><script src=a.js></script>\
+                     <img src=missing.png>",
+                ))),
+                "/a.css" => Ok(Some(FetchedResource::new("a { b: url(a.png) }"))),
+                "/a.png" => Ok(Some(FetchedResource::new("png"))),
+                _ => Ok(None),
+            }
+            },
+        );
+        let options = ArchiveOptions::builder()
+            .fetcher(fetcher)
+            .resource_filter(|resource_url| {
+                resource_url.kind() != ResourceKind::Javascript
+            })
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let (archive, report) =
+            block_on(session.archive_with_report("asset://app/")).unwrap();
+        assert_eq!(archive.resource_map.len(), 2);
+
+        let outcomes: Vec<_> = report
+            .resources
+            .iter()
+            .map(|entry| (entry.url.path(), entry.kind, entry.outcome.clone()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("/a.css", ResourceKind::Css, ResourceOutcome::Fetched),
+                ("/a.js", ResourceKind::Javascript, ResourceOutcome::Filtered),
+                (
+                    "/missing.png",
+                    ResourceKind::Image,
+                    ResourceOutcome::Skipped(
+                        "no content from the fetcher".to_string()
+                    )
+                ),
+                ("/a.png", ResourceKind::Other, ResourceOutcome::Fetched),
+            ]
+        );
+        assert!(!report.is_complete());
+        assert!(report.total_duration >= report.page_duration);
+
+        // Resources are taken from the cache for the next page
+        let (_, report) =
+            block_on(session.archive_with_report("asset://app/")).unwrap();
+        assert_eq!(report.resources[0].outcome, ResourceOutcome::Cached);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/" => Ok(Some(FetchedResource::new(
+                    "<link rel=stylesheet href=a.css>\
+                     <img src=a.png><img src=b.png><img src=c.png>",
+                ))),
+                "/a.css" => Ok(Some(FetchedResource::new("a {}"))),
+                path => Ok(Some(FetchedResource::new(format!("{:>10}", path)))),
+            },
+        );
+        let options = ArchiveOptions::builder()
+            .fetcher(fetcher)
+            .memory_budget(15)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let archive = block_on(session.archive("asset://app/")).unwrap();
+
+        let spilled: Vec<_> = archive
+            .resource_map
+            .values()
+            .filter_map(|resource| match resource {
+                Resource::Spilled(spilled) => Some(spilled),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(spilled.len(), 2);
+        assert!(spilled.iter().all(|s| s.kind() == ResourceKind::Image));
+        assert_eq!(archive.resource_map.resident_size(), 14);
+        assert_eq!(archive.resource_map.len_by_type(ResourceKind::Image), 3);
+
+        let page = archive.embed_resources();
+        for path in ["/a.png", "/b.png", "/c.png"] {
+            let uri = base64::encode(format!("{:>10}", path));
+            assert!(page.contains(&uri), "{} not embedded", path);
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        block_on(archive.write_to_disk_async(&dir.path())).unwrap();
+        let written = std::fs::read(dir.path().join("app/c.png")).unwrap();
+        assert_eq!(written, b"    /c.png");
+    }
+
+    #[test]
+    fn test_memory_budget_cached() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/one" => Ok(Some(FetchedResource::new("<img src=c.png>"))),
+                "/two" => Ok(Some(FetchedResource::new(
+                    "<img src=b.png><img src=c.png>",
+                ))),
+                path => Ok(Some(FetchedResource::new(format!("{:>10}", path)))),
+            },
+        );
+        let options = ArchiveOptions::builder()
+            .fetcher(fetcher)
+            .memory_budget(15)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let one = block_on(session.archive("asset://app/one")).unwrap();
+        assert_eq!(one.resource_map.resident_size(), 10);
+
+        // c.png is taken from the cache in memory, but no longer fits
+        let (two, report) =
+            block_on(session.archive_with_report("asset://app/two")).unwrap();
+        let c = Url::parse("asset://app/c.png").unwrap();
+        let cached = report.resources.iter().find(|r| r.url == c).unwrap();
+        assert_eq!(cached.outcome, ResourceOutcome::Cached);
+        assert_eq!(two.resource_map.resident_size(), 10);
+        assert!(matches!(
+            two.resource_map.get(&c),
+            Some(Resource::Spilled(_))
+        ));
+    }
+
+    #[test]
+    fn test_resource_filter() {
+        let (session, mut archive) =
+            asset_archive(ArchiveOptions::builder().resource_filter(
+                |resource_url| resource_url.kind() != ResourceKind::Other,
+            ));
+        let added = block_on(session.refetch_missing(&mut archive)).unwrap();
+        assert_eq!(added, 1);
+        assert!(archive
+            .resource_map
+            .get_css(&Url::parse("asset://app/a.css").unwrap())
+            .is_some());
+        assert!(!archive
+            .resource_map
+            .contains_key(&Url::parse("asset://app/a.png").unwrap()));
+    }
+
+    #[test]
+    fn test_resource_extractor() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| match url.path() {
+                "/" => Ok(Some(FetchedResource::new(
+                    r#"<img src=a.png><div data-hero="b.png"></div>"#,
+                ))),
+                _ => Ok(Some(FetchedResource::new("png"))),
+            },
+        );
+        let hero = AttributeExtractor::new(
+            "[data-hero]",
+            "data-hero",
+            ResourceKind::Image,
+        )
+        .unwrap();
+        let options = ArchiveOptions::builder()
+            .fetcher(fetcher)
+            .resource_extractor(hero)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let resource_urls =
+            block_on(session.list_resources("asset://app/")).unwrap();
+        assert_eq!(resource_urls.len(), 2);
+        assert_eq!(resource_urls[1].url().path(), "/b.png");
+
+        let archive = block_on(session.archive("asset://app/")).unwrap();
+        assert!(archive
+            .resource_map
+            .contains_key(&Url::parse("asset://app/b.png").unwrap()));
+    }
+
+    #[test]
+    fn test_frameset() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| {
+                let body = match url.path() {
+                    "/" => {
+                        r#"<frameset cols="20%,*"><frame src="menu.html">
+                        <frame src="main.html"></frameset>"#
+                    }
+                    "/menu.html" => r#"<img src="logo.png">"#,
+                    "/main.html" => {
+                        r#"<frameset rows="*,*"><frame src="menu.html">
+                        <frame src="article.html"></frameset>"#
+                    }
+                    "/article.html" => {
+                        r#"<link rel="stylesheet" href="article.css">"#
+                    }
+                    "/article.css" => "p { background: url(paper.png) }",
+                    _ => "png",
+                };
+                Ok(Some(FetchedResource::new(body)))
+            },
+        );
+        let options =
+            ArchiveOptions::builder().fetcher(fetcher).build().unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let archive = block_on(session.archive("asset://app/")).unwrap();
+
+        let mut paths: Vec<_> =
+            archive.resource_map.keys().map(Url::path).collect();
+        paths.sort_unstable();
+        assert_eq!(
+            paths,
+            [
+                "/article.css",
+                "/article.html",
+                "/logo.png",
+                "/main.html",
+                "/menu.html",
+                "/paper.png",
+            ]
+        );
+
+        // Each frame is embedded with its own resources
+        let page = archive.embed_resources();
+        assert!(!page.contains("menu.html"));
+        assert!(!page.contains("main.html"));
+        assert_eq!(page.matches("<frame src=\"data:text/html").count(), 2);
+    }
+
+    #[test]
+    fn test_service_workers() {
+        let fetcher = FnFetcher::new(
+            |url| url.scheme() == "asset",
+            |url| {
+                let body = match url.path() {
+                    "/" => {
+                        r#"<script>
+                        navigator.serviceWorker.register("/sw.js");
+                        </script>"#
+                    }
+                    _ => "self.addEventListener('fetch', () => {});",
+                };
+                Ok(Some(FetchedResource::new(body)))
+            },
+        );
+        let options = ArchiveOptions::builder()
+            .fetcher(fetcher)
+            .service_workers(true)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let archive = block_on(session.archive("asset://app/")).unwrap();
+        let worker = Url::parse("asset://app/sw.js").unwrap();
+        assert!(matches!(
+            archive.resource_map.get(&worker),
+            Some(Resource::Javascript(_))
+        ));
+
+        let page = archive.embed_resources_with(&EmbedOptions {
+            strip_service_workers: true,
+            ..Default::default()
+        });
+        assert!(page.contains("ServiceWorkerContainer.prototype.register"));
+    }
+
+    #[test]
+    fn test_attachments() {
+        let fetcher = || {
+            FnFetcher::new(
+                |url| url.scheme() == "asset",
+                |url| {
+                    let body = match url.path() {
+                        "/" => {
+                            r#"<a href="report.pdf" download>Report</a>
+                            <a href="video.mp4" download="talk.mp4">Talk</a>"#
+                        }
+                        "/report.pdf" => "%PDF-1.4",
+                        _ => "a rather large video file",
+                    };
+                    Ok(Some(FetchedResource::new(body)))
+                },
+            )
+        };
+        let builder = || ArchiveOptions::builder().fetcher(fetcher());
+
+        // Attachments are not downloaded by default
+        let session = ArchiveSession::new(builder().build().unwrap()).unwrap();
+        let archive = block_on(session.archive("asset://app/")).unwrap();
+        assert!(archive.resource_map.is_empty());
+
+        let options = builder()
+            .download_attachments(true)
+            .max_attachment_size(16)
+            .build()
+            .unwrap();
+        let session = ArchiveSession::new(options).unwrap();
+        let (archive, report) =
+            block_on(session.archive_with_report("asset://app/")).unwrap();
+        let paths: Vec<_> =
+            archive.resource_map.keys().map(Url::path).collect();
+        assert_eq!(paths, ["/report.pdf"]);
+        let video = report
+            .resources
+            .iter()
+            .find(|resource| resource.url.path() == "/video.mp4")
+            .unwrap();
+        assert_eq!(
+            video.outcome,
+            ResourceOutcome::Skipped(
+                "larger than the attachment size limit".to_string()
+            )
+        );
+
+        let page = archive.embed_resources();
+        assert!(page.contains(r#"href="data:"#));
+        assert!(page.contains(r#"download="report.pdf""#));
+        assert!(page.contains(r#"href="video.mp4""#));
+    }
+
+    #[test]
+    fn test_resource_queue() {
+        let url = |path| {
+            Url::parse("http://example.com/")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        let mut queue = ResourceQueue::new(vec![
+            ResourceUrl::Css(url("a.css")),
+            ResourceUrl::Image(url("a.png")),
+        ]);
+        assert_eq!(queue.next(), Some(ResourceUrl::Css(url("a.css"))));
+        queue.discover(
+            &url("a.css"),
+            &Resource::Css(
+                "@import 'a.css'; b { c: url(b.png) } d { e: url(a.png) }"
+                    .into(),
+            ),
+            &[],
+        );
+        queue.discover(
+            &url("a.png"),
+            &Resource::Javascript("url(c.png)".into()),
+            &[],
+        );
+        queue.discover(
+            &url("frames/menu.html"),
+            &Resource::Html(
+                r#"<img src="../a.png"><iframe src="nested.html">"#.into(),
+            ),
+            &[],
+        );
+        assert_eq!(queue.next(), Some(ResourceUrl::Image(url("a.png"))));
+        assert_eq!(queue.next(), Some(ResourceUrl::Other(url("b.png"))));
+        assert_eq!(
+            queue.next(),
+            Some(ResourceUrl::Html(url("frames/nested.html")))
+        );
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let url = Url::parse("http://example.com/a.css").unwrap();
+        let events = event_stream(|reporter| {
+            let url = url.clone();
+            async move {
+                reporter.report(ArchiveEvent::ResourceStarted(url.clone()));
+                reporter
+                    .report(ArchiveEvent::ResourceDone { url, stored: true });
+                Err(Error::ParseError("failed".to_string()))
+            }
+        });
+        let events: Vec<_> = tokio_test::block_on(events.collect());
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ArchiveEvent::ResourceStarted(_),
+                ArchiveEvent::ResourceDone { stored: true, .. },
+                ArchiveEvent::Failed(Error::ParseError(_)),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_session_invalid_url() {
+        let session = ArchiveSession::new(Default::default()).unwrap();
+        let res = tokio_test::block_on(session.archive("this~is~not~a~url"));
+        assert!(matches!(res, Err(Error::ParseError(_))));
+    }
+}
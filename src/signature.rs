@@ -0,0 +1,222 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for signing archives so that they can be shown to be
+//! untampered
+//!
+//! Signatures are Ed25519, using the keys from `ed25519-dalek`. An
+//! in-memory [`PageArchive`] is signed with
+//! [`PageArchive::sign`] over the SHA-256 hashes of its page and
+//! resources, while an archive written to disk is signed with
+//! [`sign_directory`] over its `manifest.json`, which itself records the
+//! hash of every file. Requires the `signing` feature.
+//!
+//! ```no_run
+//! use web_archive::blocking;
+//! use web_archive::signature::{sign_directory, verify_directory, SigningKey};
+//!
+//! let key = SigningKey::from_bytes(&[7; 32]);
+//! let archive =
+//!     blocking::archive("http://example.com", Default::default()).unwrap();
+//! archive.write_to_disk(&"example_archive").unwrap();
+//! sign_directory(&"example_archive", &key).unwrap();
+//!
+//! verify_directory(&"example_archive", &key.verifying_key()).unwrap();
+//! ```
+
+use crate::digest::hex;
+use crate::error::Error;
+use crate::manifest::{
+    manifest_file_path, resource_sha256, sha256_hex, Manifest, ManifestEntry,
+    MANIFEST_FILE,
+};
+use crate::page_archive::PageArchive;
+use ed25519_dalek::Signer;
+pub use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use std::fs;
+use std::path::Path;
+
+/// Name of the file holding the detached signature of the manifest,
+/// written next to it by [`sign_directory`]
+pub const SIGNATURE_FILE: &str = "manifest.json.sig";
+
+/// The message signed for an in-memory archive: the URLs and SHA-256
+/// hashes of the page and its resources, one per line in order of URL
+pub(crate) fn archive_message(archive: &PageArchive) -> Vec<u8> {
+    let mut message = format!(
+        "web-archive signature v1\nurl {}\nfetched_at {}\npage {}\n",
+        archive.url,
+        humantime::format_rfc3339_seconds(archive.fetched_at),
+        sha256_hex(archive.content.as_bytes()),
+    );
+    let mut resources: Vec<_> = archive.resource_map.iter().collect();
+    resources.sort_by(|a, b| a.0.cmp(b.0));
+    for (url, resource) in resources {
+        message.push_str(&format!(
+            "resource {} {}\n",
+            url,
+            resource_sha256(resource)
+        ));
+    }
+    message.into_bytes()
+}
+
+/// Sign `message`
+pub(crate) fn sign(message: &[u8], key: &SigningKey) -> Signature {
+    key.sign(message)
+}
+
+/// Check that `signature` is a valid signature of `message`
+pub(crate) fn verify(
+    message: &[u8],
+    signature: &Signature,
+    key: &VerifyingKey,
+) -> Result<(), Error> {
+    key.verify_strict(message, signature)
+        .map_err(|_| Error::VerificationError("Invalid signature".to_string()))
+}
+
+/// Sign the manifest of an archive written to disk with
+/// [`PageArchive::write_to_disk`], writing the hex-encoded signature to
+/// [`SIGNATURE_FILE`]
+pub fn sign_directory<P: AsRef<Path>>(
+    dir: &P,
+    key: &SigningKey,
+) -> Result<(), Error> {
+    let dir = dir.as_ref();
+    let manifest = fs::read(dir.join(MANIFEST_FILE))?;
+    let signature = sign(&manifest, key);
+    fs::write(dir.join(SIGNATURE_FILE), hex(&signature.to_bytes()))?;
+    Ok(())
+}
+
+/// Verify an archive signed with [`sign_directory`]: the signature must
+/// match the manifest, and every file listed in the manifest must match
+/// the size and SHA-256 hash recorded for it
+pub fn verify_directory<P: AsRef<Path>>(
+    dir: &P,
+    key: &VerifyingKey,
+) -> Result<(), Error> {
+    let dir = dir.as_ref();
+    let manifest_data = fs::read(dir.join(MANIFEST_FILE))?;
+    let signature = fs::read_to_string(dir.join(SIGNATURE_FILE))?;
+    let signature = parse_signature(signature.trim())?;
+    verify(&manifest_data, &signature, key)?;
+
+    let manifest: Manifest = serde_json::from_slice(&manifest_data)
+        .map_err(|e| Error::ParseError(e.to_string()))?;
+    for entry in std::iter::once(&manifest.page)
+        .chain(manifest.raw_page.iter())
+        .chain(manifest.resources.iter())
+    {
+        verify_entry(dir, entry)?;
+    }
+    Ok(())
+}
+
+/// Check that a file matches its manifest entry
+fn verify_entry(dir: &Path, entry: &ManifestEntry) -> Result<(), Error> {
+    let data = fs::read(manifest_file_path(dir, &entry.path)?)?;
+    if data.len() as u64 != entry.size || sha256_hex(&data) != entry.sha256 {
+        return Err(Error::VerificationError(format!(
+            "{} does not match the manifest",
+            entry.path.display()
+        )));
+    }
+    Ok(())
+}
+
+fn parse_signature(hex: &str) -> Result<Signature, Error> {
+    let invalid = || Error::ParseError("Invalid signature file".to_string());
+    if hex.len() != 128 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0; 64];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(Signature::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::{Resource, ResourceMap};
+    use std::time::UNIX_EPOCH;
+    use url::Url;
+
+    fn archive() -> PageArchive {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("style.css").unwrap(),
+            Resource::Css("body {}".into()),
+        );
+        PageArchive {
+            url,
+            content: "<html></html>".to_string(),
+            raw_content: None,
+            resource_map,
+            fetched_at: UNIX_EPOCH,
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_archive_message() {
+        let expected = format!(
+            "web-archive signature v1\n\
+             url http://example.com/\n\
+             fetched_at 1970-01-01T00:00:00Z\n\
+             page {}\n\
+             resource http://example.com/style.css {}\n",
+            sha256_hex(b"<html></html>"),
+            sha256_hex(b"body {}"),
+        );
+        assert_eq!(archive_message(&archive()), expected.into_bytes());
+    }
+
+    #[test]
+    fn test_sign_archive() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let mut archive = archive();
+        let signature = archive.sign(&key);
+        archive.verify(&signature, &key.verifying_key()).unwrap();
+
+        archive.content.push(' ');
+        assert!(matches!(
+            archive.verify(&signature, &key.verifying_key()),
+            Err(Error::VerificationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_sign_directory() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        archive().write_to_disk(&dir.path()).unwrap();
+        sign_directory(&dir.path(), &key).unwrap();
+        verify_directory(&dir.path(), &key.verifying_key()).unwrap();
+
+        // A different key is rejected
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(matches!(
+            verify_directory(&dir.path(), &other),
+            Err(Error::VerificationError(_))
+        ));
+
+        // As is a modified file
+        let css = dir.path().join("example.com/style.css");
+        fs::write(css, "body { color: red }").unwrap();
+        assert!(matches!(
+            verify_directory(&dir.path(), &key.verifying_key()),
+            Err(Error::VerificationError(_))
+        ));
+    }
+}
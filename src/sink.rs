@@ -0,0 +1,1219 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for the storage backends that archives can be written to
+//!
+//! [`PageArchive::write_to_sink`](crate::PageArchive::write_to_sink)
+//! writes an archive to any [`ArchiveSink`], so the output can go to
+//! object storage, a database, etc. by implementing the trait.
+//! [`FileSystemSink`] is the implementation used by
+//! [`PageArchive::write_to_disk`](crate::PageArchive::write_to_disk), and
+//! [`TarGzSink`] writes the same files to a compressed tarball.
+
+use crate::error::Error;
+use crate::manifest::{
+    resource_sha256, sha256_hex, Manifest, ManifestEntry, MANIFEST_FILE,
+    MANIFEST_VERSION,
+};
+use crate::page_archive::{PageArchive, ResourceInfo};
+use crate::parsing::{decode_non_ascii, display_host, Resource};
+use crate::spill::write_resource_file;
+use bytes::Bytes;
+use flate2::{Compression, GzBuilder};
+use futures::channel::oneshot;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::SystemTime;
+use url::Url;
+
+/// A storage backend which an archive can be written to.
+///
+/// The page is written first, followed by each of its resources, and
+/// then [`finalize`](Self::finalize) is called exactly once if all of the
+/// writes succeeded.
+pub trait ArchiveSink {
+    /// Write the page itself. `content` is the data to be stored for
+    /// the page, and `archive` provides its metadata.
+    fn write_page(
+        &mut self,
+        archive: &PageArchive,
+        content: &[u8],
+    ) -> Result<(), Error>;
+
+    /// Write one of the page's resources
+    fn write_resource(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+    ) -> Result<(), Error>;
+
+    /// Complete the archive once everything has been written
+    fn finalize(&mut self) -> Result<(), Error>;
+}
+
+/// Configuration options to control how archives are written to disk
+#[derive(Clone, Debug, Default)]
+pub struct DiskOptions {
+    /// The directory structure used for the resources.
+    ///
+    /// Default: [`DiskLayout::Mirrored`]
+    pub layout: DiskLayout,
+    /// What happens when the output directory already exists and is not
+    /// empty.
+    ///
+    /// Default: [`ExistingOutput::Merge`]
+    pub existing: ExistingOutput,
+}
+
+/// What to do when the output directory of an archive written to disk
+/// already exists and is not empty. An empty directory is treated as if
+/// it did not exist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingOutput {
+    /// Return [`Error::OutputExists`] without writing anything
+    Error,
+    /// Replace the directory and everything in it with the archive. If
+    /// the old contents cannot be deleted once the archive has taken
+    /// their place, they are left in a hidden directory next to it
+    /// whose name ends in `.old`.
+    Overwrite,
+    /// Write the archive's files into the directory, replacing files
+    /// with the same paths and keeping any others
+    #[default]
+    Merge,
+    /// Write each archive to a new subdirectory of the output directory
+    /// named by the time at which the page was fetched, e.g.
+    /// `2021-02-03T04-05-06Z`, with a numeric suffix if the name is
+    /// taken. This happens whether or not the directory already exists.
+    Version,
+}
+
+/// Directory structure used when writing resources to disk. The page is
+/// always written to `index.html` in the output directory, and the
+/// [`Manifest`] in `manifest.json` records the original URL of every
+/// file written.
+///
+/// File names are made safe for common file systems: a resource's query
+/// string is added to its name, characters which are not allowed in file
+/// names are replaced with `_`, names reserved by Windows are prefixed
+/// with `_`, and long names are shortened. Except in the
+/// [`Flat`](Self::Flat) layout, a numeric suffix is added to any path
+/// which would clash with an earlier file or directory, ignoring case.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiskLayout {
+    /// Resources are named by the SHA-256 hash of their contents in a
+    /// `resources` directory, e.g. `resources/9f86d08….png`
+    Flat,
+    /// Resources are written to paths mirroring their URLs, e.g.
+    /// `example.com/images/foo.png`
+    #[default]
+    Mirrored,
+    /// Resources are written alongside `index.html` using their original
+    /// file names, with a numeric suffix added to avoid collisions, e.g.
+    /// `foo.png` and `foo-1.png`
+    SingleDirectory,
+}
+
+/// An [`ArchiveSink`] that writes the archive to a directory.
+///
+/// The page is written to `index.html`, and the resources are written
+/// according to the [`DiskLayout`] in the [`DiskOptions`]. A
+/// [`Manifest`] describing the archive is written to `manifest.json`.
+///
+/// Files are first written to a hidden staging directory next to the
+/// output directory, which is renamed to the output directory when the
+/// archive is finalised, so that an interrupted run never leaves a
+/// partial archive in its place. The staging directory is removed if the
+/// sink is dropped before then. If the output directory already exists
+/// and is not empty, the [`ExistingOutput`] in the [`DiskOptions`]
+/// decides what happens; when merging, the files are moved into it one
+/// at a time, with `manifest.json` last.
+#[derive(Debug)]
+pub struct FileSystemSink {
+    root: PathBuf,
+    output_dir: PathBuf,
+    staging: Option<PathBuf>,
+    options: DiskOptions,
+    planner: LayoutPlanner,
+}
+
+impl FileSystemSink {
+    /// Create a sink writing to the given directory, which is created if
+    /// it does not already exist
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self::with_options(root, DiskOptions::default())
+    }
+
+    /// Create a sink writing to the given directory with the specified
+    /// options
+    pub fn with_options<P: AsRef<Path>>(root: P, options: DiskOptions) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            output_dir: root.as_ref().to_path_buf(),
+            staging: None,
+            planner: LayoutPlanner::new(options.layout),
+            options,
+        }
+    }
+
+    /// The directory that the archive is written to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The directory that the archive's files are in once it has been
+    /// finalised. This is the [`root`](Self::root), unless the
+    /// [`ExistingOutput::Version`] subdirectory has been chosen by
+    /// writing the page.
+    pub fn output_dir(&self) -> &Path {
+        &self.output_dir
+    }
+
+    /// The path, relative to the root, that a resource is written to in
+    /// the [`DiskLayout::Mirrored`] layout, before any suffix is added to
+    /// avoid a clash. Internationalised domain names and non-ASCII
+    /// characters in the path are written in their original Unicode form,
+    /// as shown by [`display_url`](crate::parsing::display_url), and each
+    /// name is made safe as described for [`DiskLayout`].
+    pub fn resource_path(&self, url: &Url) -> PathBuf {
+        mirrored_path(url)
+    }
+
+    /// Determine where the page is written, relative to the root. The
+    /// path planning is shared between the blocking and async writers.
+    pub(crate) fn plan_page(
+        &mut self,
+        archive: &PageArchive,
+        content: &[u8],
+    ) -> Result<PathBuf, Error> {
+        if self.staging.is_none() {
+            self.output_dir = match self.options.existing {
+                ExistingOutput::Error if has_contents(&self.root)? => {
+                    return Err(Error::OutputExists(self.root.clone()));
+                }
+                ExistingOutput::Version => {
+                    version_dir(&self.root, archive.fetched_at)?
+                }
+                _ => self.root.clone(),
+            };
+            self.staging = Some(staging_dir(&self.output_dir));
+        }
+        Ok(self.planner.plan_page(archive, content))
+    }
+
+    /// Determine where the body of the page as it was received is
+    /// written, if the archive kept it
+    pub(crate) fn plan_raw_page(
+        &mut self,
+        archive: &PageArchive,
+    ) -> Option<(PathBuf, Bytes)> {
+        self.planner.plan_raw_page(archive)
+    }
+
+    /// Determine where a resource is written, relative to the root
+    pub(crate) fn plan_resource(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+    ) -> PathBuf {
+        self.planner.plan_resource(url, resource)
+    }
+
+    /// Any additional files to write once everything else is written
+    pub(crate) fn plan_finalize(
+        &mut self,
+    ) -> Result<Vec<(PathBuf, Vec<u8>)>, Error> {
+        self.planner.plan_finalize()
+    }
+
+    /// The directory that files are written to until the archive is
+    /// complete
+    fn write_dir(&self) -> &Path {
+        self.staging.as_deref().unwrap_or(&self.root)
+    }
+
+    fn write_file(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let path = self.write_dir().join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    /// Move the files from the staging directory into place
+    fn commit(&mut self) -> Result<(), Error> {
+        match self.staging.take() {
+            Some(staging) => {
+                commit_dir(&staging, &self.output_dir, self.options.existing)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Async version of writing the planned files and then
+    /// [`commit`](Self::commit)ting them. Every file is written on the
+    /// same separate thread, so that it works with any async runtime.
+    pub(crate) async fn write_all_async(
+        &mut self,
+        files: Vec<(PathBuf, PlannedFile)>,
+    ) -> Result<(), Error> {
+        let write_dir = self.write_dir().to_path_buf();
+        let staging = self.staging.take();
+        let output_dir = self.output_dir.clone();
+        let existing = self.options.existing;
+        spawn_io(move || {
+            let result = files.into_iter().try_for_each(|(path, file)| {
+                let path = write_dir.join(path);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                match file {
+                    PlannedFile::Data(data) => fs::write(path, data)?,
+                    PlannedFile::Resource(resource) => {
+                        write_resource_file(&path, &resource)?
+                    }
+                }
+                Ok(())
+            });
+            match staging {
+                Some(staging) if result.is_ok() => {
+                    commit_dir(&staging, &output_dir, existing)
+                }
+                Some(staging) => {
+                    let _ = fs::remove_dir_all(staging);
+                    result
+                }
+                None => result,
+            }
+        })
+        .await
+    }
+}
+
+/// The contents of a file passed to [`FileSystemSink::write_all_async`]
+pub(crate) enum PlannedFile {
+    /// Data held in memory, such as the page or the manifest
+    Data(Bytes),
+    /// A resource, which may have been spilled to a temporary file
+    Resource(Resource),
+}
+
+/// Chooses the paths of the files of an archive according to a
+/// [`DiskLayout`] and builds its [`Manifest`]. Shared by the sinks which
+/// write the same layout as [`FileSystemSink`].
+#[derive(Debug)]
+pub(crate) struct LayoutPlanner {
+    layout: DiskLayout,
+    used_files: HashSet<String>,
+    used_dirs: HashSet<String>,
+    manifest: Option<Manifest>,
+    resource_info: HashMap<Url, ResourceInfo>,
+}
+
+impl LayoutPlanner {
+    pub(crate) fn new(layout: DiskLayout) -> Self {
+        let mut used_files = HashSet::new();
+        used_files.insert(path_key(Path::new(MANIFEST_FILE)));
+        Self {
+            layout,
+            used_files,
+            used_dirs: HashSet::new(),
+            manifest: None,
+            resource_info: HashMap::new(),
+        }
+    }
+
+    /// Determine where the page is written, and start the manifest
+    pub(crate) fn plan_page(
+        &mut self,
+        archive: &PageArchive,
+        content: &[u8],
+    ) -> PathBuf {
+        let path = PathBuf::from("index.html");
+        self.use_path(&path);
+        self.manifest = Some(Manifest {
+            version: MANIFEST_VERSION,
+            generator: format!("web-archive {}", env!("CARGO_PKG_VERSION")),
+            fetched_at: humantime::format_rfc3339_seconds(archive.fetched_at)
+                .to_string(),
+            page: ManifestEntry::page(
+                &archive.url,
+                path.clone(),
+                content,
+                &archive.info.page,
+            ),
+            raw_page: None,
+            resources: Vec::new(),
+        });
+        self.resource_info = archive.info.resources.clone();
+        path
+    }
+
+    /// Determine where the body of the page as it was received is
+    /// written, if the archive kept it, and add it to the manifest
+    pub(crate) fn plan_raw_page(
+        &mut self,
+        archive: &PageArchive,
+    ) -> Option<(PathBuf, Bytes)> {
+        let raw = archive.raw_content.clone()?;
+        let path = PathBuf::from("index.raw");
+        self.use_path(&path);
+        if let Some(manifest) = &mut self.manifest {
+            manifest.raw_page = Some(ManifestEntry::page(
+                &archive.url,
+                path.clone(),
+                &raw,
+                &archive.info.page,
+            ));
+        }
+        Some((path, raw))
+    }
+
+    /// Determine where a resource is written, relative to the root
+    pub(crate) fn plan_resource(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+    ) -> PathBuf {
+        let path = match self.layout {
+            DiskLayout::Mirrored => self.unique_path(mirrored_path(url)),
+            DiskLayout::Flat => {
+                // Identical content shares a single file
+                Path::new("resources")
+                    .join(content_addressed_name(url, resource))
+            }
+            DiskLayout::SingleDirectory => {
+                let name = url
+                    .path_segments()
+                    .and_then(|mut segments| segments.next_back())
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or("index");
+                let mut name = with_query(&decode_non_ascii(name), url);
+                if Path::new(&name).extension().is_none() {
+                    name = format!("{}.{}", name, extension(url, resource));
+                }
+                self.unique_path(PathBuf::from(safe_file_name(&name)))
+            }
+        };
+        self.use_path(&path);
+        if let Some(manifest) = &mut self.manifest {
+            manifest.resources.push(ManifestEntry::resource(
+                url,
+                path.clone(),
+                resource,
+                self.resource_info.get(url).unwrap_or(&Default::default()),
+            ));
+        }
+        path
+    }
+
+    /// Add a numeric suffix to the name of a file which would have the
+    /// same path as an earlier file or directory, and to the name of any
+    /// of its directories which would have the same path as a file
+    fn unique_path(&self, path: PathBuf) -> PathBuf {
+        let mut dir = PathBuf::new();
+        for component in path.parent().into_iter().flat_map(Path::components) {
+            let name = component.as_os_str().to_string_lossy();
+            let mut candidate = dir.join(&*name);
+            let mut suffix = 0;
+            while self.used_files.contains(&path_key(&candidate)) {
+                suffix += 1;
+                candidate = dir.join(format!("{}-{}", name, suffix));
+            }
+            dir = candidate;
+        }
+
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let extension = path.extension().map(|e| e.to_owned());
+        let mut candidate = dir.join(path.file_name().unwrap_or_default());
+        let mut suffix = 0;
+        while self.used_files.contains(&path_key(&candidate))
+            || self.used_dirs.contains(&path_key(&candidate))
+        {
+            suffix += 1;
+            candidate = dir.join(format!("{}-{}", stem, suffix));
+            if let Some(extension) = &extension {
+                candidate.set_extension(extension);
+            }
+        }
+        candidate
+    }
+
+    /// Record that a file is written to the path
+    fn use_path(&mut self, path: &Path) {
+        self.used_files.insert(path_key(path));
+        for dir in path.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                self.used_dirs.insert(path_key(dir));
+            }
+        }
+    }
+
+    /// The manifest describing the files planned so far, which is only
+    /// available once
+    pub(crate) fn take_manifest(&mut self) -> Option<Manifest> {
+        self.manifest.take()
+    }
+
+    /// Any additional files to write once everything else is written
+    pub(crate) fn plan_finalize(
+        &mut self,
+    ) -> Result<Vec<(PathBuf, Vec<u8>)>, Error> {
+        let mut files = Vec::new();
+        if let Some(manifest) = self.take_manifest() {
+            let json = serde_json::to_vec_pretty(&manifest)
+                .map_err(|e| Error::ParseError(e.to_string()))?;
+            files.push((PathBuf::from(MANIFEST_FILE), json));
+        }
+        Ok(files)
+    }
+}
+
+/// The path of a resource in the [`DiskLayout::Mirrored`] layout, before
+/// any suffix is added to avoid a clash
+fn mirrored_path(url: &Url) -> PathBuf {
+    let mut path = PathBuf::new();
+    path.push(safe_file_name(
+        &display_host(url).unwrap_or_else(|| "local".to_string()),
+    ));
+    let mut names: Vec<_> = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|s| !s.is_empty())
+        .map(decode_non_ascii)
+        .collect();
+    if url.path().ends_with('/') || names.is_empty() {
+        names.push("index".to_string());
+    }
+    if let Some(last) = names.last_mut() {
+        *last = with_query(last, url);
+    }
+    for name in names {
+        path.push(safe_file_name(&name));
+    }
+    path
+}
+
+impl Drop for FileSystemSink {
+    fn drop(&mut self) {
+        // The archive was never finalised, so nothing is moved into place
+        if let Some(staging) = self.staging.take() {
+            let _ = fs::remove_dir_all(staging);
+        }
+    }
+}
+
+/// Number of staging directories created by this process, used to give
+/// each one a unique name
+static STAGED: AtomicUsize = AtomicUsize::new(0);
+
+/// A hidden path next to the output directory or file for writing to
+/// until the archive is complete
+pub(crate) fn staging_dir(root: &Path) -> PathBuf {
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "archive".to_string());
+    root.with_file_name(format!(
+        ".{}.partial-{}-{}",
+        name,
+        std::process::id(),
+        STAGED.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Whether the path is a directory with anything in it
+fn has_contents(dir: &Path) -> Result<bool, Error> {
+    Ok(dir.is_dir() && fs::read_dir(dir)?.next().is_some())
+}
+
+/// A subdirectory of the root named by the time at which the page was
+/// fetched, which is not already in use
+fn version_dir(root: &Path, fetched_at: SystemTime) -> Result<PathBuf, Error> {
+    let stamp = humantime::format_rfc3339_seconds(fetched_at)
+        .to_string()
+        .replace(':', "-");
+    let mut dir = root.join(&stamp);
+    let mut suffix = 0;
+    while dir.exists() && (has_contents(&dir)? || !dir.is_dir()) {
+        suffix += 1;
+        dir = root.join(format!("{}-{}", stamp, suffix));
+    }
+    Ok(dir)
+}
+
+/// Rename the staging directory to the output directory, or deal with
+/// the output directory's contents according to `existing`. The staging
+/// directory is removed if this fails.
+fn commit_dir(
+    staging: &Path,
+    output_dir: &Path,
+    existing: ExistingOutput,
+) -> Result<(), Error> {
+    let result = (|| {
+        if !staging.exists() {
+            return Ok(());
+        }
+        // An empty output directory can be replaced outright
+        if has_contents(output_dir)? {
+            match existing {
+                ExistingOutput::Error => {
+                    return Err(Error::OutputExists(output_dir.to_path_buf()))
+                }
+                ExistingOutput::Overwrite => {
+                    // Directories cannot be swapped atomically, so the
+                    // old contents are moved aside rather than deleted,
+                    // and put back if the archive cannot take their place.
+                    // The directory is briefly missing between the two
+                    // renames.
+                    let mut old = staging.as_os_str().to_owned();
+                    old.push(".old");
+                    fs::rename(output_dir, &old)?;
+                    if let Err(e) = fs::rename(staging, output_dir) {
+                        let _ = fs::rename(&old, output_dir);
+                        return Err(e.into());
+                    }
+                    // The archive is in place, so failing to delete the
+                    // old contents only leaves them behind and does not
+                    // fail the write
+                    let _ = fs::remove_dir_all(old);
+                    return Ok(());
+                }
+                ExistingOutput::Merge | ExistingOutput::Version => {
+                    move_files(staging, output_dir)?;
+                    fs::rename(
+                        staging.join(MANIFEST_FILE),
+                        output_dir.join(MANIFEST_FILE),
+                    )?;
+                    return Ok(());
+                }
+            }
+        }
+        if output_dir.is_dir() {
+            fs::remove_dir(output_dir)?;
+        }
+        if let Some(parent) = output_dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(staging, output_dir)?;
+        Ok(())
+    })();
+    if staging.exists() {
+        let _ = fs::remove_dir_all(staging);
+    }
+    result
+}
+
+/// Move each file under `from` to the same path under `to`, apart from
+/// the manifest at the top level
+fn move_files(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        if entry.file_type()?.is_dir() {
+            move_files(&source, &target)?;
+        } else if from.join(MANIFEST_FILE) != source {
+            fs::rename(source, target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run file system operations on a separate thread, so that they work
+/// with any async runtime
+async fn spawn_io<F>(operation: F) -> Result<(), Error>
+where
+    F: FnOnce() -> Result<(), Error> + Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        // Nothing is waiting if the future was dropped
+        let _ = sender.send(operation());
+    });
+    receiver.await.map_err(|_| {
+        Error::IoError("the thread writing the file panicked".to_string())
+    })?
+}
+
+impl ArchiveSink for FileSystemSink {
+    fn write_page(
+        &mut self,
+        archive: &PageArchive,
+        content: &[u8],
+    ) -> Result<(), Error> {
+        let path = self.plan_page(archive, content)?;
+        self.write_file(&path, content)?;
+        if let Some((path, raw)) = self.plan_raw_page(archive) {
+            self.write_file(&path, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn write_resource(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+    ) -> Result<(), Error> {
+        // Spilled resources are copied rather than read back into memory
+        let path = self.plan_resource(url, resource);
+        let path = self.write_dir().join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_resource_file(&path, resource)
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        for (path, data) in self.plan_finalize()? {
+            self.write_file(&path, &data)?;
+        }
+        self.commit()
+    }
+}
+
+/// An [`ArchiveSink`] that writes the archive as a gzip-compressed tar
+/// file, with the same files as [`FileSystemSink`] would write to disk.
+///
+/// The tarball is built in memory and written to the writer when the
+/// archive is finalised, with every file given the time at which the
+/// page was fetched. Paths which are too long for a plain tar header are
+/// recorded in GNU long name entries, which all common tar programs
+/// understand.
+pub struct TarGzSink<W: Write> {
+    writer: W,
+    planner: LayoutPlanner,
+    tar: tar::Builder<Vec<u8>>,
+    mtime: u64,
+}
+
+/// The tarball being built is not `Debug`, so only its size is shown
+impl<W: Write + fmt::Debug> fmt::Debug for TarGzSink<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TarGzSink")
+            .field("writer", &self.writer)
+            .field("planner", &self.planner)
+            .field("tar", &self.tar.get_ref().len())
+            .field("mtime", &self.mtime)
+            .finish()
+    }
+}
+
+impl<W: Write> TarGzSink<W> {
+    /// Create a sink writing to the given writer with the default
+    /// [`DiskLayout`]
+    pub fn new(writer: W) -> Self {
+        Self::with_layout(writer, DiskLayout::default())
+    }
+
+    /// Create a sink writing to the given writer with the specified
+    /// [`DiskLayout`]
+    pub fn with_layout(writer: W, layout: DiskLayout) -> Self {
+        Self {
+            writer,
+            planner: LayoutPlanner::new(layout),
+            tar: tar::Builder::new(Vec::new()),
+            mtime: 0,
+        }
+    }
+
+    /// Take back the writer, e.g. to get at the contents of a `Vec<u8>`
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Add a file to the tarball
+    fn append(&mut self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let name = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        self.tar.append_data(&mut header, name, data)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ArchiveSink for TarGzSink<W> {
+    fn write_page(
+        &mut self,
+        archive: &PageArchive,
+        content: &[u8],
+    ) -> Result<(), Error> {
+        self.mtime = archive
+            .fetched_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let path = self.planner.plan_page(archive, content);
+        self.append(&path, content)?;
+        if let Some((path, raw)) = self.planner.plan_raw_page(archive) {
+            self.append(&path, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn write_resource(
+        &mut self,
+        url: &Url,
+        resource: &Resource,
+    ) -> Result<(), Error> {
+        let path = self.planner.plan_resource(url, resource);
+        self.append(&path, &resource.read_data()?)
+    }
+
+    fn finalize(&mut self) -> Result<(), Error> {
+        for (path, data) in self.planner.plan_finalize()? {
+            self.append(&path, &data)?;
+        }
+        let tar =
+            std::mem::replace(&mut self.tar, tar::Builder::new(Vec::new()))
+                .into_inner()?;
+        let mut gz = GzBuilder::new()
+            .mtime(self.mtime.min(u32::MAX as u64) as u32)
+            .write(&mut self.writer, Compression::default());
+        gz.write_all(&tar)?;
+        gz.finish()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Longest file name written, in bytes, leaving room for a suffix within
+/// the limit of 255 bytes of common file systems
+const MAX_NAME_LEN: usize = 200;
+
+/// Names of devices reserved by Windows, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6",
+    "com7", "com8", "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6",
+    "lpt7", "lpt8", "lpt9",
+];
+
+/// Key identifying a path in the sets of used paths. File systems on
+/// Windows and macOS ignore case, so paths differing only in case clash.
+fn path_key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+/// Add the query string of the URL to a file name, before its extension,
+/// so that resources differing only in their query are kept apart
+fn with_query(name: &str, url: &Url) -> String {
+    let query = match url.query() {
+        Some(query) if !query.is_empty() => query,
+        _ => return name.to_string(),
+    };
+    let path = Path::new(name);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!(
+            "{}-{}.{}",
+            stem.to_string_lossy(),
+            query,
+            extension.to_string_lossy()
+        ),
+        _ => format!("{}-{}", name, query),
+    }
+}
+
+/// Make a name safe to use for a file or directory on common file
+/// systems. Characters which are not allowed are replaced with `_`, as
+/// are trailing dots and spaces, Windows device names are prefixed with
+/// `_`, and names longer than [`MAX_NAME_LEN`] are shortened, keeping
+/// their extension and adding a hash of the full name so that they stay
+/// distinct.
+pub(crate) fn safe_file_name(name: &str) -> String {
+    let mut safe: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = safe.trim_end_matches(['.', ' ']).len();
+    if trimmed < safe.len() {
+        safe.truncate(trimmed);
+        safe.push('_');
+    }
+    let device = safe.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.contains(&device.to_lowercase().as_str()) {
+        safe.insert(0, '_');
+    }
+
+    if safe.len() > MAX_NAME_LEN {
+        let extension = Path::new(&safe)
+            .extension()
+            .map(|e| e.to_string_lossy().into_owned())
+            .filter(|e| e.len() <= 16);
+        let hash = &sha256_hex(name.as_bytes())[..8];
+        let suffix = match extension {
+            Some(extension) => format!("-{}.{}", hash, extension),
+            None => format!("-{}", hash),
+        };
+        let mut end = MAX_NAME_LEN - suffix.len();
+        while !safe.is_char_boundary(end) {
+            end -= 1;
+        }
+        safe.truncate(end);
+        safe.push_str(&suffix);
+    }
+    safe
+}
+
+/// File name for a resource based on the SHA-256 hash of its contents
+pub(crate) fn content_addressed_name(url: &Url, resource: &Resource) -> String {
+    format!("{}.{}", resource_sha256(resource), extension(url, resource))
+}
+
+/// Choose a file extension for a resource based on its mimetype,
+/// falling back to the extension in its URL
+fn extension(url: &Url, resource: &Resource) -> String {
+    let from_mimetype = match resource {
+        Resource::Css(_) => Some("css"),
+        Resource::Javascript(_) => Some("js"),
+        Resource::Html(_) => Some("html"),
+        _ => mime_guess::get_mime_extensions_str(resource.mimetype())
+            .and_then(|extensions| extensions.first().copied()),
+    };
+    from_mimetype
+        .map(str::to_string)
+        .or_else(|| {
+            Path::new(url.path())
+                .extension()
+                .map(|e| e.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "bin".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resource_path() {
+        let sink = FileSystemSink::new("out");
+        let path = |u: &str| sink.resource_path(&Url::parse(u).unwrap());
+
+        assert_eq!(
+            path("http://example.com/images/ferris.png"),
+            Path::new("example.com/images/ferris.png")
+        );
+        assert_eq!(
+            path("http://example.com/a/../b/"),
+            Path::new("example.com/b/index")
+        );
+        assert_eq!(
+            path("http://bücher.example/bücher/a%20b%2F.png"),
+            Path::new("bücher.example/bücher/a%20b%2F.png")
+        );
+        assert_eq!(
+            path("http://example.com:8080/style.css?v=1&t=a/b"),
+            Path::new("example.com/style-v=1&t=a_b.css")
+        );
+        assert_eq!(
+            path("http://example.com/?id=3"),
+            Path::new("example.com/index-id=3")
+        );
+        assert_eq!(
+            path("http://example.com/con.txt/a|b./"),
+            Path::new("example.com/_con.txt/a_b_/index")
+        );
+    }
+
+    #[test]
+    fn test_safe_file_name() {
+        assert_eq!(safe_file_name("a*b?c\"d\u{7}.png"), "a_b_c_d_.png");
+        assert_eq!(safe_file_name(".."), "_");
+        assert_eq!(safe_file_name("LPT1"), "_LPT1");
+        assert_eq!(safe_file_name("lpt10"), "lpt10");
+
+        let long = format!("{}.png", "é".repeat(150));
+        let safe = safe_file_name(&long);
+        assert!(safe.len() <= MAX_NAME_LEN);
+        assert!(safe.starts_with("éé"));
+        assert!(safe.ends_with(".png"));
+        let other = format!("{}x.png", "é".repeat(150));
+        assert_ne!(safe_file_name(&other), safe);
+    }
+
+    #[test]
+    fn test_layouts() {
+        let css = Resource::Css("body {}".into());
+        let url = |u: &str| Url::parse(u).unwrap();
+
+        let mut sink = FileSystemSink::with_options(
+            "out",
+            DiskOptions {
+                layout: DiskLayout::Flat,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/a/style"), &css),
+            Path::new(
+                "resources/\
+                 62368a1a29259b30bac235c0e75dc700c9b3bacf1513ad5708e4fe4a6c0d6560.css"
+            )
+        );
+
+        let mut sink = FileSystemSink::with_options(
+            "out",
+            DiskOptions {
+                layout: DiskLayout::SingleDirectory,
+                ..Default::default()
+            },
+        );
+        sink.plan_page(&archive(), b"").unwrap();
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/a/style.css"), &css),
+            Path::new("style.css")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/STYLE.CSS"), &css),
+            Path::new("STYLE-1.CSS")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/style.css?v=2"), &css),
+            Path::new("style-v=2.css")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/b/style.css"), &css),
+            Path::new("style-2.css")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/index"), &css),
+            Path::new("index.css")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/index.html"), &css),
+            Path::new("index-1.html")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/manifest.json"), &css),
+            Path::new("manifest-1.json")
+        );
+
+        let mut sink = FileSystemSink::new("out");
+        sink.plan_page(&archive(), b"").unwrap();
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/a"), &css),
+            Path::new("example.com/a")
+        );
+        // A directory cannot share the path of a file, nor a file that of
+        // a directory
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/A/b.css"), &css),
+            Path::new("example.com/A-1/b.css")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/a/c.css"), &css),
+            Path::new("example.com/a-1/c.css")
+        );
+        assert_eq!(
+            sink.plan_resource(&url("http://example.com/a-1"), &css),
+            Path::new("example.com/a-1-1")
+        );
+    }
+
+    fn archive() -> PageArchive {
+        PageArchive {
+            url: Url::parse("http://example.com/").unwrap(),
+            content: "<html></html>".to_string(),
+            raw_content: None,
+            resource_map: Default::default(),
+            fetched_at: std::time::UNIX_EPOCH,
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_write_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = FileSystemSink::new(dir.path());
+        let url = Url::parse("http://example.com/style.css").unwrap();
+
+        sink.write_page(&archive(), b"<html></html>").unwrap();
+        sink.write_resource(&url, &Resource::Css("body {}".into()))
+            .unwrap();
+        sink.finalize().unwrap();
+
+        assert_eq!(
+            fs::read(dir.path().join("index.html")).unwrap(),
+            b"<html></html>"
+        );
+        assert_eq!(
+            fs::read(dir.path().join("example.com/style.css")).unwrap(),
+            b"body {}"
+        );
+
+        let manifest: Manifest = serde_json::from_slice(
+            &fs::read(dir.path().join(MANIFEST_FILE)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest.fetched_at, "1970-01-01T00:00:00Z");
+        assert_eq!(manifest.page.path, Path::new("index.html"));
+        assert_eq!(manifest.page.size, 13);
+        assert_eq!(manifest.resources.len(), 1);
+        assert_eq!(manifest.resources[0].url, url);
+        assert_eq!(
+            manifest.resources[0].path,
+            Path::new("example.com/style.css")
+        );
+        assert_eq!(manifest.resources[0].mimetype, "text/css");
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("archive");
+        let url = Url::parse("http://example.com/style.css").unwrap();
+        let css = Resource::Css("body {}".into());
+        let entries = || fs::read_dir(dir.path()).unwrap().count();
+
+        // Nothing is left behind by an archive which is not finalised
+        let mut sink = FileSystemSink::new(&root);
+        sink.write_page(&archive(), b"<html></html>").unwrap();
+        sink.write_resource(&url, &css).unwrap();
+        assert!(!root.exists());
+        assert_eq!(entries(), 1);
+        drop(sink);
+        assert_eq!(entries(), 0);
+
+        // An empty directory is replaced
+        fs::create_dir(&root).unwrap();
+        let mut sink = FileSystemSink::new(&root);
+        sink.write_page(&archive(), b"<html></html>").unwrap();
+        sink.finalize().unwrap();
+        assert!(root.join(MANIFEST_FILE).exists());
+        assert_eq!(entries(), 1);
+
+        // Files are moved into a directory with contents
+        let mut sink = FileSystemSink::new(&root);
+        sink.write_page(&archive(), b"<html>new</html>").unwrap();
+        sink.write_resource(&url, &css).unwrap();
+        sink.finalize().unwrap();
+        assert_eq!(
+            fs::read(root.join("index.html")).unwrap(),
+            b"<html>new</html>"
+        );
+        assert!(root.join("example.com/style.css").exists());
+        let manifest: Manifest = serde_json::from_slice(
+            &fs::read(root.join(MANIFEST_FILE)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest.resources.len(), 1);
+        assert_eq!(entries(), 1);
+    }
+
+    #[test]
+    fn test_existing_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("archive");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("old.txt"), b"old").unwrap();
+        let write = |existing| {
+            let mut sink = FileSystemSink::with_options(
+                &root,
+                DiskOptions {
+                    existing,
+                    ..Default::default()
+                },
+            );
+            sink.write_page(&archive(), b"<html></html>")?;
+            sink.finalize()?;
+            Ok::<_, Error>(sink.output_dir().to_path_buf())
+        };
+
+        assert!(matches!(
+            write(ExistingOutput::Error),
+            Err(Error::OutputExists(path)) if path == root
+        ));
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+
+        assert_eq!(write(ExistingOutput::Merge).unwrap(), root);
+        assert!(root.join("old.txt").exists());
+        assert!(root.join("index.html").exists());
+
+        let version = write(ExistingOutput::Version).unwrap();
+        assert_eq!(version, root.join("1970-01-01T00-00-00Z"));
+        assert!(version.join(MANIFEST_FILE).exists());
+        assert_eq!(
+            write(ExistingOutput::Version).unwrap(),
+            root.join("1970-01-01T00-00-00Z-1")
+        );
+
+        assert_eq!(write(ExistingOutput::Overwrite).unwrap(), root);
+        let mut names: Vec<_> = fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["index.html", MANIFEST_FILE]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_tar_gz() {
+        let long = format!("http://example.com/{}/style.css", "a".repeat(120));
+        let mut page = archive();
+        page.fetched_at += std::time::Duration::from_secs(1_612_345_678);
+        let mut sink = TarGzSink::new(Vec::new());
+        sink.write_page(&page, b"<html></html>").unwrap();
+        for u in ["http://example.com/style.css", long.as_str()] {
+            let u = Url::parse(u).unwrap();
+            sink.write_resource(&u, &Resource::Css("body {}".into()))
+                .unwrap();
+        }
+        sink.finalize().unwrap();
+
+        let gz = sink.into_inner();
+        let mut decoder = flate2::read::GzDecoder::new(gz.as_slice());
+        let mut tar = tar::Archive::new(&mut decoder);
+        let mut files = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            assert_eq!(entry.header().mtime().unwrap(), 1_612_345_678);
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+            files.push((name, data));
+        }
+        assert_eq!(decoder.header().unwrap().mtime(), 1_612_345_678);
+
+        let names: Vec<_> =
+            files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "index.html",
+                "example.com/style.css",
+                format!("example.com/{}/style.css", "a".repeat(120)).as_str(),
+                MANIFEST_FILE,
+            ]
+        );
+        assert_eq!(files[0].1, b"<html></html>");
+        assert_eq!(files[1].1, b"body {}");
+        let manifest: Manifest = serde_json::from_slice(&files[3].1).unwrap();
+        assert_eq!(manifest.resources.len(), 2);
+        assert_eq!(manifest.resources[1].path, Path::new(names[2]));
+    }
+}
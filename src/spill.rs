@@ -0,0 +1,206 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for keeping downloaded resources on disk rather than in memory
+//!
+//! When archiving with an
+//! [`ArchiveOptions::memory_budget`](crate::ArchiveOptions::memory_budget),
+//! images and other binary resources which would take the resources held
+//! in memory over the budget are written to temporary files and stored
+//! as [`Resource::Spilled`]. They are read back as they are needed when
+//! the archive is embedded or exported, and each file is deleted once the
+//! last copy of its resource is dropped. Stylesheets, scripts and
+//! documents always stay in memory, as they are searched for the
+//! resources they reference.
+
+use crate::error::Error;
+use crate::manifest::sha256_hex;
+use crate::parsing::{ImageResource, Resource, ResourceKind};
+use bytes::Bytes;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Number of temporary files created by this process, used to give each
+/// one a unique name
+static SPILLED: AtomicUsize = AtomicUsize::new(0);
+
+/// A binary resource whose data is stored in a temporary file
+#[derive(Clone, Debug)]
+pub struct SpilledResource {
+    file: Arc<SpillFile>,
+    kind: ResourceKind,
+    mimetype: String,
+    size: usize,
+    sha256: String,
+}
+
+/// A temporary file which is deleted when dropped
+#[derive(Debug)]
+struct SpillFile {
+    path: PathBuf,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl SpilledResource {
+    /// Write the data of an image or other binary resource to a new
+    /// temporary file, returning `None` for any other kind of resource
+    pub(crate) fn spill(resource: &Resource) -> Result<Option<Self>, Error> {
+        let (kind, data) = match resource {
+            Resource::Image(image) => (ResourceKind::Image, &image.data),
+            Resource::Other { data, .. } => (ResourceKind::Other, data),
+            _ => return Ok(None),
+        };
+        let dir = std::env::temp_dir();
+        let (path, mut file) = loop {
+            let path = dir.join(format!(
+                "web-archive-{}-{}.tmp",
+                std::process::id(),
+                SPILLED.fetch_add(1, Ordering::Relaxed)
+            ));
+            // A file left behind by an earlier process is not reused
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let file_guard = SpillFile { path };
+        file.write_all(data)?;
+        Ok(Some(Self {
+            file: Arc::new(file_guard),
+            kind,
+            mimetype: resource.mimetype().to_string(),
+            size: data.len(),
+            sha256: sha256_hex(data),
+        }))
+    }
+
+    /// Path of the temporary file holding the data
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    /// The kind of the resource, either [`ResourceKind::Image`] or
+    /// [`ResourceKind::Other`]
+    pub fn kind(&self) -> ResourceKind {
+        self.kind
+    }
+
+    /// Mime type of the resource
+    pub fn mimetype(&self) -> &str {
+        &self.mimetype
+    }
+
+    /// Size of the data in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Hex-encoded SHA-256 hash of the data, computed when it was spilled
+    pub(crate) fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// Read the data back from the temporary file
+    pub fn read(&self) -> Result<Bytes, Error> {
+        Ok(fs::read(self.path())?.into())
+    }
+
+    /// Read the data back into the [`Resource::Image`] or
+    /// [`Resource::Other`] it was spilled from
+    pub fn load(&self) -> Result<Resource, Error> {
+        let data = self.read()?;
+        let mimetype = self.mimetype.clone();
+        Ok(match self.kind {
+            ResourceKind::Image => {
+                Resource::Image(ImageResource { data, mimetype })
+            }
+            _ => Resource::Other { data, mimetype },
+        })
+    }
+}
+
+/// Spilled resources are equal if their contents are, wherever they are
+/// stored
+impl PartialEq for SpilledResource {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.mimetype == other.mimetype
+            && self.sha256 == other.sha256
+    }
+}
+
+impl Eq for SpilledResource {}
+
+/// Write the data of a resource to a file, copying spilled resources
+/// from their temporary file without reading them into memory
+pub(crate) fn write_resource_file(
+    path: &Path,
+    resource: &Resource,
+) -> Result<(), Error> {
+    match resource.stored_data() {
+        Ok(data) => fs::write(path, data)?,
+        Err(spilled) => {
+            fs::copy(spilled.path(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_spill() {
+        let image = Resource::Image(ImageResource {
+            data: Bytes::from_static(b"png data"),
+            mimetype: "image/png".to_string(),
+        });
+        let spilled = SpilledResource::spill(&image).unwrap().unwrap();
+        let path = spilled.path().to_path_buf();
+        assert_eq!(fs::read(&path).unwrap(), b"png data");
+        assert_eq!(spilled.kind(), ResourceKind::Image);
+        assert_eq!(spilled.mimetype(), "image/png");
+        assert_eq!(spilled.size(), 8);
+        assert_eq!(spilled.load().unwrap(), image);
+
+        let resource = Resource::Spilled(spilled);
+        assert_eq!(resource.size(), 8);
+        assert!(resource.data().is_none());
+        assert_eq!(&resource.read_data().unwrap()[..], b"png data");
+        assert_eq!(resource, Resource::Spilled(image_spill(&image)));
+
+        let copy = std::env::temp_dir()
+            .join(format!("web-archive-test-{}.png", std::process::id()));
+        write_resource_file(&copy, &resource).unwrap();
+        assert_eq!(fs::read(&copy).unwrap(), b"png data");
+        fs::remove_file(copy).unwrap();
+
+        // The file lasts as long as any copy of the resource
+        let clone = resource.clone();
+        drop(resource);
+        assert!(path.exists());
+        drop(clone);
+        assert!(!path.exists());
+
+        let css = Resource::Css("a {}".into());
+        assert!(SpilledResource::spill(&css).unwrap().is_none());
+    }
+
+    fn image_spill(resource: &Resource) -> SpilledResource {
+        SpilledResource::spill(resource).unwrap().unwrap()
+    }
+}
@@ -0,0 +1,1019 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for discovering and embedding resources without building a DOM
+//!
+//! [`parse_resource_urls`] and [`embed_resources_to`] run the page
+//! through html5ever's tokenizer and deal with each tag as it is read,
+//! instead of parsing the whole page into a tree as
+//! [`crate::parse_resource_urls`] and
+//! [`PageArchive::embed_resources_with`] do, and the output is written as
+//! it is produced. This saves the memory of the tree, which is several
+//! times the size of the page, but the input is still the whole page as
+//! an in-memory `&str`, alongside the downloaded resources: memory use
+//! grows with the size of the page rather than staying constant, and
+//! there is no API taking the page from a reader or in chunks.
+//!
+//! In exchange, anything which needs a tree is not supported:
+//!
+//! * Tags are written back as they were read, so markup which the HTML
+//!   parser would correct, such as unclosed elements, is left alone
+//! * [`EmbedOptions::sanitize`], [`EmbedOptions::forms`],
+//!   [`EmbedOptions::provenance`], [`EmbedOptions::iframe_placeholders`],
+//!   [`EmbedOptions::embedders`], [`EmbedOptions::format`], and the
+//!   options preserving the DOCTYPE and whitespace are ignored, as the
+//!   page is never re-serialised
+//! * Resources larger than [`EmbedOptions::inline_size_limit`] are left
+//!   out rather than written to separate files
+//! * SVG images are embedded as URL-encoded `data:` URIs when
+//!   [`EmbedOptions::svg`] is [`SvgEmbedding::Inline`](crate::SvgEmbedding::Inline)
+//!
+//! Requires the `streaming` feature.
+//!
+//! ```
+//! use url::Url;
+//! use web_archive::streaming::embed_resources_to;
+//! use web_archive::{PageArchive, Resource, ResourceMap};
+//!
+//! let mut resource_map = ResourceMap::new();
+//! resource_map.insert(
+//!     Url::parse("http://example.com/style.css").unwrap(),
+//!     Resource::Css("body { color: red; }".into()),
+//! );
+//! let archive = PageArchive::from_parts(
+//!     "http://example.com/",
+//!     r#"<link rel="stylesheet" href="style.css"><p>Hello</p>"#,
+//!     resource_map,
+//! )
+//! .unwrap();
+//!
+//! let mut output = Vec::new();
+//! embed_resources_to(&archive, &Default::default(), &mut output).unwrap();
+//! assert_eq!(
+//!     String::from_utf8(output).unwrap(),
+//!     "<style>body { color: red; }</style><p>Hello</p>"
+//! );
+//! ```
+
+use crate::css::css_resource_urls;
+use crate::embed::{EmbedOptions, ScriptEmbedding, StylesheetEmbedding};
+use crate::error::Error;
+use crate::page_archive::{
+    Externals, PageArchive, Placement, STYLE_ATTRIBUTES,
+};
+use crate::parsing::{
+    encode_text_data_uri, is_binary, is_stylesheet_rel,
+    is_switchable_stylesheet, Resource, ResourceKind, ResourceUrl,
+};
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, Doctype, Tag, TagKind, Token, TokenSink, TokenSinkResult,
+    Tokenizer, TokenizerOpts,
+};
+use html5ever::{namespace_url, ns, Attribute, LocalName, QualName};
+use std::borrow::Cow;
+use std::io::{self, Write};
+use url::Url;
+
+/// Size of the pieces in which the page is passed to the tokenizer
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Search the page for the resources it references in the same way as
+/// [`crate::parse_resource_urls`], without building a DOM. The returned
+/// list is sorted and free of duplicates.
+///
+/// ## Example
+/// ```
+/// use url::Url;
+/// use web_archive::streaming::parse_resource_urls;
+/// use web_archive::ResourceUrl;
+///
+/// let base = Url::parse("http://example.com/blog/").unwrap();
+/// let resource_urls = parse_resource_urls(&base, r#"<img src="ferris.png">"#);
+/// assert_eq!(
+///     resource_urls,
+///     vec![ResourceUrl::Image(
+///         Url::parse("http://example.com/blog/ferris.png").unwrap()
+///     )]
+/// );
+/// ```
+pub fn parse_resource_urls(url_base: &Url, page: &str) -> Vec<ResourceUrl> {
+    let discovery = Discovery {
+        base: url_base,
+        urls: Vec::new(),
+        style: None,
+    };
+    let mut urls = tokenize(discovery, page).urls;
+    urls.sort();
+    urls.dedup();
+    urls
+}
+
+/// Write the page to `output` with the downloaded resources embedded,
+/// following the [`EmbedOptions`] except for those listed in the
+/// [module documentation](self)
+pub fn embed_resources_to<W: Write>(
+    archive: &PageArchive,
+    options: &EmbedOptions,
+    output: W,
+) -> Result<(), Error> {
+    embed_document(
+        archive,
+        &archive.content,
+        archive.url.clone(),
+        options,
+        vec![archive.url.clone()],
+        output,
+    )?;
+    Ok(())
+}
+
+/// Embed the resources of a document resolved against `base`, where
+/// `ancestors` lists the URLs of the documents containing it
+fn embed_document<W: Write>(
+    archive: &PageArchive,
+    html: &str,
+    base: Url,
+    options: &EmbedOptions,
+    ancestors: Vec<Url>,
+    output: W,
+) -> io::Result<W> {
+    let embedder = Embedder {
+        archive,
+        base,
+        options,
+        externals: Externals::new(None),
+        ancestors,
+        output,
+        error: None,
+        raw_text: false,
+        style: None,
+    };
+    let embedder = tokenize(embedder, html);
+    match embedder.error {
+        Some(e) => Err(e),
+        None => Ok(embedder.output),
+    }
+}
+
+/// Embed the resources of a nested document, e.g. that of a frame
+fn embed_nested(
+    archive: &PageArchive,
+    html: &str,
+    base: Url,
+    options: &EmbedOptions,
+    ancestors: Vec<Url>,
+) -> String {
+    let output =
+        embed_document(archive, html, base, options, ancestors, Vec::new())
+            .expect("writing to a Vec cannot fail");
+    String::from_utf8(output).expect("only strings are written")
+}
+
+/// Pass the page through the tokenizer a piece at a time
+fn tokenize<S: TokenSink<Handle = ()>>(sink: S, html: &str) -> S {
+    let mut tokenizer = Tokenizer::new(sink, TokenizerOpts::default());
+    let mut queue = BufferQueue::new();
+    let mut rest = html;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(CHUNK_SIZE);
+        while !rest.is_char_boundary(end) {
+            end += 1;
+        }
+        let (chunk, remainder) = rest.split_at(end);
+        queue.push_back(StrTendril::from_slice(chunk));
+        // Scripts are never run, so there is nothing to do between pieces
+        let _ = tokenizer.feed(&mut queue);
+        rest = remainder;
+    }
+    tokenizer.end();
+    tokenizer.sink
+}
+
+/// How the tokenizer should read the contents of an element. This would
+/// normally be decided by the tree builder.
+fn content_kind(name: &str) -> TokenSinkResult<()> {
+    match name {
+        "script" => TokenSinkResult::RawData(RawKind::ScriptData),
+        "style" | "xmp" | "iframe" | "noembed" | "noframes" | "noscript" => {
+            TokenSinkResult::RawData(RawKind::Rawtext)
+        }
+        "title" | "textarea" => TokenSinkResult::RawData(RawKind::Rcdata),
+        "plaintext" => TokenSinkResult::Plaintext,
+        _ => TokenSinkResult::Continue,
+    }
+}
+
+/// Constructor of the [`ResourceUrl`] of a kind of resource
+type ResourceUrlKind = fn(Url) -> ResourceUrl;
+
+/// The kind of resource a tag references and the attribute holding its
+/// URL, for the tags handled the same way by discovery and embedding.
+/// Frames are dealt with separately.
+fn tag_resource(tag: &Tag) -> Option<(ResourceUrlKind, &'static str)> {
+    match &*tag.name {
+        "img" => Some((ResourceUrl::Image, "src")),
+        "script" => Some((ResourceUrl::Javascript, "src")),
+        "link" => {
+            let rel = attr(tag, "rel")?;
+            let has_token =
+                |name| rel.split_ascii_whitespace().any(|token| token == name);
+            if is_stylesheet_rel(rel) {
+                Some((ResourceUrl::Css, "href"))
+            } else if has_token("modulepreload") {
+                Some((ResourceUrl::Javascript, "href"))
+            } else if rel == "manifest" || has_token("icon") {
+                Some((ResourceUrl::Other, "href"))
+            } else {
+                None
+            }
+        }
+        "audio" | "video" | "source" | "track" | "embed" => {
+            Some((ResourceUrl::Other, "src"))
+        }
+        "object" => Some((ResourceUrl::Other, "data")),
+        _ => None,
+    }
+}
+
+/// Whether the tag is a frame showing the document at its `src`
+fn is_frame(tag: &Tag) -> bool {
+    match &*tag.name {
+        "iframe" => attr(tag, "srcdoc").is_none(),
+        "frame" => true,
+        _ => false,
+    }
+}
+
+/// The value of an attribute of the tag
+fn attr<'t>(tag: &'t Tag, name: &str) -> Option<&'t str> {
+    tag.attrs
+        .iter()
+        .find(|attr| &*attr.name.local == name)
+        .map(|attr| &*attr.value)
+}
+
+/// Set the value of an attribute of the tag, adding it if necessary
+fn set_attr(tag: &mut Tag, name: &str, value: String) {
+    match tag.attrs.iter_mut().find(|attr| &*attr.name.local == name) {
+        Some(attr) => attr.value = value.into(),
+        None => tag.attrs.push(Attribute {
+            name: QualName::new(None, ns!(), LocalName::from(name)),
+            value: value.into(),
+        }),
+    }
+}
+
+/// Remove an attribute from the tag
+fn remove_attr(tag: &mut Tag, name: &str) {
+    tag.attrs.retain(|attr| &*attr.name.local != name);
+}
+
+/// Token sink collecting the resources referenced by a document
+struct Discovery<'a> {
+    base: &'a Url,
+    urls: Vec<ResourceUrl>,
+    /// Text of the `<style>` being read
+    style: Option<String>,
+}
+
+impl Discovery<'_> {
+    fn start_tag(&mut self, tag: &Tag) {
+        let base = self.base;
+        let join = |name| attr(tag, name).and_then(|u| base.join(u).ok());
+        if let Some((resource_url, name)) = tag_resource(tag) {
+            if let Some(url) = join(name) {
+                self.urls.push(resource_url(url));
+            }
+        }
+        if is_frame(tag) {
+            // Blank frames and inline documents need no download
+            if let Some(url) = join("src").filter(|url| {
+                !matches!(url.scheme(), "about" | "data" | "javascript")
+            }) {
+                self.urls.push(ResourceUrl::Html(url));
+            }
+        }
+        if let Some(style) = attr(tag, "style") {
+            self.urls.extend(css_resource_urls(self.base, style));
+        }
+        if &*tag.name == "iframe" {
+            if let Some(srcdoc) = attr(tag, "srcdoc") {
+                self.urls.extend(parse_resource_urls(self.base, srcdoc));
+            }
+        }
+        if &*tag.name == "style" {
+            self.style = Some(String::new());
+        }
+    }
+}
+
+impl TokenSink for Discovery<'_> {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::TagToken(tag) if tag.kind == TagKind::StartTag => {
+                self.start_tag(&tag);
+                return content_kind(&tag.name);
+            }
+            Token::TagToken(_) | Token::EOFToken => {
+                if let Some(css) = self.style.take() {
+                    self.urls.extend(css_resource_urls(self.base, &css));
+                }
+            }
+            Token::CharacterTokens(text) => {
+                if let Some(style) = &mut self.style {
+                    style.push_str(&text);
+                }
+            }
+            _ => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// Token sink writing a document with its resources embedded
+struct Embedder<'a, W> {
+    archive: &'a PageArchive,
+    base: Url,
+    options: &'a EmbedOptions,
+    externals: Externals<'a>,
+    ancestors: Vec<Url>,
+    output: W,
+    /// The first error writing the output, after which nothing more is
+    /// written
+    error: Option<io::Error>,
+    /// Whether the text being read is written as it is rather than
+    /// escaped, e.g. in a `<script>`
+    raw_text: bool,
+    /// Text of the `<style>` being read, which is written once its URLs
+    /// can be rewritten
+    style: Option<String>,
+}
+
+impl<'a, W: Write> Embedder<'a, W> {
+    fn write(&mut self, text: &str) {
+        if self.error.is_none() {
+            if let Err(e) = self.output.write_all(text.as_bytes()) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn write_escaped(&mut self, text: &str, attribute: bool) {
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '&' => escaped.push_str("&amp;"),
+                '\u{a0}' => escaped.push_str("&nbsp;"),
+                '"' if attribute => escaped.push_str("&quot;"),
+                '<' if !attribute => escaped.push_str("&lt;"),
+                '>' if !attribute => escaped.push_str("&gt;"),
+                c => escaped.push(c),
+            }
+        }
+        self.write(&escaped);
+    }
+
+    fn write_tag(&mut self, tag: &Tag) {
+        self.write("<");
+        self.write(&tag.name);
+        for attr in &tag.attrs {
+            self.write(" ");
+            self.write(&attr.name.local);
+            self.write("=\"");
+            self.write_escaped(&attr.value, true);
+            self.write("\"");
+        }
+        self.write(if tag.self_closing { "/>" } else { ">" });
+    }
+
+    fn write_doctype(&mut self, doctype: &Doctype) {
+        let mut declaration = String::from("<!DOCTYPE");
+        if let Some(name) = &doctype.name {
+            declaration.push(' ');
+            declaration.push_str(name);
+        }
+        match (&doctype.public_id, &doctype.system_id) {
+            (Some(public), Some(system)) => declaration
+                .push_str(&format!(" PUBLIC \"{}\" \"{}\"", public, system)),
+            (Some(public), None) => {
+                declaration.push_str(&format!(" PUBLIC \"{}\"", public))
+            }
+            (None, Some(system)) => {
+                declaration.push_str(&format!(" SYSTEM \"{}\"", system))
+            }
+            (None, None) => {}
+        }
+        declaration.push('>');
+        self.write(&declaration);
+    }
+
+    fn start_tag(&mut self, mut tag: Tag) -> TokenSinkResult<()> {
+        let archive = self.archive;
+        let options = self.options;
+
+        if let Some(style) = attr(&tag, "style") {
+            let css = archive.embed_css(
+                style,
+                &self.base,
+                options,
+                &mut self.externals,
+                &mut vec![],
+            );
+            if let Cow::Owned(css) = css {
+                set_attr(&mut tag, "style", css);
+            }
+        }
+
+        let mut script_text = None;
+        match tag_resource(&tag) {
+            Some((_, _)) if &*tag.name == "script" => {
+                script_text = self.script(&mut tag);
+            }
+            Some((resource_url, name)) => {
+                let url = attr(&tag, name).and_then(|u| self.base.join(u).ok());
+                if let Some(url) = url {
+                    match resource_url(url.clone()) {
+                        ResourceUrl::Css(_) => {
+                            if self.stylesheet(&mut tag, &url) {
+                                // Replaced with a `<style>`
+                                return TokenSinkResult::Continue;
+                            }
+                        }
+                        ResourceUrl::Javascript(_) => {
+                            if !self.modulepreload(&mut tag, &url) {
+                                return TokenSinkResult::Continue;
+                            }
+                        }
+                        ResourceUrl::Image(_) => {
+                            let reference = archive.binary_reference(
+                                &url,
+                                |r| r.kind() == Some(ResourceKind::Image),
+                                options,
+                                &mut self.externals,
+                            );
+                            if let Some(reference) = reference {
+                                set_attr(&mut tag, name, reference);
+                            }
+                        }
+                        _ => {
+                            let reference = archive.binary_reference(
+                                &url,
+                                is_binary,
+                                options,
+                                &mut self.externals,
+                            );
+                            if let Some(reference) = reference {
+                                set_attr(&mut tag, name, reference);
+                            }
+                        }
+                    }
+                }
+            }
+            None => {}
+        }
+
+        match &*tag.name {
+            "a" | "area" if options.absolutize_links => {
+                let url = attr(&tag, "href")
+                    .filter(|u| !u.starts_with('#'))
+                    .and_then(|u| self.base.join(u).ok());
+                if let Some(url) = url {
+                    set_attr(&mut tag, "href", url.to_string());
+                }
+            }
+            "iframe" if attr(&tag, "srcdoc").is_some() => {
+                let srcdoc = attr(&tag, "srcdoc").unwrap_or_default();
+                let nested = embed_nested(
+                    archive,
+                    srcdoc,
+                    self.base.clone(),
+                    options,
+                    self.ancestors.clone(),
+                );
+                set_attr(&mut tag, "srcdoc", nested);
+            }
+            _ if is_frame(&tag) => self.frame(&mut tag),
+            _ => {}
+        }
+
+        self.write_tag(&tag);
+        if let Some(text) = script_text {
+            self.write(text);
+        }
+        let kind = content_kind(&tag.name);
+        self.raw_text = !matches!(
+            kind,
+            TokenSinkResult::Continue
+                | TokenSinkResult::RawData(RawKind::Rcdata)
+        );
+        if &*tag.name == "style" {
+            self.style = Some(String::new());
+        }
+        kind
+    }
+
+    /// Point a `<link rel="modulepreload">` at the stored copy of its
+    /// module, returning whether it is kept. Preloading only helps when
+    /// the module is loaded from the same URL, so the preloads of
+    /// embedded modules are dropped, as when embedding with a DOM.
+    fn modulepreload(&mut self, tag: &mut Tag, url: &Url) -> bool {
+        let options = self.options;
+        let resource = self.archive.resource_map.get(url);
+        let reference = match options.rewrite_url(url, resource) {
+            Some(rewritten) => Some(rewritten),
+            None => match resource {
+                Some(resource @ Resource::Javascript(_)) => {
+                    match self.externals.place(url, resource, options) {
+                        Placement::External(path) => Some(path),
+                        _ => None,
+                    }
+                }
+                _ if options.keep_unfetched_script_src => Some(url.to_string()),
+                _ => None,
+            },
+        };
+        match reference {
+            Some(reference) => {
+                set_attr(tag, "href", reference);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Embed the stylesheet of a `<link>`, returning whether it has been
+    /// written as a `<style>` in place of the link
+    fn stylesheet(&mut self, tag: &mut Tag, url: &Url) -> bool {
+        let archive = self.archive;
+        let options = self.options;
+        let resource = archive.resource_map.get(url);
+        if let Some(rewritten) = options.rewrite_url(url, resource) {
+            set_attr(tag, "href", rewritten);
+            return false;
+        }
+        let switchable = is_switchable_stylesheet(
+            attr(tag, "rel").unwrap_or_default(),
+            attr(tag, "disabled").is_some(),
+        );
+        let placement = match resource {
+            Some(resource @ Resource::Css(css)) => {
+                match self.externals.place(url, resource, options) {
+                    Placement::Inline => {
+                        let css = archive.embed_css(
+                            css.text(),
+                            url,
+                            options,
+                            &mut self.externals,
+                            &mut vec![url.clone()],
+                        );
+                        match options.stylesheets {
+                            StylesheetEmbedding::Style if !switchable => {
+                                let mut style = new_tag("style");
+                                for name in STYLE_ATTRIBUTES.iter() {
+                                    if let Some(value) = attr(tag, name) {
+                                        set_attr(
+                                            &mut style,
+                                            name,
+                                            value.to_string(),
+                                        );
+                                    }
+                                }
+                                self.write_tag(&style);
+                                self.write(&css);
+                                self.write("</style>");
+                                return true;
+                            }
+                            _ => {
+                                set_attr(
+                                    tag,
+                                    "href",
+                                    encode_text_data_uri(
+                                        resource.mimetype(),
+                                        &css,
+                                    ),
+                                );
+                                return false;
+                            }
+                        }
+                    }
+                    placement => placement,
+                }
+            }
+            _ => Placement::Omitted,
+        };
+        match placement {
+            Placement::External(path) => set_attr(tag, "href", path),
+            Placement::Omitted if options.absolutize_unembedded => {
+                set_attr(tag, "href", url.to_string())
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Embed the script of a `<script>`, returning the text to write
+    /// after its start tag if it is inlined
+    fn script(&mut self, tag: &mut Tag) -> Option<&'a str> {
+        let archive = self.archive;
+        let options = self.options;
+        let mut embedded = None;
+        if let Some(url) = attr(tag, "src").and_then(|u| self.base.join(u).ok())
+        {
+            let resource = archive.resource_map.get(&url);
+            if let Some(rewritten) = options.rewrite_url(&url, resource) {
+                set_attr(tag, "src", rewritten);
+                return None;
+            }
+            let placement = match resource {
+                Some(resource @ Resource::Javascript(_)) => {
+                    self.externals.place(&url, resource, options)
+                }
+                _ => Placement::Omitted,
+            };
+            match (placement, archive.resource_map.get_javascript(&url)) {
+                (Placement::Inline, Some(script)) => {
+                    if options.scripts == ScriptEmbedding::DataUri {
+                        let uri = encode_text_data_uri(
+                            "text/javascript",
+                            script.text(),
+                        );
+                        set_attr(tag, "src", uri);
+                        return None;
+                    }
+                    embedded = Some(script.text());
+                }
+                (Placement::External(path), _) => {
+                    set_attr(tag, "src", path);
+                    return None;
+                }
+                _ if options.keep_unfetched_script_src => {
+                    set_attr(tag, "src", url.to_string());
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        // As with the DOM, the `src` is removed unless the options ask
+        // for the scripts which were not downloaded to keep theirs
+        if embedded.is_some() || !options.keep_unfetched_script_src {
+            remove_attr(tag, "src");
+        }
+        embedded
+    }
+
+    /// Embed the document shown in a frame
+    fn frame(&mut self, tag: &mut Tag) {
+        let archive = self.archive;
+        let options = self.options;
+        let url = match attr(tag, "src").map(|u| self.base.join(u)) {
+            Some(Ok(url)) => url,
+            _ => return,
+        };
+        let resource = archive.resource_map.get(&url);
+        if let Some(rewritten) = options.rewrite_url(&url, resource) {
+            set_attr(tag, "src", rewritten);
+            return;
+        }
+        let placement = match resource {
+            // A document which contains itself is left as a link
+            _ if self.ancestors.contains(&url) => Placement::Omitted,
+            Some(resource @ Resource::Html(html)) => {
+                match self.externals.place(&url, resource, options) {
+                    Placement::Inline => {
+                        let mut ancestors = self.ancestors.clone();
+                        ancestors.push(url.clone());
+                        let nested = embed_nested(
+                            archive,
+                            html.text(),
+                            url,
+                            options,
+                            ancestors,
+                        );
+                        if &*tag.name == "iframe" {
+                            remove_attr(tag, "src");
+                            set_attr(tag, "srcdoc", nested);
+                        } else {
+                            let uri = encode_text_data_uri(
+                                resource.mimetype(),
+                                &nested,
+                            );
+                            set_attr(tag, "src", uri);
+                        }
+                        return;
+                    }
+                    placement => placement,
+                }
+            }
+            _ => Placement::Omitted,
+        };
+        match placement {
+            Placement::External(path) => set_attr(tag, "src", path),
+            Placement::Omitted if options.absolutize_unembedded => {
+                set_attr(tag, "src", url.to_string())
+            }
+            _ => {}
+        }
+    }
+
+    fn end_style(&mut self) {
+        if let Some(css) = self.style.take() {
+            let css = self.archive.embed_css(
+                &css,
+                &self.base,
+                self.options,
+                &mut self.externals,
+                &mut vec![],
+            );
+            self.write(&css);
+        }
+    }
+}
+
+impl<W: Write> TokenSink for Embedder<'_, W> {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _: u64) -> TokenSinkResult<()> {
+        match token {
+            Token::DoctypeToken(doctype) => self.write_doctype(&doctype),
+            Token::TagToken(tag) if tag.kind == TagKind::StartTag => {
+                return self.start_tag(tag);
+            }
+            Token::TagToken(tag) => {
+                self.end_style();
+                self.raw_text = false;
+                self.write("</");
+                self.write(&tag.name);
+                self.write(">");
+            }
+            Token::CommentToken(comment) => {
+                self.write("<!--");
+                self.write(&comment);
+                self.write("-->");
+            }
+            Token::CharacterTokens(text) => match &mut self.style {
+                Some(style) => style.push_str(&text),
+                None if self.raw_text => self.write(&text),
+                None => self.write_escaped(&text, false),
+            },
+            Token::EOFToken => self.end_style(),
+            Token::NullCharacterToken | Token::ParseError(_) => {}
+        }
+        TokenSinkResult::Continue
+    }
+}
+
+/// A start tag with no attributes
+fn new_tag(name: &str) -> Tag {
+    Tag {
+        kind: TagKind::StartTag,
+        name: LocalName::from(name),
+        self_closing: false,
+        attrs: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::{ImageResource, ResourceMap};
+    use bytes::Bytes;
+
+    const PAGE: &str = r##"<!DOCTYPE html>
+<html><head>
+<title>Fish &amp; chips &lt;3</title>
+<link rel="stylesheet" href="css/main.css" media="print">
+<link rel="shortcut icon" href="favicon.ico">
+<link rel="manifest" href="app.webmanifest">
+<style>body { background: url(img/bg.png) }</style>
+<script src="js/app.js"></script>
+<script>if (a < b) { document.write("<img src=x.png>") }</script>
+</head>
+<body>
+<!-- a comment -->
+<p style="background: url('img/bg.png')">Caf&eacute;&nbsp;&quot;ok&quot;</p>
+<img src="img/a.png" alt="A &quot;quoted&quot; alt"><br/>
+<video src="media/a.mp4"></video><object data="doc.pdf"></object>
+<iframe src="frame.html"></iframe>
+<iframe srcdoc="<img src=img/a.png>"></iframe>
+<frame src="about:blank">
+<a href="/about">About</a> <a href="#top">Top</a>
+</body></html>"##;
+
+    fn archive() -> PageArchive {
+        let url = Url::parse("http://example.com/").unwrap();
+        let image = |data: &'static [u8]| {
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(data),
+                mimetype: "image/png".to_string(),
+            })
+        };
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(
+            url.join("css/main.css").unwrap(),
+            Resource::Css("a { b: url(../img/bg.png) }".into()),
+        );
+        resource_map.insert(url.join("img/bg.png").unwrap(), image(b"bg"));
+        resource_map.insert(url.join("img/a.png").unwrap(), image(b"a"));
+        resource_map.insert(
+            url.join("js/app.js").unwrap(),
+            Resource::Javascript("app()".into()),
+        );
+        resource_map.insert(
+            url.join("frame.html").unwrap(),
+            Resource::Html(r#"<iframe src="frame.html"></iframe>"#.into()),
+        );
+        PageArchive::from_parts(url, PAGE, resource_map).unwrap()
+    }
+
+    fn embed(archive: &PageArchive, options: &EmbedOptions) -> String {
+        let mut output = Vec::new();
+        embed_resources_to(archive, options, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_parse_resource_urls() {
+        let url = Url::parse("http://example.com/").unwrap();
+        assert_eq!(
+            parse_resource_urls(&url, PAGE),
+            crate::parse_resource_urls(&url, PAGE)
+        );
+        assert_eq!(parse_resource_urls(&url, PAGE).len(), 9);
+    }
+
+    #[test]
+    fn test_embed_resources_to() {
+        let output = embed(&archive(), &Default::default());
+
+        let bg = "url(\"data:image/png;base64,Ymc=\")";
+        assert!(output.starts_with("<!DOCTYPE html>\n<html><head>\n"));
+        assert!(output.contains("<title>Fish &amp; chips &lt;3</title>"));
+        assert!(output.contains(&format!(
+            r#"<style media="print">a {{ b: {} }}</style>"#,
+            bg
+        )));
+        assert!(
+            output.contains(r#"<link rel="shortcut icon" href="favicon.ico">"#)
+        );
+        assert!(output.contains(&format!(
+            "<style>body {{ background: {} }}</style>",
+            bg
+        )));
+        assert!(output.contains("<script>app()</script>"));
+        assert!(output.contains(
+            r#"<script>if (a < b) { document.write("<img src=x.png>") }</script>"#
+        ));
+        assert!(output.contains("<!-- a comment -->"));
+        assert!(output.contains(&format!(
+            r#"<p style="background: {}">Café&nbsp;"ok"</p>"#,
+            bg.replace('"', "&quot;")
+        )));
+        assert!(output.contains(
+            r#"<img src="data:image/png;base64,YQ==" alt="A &quot;quoted&quot; alt"><br/>"#
+        ));
+        assert!(output.contains(r#"<video src="media/a.mp4"></video>"#));
+        // The frame showing itself is not embedded again
+        assert!(output.contains(
+            r#"<iframe srcdoc="<iframe src=&quot;frame.html&quot;></iframe>"></iframe>"#
+        ));
+        assert!(output.contains(
+            r#"<iframe srcdoc="<img src=&quot;data:image/png;base64,YQ==&quot;>">"#
+        ));
+        assert!(output.contains(r#"<frame src="about:blank">"#));
+        assert!(output.contains(r#"<a href="/about">About</a>"#));
+    }
+
+    #[test]
+    fn test_embed_options() {
+        let options = EmbedOptions {
+            stylesheets: StylesheetEmbedding::DataUri,
+            scripts: ScriptEmbedding::DataUri,
+            absolutize_unembedded: true,
+            absolutize_links: true,
+            inline_size_limit: Some(16),
+            ..Default::default()
+        };
+        let output = embed(&archive(), &options);
+        // The stylesheet is too large to embed
+        assert!(output.contains(
+            r#"<link rel="stylesheet" href="http://example.com/css/main.css" media="print">"#
+        ));
+        assert!(output.contains(r#"href="http://example.com/favicon.ico""#));
+        assert!(output.contains(
+            r#"<script src="data:text/javascript;charset=utf-8;base64,YXBwKCk="></script>"#
+        ));
+        assert!(
+            output.contains(r#"<a href="http://example.com/about">About</a>"#)
+        );
+        assert!(output.contains(r##"<a href="#top">Top</a>"##));
+
+        let options = EmbedOptions {
+            keep_unfetched_script_src: true,
+            ..Default::default()
+        };
+        let archive = PageArchive::from_parts(
+            "http://example.com/",
+            r#"<script src="missing.js"></script><script src=""></script>"#,
+            ResourceMap::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            embed(&archive, &options),
+            r#"<script src="http://example.com/missing.js"></script><script src="http://example.com/"></script>"#
+        );
+        assert_eq!(
+            embed(&archive, &Default::default()),
+            "<script></script><script></script>"
+        );
+    }
+
+    #[test]
+    fn test_alternate_stylesheets() {
+        let content = r#"<link rel="stylesheet" href="light.css" title="Light"><link rel="alternate stylesheet" href="dark.css" title="Dark">"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        for name in ["light.css", "dark.css"] {
+            resource_map
+                .insert(url.join(name).unwrap(), Resource::Css("a{}".into()));
+        }
+        assert_eq!(parse_resource_urls(&url, content).len(), 2);
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+        assert_eq!(
+            embed(&archive, &Default::default()),
+            r#"<style title="Light">a{}</style><link rel="alternate stylesheet" href="data:text/css;charset=utf-8;base64,YXt9" title="Dark">"#
+        );
+    }
+
+    #[test]
+    fn test_modulepreload() {
+        let content = r#"<link rel="modulepreload" href="chunk.js"><link rel="modulepreload" href="missing.js"><script type="module" src="chunk.js"></script>"#;
+        let url = Url::parse("http://example.com/").unwrap();
+        let chunk = url.join("chunk.js").unwrap();
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(chunk.clone(), Resource::Javascript("a()".into()));
+        assert_eq!(
+            parse_resource_urls(&url, content),
+            vec![
+                ResourceUrl::Javascript(chunk),
+                ResourceUrl::Javascript(url.join("missing.js").unwrap()),
+            ]
+        );
+        let archive =
+            PageArchive::from_parts(url, content, resource_map).unwrap();
+        let output = embed(&archive, &Default::default());
+        assert!(!output.contains("modulepreload"));
+        let output = embed(
+            &archive,
+            &EmbedOptions {
+                keep_unfetched_script_src: true,
+                ..Default::default()
+            },
+        );
+        assert!(output.starts_with(
+            r#"<link rel="modulepreload" href="http://example.com/missing.js"><script"#
+        ));
+    }
+
+    #[test]
+    fn test_large_page() {
+        // Multi-byte characters straddle the pieces given to the tokenizer
+        let text = "é".repeat(CHUNK_SIZE);
+        let page = format!("<p>{}</p><img src=\"img/a.png\">", text);
+        let mut archive = archive();
+        archive.content = page;
+        let output = embed(&archive, &Default::default());
+        assert_eq!(
+            output,
+            format!("<p>{}</p><img src=\"data:image/png;base64,YQ==\">", text)
+        );
+        assert_eq!(
+            parse_resource_urls(&archive.url, &archive.content),
+            vec![ResourceUrl::Image(archive.url.join("img/a.png").unwrap())]
+        );
+    }
+
+    #[test]
+    fn test_write_error() {
+        struct Failing;
+        impl Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let result =
+            embed_resources_to(&archive(), &Default::default(), Failing);
+        assert!(matches!(result, Err(Error::IoError(_))));
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for extracting the visible text of a page
+
+use kuchiki::traits::TendrilSink;
+use kuchiki::{parse_html, NodeData, NodeRef};
+
+/// Elements whose contents are never shown
+const HIDDEN_ELEMENTS: [&str; 6] =
+    ["head", "script", "style", "noscript", "template", "iframe"];
+
+/// Elements displayed as blocks, whose text is put on lines of its own
+const BLOCK_ELEMENTS: [&str; 36] = [
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "caption",
+    "dd",
+    "details",
+    "dialog",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hgroup",
+    "hr",
+    "legend",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "tr",
+];
+
+/// Extract the visible text of an HTML document, with the contents of
+/// each block element on separate lines. Whitespace within each line is
+/// collapsed as a browser does, except that line breaks in `<pre>` are
+/// kept, and blank lines are removed.
+pub(crate) fn extract(html: &str) -> String {
+    node_text(&parse_html().one(html))
+}
+
+/// The visible text of a parsed document or element, laid out as by
+/// [`extract`]
+pub(crate) fn node_text(node: &NodeRef) -> String {
+    let mut text = String::new();
+    collect_text(node, false, &mut text);
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Append the text of a node to `text`, with a line break around each
+/// block element, leaving the whitespace to be normalised by the caller
+fn collect_text(node: &NodeRef, preformatted: bool, text: &mut String) {
+    let element = match node.data() {
+        NodeData::Text(contents) => {
+            text.extend(contents.borrow().chars().map(|c| match c {
+                '\n' if preformatted => '\n',
+                c if c.is_whitespace() => ' ',
+                c => c,
+            }));
+            return;
+        }
+        NodeData::Element(element) => element,
+        NodeData::Document(_) | NodeData::DocumentFragment => {
+            for child in node.children() {
+                collect_text(&child, preformatted, text);
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    let name = &*element.name.local;
+    if HIDDEN_ELEMENTS.contains(&name)
+        || element.attributes.borrow().contains("hidden")
+    {
+        return;
+    }
+    if name == "br" {
+        text.push('\n');
+        return;
+    }
+    // Table cells are laid out side by side, so only need separating
+    let separator = if BLOCK_ELEMENTS.contains(&name) {
+        Some('\n')
+    } else if name == "td" || name == "th" {
+        Some(' ')
+    } else {
+        None
+    };
+    text.extend(separator);
+    for child in node.children() {
+        collect_text(&child, preformatted || name == "pre", text);
+    }
+    text.extend(separator);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_extract_text() {
+        let html = r#"<!DOCTYPE html>
+<html><head><title>Ferris</title><style>p { color: red }</style></head>
+<body>
+  <!-- A comment -->
+  <h1>Ferris   the
+    crab</h1>
+  <p>Ferris is the <em>unofficial</em> mascot<br>of Rust.</p>
+  <script>document.write("Not text");</script>
+  <noscript>Enable scripts</noscript>
+  <div hidden>Secret</div>
+  <ul><li>Orange</li><li>Friendly</li></ul>
+  <table><tr><th>Legs</th><td>10</td></tr></table>
+  <pre>fn main() {
+    println!("Hello");
+}</pre>
+</body></html>"#;
+        assert_eq!(
+            extract(html),
+            "Ferris the crab\n\
+             Ferris is the unofficial mascot\n\
+             of Rust.\n\
+             Orange\n\
+             Friendly\n\
+             Legs 10\n\
+             fn main() {\n\
+             println!(\"Hello\");\n\
+             }"
+        );
+
+        assert_eq!(extract(""), "");
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for archiving pages served over a unix domain socket
+//!
+//! Local services, e.g. in containers, are often only reachable through
+//! a socket rather than a TCP port. A [`UnixSocketFetcher`] sends the
+//! requests for the URLs of one HTTP origin, `http://localhost` unless
+//! another is given, to the socket, so that a page and the resources it
+//! links with relative URLs are all fetched from the service, while
+//! those on other hosts are downloaded as usual.
+//!
+//! ```no_run
+//! use web_archive::archive_with_socket;
+//!
+//! # async fn archive_async() {
+//! let archive =
+//!     archive_with_socket("/run/app.sock", "/page", Default::default())
+//!         .await
+//!         .unwrap();
+//! println!("{}", archive.embed_resources());
+//! # }
+//! ```
+//!
+//! Each request is made on a new connection, without following
+//! redirects, and only available on unix platforms. Resources served
+//! with an error status are handled according to
+//! [`ArchiveOptions::failure_policy`](crate::ArchiveOptions::failure_policy).
+
+use crate::error::Error;
+use crate::fetcher::{FetchedResource, ResourceFetcher};
+use reqwest::StatusCode;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Url;
+
+/// A [`ResourceFetcher`] which requests the URLs of an origin from an
+/// HTTP server listening on a unix domain socket
+#[derive(Clone, Debug)]
+pub struct UnixSocketFetcher {
+    socket: PathBuf,
+    origin: Url,
+    timeout: Option<Duration>,
+    max_response_size: u64,
+}
+
+impl UnixSocketFetcher {
+    /// Origin whose URLs are requested from the socket by default
+    pub const DEFAULT_ORIGIN: &'static str = "http://localhost";
+
+    /// Time allowed for each read from or write to the socket by default
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Largest response read from the socket by default, in bytes
+    pub const DEFAULT_MAX_RESPONSE_SIZE: u64 = 256 * 1024 * 1024;
+
+    /// Request the URLs of [`UnixSocketFetcher::DEFAULT_ORIGIN`] from the
+    /// server listening on `socket`
+    pub fn new(socket: impl Into<PathBuf>) -> Self {
+        Self {
+            socket: socket.into(),
+            origin: Url::parse(Self::DEFAULT_ORIGIN).unwrap(),
+            timeout: Some(Self::DEFAULT_TIMEOUT),
+            max_response_size: Self::DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Request the URLs of another origin from the socket, e.g. the one
+    /// its pages use in absolute links
+    pub fn origin(mut self, origin: Url) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// Time allowed for each read from or write to the socket before the
+    /// request fails. A zero duration waits indefinitely.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// Largest response, including its headers, read from the socket
+    /// before the request fails, in bytes
+    pub fn max_response_size(mut self, size: u64) -> Self {
+        self.max_response_size = size;
+        self
+    }
+
+    /// The URL of a path on the server, such as `/page`
+    pub fn url(&self, path: &str) -> Result<Url, Error> {
+        self.origin.join(path).map_err(|e| {
+            Error::ParseError(format!("invalid path {}: {}", path, e))
+        })
+    }
+}
+
+impl ResourceFetcher for UnixSocketFetcher {
+    fn handles(&self, url: &Url) -> bool {
+        url.origin() == self.origin.origin()
+    }
+
+    fn fetch(&self, url: &Url) -> Result<Option<FetchedResource>, Error> {
+        let mut stream = UnixStream::connect(&self.socket).map_err(|e| {
+            Error::IoError(format!(
+                "could not connect to {}: {}",
+                self.socket.display(),
+                e
+            ))
+        })?;
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        stream.write_all(request(url).as_bytes())?;
+        let mut response = Vec::new();
+        // One byte more than the limit shows whether it was exceeded
+        stream
+            .take(self.max_response_size.saturating_add(1))
+            .read_to_end(&mut response)?;
+        if response.len() as u64 > self.max_response_size {
+            return Err(Error::IoError(format!(
+                "the response for {} is larger than {} bytes",
+                url, self.max_response_size
+            )));
+        }
+        parse_response(&response).map(Some)
+    }
+}
+
+/// The request for the URL, asking for the connection to be closed after
+/// the response so that its end is the end of the stream
+fn request(url: &Url) -> String {
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let host = &url[url::Position::BeforeHost..url::Position::AfterPort];
+    format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\
+         Connection: close\r\n\r\n",
+        target, host
+    )
+}
+
+/// Split a complete HTTP/1.1 response into its status and contents,
+/// skipping any interim responses such as `100 Continue`
+fn parse_response(mut response: &[u8]) -> Result<FetchedResource, Error> {
+    let malformed = || Error::ParseError("malformed HTTP response".to_string());
+    let (status, lines, body) = loop {
+        let head_length = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(malformed)?;
+        let head = std::str::from_utf8(&response[..head_length])
+            .map_err(|_| malformed())?;
+        let body = &response[head_length + 4..];
+
+        let mut lines = head.split("\r\n");
+        let status = lines
+            .next()
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|code| code.parse::<StatusCode>().ok())
+            .ok_or_else(malformed)?;
+        if !status.is_informational() {
+            break (status, lines, body);
+        }
+        response = body;
+    };
+    let mut content_type = None;
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-type" => content_type = Some(value.to_string()),
+            "content-length" => {
+                content_length =
+                    Some(value.parse::<usize>().map_err(|_| malformed())?)
+            }
+            "transfer-encoding" => {
+                chunked = value.to_ascii_lowercase().ends_with("chunked")
+            }
+            _ => {}
+        }
+    }
+
+    let data = if chunked {
+        decode_chunked(body).ok_or_else(malformed)?
+    } else if let Some(length) = content_length {
+        body.get(..length).ok_or_else(malformed)?.to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok(FetchedResource {
+        data: data.into(),
+        content_type,
+        status: Some(status),
+    })
+}
+
+/// Join the chunks of a body sent with `Transfer-Encoding: chunked`,
+/// ignoring any extensions and trailers
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = size.split(';').next()?.trim();
+        let size = usize::from_str_radix(size, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(data);
+        }
+        data.extend(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_request() {
+        let url = Url::parse("http://localhost:8080/page?id=1#top").unwrap();
+        assert_eq!(
+            request(&url),
+            "GET /page?id=1 HTTP/1.1\r\nHost: localhost:8080\r\n\
+             Accept: */*\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let fetched = parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\n\
+              Content-Length: 7\r\n\r\nbody {}",
+        )
+        .unwrap();
+        assert_eq!(fetched.status, Some(StatusCode::OK));
+        assert_eq!(fetched.content_type.as_deref(), Some("text/css"));
+        assert_eq!(fetched.data, "body {}");
+
+        let fetched = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4;ext=1\r\nbody\r\n3\r\n {}\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(fetched.content_type, None);
+        assert_eq!(fetched.data, "body {}");
+
+        let fetched =
+            parse_response(b"HTTP/1.0 404 Not Found\r\n\r\n").unwrap();
+        assert_eq!(fetched.status, Some(StatusCode::NOT_FOUND));
+
+        // Interim responses are followed by the final one
+        let fetched = parse_response(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 103 Early Hints\r\nLink: </style.css>\r\n\r\n\
+              HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        )
+        .unwrap();
+        assert_eq!(fetched.status, Some(StatusCode::OK));
+        assert_eq!(fetched.data, "ok");
+
+        assert!(matches!(
+            parse_response(b"HTTP/1.1 200 OK\r\n"),
+            Err(Error::ParseError(_))
+        ));
+        assert!(matches!(
+            parse_response(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n"
+            ),
+            Err(Error::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in [
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>Hi</p>",
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let length = stream.read(&mut request).unwrap();
+                requests.push(
+                    String::from_utf8_lossy(&request[..length]).to_string(),
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let fetcher = UnixSocketFetcher::new(&socket);
+        let page = fetcher.url("/page").unwrap();
+        assert_eq!(page.as_str(), "http://localhost/page");
+        assert!(fetcher.handles(&page));
+        assert!(!fetcher.handles(&Url::parse("https://localhost/").unwrap()));
+        assert!(!fetcher.handles(&Url::parse("http://example.com/").unwrap()));
+
+        let fetched = fetcher.fetch(&page).unwrap().unwrap();
+        assert_eq!(fetched.data, "<p>Hi</p>");
+        assert_eq!(fetched.content_type.as_deref(), Some("text/html"));
+        let missing = fetcher.url("missing.css").unwrap();
+        let fetched = fetcher.fetch(&missing).unwrap().unwrap();
+        assert_eq!(fetched.status, Some(StatusCode::NOT_FOUND));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /page HTTP/1.1\r\n"));
+        assert!(requests[1].starts_with("GET /missing.css HTTP/1.1\r\n"));
+
+        let fetcher = UnixSocketFetcher::new(dir.path().join("other.sock"));
+        assert!(matches!(fetcher.fetch(&page), Err(Error::IoError(_))));
+    }
+
+    #[test]
+    fn test_fetch_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            // Hold the connection open without responding
+            let (stream, _) = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_secs(2));
+            drop(stream);
+        });
+
+        let fetcher =
+            UnixSocketFetcher::new(&socket).timeout(Duration::from_millis(100));
+        let page = fetcher.url("/page").unwrap();
+        assert!(matches!(fetcher.fetch(&page), Err(Error::IoError(_))));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\n\r\n<p>Hello</p>")
+                    .unwrap();
+            }
+        });
+
+        // A zero timeout waits for as long as the server takes
+        let fetcher = UnixSocketFetcher::new(&socket).timeout(Duration::ZERO);
+        let page = fetcher.url("/page").unwrap();
+        let fetched = fetcher.fetch(&page).unwrap().unwrap();
+        assert_eq!(fetched.data, "<p>Hello</p>");
+
+        let fetcher = fetcher.max_response_size(20);
+        assert!(matches!(fetcher.fetch(&page), Err(Error::IoError(_))));
+        server.join().unwrap();
+    }
+
+    /// Serve a page linking a stylesheet and a missing script
+    fn serve(listener: UnixListener) {
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let length = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..length]);
+                let response = if request.starts_with("GET /page ") {
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n\
+                     <link rel=\"stylesheet\" href=\"style.css\">\
+                     <script src=\"missing.js\"></script>"
+                } else if request.starts_with("GET /style.css ") {
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/css\r\n\r\n\
+                     body {}"
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\n\
+                     \r\nNot Found"
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_archive_with_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("app.sock");
+        serve(UnixListener::bind(&socket).unwrap());
+
+        let archive = tokio_test::block_on(crate::archive_with_socket(
+            &socket,
+            "/page",
+            Default::default(),
+        ))
+        .unwrap();
+        assert_eq!(archive.url.as_str(), "http://localhost/page");
+        assert_eq!(archive.info.page.status, Some(StatusCode::OK));
+        let style = Url::parse("http://localhost/style.css").unwrap();
+        assert_eq!(
+            archive.resource_map.get(&style),
+            Some(&crate::Resource::Css("body {}".into()))
+        );
+        let missing = Url::parse("http://localhost/missing.js").unwrap();
+        assert!(!archive.resource_map.contains_key(&missing));
+
+        let options = crate::ArchiveOptions::builder()
+            .resource_failure(crate::FailurePolicy::Record)
+            .build()
+            .unwrap();
+        let archive = tokio_test::block_on(crate::archive_with_socket(
+            &socket, "/page", options,
+        ))
+        .unwrap();
+        assert!(matches!(
+            archive.resource_map.get(&missing),
+            Some(crate::Resource::Failed {
+                status: StatusCode::NOT_FOUND,
+                ..
+            })
+        ));
+        assert_eq!(
+            archive.info.resources[&missing].status,
+            Some(StatusCode::NOT_FOUND)
+        );
+
+        let options = crate::ArchiveOptions::builder()
+            .resource_failure(crate::FailurePolicy::Abort)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            tokio_test::block_on(crate::archive_with_socket(
+                &socket, "/page", options
+            )),
+            Err(Error::HttpStatus {
+                status: StatusCode::NOT_FOUND,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for interacting with the Internet Archive's Wayback Machine
+//!
+//! In addition to the fallback used by
+//! [`ArchiveOptions::wayback_fallback`](crate::ArchiveOptions::wayback_fallback),
+//! this module provides [`save_page_now`] for submitting an archived page
+//! to the Internet Archive, so that it is preserved publicly as well as
+//! locally.
+//!
+//! ```no_run
+//! use web_archive::archive;
+//! use web_archive::wayback::{save_page_now, SavePageNowOptions};
+//!
+//! # async fn archive_async() {
+//! let archive = archive("http://example.com", Default::default())
+//!     .await
+//!     .unwrap();
+//! let submitted = save_page_now(&archive, &SavePageNowOptions::default())
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+
+use crate::error::Error;
+use crate::page_archive::PageArchive;
+use crate::{client_builder, ArchiveOptions};
+use reqwest::header::{ACCEPT, AUTHORIZATION};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use url::Url;
+
+/// Endpoint of the Wayback Machine availability API
+const AVAILABILITY_API: &str = "https://archive.org/wayback/available";
+
+/// Endpoint of the Save Page Now API
+pub(crate) const SAVE_PAGE_NOW_API: &str = "https://web.archive.org/save";
+
+/// Options controlling submissions to the Internet Archive's Save Page
+/// Now service
+#[derive(Clone, Debug, Default)]
+pub struct SavePageNowOptions {
+    /// Also submit every resource in the archive's resource map, not
+    /// just the page itself. Save Page Now is heavily rate limited, so
+    /// resources which fail to submit are skipped rather than treated
+    /// as errors.
+    ///
+    /// Default: `false`
+    pub include_resources: bool,
+    /// Internet Archive S3-style API keys, available from
+    /// <https://archive.org/account/s3.php>. Pages are submitted
+    /// anonymously if no keys are provided.
+    ///
+    /// Default: `None`
+    pub credentials: Option<SavePageNowCredentials>,
+    /// Options for the client the submissions are sent with, so that
+    /// they take the same route as archiving, e.g. through
+    /// [`ArchiveOptions::proxy`] and with the same TLS settings
+    ///
+    /// Default: [`ArchiveOptions::default`]
+    pub client_options: ArchiveOptions,
+}
+
+/// Internet Archive API keys used to authenticate with Save Page Now
+#[derive(Clone, Debug)]
+pub struct SavePageNowCredentials {
+    /// The S3 access key
+    pub access_key: String,
+    /// The S3 secret key
+    pub secret_key: String,
+}
+
+impl SavePageNowCredentials {
+    /// Value of the `Authorization` header for these credentials
+    pub(crate) fn authorization(&self) -> String {
+        format!("LOW {}:{}", self.access_key, self.secret_key)
+    }
+}
+
+/// The URLs which should be submitted for an archive
+pub(crate) fn submission_urls(
+    archive: &PageArchive,
+    options: &SavePageNowOptions,
+) -> Vec<Url> {
+    let mut urls = vec![archive.url.clone()];
+    if options.include_resources {
+        let mut resources: Vec<Url> =
+            archive.resource_map.keys().cloned().collect();
+        resources.sort();
+        urls.extend(resources);
+    }
+    urls
+}
+
+/// Submit the page (and optionally its resources) to the Internet
+/// Archive's Save Page Now service, returning the URLs which were
+/// successfully submitted.
+///
+/// Failure to submit the page itself is returned as an error.
+pub async fn save_page_now(
+    archive: &PageArchive,
+    options: &SavePageNowOptions,
+) -> Result<Vec<Url>, Error> {
+    let client = client_builder(&options.client_options)?.build()?;
+    let mut submitted = Vec::new();
+
+    for (idx, url) in submission_urls(archive, options).into_iter().enumerate()
+    {
+        let request = match &options.credentials {
+            Some(credentials) => client
+                .post(SAVE_PAGE_NOW_API)
+                .header(ACCEPT, "application/json")
+                .header(AUTHORIZATION, credentials.authorization())
+                .form(&[("url", url.as_str())]),
+            None => client.get(format!("{}/{}", SAVE_PAGE_NOW_API, url)),
+        };
+        let result = request.send().await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                submitted.push(url)
+            }
+            // Only a failure to submit the page itself is an error
+            Ok(response) if idx == 0 => {
+                return Err(Error::HttpStatus {
+                    url,
+                    status: response.status(),
+                })
+            }
+            Err(e) if idx == 0 => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(submitted)
+}
+
+#[derive(Deserialize)]
+struct Availability {
+    archived_snapshots: Snapshots,
+}
+
+#[derive(Deserialize)]
+struct Snapshots {
+    closest: Option<Snapshot>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    available: bool,
+    url: String,
+    timestamp: String,
+}
+
+/// Build the availability API query for the given URL
+pub(crate) fn availability_url(url: &Url) -> Url {
+    let mut query = Url::parse(AVAILABILITY_API).unwrap();
+    query.query_pairs_mut().append_pair("url", url.as_str());
+    query
+}
+
+/// Parse an availability API response, returning the URL of the raw
+/// content of the closest snapshot if there is one.
+///
+/// The `id_` modifier is added after the snapshot timestamp so that the
+/// Wayback Machine returns the original bytes rather than a copy
+/// rewritten for its replay interface.
+pub(crate) fn snapshot_url(response: &[u8]) -> Option<Url> {
+    let availability: Availability = serde_json::from_slice(response).ok()?;
+    let snapshot = availability.archived_snapshots.closest?;
+    if !snapshot.available {
+        return None;
+    }
+
+    let marker = format!("/{}/", snapshot.timestamp);
+    let raw = snapshot.url.replacen(
+        &marker,
+        &format!("/{}id_/", snapshot.timestamp),
+        1,
+    );
+    Url::parse(&raw).ok()
+}
+
+/// Fetch the closest Wayback Machine snapshot of a URL. Any failure to
+/// locate or download a snapshot results in `None`.
+pub(crate) async fn fetch(
+    client: &reqwest::Client,
+    url: &Url,
+) -> Option<reqwest::Response> {
+    let response = client.get(availability_url(url)).send().await.ok()?;
+    let snapshot = snapshot_url(&response.bytes().await.ok()?)?;
+    let response = client.get(snapshot).send().await.ok()?;
+    if response.status() == StatusCode::OK {
+        Some(response)
+    } else {
+        None
+    }
+}
+
+/// Blocking version of [`fetch`]
+#[cfg(feature = "blocking")]
+pub(crate) fn fetch_blocking(
+    client: &reqwest::blocking::Client,
+    url: &Url,
+) -> Option<reqwest::blocking::Response> {
+    let response = client.get(availability_url(url)).send().ok()?;
+    let snapshot = snapshot_url(&response.bytes().ok()?)?;
+    let response = client.get(snapshot).send().ok()?;
+    if response.status() == StatusCode::OK {
+        Some(response)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::{Resource, ResourceMap};
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_submission_urls() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut resource_map = ResourceMap::new();
+        for resource in ["b.js", "a.css"].iter() {
+            resource_map.insert(
+                url.join(resource).unwrap(),
+                Resource::Javascript("".into()),
+            );
+        }
+        let archive = PageArchive {
+            url,
+            content: String::new(),
+            raw_content: None,
+            resource_map,
+            fetched_at: SystemTime::now(),
+            info: Default::default(),
+            metadata_cache: Default::default(),
+        };
+
+        let urls = submission_urls(&archive, &Default::default());
+        assert_eq!(urls, vec![archive.url.clone()]);
+
+        let options = SavePageNowOptions {
+            include_resources: true,
+            ..Default::default()
+        };
+        let urls: Vec<String> = submission_urls(&archive, &options)
+            .iter()
+            .map(Url::to_string)
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://example.com/",
+                "http://example.com/a.css",
+                "http://example.com/b.js"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_authorization() {
+        let credentials = SavePageNowCredentials {
+            access_key: "access".to_string(),
+            secret_key: "secret".to_string(),
+        };
+        assert_eq!(credentials.authorization(), "LOW access:secret");
+    }
+
+    #[test]
+    fn test_availability_url() {
+        let url = Url::parse("http://example.com/a.png?size=2&x=y").unwrap();
+        assert_eq!(
+            availability_url(&url).as_str(),
+            "https://archive.org/wayback/available\
+             ?url=http%3A%2F%2Fexample.com%2Fa.png%3Fsize%3D2%26x%3Dy"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_url() {
+        let response = br#"{
+            "url": "example.com/a.png",
+            "archived_snapshots": {
+                "closest": {
+                    "status": "200",
+                    "available": true,
+                    "url": "http://web.archive.org/web/20130919044612/http://example.com/a.png",
+                    "timestamp": "20130919044612"
+                }
+            }
+        }"#;
+        assert_eq!(
+            snapshot_url(response).unwrap().as_str(),
+            "http://web.archive.org/web/20130919044612id_/http://example.com/a.png"
+        );
+
+        let response = br#"{"url": "example.com", "archived_snapshots": {}}"#;
+        assert!(snapshot_url(response).is_none());
+        assert!(snapshot_url(b"not json").is_none());
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2021 David Young
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Module for exporting an archive as a browsable folder
+//!
+//! [`write_website`] writes the page to `index.html` and every resource
+//! it uses to `assets/`, named by the SHA-256 hash of its contents, with
+//! every reference rewritten to a relative path and no `data:` URIs.
+//! Stylesheets and the documents shown in frames are rewritten in the
+//! same way, so the folder can be opened straight from disk or served as
+//! a static site. Browsers load such a folder faster than one very large
+//! page with everything embedded, and it diffs cleanly under version
+//! control, as files only change when their contents do.
+//!
+//! ```no_run
+//! use web_archive::blocking;
+//! use web_archive::website::write_website;
+//!
+//! let archive =
+//!     blocking::archive("http://example.com", Default::default()).unwrap();
+//! write_website(&archive, &"example_site", &Default::default()).unwrap();
+//! ```
+
+use crate::embed::{finish_document, EmbedOptions};
+use crate::error::Error;
+use crate::page_archive::{Externals, PageArchive};
+use crate::parsing::Resource;
+use crate::spill::write_resource_file;
+use kuchiki::parse_html;
+use kuchiki::traits::TendrilSink;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// Directory of the website that resources are written to
+const ASSETS_DIR: &str = "assets";
+
+/// Write the page to `index.html` in the directory and its resources to
+/// `assets/`, processed with the given [`EmbedOptions`] except that
+/// nothing is embedded, so [`EmbedOptions::inline_size_limit`],
+/// [`stylesheets`](EmbedOptions::stylesheets) and
+/// [`scripts`](EmbedOptions::scripts) are ignored.
+///
+/// Resources which were not downloaded are left as they were, or made
+/// absolute if [`EmbedOptions::absolutize_unembedded`] is set, so that
+/// they are loaded from the original site.
+pub fn write_website<P: AsRef<Path>>(
+    archive: &PageArchive,
+    output_dir: &P,
+    options: &EmbedOptions,
+) -> Result<(), Error> {
+    let output_dir = output_dir.as_ref();
+    let assets_dir = output_dir.join(ASSETS_DIR);
+    fs::create_dir_all(&assets_dir)?;
+
+    let mut page_externals = Externals::external_only(ASSETS_DIR);
+    let content = archive.embed_into(options, &mut page_externals);
+    write_resources(
+        archive,
+        &assets_dir,
+        options,
+        &page_externals,
+        &mut HashSet::new(),
+    )?;
+
+    fs::write(output_dir.join("index.html"), content)?;
+    Ok(())
+}
+
+/// Write the resources which a page stores separately to `dir`, along
+/// with those they reference in turn. Stylesheets and the documents
+/// shown in frames are rewritten to reference their own resources from
+/// the same directory. Names already in `written` are skipped, and each
+/// name written is added to it.
+pub(crate) fn write_resources<'a>(
+    archive: &'a PageArchive,
+    dir: &Path,
+    options: &EmbedOptions,
+    page_externals: &Externals<'a>,
+    written: &mut HashSet<String>,
+) -> Result<(), Error> {
+    // Resources referenced from other resources are in the same directory
+    let mut asset_externals = Externals::external_only(".");
+    loop {
+        let pending: Vec<_> = page_externals
+            .files
+            .iter()
+            .chain(&asset_externals.files)
+            .filter(|(name, _)| !written.contains(*name))
+            .map(|(name, resource)| (name.clone(), *resource))
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+
+        for (name, resource) in pending {
+            let url = page_externals
+                .urls
+                .get(&name)
+                .or_else(|| asset_externals.urls.get(&name))
+                .cloned()
+                .expect("every stored resource has a URL");
+            let path = dir.join(&name);
+            match resource {
+                Resource::Css(css) => {
+                    let css = archive.embed_css(
+                        css.text(),
+                        &url,
+                        options,
+                        &mut asset_externals,
+                        &mut vec![url.clone()],
+                    );
+                    fs::write(path, css.as_bytes())?;
+                }
+                Resource::Html(html) => {
+                    let document = parse_html().one(html.text());
+                    archive.embed_document(
+                        &document,
+                        &url,
+                        options,
+                        &mut asset_externals,
+                        &mut vec![archive.url.clone(), url.clone()],
+                    );
+                    finish_document(&document, &url, options);
+                    fs::write(path, document.to_string())?;
+                }
+                _ => write_resource_file(&path, resource)?,
+            }
+            written.insert(name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parsing::{ImageResource, ResourceMap};
+    use bytes::Bytes;
+    use url::Url;
+
+    #[test]
+    fn test_write_website() {
+        let url = Url::parse("http://example.com/blog/").unwrap();
+        let png = || {
+            Resource::Image(ImageResource {
+                data: Bytes::from_static(b"\x89PNG\r\n\x1a\n"),
+                mimetype: "image/png".to_string(),
+            })
+        };
+        let mut resource_map = ResourceMap::new();
+        resource_map.insert(url.join("ferris.png").unwrap(), png());
+        resource_map.insert(
+            url.join("/empty.gif").unwrap(),
+            Resource::Image(ImageResource {
+                data: Bytes::new(),
+                mimetype: "image/gif".to_string(),
+            }),
+        );
+        resource_map.insert(
+            url.join("/css/style.css").unwrap(),
+            Resource::Css(
+                "@import 'fonts.css'; body { background: url(../bg.png) }"
+                    .into(),
+            ),
+        );
+        resource_map.insert(
+            url.join("/css/fonts.css").unwrap(),
+            Resource::Css("h1 { color: red }".into()),
+        );
+        resource_map.insert(url.join("/bg.png").unwrap(), png());
+        resource_map.insert(
+            url.join("frame.html").unwrap(),
+            Resource::Html(r#"<img src="ferris.png">"#.into()),
+        );
+        let archive = PageArchive::from_parts(
+            url.as_str(),
+            r#"<link rel="stylesheet" href="/css/style.css">
+            <img src="ferris.png"><img src="/empty.gif">
+            <iframe src="frame.html"></iframe>"#,
+            resource_map,
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        write_website(&archive, &dir.path(), &Default::default()).unwrap();
+
+        let read = |path: &str| fs::read_to_string(dir.path().join(path));
+        let index = read("index.html").unwrap();
+        assert!(!index.contains("data:"));
+        assert!(!index.contains("<style>"));
+
+        let reference = |content: &str, attribute: &str| {
+            let start = content.find(attribute).unwrap() + attribute.len();
+            let end = start + content[start..].find('"').unwrap();
+            content[start..end].to_string()
+        };
+        let stylesheet = reference(&index, "href=\"");
+        assert!(stylesheet.starts_with("assets/"));
+        let css = read(&stylesheet).unwrap();
+        assert!(css.contains(r#"@import "./"#));
+        assert!(css.contains(r#"url("./"#));
+
+        let frame = read(&reference(&index, "<iframe src=\"")).unwrap();
+        let image = reference(&frame, "src=\"");
+        assert!(image.starts_with("./"));
+        // The frame and the page share the stored image
+        assert_eq!(
+            format!("assets/{}", &image[2..]),
+            reference(&index, "src=\"")
+        );
+
+        // The identical images are stored once, and the empty one too
+        assert_eq!(
+            fs::read_dir(dir.path().join(ASSETS_DIR)).unwrap().count(),
+            5
+        );
+    }
+}